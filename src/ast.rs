@@ -6,6 +6,10 @@ use std::rc::Rc;
 use std::fmt;
 use num::Num;
 use chrono_tz::Tz;
+use table::{Interpolation, Table};
+use diagnostic::Location;
+use system::UnitSystem;
+use text_query::Span;
 
 #[derive(Debug, Clone)]
 pub enum SuffixOp {
@@ -17,6 +21,20 @@ pub enum SuffixOp {
     Newton,
 }
 
+/// Comparisons, like `1 mile > 1600 m`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Eq,
+    NotEq,
+    /// Equality up to the significant digits results are shown with,
+    /// or within the uncertainty of uncertain values, `~=`.
+    Approx,
+}
+
 #[derive(Debug, Clone)]
 pub enum DateToken {
     Literal(String),
@@ -28,24 +46,34 @@ pub enum DateToken {
     Error(String),
 }
 
+/// An expression. Names, numbers, calls and syntax errors from
+/// queries carry the part of the query they were read from, so that
+/// errors can point at it; ones from units files don't.
 #[derive(Debug, Clone)]
 pub enum Expr {
-    Unit(String),
+    Unit(String, Option<Span>),
     Quote(String),
-    Const(Num),
+    Const(Num, Option<Span>),
+    Imaginary(Num),
     Date(Vec<DateToken>),
     Frac(Box<Expr>, Box<Expr>),
     Mul(Vec<Expr>),
     Pow(Box<Expr>, Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
+    PlusMinus(Box<Expr>, Box<Expr>),
     Neg(Box<Expr>),
     Plus(Box<Expr>),
     Equals(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
     Suffix(SuffixOp, Box<Expr>),
     Of(String, Box<Expr>),
-    Call(String, Vec<Expr>),
-    Error(String),
+    /// A tabulated property at some value of its parameter, like
+    /// `density of water at 60 °C`.
+    OfAt(String, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>, Option<Span>),
+    Vector(Vec<Expr>),
+    Error(String, Option<Span>),
 }
 
 #[derive(Debug, Clone)]
@@ -61,6 +89,11 @@ pub enum Conversion {
     List(Vec<String>),
     Offset(i64),
     Timezone(Tz),
+    Interval,
+    Polar,
+    Composition,
+    System(UnitSystem),
+    Alternatives,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -77,7 +110,34 @@ pub enum Query {
     Factorize(Expr),
     UnitsFor(Expr),
     Search(String),
-    Error(String),
+    Vars,
+    Unset(String),
+    Function(String, FunctionDef),
+    Balance(Reaction),
+    Stoichiometry(Expr, Reaction, Expr),
+    /// Shows how the result of a query was derived.
+    Explain(Box<Query>),
+    /// Checks that a comparison holds, optionally `within` a relative
+    /// or absolute tolerance.
+    Assert(Expr, Option<Expr>),
+    Error(String, Option<Span>),
+}
+
+/// A chemical reaction like `CH4 + 2 O2 -> CO2 + 2 H2O`, with the
+/// coefficient of each formula where one is given.
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub reactants: Vec<(Option<u64>, String)>,
+    pub products: Vec<(Option<u64>, String)>,
+}
+
+/// A user-defined function, such as `f(x, y) := x^2 / y`, optionally
+/// with the units its result must conform to.
+#[derive(Debug, Clone)]
+pub struct FunctionDef {
+    pub params: Vec<String>,
+    pub body: Expr,
+    pub result: Option<Expr>,
 }
 
 #[derive(Debug, Clone)]
@@ -91,6 +151,41 @@ pub enum DatePattern {
     Space,
 }
 
+/// The interval a nonlinear unit is defined on, as given by the
+/// `domain=[a,b)` and `range=(a,)` options. `true` marks a closed end.
+#[derive(Debug, Clone)]
+pub struct Bounds {
+    pub lower: Option<(Expr, bool)>,
+    pub upper: Option<(Expr, bool)>,
+}
+
+/// A nonlinear unit in the GNU units syntax,
+/// `name(x) units=[in;out] forward ; inverse`. The inverse refers to
+/// the value being converted by `name`, which stays the same when the
+/// unit is aliased.
+#[derive(Debug, Clone)]
+pub struct NonlinearDef {
+    pub name: String,
+    pub param: String,
+    pub input: Option<Expr>,
+    pub output: Option<Expr>,
+    pub domain: Option<Bounds>,
+    pub range: Option<Bounds>,
+    pub forward: Expr,
+    pub inverse: Option<Expr>,
+}
+
+/// A piecewise linear unit in the GNU units syntax,
+/// `name[in;out] x1 y1, x2 y2, ...`, which is interpolated in both
+/// directions. The input units default to dimensionless.
+#[derive(Debug, Clone)]
+pub struct TableDef {
+    pub name: String,
+    pub input: Option<Expr>,
+    pub output: Option<Expr>,
+    pub table: Table,
+}
+
 #[derive(Debug)]
 pub struct Property {
     pub name: String,
@@ -101,6 +196,19 @@ pub struct Property {
     pub doc: Option<String>,
 }
 
+/// A property given as a table against some parameter, such as
+/// `density table temperature K (g/cm^3) spline  273.15 0.99984 ...`.
+#[derive(Debug)]
+pub struct PropertyTable {
+    pub name: String,
+    pub param_name: String,
+    pub param_unit: Expr,
+    pub unit: Expr,
+    pub interpolation: Interpolation,
+    pub points: Vec<(Num, Num)>,
+    pub doc: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum Def {
     Dimension,
@@ -111,9 +219,18 @@ pub enum Def {
     Quantity(Expr),
     Substance {
         symbol: Option<String>,
-        properties: Vec<Property>
+        properties: Vec<Property>,
+        tables: Vec<PropertyTable>,
     },
     Category(String),
+    Function(FunctionDef),
+    Nonlinear(NonlinearDef),
+    Table(TableDef),
+    Logarithmic {
+        step: Expr,
+        field: bool,
+        reference: Option<Expr>,
+    },
     Error(String),
 }
 
@@ -123,6 +240,8 @@ pub struct DefEntry {
     pub def: Rc<Def>,
     pub doc: Option<String>,
     pub category: Option<String>,
+    /// Where the definition was read from, for diagnostics.
+    pub location: Option<Location>,
 }
 
 #[derive(Debug)]
@@ -153,6 +272,25 @@ impl fmt::Display for Conversion {
                 write!(fmt, "{:02}:{:02}", off / 3600, (off / 60) % 60),
             Conversion::Timezone(ref tz) =>
                 write!(fmt, "{:?}", tz),
+            Conversion::Interval => write!(fmt, "interval"),
+            Conversion::Polar => write!(fmt, "polar"),
+            Conversion::Composition => write!(fmt, "composition"),
+            Conversion::System(system) => write!(fmt, "{}", system),
+            Conversion::Alternatives => write!(fmt, "alternatives"),
+        }
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompareOp::Less => write!(fmt, "<"),
+            CompareOp::LessEq => write!(fmt, "<="),
+            CompareOp::Greater => write!(fmt, ">"),
+            CompareOp::GreaterEq => write!(fmt, ">="),
+            CompareOp::Eq => write!(fmt, "=="),
+            CompareOp::NotEq => write!(fmt, "!="),
+            CompareOp::Approx => write!(fmt, "~="),
         }
     }
 }
@@ -193,12 +331,20 @@ impl fmt::Display for Expr {
                 }}
             }
             match *expr {
-                Expr::Unit(ref name) => write!(fmt, "{}", name),
+                Expr::Unit(ref name, _) => write!(fmt, "{}", name),
                 Expr::Quote(ref name) => write!(fmt, "'{}'", name),
-                Expr::Const(ref num) => {
+                Expr::Const(ref num, _) => {
                     let (_exact, val) = ::number::to_string(num, 10, Digits::Default);
                     write!(fmt, "{}", val)
                 },
+                Expr::Imaginary(ref num) => {
+                    let (_exact, val) = ::number::to_string(num, 10, Digits::Default);
+                    if val == "1" {
+                        write!(fmt, "i")
+                    } else {
+                        write!(fmt, "{}i", val)
+                    }
+                },
                 Expr::Date(ref _date) => write!(fmt, "NYI: date expr Display"),
                 Expr::Mul(ref exprs) => {
                     if prec < Prec::Mul {
@@ -216,7 +362,7 @@ impl fmt::Display for Expr {
                     }
                     Ok(())
                 },
-                Expr::Call(ref name, ref args) => {
+                Expr::Call(ref name, ref args, _) => {
                     try!(write!(fmt, "{}(", name));
                     if let Some(first) = args.first() {
                         try!(recurse(first, fmt, Prec::Equals));
@@ -227,10 +373,23 @@ impl fmt::Display for Expr {
                     }
                     write!(fmt, ")")
                 },
+                Expr::Vector(ref elems) => {
+                    try!(write!(fmt, "["));
+                    if let Some(first) = elems.first() {
+                        try!(recurse(first, fmt, Prec::Equals));
+                    }
+                    for elem in elems.iter().skip(1) {
+                        try!(write!(fmt, ", "));
+                        try!(recurse(elem, fmt, Prec::Equals));
+                    }
+                    write!(fmt, "]")
+                },
                 Expr::Pow(ref left, ref right) => binop!(left, right, Prec::Pow, Prec::Term, "^"),
                 Expr::Frac(ref left, ref right) => binop!(left, right, Prec::Div, Prec::Mul, " / "),
                 Expr::Add(ref left, ref right) => binop!(left, right, Prec::Add, Prec::Div, " + "),
                 Expr::Sub(ref left, ref right) => binop!(left, right, Prec::Add, Prec::Div, " - "),
+                Expr::PlusMinus(ref left, ref right) =>
+                    binop!(left, right, Prec::Add, Prec::Div, " ± "),
                 Expr::Plus(ref expr) => {
                     try!(write!(fmt, "+"));
                     recurse(expr, fmt, Prec::Plus)
@@ -240,6 +399,18 @@ impl fmt::Display for Expr {
                     recurse(expr, fmt, Prec::Plus)
                 },
                 Expr::Equals(ref left, ref right) => binop!(left, right, Prec::Equals, Prec::Add, " = "),
+                Expr::Compare(op, ref left, ref right) => {
+                    if prec < Prec::Equals {
+                        try!(write!(fmt, "("));
+                    }
+                    try!(recurse(left, fmt, Prec::Add));
+                    try!(write!(fmt, " {} ", op));
+                    try!(recurse(right, fmt, Prec::Add));
+                    if prec < Prec::Equals {
+                        try!(write!(fmt, ")"));
+                    }
+                    Ok(())
+                },
                 Expr::Suffix(ref op, ref expr) => {
                     if prec < Prec::Mul {
                         try!(write!(fmt, "("));
@@ -262,7 +433,20 @@ impl fmt::Display for Expr {
                     }
                     Ok(())
                 },
-                Expr::Error(ref err) => write!(fmt, "<error: {}>", err)
+                Expr::OfAt(ref field, ref expr, ref at) => {
+                    if prec < Prec::Add {
+                        try!(write!(fmt, "("));
+                    }
+                    try!(write!(fmt, "{} of ", field));
+                    try!(recurse(expr, fmt, Prec::Div));
+                    try!(write!(fmt, " at "));
+                    try!(recurse(at, fmt, Prec::Div));
+                    if prec < Prec::Add {
+                        try!(write!(fmt, ")"));
+                    }
+                    Ok(())
+                },
+                Expr::Error(ref err, _) => write!(fmt, "<error: {}>", err)
             }
         }
 
@@ -289,6 +473,167 @@ impl fmt::Display for DatePattern {
     }
 }
 
+/// Replaces every occurrence of the named parameters in an expression
+/// with the corresponding argument. The spans of the expression are
+/// dropped, since they belong to a different query than the arguments.
+pub fn substitute(expr: &Expr, params: &[String], args: &[Expr]) -> Expr {
+    let rec = |x: &Expr| Box::new(substitute(x, params, args));
+    match *expr {
+        Expr::Unit(ref name, _) => match params.iter().position(|x| x == name) {
+            Some(i) => args[i].clone(),
+            None => Expr::Unit(name.clone(), None),
+        },
+        Expr::Const(ref num, _) => Expr::Const(num.clone(), None),
+        Expr::Error(ref err, _) => Expr::Error(err.clone(), None),
+        Expr::Quote(_) | Expr::Imaginary(_) | Expr::Date(_) =>
+            expr.clone(),
+        Expr::Frac(ref left, ref right) => Expr::Frac(rec(left), rec(right)),
+        Expr::Mul(ref exprs) => Expr::Mul(
+            exprs.iter().map(|x| substitute(x, params, args)).collect()),
+        Expr::Pow(ref left, ref right) => Expr::Pow(rec(left), rec(right)),
+        Expr::Add(ref left, ref right) => Expr::Add(rec(left), rec(right)),
+        Expr::Sub(ref left, ref right) => Expr::Sub(rec(left), rec(right)),
+        Expr::PlusMinus(ref left, ref right) => Expr::PlusMinus(rec(left), rec(right)),
+        Expr::Neg(ref expr) => Expr::Neg(rec(expr)),
+        Expr::Plus(ref expr) => Expr::Plus(rec(expr)),
+        Expr::Equals(ref left, ref right) => Expr::Equals(rec(left), rec(right)),
+        Expr::Compare(op, ref left, ref right) => Expr::Compare(op, rec(left), rec(right)),
+        Expr::Suffix(ref op, ref expr) => Expr::Suffix(op.clone(), rec(expr)),
+        Expr::Of(ref name, ref expr) => Expr::Of(name.clone(), rec(expr)),
+        Expr::OfAt(ref name, ref expr, ref at) => Expr::OfAt(name.clone(), rec(expr), rec(at)),
+        Expr::Call(ref name, ref exprs, _) => Expr::Call(
+            name.clone(),
+            exprs.iter().map(|x| substitute(x, params, args)).collect(),
+            None),
+        Expr::Vector(ref exprs) => Expr::Vector(
+            exprs.iter().map(|x| substitute(x, params, args)).collect()),
+    }
+}
+
+/// Collects the names of the units an expression refers to, in
+/// order of appearance.
+pub fn unit_names(expr: &Expr, out: &mut Vec<String>) {
+    match *expr {
+        Expr::Unit(ref name, _) => out.push(name.clone()),
+        Expr::Quote(_) | Expr::Const(_, _) | Expr::Imaginary(_) |
+        Expr::Date(_) | Expr::Error(_, _) => (),
+        Expr::Frac(ref left, ref right) | Expr::Pow(ref left, ref right) |
+        Expr::Add(ref left, ref right) | Expr::Sub(ref left, ref right) |
+        Expr::PlusMinus(ref left, ref right) | Expr::Equals(ref left, ref right) |
+        Expr::Compare(_, ref left, ref right) | Expr::OfAt(_, ref left, ref right) => {
+            unit_names(left, out);
+            unit_names(right, out);
+        },
+        Expr::Neg(ref expr) | Expr::Plus(ref expr) | Expr::Suffix(_, ref expr) |
+        Expr::Of(_, ref expr) => unit_names(expr, out),
+        Expr::Mul(ref exprs) | Expr::Call(_, ref exprs, _) | Expr::Vector(ref exprs) => {
+            for expr in exprs {
+                unit_names(expr, out);
+            }
+        },
+    }
+}
+
+fn join(a: Option<Span>, b: Option<Span>) -> Option<Span> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.join(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+impl Expr {
+    /// The part of the query the expression was read from, as far as
+    /// it is known from the names, numbers and calls in it.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Expr::Unit(_, span) | Expr::Const(_, span) | Expr::Call(_, _, span) |
+            Expr::Error(_, span) => span,
+            Expr::Quote(_) | Expr::Imaginary(_) | Expr::Date(_) => None,
+            Expr::Frac(ref left, ref right) | Expr::Pow(ref left, ref right) |
+            Expr::Add(ref left, ref right) | Expr::Sub(ref left, ref right) |
+            Expr::PlusMinus(ref left, ref right) | Expr::Equals(ref left, ref right) |
+            Expr::Compare(_, ref left, ref right) | Expr::OfAt(_, ref left, ref right) =>
+                join(left.span(), right.span()),
+            Expr::Neg(ref expr) | Expr::Plus(ref expr) | Expr::Suffix(_, ref expr) |
+            Expr::Of(_, ref expr) => expr.span(),
+            Expr::Mul(ref exprs) | Expr::Vector(ref exprs) =>
+                exprs.iter().fold(None, |acc, x| join(acc, x.span())),
+        }
+    }
+}
+
+impl Query {
+    /// The part of the query that its expressions were read from, or
+    /// where a syntax error was found.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Query::Expr(ref expr) | Query::Factorize(ref expr) | Query::UnitsFor(ref expr) |
+            Query::Assert(ref expr, None) => expr.span(),
+            Query::Convert(ref expr, Conversion::Expr(ref bottom), _, _) |
+            Query::Assert(ref expr, Some(ref bottom)) |
+            Query::Stoichiometry(ref expr, _, ref bottom) => join(expr.span(), bottom.span()),
+            Query::Convert(ref expr, _, _, _) => expr.span(),
+            Query::Explain(ref query) => query.span(),
+            Query::Error(_, span) => span,
+            _ => None,
+        }
+    }
+}
+
+impl FunctionDef {
+    /// Returns the body of the function with every parameter replaced
+    /// by the corresponding argument.
+    pub fn apply(&self, args: &[Expr]) -> Expr {
+        substitute(&self.body, &self.params, args)
+    }
+}
+
+impl Reaction {
+    /// The formulas of the reactants followed by those of the products.
+    pub fn formulas(&self) -> Vec<&str> {
+        self.reactants.iter().chain(self.products.iter()).map(|x| &*x.1).collect()
+    }
+}
+
+impl fmt::Display for Reaction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fn side(species: &[(Option<u64>, String)]) -> String {
+            species.iter().map(|&(coefficient, ref formula)| match coefficient {
+                Some(n) => format!("{} {}", n, formula),
+                None => formula.clone(),
+            }).collect::<Vec<_>>().join(" + ")
+        }
+        write!(fmt, "{} -> {}", side(&self.reactants), side(&self.products))
+    }
+}
+
+impl fmt::Display for Bounds {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.lower {
+            Some((ref x, true)) => try!(write!(fmt, "[{}", x)),
+            Some((ref x, false)) => try!(write!(fmt, "({}", x)),
+            None => try!(write!(fmt, "(")),
+        }
+        try!(write!(fmt, ", "));
+        match self.upper {
+            Some((ref x, true)) => write!(fmt, "{}]", x),
+            Some((ref x, false)) => write!(fmt, "{})", x),
+            None => write!(fmt, ")"),
+        }
+    }
+}
+
+impl fmt::Display for FunctionDef {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(fmt, "({}) := {}", self.params.join(", "), self.body));
+        if let Some(ref result) = self.result {
+            try!(write!(fmt, " -> {}", result));
+        }
+        Ok(())
+    }
+}
+
 pub fn show_datepattern(pat: &[DatePattern]) -> String {
     use std::io::Write;
 
@@ -324,15 +669,33 @@ mod test {
 
     impl From<i64> for Expr {
         fn from(x: i64) -> Self {
-            Const(x.into())
+            Const(x.into(), None)
         }
     }
 
     #[test]
     fn test_display_call() {
-        check(Call("f".into(), vec![]), "f()");
-        check(Call("f".into(), vec![1.into()]), "f(1)");
-        check(Call("f".into(), vec![1.into(), 2.into()]), "f(1, 2)");
-        check(Call("f".into(), vec![1.into(), 2.into(), 3.into()]), "f(1, 2, 3)");
+        check(Call("f".into(), vec![], None), "f()");
+        check(Call("f".into(), vec![1.into()], None), "f(1)");
+        check(Call("f".into(), vec![1.into(), 2.into()], None), "f(1, 2)");
+        check(Call("f".into(), vec![1.into(), 2.into(), 3.into()], None), "f(1, 2, 3)");
+    }
+
+    #[test]
+    fn test_apply_function() {
+        use super::FunctionDef;
+
+        let f = FunctionDef {
+            params: vec!["x".into(), "y".into()],
+            body: Frac(
+                Box::new(Pow(Box::new(Unit("x".into(), None)), Box::new(2.into()))),
+                Box::new(Mul(vec![Unit("y".into(), None), Unit("z".into(), None)]))),
+            result: None,
+        };
+        check(&f, "(x, y) := x^2 / y z");
+        check(f.apply(&[
+            Add(Box::new(1.into()), Box::new(2.into())),
+            Unit("m".into(), None),
+        ]), "(1 + 2)^2 / m z");
     }
 }
@@ -8,19 +8,67 @@ extern crate linefeed;
 
 use std::fs::File;
 use std::io::{BufRead, BufReader, stdin};
+use std::path::PathBuf;
 
 use rink::*;
+use rink::config::{Config, Color, parse_digits, parse_system};
+use rink::text_query::Span;
 
-fn main_noninteractive<T: BufRead>(mut f: T, show_prompt: bool) {
-    use std::io::{stdout, Write};
-
-    let mut ctx = match load() {
-        Ok(ctx) => ctx,
+/// Loads the units files named in the config and applies its
+/// settings. Problems with them go to stderr when stdout is reserved
+/// for JSON replies.
+fn load_context(config: &Config, json: bool) -> Option<Context> {
+    let report = |message: String| if json {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    };
+    match load_with_units(&config.units, config.currency) {
+        Ok((mut ctx, diagnostics)) => {
+            for diagnostic in diagnostics {
+                report(diagnostic.to_string());
+            }
+            config.apply(&mut ctx);
+            Some(ctx)
+        },
         Err(e) => {
-            println!("{}", e);
-            return
+            report(e);
+            None
         }
+    }
+}
+
+/// Prints the result of a query. Errors are followed by the query
+/// with the part that they are about underlined.
+fn print_reply(line: &str, reply: Result<String, (String, Option<Span>)>, color: bool) {
+    let paint = |text: &str| if color {
+        format!("\x1b[31m{}\x1b[0m", text)
+    } else {
+        text.to_owned()
     };
+    match reply {
+        Ok(v) => println!("{}", v),
+        Err((e, span)) => {
+            println!("{}", paint(&*e));
+            if let Some(span) = span {
+                let line = line.trim();
+                println!("    {}", line);
+                println!("    {}", paint(&*span.underline(line)));
+            }
+        },
+    }
+}
+
+/// Evaluates queries line by line, printing each reply as text or as
+/// one line of JSON.
+fn main_noninteractive<T: BufRead>(mut f: T, show_prompt: bool, json: bool, config: &Config) {
+    use std::io::{stdout, Write};
+
+    let mut ctx = match load_context(config, json) {
+        Some(ctx) => ctx,
+        None => std::process::exit(1)
+    };
+    let color = config.color == Color::Always;
     let mut line = String::new();
     loop {
         if show_prompt {
@@ -38,16 +86,17 @@ fn main_noninteractive<T: BufRead>(mut f: T, show_prompt: bool) {
             Some(_) => (),
             None => return
         }
-        match one_line(&mut ctx, &*line) {
-            Ok(v) => println!("{}", v),
-            Err(e) => println!("{}", e)
-        };
+        if json {
+            println!("{}", json_reply::to_json(&eval_spanned(&mut ctx, &*line)).dump());
+        } else {
+            print_reply(&*line, one_line_spanned(&mut ctx, &*line), color);
+        }
         line.clear();
     }
 }
 
 #[cfg(feature = "linefeed")]
-fn main_interactive() {
+fn main_interactive(config: &Config) {
     use linefeed::{Reader, ReadResult, Suffix, Terminal, Completer, Completion};
     use std::rc::Rc;
     use std::cell::RefCell;
@@ -58,7 +107,7 @@ fn main_interactive() {
             // e.g. it being a pipe instead of a tty, use the noninteractive version
             // with prompt instead.
             let stdin_handle = stdin();
-            return main_noninteractive(stdin_handle.lock(), true);
+            return main_noninteractive(stdin_handle.lock(), true, false, config);
         },
         Ok(rl) => rl
     };
@@ -206,13 +255,11 @@ fn main_interactive() {
         }
     }
 
-    let ctx = match load() {
-        Ok(ctx) => ctx,
-        Err(e) => {
-            println!("{}", e);
-            return
-        }
+    let ctx = match load_context(config, false) {
+        Some(ctx) => ctx,
+        None => return
     };
+    let color = config.color != Color::Never;
     let ctx = Rc::new(RefCell::new(ctx));
     let completer = RinkCompleter(ctx.clone());
     rl.set_completer(Rc::new(completer));
@@ -245,10 +292,7 @@ fn main_interactive() {
             },
             Ok(ReadResult::Input(line)) => {
                 rl.add_history(line.clone());
-                match one_line(&mut *ctx.borrow_mut(), &*line) {
-                    Ok(v) => println!("{}", v),
-                    Err(e) => println!("{}", e)
-                };
+                print_reply(&*line, one_line_spanned(&mut *ctx.borrow_mut(), &*line), color);
             },
             Ok(ReadResult::Eof) => {
                 println!("");
@@ -273,22 +317,87 @@ fn main_interactive() {
 // If we aren't compiling with linefeed support we should just call the
 // noninteractive version
 #[cfg(not(feature = "linefeed"))]
-fn main_interactive() {
+fn main_interactive(config: &Config) {
     let stdin = stdin();
-    main_noninteractive(stdin.lock(), true);
+    main_noninteractive(stdin.lock(), true, false, config);
+}
+
+/// Lints a units file, printing the problems found in it. Exits with
+/// an error if any of them are errors rather than warnings.
+fn main_check(name: &str, config: &Config) {
+    use std::io::Read;
+    use rink::diagnostic::Severity;
+
+    let mut input = String::new();
+    if let Err(e) = File::open(name).and_then(|mut f| f.read_to_string(&mut input)) {
+        eprintln!("Could not open units file '{}': {}", name, e);
+        std::process::exit(1);
+    }
+    let diagnostics = check(name, &*input, config.currency).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics.iter().filter(|x| x.severity == Severity::Error).count();
+    println!("{}: {} errors, {} warnings", name, errors, diagnostics.len() - errors);
+    if errors > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Runs a script of queries, printing the assertions that failed and
+/// the lines that couldn't be evaluated. Exits with an error if there
+/// are any.
+fn main_script(name: &str, config: &Config) {
+    use std::io::Read;
+
+    let mut input = String::new();
+    if let Err(e) = File::open(name).and_then(|mut f| f.read_to_string(&mut input)) {
+        eprintln!("Could not open script '{}': {}", name, e);
+        std::process::exit(1);
+    }
+    let mut ctx = match load_context(config, false) {
+        Some(ctx) => ctx,
+        None => std::process::exit(1),
+    };
+    let report = script::run(&mut ctx, name, &*input);
+    for failure in &report.failures {
+        println!("{}", failure);
+    }
+    println!("{}: {} assertions, {} failures", name, report.assertions, report.failures.len());
+    if !report.passed() {
+        std::process::exit(1);
+    }
 }
 
 fn usage() {
     println!(
         "{} {}\n{}\n{}\n\n\
-        USAGE:\n    {0} [input file]\n\n\
+        USAGE:\n    {0} [options] [input file]\n    {0} --check <units file>\n    {0} [options] --script <file>\n    {0} [options] --server-stdio\n\n\
         FLAGS:\n    -h, --help      Prints help information\n    \
-        -V, --version   Prints version information\n\n\
+        -V, --version   Prints version information\n    \
+        --check         Reports problems in a units file, like definitions.units\n    \
+        --script        Runs a script, failing if any of its assertions fail\n    \
+        --json          Prints each reply as one line of JSON\n    \
+        --server-stdio  Answers queries from stdin with JSON until it closes\n\n\
+        OPTIONS:\n    \
+        --short, --long             Leaves out or shows quantities and fractions\n    \
+        --humanize, --no-humanize   Shows how far away dates are, or not\n    \
+        --digits <n>                Shows results with n significant digits\n    \
+        --color <when>              Colors errors: auto, always or never\n    \
+        --system <name>             Shows results in si, cgs, imperial, customary or natural units\n    \
+        --units <file>              Loads definitions from a units file\n    \
+        --currency, --no-currency   Downloads exchange rates, or not\n\n\
+        These override the settings in {}.\n\n\
         ARGS:\n    <input file>    Evaluate queries from this file",
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION"),
         env!("CARGO_PKG_AUTHORS"),
         env!("CARGO_PKG_DESCRIPTION"),
+        Config::path().map(|x| x.display().to_string())
+            .unwrap_or_else(|_| "config.toml".to_owned()),
     );
 }
 
@@ -296,45 +405,133 @@ fn version() {
     println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 }
 
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
 fn main() {
     use std::env::args;
 
-    if args().any(|arg| arg == "-h" || arg == "--help") {
-        usage();
+    // a broken config.toml is only reported after --help and
+    // --version have had a chance to run
+    let (mut config, config_error) = match Config::load() {
+        Ok(config) => (config, None),
+        Err(e) => (Config::default(), Some(e)),
+    };
+    let mut units = vec![];
+    let mut check_file = None;
+    let mut script_file = None;
+    let mut json = false;
+    let mut server = false;
+    let mut input_file_name = None;
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().unwrap_or_else(|| {
+            fail(format!("{} needs a value, see --help", name))
+        });
+        match &*arg {
+            "-h" | "--help" => {
+                usage();
+                return;
+            },
+            "-V" | "--version" => {
+                version();
+                return;
+            },
+            "--check" => check_file = Some(value("--check")),
+            "--script" => script_file = Some(value("--script")),
+            "--json" => json = true,
+            "--server-stdio" => server = true,
+            "--short" => config.brief_output = true,
+            "--long" => config.brief_output = false,
+            "--humanize" => config.humanize = true,
+            "--no-humanize" => config.humanize = false,
+            "--digits" => {
+                let digits = value("--digits");
+                config.digits = digits.parse::<i64>()
+                    .map_err(|_| format!("Expected a number for --digits, got {}", digits))
+                    .and_then(parse_digits)
+                    .unwrap_or_else(|e| fail(e));
+            },
+            "--color" => {
+                config.color = Color::parse(&*value("--color")).unwrap_or_else(|e| fail(e));
+            },
+            "--system" => {
+                config.system = parse_system(&*value("--system")).unwrap_or_else(|e| fail(e));
+            },
+            "--units" => units.push(PathBuf::from(value("--units"))),
+            "--currency" => config.currency = true,
+            "--no-currency" => config.currency = false,
+            // Specify the file to parse commands from as a shell argument
+            // i.e. "rink <file>"
+            _ if input_file_name.is_none() && (arg == "-" || !arg.starts_with("-")) =>
+                input_file_name = Some(arg.clone()),
+            _ => {
+                usage();
+                std::process::exit(1);
+            },
+        }
+    }
+    if let Some(e) = config_error {
+        fail(e);
+    }
+    // config.toml, then the environment and project, then the
+    // command line
+    config.units.extend(units_path());
+    config.units.append(&mut units);
+
+    if let Some(name) = check_file {
+        if input_file_name.is_some() {
+            usage();
+            std::process::exit(1);
+        }
+        main_check(&*name, &config);
         return;
     }
 
-    if args().any(|arg| arg == "-V" || arg == "--version") {
-        version();
+    if let Some(name) = script_file {
+        if input_file_name.is_some() {
+            usage();
+            std::process::exit(1);
+        }
+        main_script(&*name, &config);
         return;
     }
 
-    if args().len() > 2 {
-        usage();
-        std::process::exit(1);
+    if server {
+        if input_file_name.is_some() {
+            usage();
+            std::process::exit(1);
+        }
+        let stdin_handle = stdin();
+        main_noninteractive(stdin_handle.lock(), false, true, &config);
+        return;
     }
 
-    // Specify the file to parse commands from as a shell argument
-    // i.e. "rink <file>"
-    let input_file_name = args().nth(1);
     match input_file_name {
         // if we have an input, buffer it and call main_noninteractive
         Some(name) => {
             match name.as_ref() {
                 "-" => {
                     let stdin_handle = stdin();
-                    main_noninteractive(stdin_handle.lock(), false);
+                    main_noninteractive(stdin_handle.lock(), false, json, &config);
                 },
                 _ => {
                     let file = File::open(&name).unwrap_or_else(|e| {
                         eprintln!("Could not open input file '{}': {}", name, e);
                         std::process::exit(1);
                     });
-                    main_noninteractive(BufReader::new(file), false);
+                    main_noninteractive(BufReader::new(file), false, json, &config);
                 }
             };
         },
+        // JSON is for other programs, which send queries on stdin
+        None if json => {
+            let stdin_handle = stdin();
+            main_noninteractive(stdin_handle.lock(), false, true, &config);
+        },
         // else call the interactive version
-        None => main_interactive()
+        None => main_interactive(&config)
     };
 }
@@ -24,11 +24,12 @@ pub fn parse(mut f: File) -> Result<Defs, String> {
                 name: "BTC".to_owned(),
                 def: Rc::new(Def::Unit(
                     Expr::Mul(vec![
-                        Expr::Const(price),
-                        Expr::Unit("USD".to_owned())
+                        Expr::Const(price, None),
+                        Expr::Unit("USD".to_owned(), None)
                     ]))),
                 doc: Some(format!("Sourced from blockchain.info.")),
                 category: Some("currencies".to_owned()),
+                location: None,
             });
         }
     }
@@ -0,0 +1,350 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use ast::Digits;
+use context::Context;
+use number::{Number, NumberParts, Unit, to_string};
+use num::Num;
+use value::{Value, Show};
+
+/// A complex number with units, like the impedance `(3 + 4i) ohm`.
+/// Both parts are in the same unit. Arithmetic on exact parts stays
+/// exact, while functions like `exp` are computed with floats.
+#[derive(Debug, Clone)]
+pub struct Complex {
+    pub re: Num,
+    pub im: Num,
+    pub unit: Unit,
+}
+
+fn is_zero(num: &Num) -> bool {
+    num.to_f64() == 0.0
+}
+
+fn is_float(num: &Num) -> bool {
+    match *num {
+        Num::Float(_) => true,
+        Num::Mpq(_) => false,
+    }
+}
+
+/// The unit of a product or quotient, computed on unit values.
+fn unit_of(unit: &Unit) -> Number {
+    Number {
+        value: Num::one(),
+        unit: unit.clone(),
+    }
+}
+
+impl Complex {
+    /// The imaginary number `im i`, as in the literal `4i`.
+    pub fn imaginary(im: Num) -> Complex {
+        Complex {
+            re: Num::zero(),
+            im: im,
+            unit: Unit::new(),
+        }
+    }
+
+    pub fn from_number(num: &Number) -> Complex {
+        Complex {
+            re: num.value.clone(),
+            im: Num::zero(),
+            unit: num.unit.clone(),
+        }
+    }
+
+    fn from_polar(abs: f64, arg: f64, unit: Unit) -> Complex {
+        Complex {
+            re: Num::Float(abs * arg.cos()),
+            im: Num::Float(abs * arg.sin()),
+            unit: unit,
+        }
+    }
+
+    /// Returns the real number, if the imaginary part is zero.
+    pub fn to_number(&self) -> Option<Number> {
+        if is_zero(&self.im) {
+            Some(self.re())
+        } else {
+            None
+        }
+    }
+
+    /// Turns the result of an operation into a value, which is a plain
+    /// number when the imaginary part cancels out, as in `i * i`.
+    pub fn into_value(self) -> Value {
+        match self.to_number() {
+            Some(num) => Value::Number(num),
+            None => Value::Complex(self),
+        }
+    }
+
+    pub fn re(&self) -> Number {
+        Number {
+            value: self.re.clone(),
+            unit: self.unit.clone(),
+        }
+    }
+
+    pub fn im(&self) -> Number {
+        Number {
+            value: self.im.clone(),
+            unit: self.unit.clone(),
+        }
+    }
+
+    pub fn conj(&self) -> Complex {
+        Complex {
+            re: self.re.clone(),
+            im: -&self.im,
+            unit: self.unit.clone(),
+        }
+    }
+
+    /// The magnitude, which is exact when the result is rational.
+    pub fn abs(&self) -> Number {
+        let value = if is_zero(&self.im) {
+            self.re.abs()
+        } else if is_zero(&self.re) {
+            self.im.abs()
+        } else if is_float(&self.re) || is_float(&self.im) {
+            Num::Float(self.re.to_f64().hypot(self.im.to_f64()))
+        } else {
+            let square = &(&self.re * &self.re) + &(&self.im * &self.im);
+            match ::precise::exact_root(&square.to_mpq(), 2) {
+                Some(root) => Num::Mpq(root),
+                None => Num::Float(self.re.to_f64().hypot(self.im.to_f64())),
+            }
+        };
+        Number {
+            value: value,
+            unit: self.unit.clone(),
+        }
+    }
+
+    /// The angle from the positive real axis, between -π and π.
+    pub fn arg(&self) -> Number {
+        let value = if is_zero(&self.im) && self.re >= Num::zero() {
+            Num::zero()
+        } else {
+            Num::Float(self.im.to_f64().atan2(self.re.to_f64()))
+        };
+        Number::new(value)
+    }
+
+    pub fn add(&self, other: &Complex) -> Result<Complex, String> {
+        if self.unit != other.unit {
+            return Err(format!(
+                "Addition of units with mismatched units is not meaningful"
+            ))
+        }
+        Ok(Complex {
+            re: &self.re + &other.re,
+            im: &self.im + &other.im,
+            unit: self.unit.clone(),
+        })
+    }
+
+    pub fn sub(&self, other: &Complex) -> Result<Complex, String> {
+        if self.unit != other.unit {
+            return Err(format!(
+                "Subtraction of units with mismatched units is not meaningful"
+            ))
+        }
+        Ok(Complex {
+            re: &self.re - &other.re,
+            im: &self.im - &other.im,
+            unit: self.unit.clone(),
+        })
+    }
+
+    pub fn neg(&self) -> Complex {
+        Complex {
+            re: -&self.re,
+            im: -&self.im,
+            unit: self.unit.clone(),
+        }
+    }
+
+    pub fn mul(&self, other: &Complex) -> Complex {
+        let unit = (&unit_of(&self.unit) * &unit_of(&other.unit))
+            .expect("Bug: Mul should not fail");
+        Complex {
+            re: &(&self.re * &other.re) - &(&self.im * &other.im),
+            im: &(&self.re * &other.im) + &(&self.im * &other.re),
+            unit: unit.unit,
+        }
+    }
+
+    pub fn div(&self, other: &Complex) -> Result<Complex, String> {
+        let divisor = &(&other.re * &other.re) + &(&other.im * &other.im);
+        if is_zero(&divisor) {
+            return Err(format!("Division by zero"))
+        }
+        let unit = (&unit_of(&self.unit) / &unit_of(&other.unit))
+            .expect("Bug: Div should not fail");
+        Ok(Complex {
+            re: &(&(&self.re * &other.re) + &(&self.im * &other.im)) / &divisor,
+            im: &(&(&self.im * &other.re) - &(&self.re * &other.im)) / &divisor,
+            unit: unit.unit,
+        })
+    }
+
+    pub fn powi(&self, exp: i64) -> Result<Complex, String> {
+        let mut res = Complex::from_number(&Number::one());
+        let mut base = self.clone();
+        let mut n = exp.abs();
+        while n > 0 {
+            if n % 2 == 1 {
+                res = res.mul(&base);
+            }
+            base = base.mul(&base);
+            n /= 2;
+        }
+        if exp < 0 {
+            Complex::from_number(&Number::one()).div(&res)
+        } else {
+            Ok(res)
+        }
+    }
+
+    /// Raises to a power, giving the principal value for fractional
+    /// and complex exponents.
+    pub fn pow(&self, exp: &Complex) -> Result<Complex, String> {
+        if exp.unit.len() > 0 {
+            return Err(format!("Exponent must be dimensionless"))
+        }
+        if is_zero(&exp.im) {
+            let (num, den) = exp.re.to_rational();
+            if den == ::num::Int::one() && exp.re.abs() < Num::from(1 << 31) {
+                let exp: Option<i64> = (&num).into();
+                return self.powi(exp.unwrap())
+            }
+            // the units of the result are checked like for real numbers
+            let unit = try!(unit_of(&self.unit).pow(&exp.re()));
+            let abs = self.abs().value.to_f64();
+            let arg = self.arg().value.to_f64();
+            let exp = exp.re.to_f64();
+            return Ok(Complex::from_polar(abs.powf(exp), arg * exp, unit.unit))
+        }
+        if self.unit.len() > 0 {
+            return Err(format!(
+                "Complex exponents require a dimensionless base"
+            ))
+        }
+        if is_zero(&self.re) && is_zero(&self.im) {
+            return Err(format!("Complex power of zero is undefined"))
+        }
+        self.ln().exp_of(&exp)
+    }
+
+    fn exp_of(&self, exp: &Complex) -> Result<Complex, String> {
+        let power = self.mul(exp);
+        Ok(Complex::from_polar(
+            power.re.to_f64().exp(), power.im.to_f64(), Unit::new()
+        ))
+    }
+
+    /// The principal square root, exact for the square roots of
+    /// negative squares like `sqrt(-4)`.
+    pub fn sqrt(&self) -> Result<Complex, String> {
+        let unit = try!(unit_of(&self.unit).root(2));
+        if is_zero(&self.im) && !is_float(&self.re) && self.re < Num::zero() {
+            let square = -&self.re;
+            return Ok(Complex {
+                re: Num::zero(),
+                im: match ::precise::exact_root(&square.to_mpq(), 2) {
+                    Some(root) => Num::Mpq(root),
+                    None => Num::Float(square.to_f64().sqrt()),
+                },
+                unit: unit.unit,
+            })
+        }
+        let abs = self.abs().value.to_f64();
+        let arg = self.arg().value.to_f64();
+        Ok(Complex::from_polar(abs.sqrt(), arg / 2.0, unit.unit))
+    }
+
+    /// Computes e^z. The argument can also be an angle, so that phasors
+    /// can be written as `230 V exp(i 30 deg)`.
+    pub fn exp(&self) -> Result<Complex, String> {
+        let angle = self.unit.len() == 1 && self.unit.iter().all(|(dim, &power)| {
+            &**dim.0 == "radian" && power == 1
+        }) && is_zero(&self.re);
+        if self.unit.len() > 0 && !angle {
+            return Err(format!("Exponent must be dimensionless"))
+        }
+        Ok(Complex::from_polar(
+            self.re.to_f64().exp(), self.im.to_f64(), Unit::new()
+        ))
+    }
+
+    /// The principal natural logarithm.
+    pub fn ln(&self) -> Complex {
+        Complex {
+            re: Num::Float(self.abs().value.to_f64().ln()),
+            im: self.arg().value,
+            unit: self.unit.clone(),
+        }
+    }
+
+    /// Replaces the numeric value in the parts with the rectangular
+    /// form, scaled by the given factor.
+    pub fn fill_parts(&self, factor: &Num, base: u8, digits: Digits, parts: NumberParts) -> NumberParts {
+        let re = &self.re * factor;
+        let im = &self.im * factor;
+        let (re_exact, re_str) = to_string(&re, base, digits);
+        let (im_exact, im_str) = to_string(&im.abs(), base, digits);
+        let imag = if im_str == "1" {
+            "i".to_owned()
+        } else {
+            format!("{}i", im_str)
+        };
+        let sign = if im < Num::zero() { "-" } else { "+" };
+        let value = if is_zero(&re) {
+            format!("{}{}", if sign == "-" { "-" } else { "" }, imag)
+        } else {
+            format!("{} {} {}", re_str, sign, imag)
+        };
+        // `(3 + 4i) ohm` rather than `3 + 4i ohm`
+        let value = if parts.format("u").len() > 0 && !is_zero(&re) {
+            format!("({})", value)
+        } else {
+            value
+        };
+        let exact = re_exact && im_exact && !is_float(&re) && !is_float(&im);
+        NumberParts {
+            exact_value: if exact { Some(value.clone()) } else { None },
+            approx_value: if exact { None } else { Some(value) },
+            ..parts
+        }
+    }
+
+    pub fn to_parts(&self, context: &Context) -> NumberParts {
+        self.to_parts_digits(context, 10, Digits::Default)
+    }
+
+    /// Like `to_parts()`, but in the given base and number of digits.
+    pub fn to_parts_digits(&self, context: &Context, base: u8, digits: Digits) -> NumberParts {
+        // the prefix is picked for the magnitude, which is made
+        // rational so that exact parts stay exact when scaled
+        let mut abs = self.abs();
+        if !is_float(&self.re) && !is_float(&self.im) {
+            abs.value = Num::Mpq(abs.value.to_mpq());
+        }
+        let factor = if is_zero(&abs.value) {
+            Num::one()
+        } else {
+            &abs.prettify(context).value / &abs.value
+        };
+        self.fill_parts(&factor, base, digits, abs.to_parts(context))
+    }
+}
+
+impl Show for Complex {
+    fn show(&self, context: &Context) -> String {
+        format!("{}", self.to_parts(context))
+    }
+}
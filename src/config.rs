@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Settings for the command line interface, read from `config.toml`
+//! in the config directory:
+//!
+//! ```toml
+//! [output]
+//! short = false
+//! humanize = true
+//! digits = 7
+//! color = "auto"
+//! system = "si"
+//!
+//! [units]
+//! files = ["team.units"]
+//!
+//! [currency]
+//! enabled = true
+//! ```
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml::Value;
+use context::Context;
+use number::DEFAULT_PRECISION;
+use system::UnitSystem;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    /// Color only when the terminal is interactive.
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Leave out the quantities and approximate fractions of results.
+    pub brief_output: bool,
+    /// Show how far away dates are, like "in 3 days".
+    pub humanize: bool,
+    /// Significant digits of results when the query doesn't ask for
+    /// a number of digits.
+    pub digits: u64,
+    pub color: Color,
+    /// The unit system results are shown in.
+    pub system: UnitSystem,
+    /// Extra units files, loaded before the ones from `units_path()`.
+    pub units: Vec<PathBuf>,
+    /// Download exchange rates on startup.
+    pub currency: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            brief_output: false,
+            humanize: true,
+            digits: DEFAULT_PRECISION,
+            color: Color::Auto,
+            system: UnitSystem::SI,
+            units: vec![],
+            currency: true,
+        }
+    }
+}
+
+impl Color {
+    pub fn parse(name: &str) -> Result<Color, String> {
+        match name {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(format!("Expected auto, always or never for color, got {}", name))
+        }
+    }
+}
+
+/// Checks that a number of significant digits is usable.
+pub fn parse_digits(digits: i64) -> Result<u64, String> {
+    if digits >= 1 && digits <= 1000 {
+        Ok(digits as u64)
+    } else {
+        Err(format!("Digits must be between 1 and 1000, got {}", digits))
+    }
+}
+
+/// Looks up a unit system by the name used in `-> cgs` queries.
+pub fn parse_system(name: &str) -> Result<UnitSystem, String> {
+    UnitSystem::parse(name).ok_or_else(|| format!(
+        "Expected si, cgs, imperial, customary or natural for system, got {}", name))
+}
+
+fn expect_bool(name: &str, value: &Value) -> Result<bool, String> {
+    value.as_bool().ok_or_else(|| format!("Expected true or false for {}", name))
+}
+
+fn expect_str<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    value.as_str().ok_or_else(|| format!("Expected a string for {}", name))
+}
+
+impl Config {
+    /// The path of `config.toml`.
+    pub fn path() -> Result<PathBuf, String> {
+        let mut path = try!(::config_dir());
+        path.push("rink/config.toml");
+        Ok(path)
+    }
+
+    /// Reads `config.toml`, using the defaults if it doesn't exist.
+    pub fn load() -> Result<Config, String> {
+        let path = try!(Config::path());
+        let mut input = String::new();
+        match File::open(&path) {
+            Ok(mut f) => try!(f.read_to_string(&mut input).map_err(|e| format!(
+                "Failed to read {}: {}", path.display(), e))),
+            Err(_) => return Ok(Config::default()),
+        };
+        Config::parse(&*input, path.parent().unwrap_or(Path::new("")))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parses the contents of a config file. Relative paths of units
+    /// files are relative to `dir`.
+    pub fn parse(input: &str, dir: &Path) -> Result<Config, String> {
+        let value = try!(input.parse::<Value>().map_err(|e| format!("{}", e)));
+        let mut config = Config::default();
+        let sections = try!(value.as_table().ok_or(format!("Expected a table")));
+        for (section, table) in sections {
+            let table = try!(table.as_table().ok_or_else(|| format!(
+                "Expected [{}] to be a section", section)));
+            for (key, value) in table {
+                let name = format!("{}.{}", section, key);
+                match (&**section, &**key) {
+                    ("output", "short") =>
+                        config.brief_output = try!(expect_bool(&name, value)),
+                    ("output", "humanize") =>
+                        config.humanize = try!(expect_bool(&name, value)),
+                    ("output", "digits") => {
+                        let digits = try!(value.as_integer().ok_or_else(|| format!(
+                            "Expected a number for {}", name)));
+                        config.digits = try!(parse_digits(digits));
+                    },
+                    ("output", "color") =>
+                        config.color = try!(Color::parse(try!(expect_str(&name, value)))),
+                    ("output", "system") =>
+                        config.system = try!(parse_system(try!(expect_str(&name, value)))),
+                    ("units", "files") => {
+                        let files = try!(value.as_array().ok_or_else(|| format!(
+                            "Expected a list of files for {}", name)));
+                        for file in files {
+                            config.units.push(dir.join(try!(expect_str(&name, file))));
+                        }
+                    },
+                    ("currency", "enabled") =>
+                        config.currency = try!(expect_bool(&name, value)),
+                    _ => return Err(format!("Unknown setting {}", name)),
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Applies the settings that are part of a context.
+    pub fn apply(&self, ctx: &mut Context) {
+        ctx.brief_output = self.brief_output;
+        ctx.use_humanize = self.humanize;
+        ctx.significant_digits = self.digits;
+        ctx.unit_system = self.system;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "[output]\n\
+             short = true\n\
+             digits = 4\n\
+             color = \"never\"\n\
+             system = \"imperial\"\n\
+             [units]\n\
+             files = [\"team.units\", \"/opt/parts.units\"]\n\
+             [currency]\n\
+             enabled = false\n",
+            Path::new("/home/user/.config/rink")).unwrap();
+        assert!(config.brief_output);
+        assert!(config.humanize);
+        assert_eq!(config.digits, 4);
+        assert_eq!(config.color, Color::Never);
+        assert_eq!(config.system, UnitSystem::Imperial);
+        assert_eq!(config.units, vec![
+            PathBuf::from("/home/user/.config/rink/team.units"),
+            PathBuf::from("/opt/parts.units"),
+        ]);
+        assert!(!config.currency);
+
+        let dir = Path::new("");
+        assert_eq!(Config::parse("[output]\nshort = 1\n", dir).unwrap_err(),
+                   "Expected true or false for output.short");
+        assert_eq!(Config::parse("[output]\ndigits = 0\n", dir).unwrap_err(),
+                   "Digits must be between 1 and 1000, got 0");
+        assert_eq!(Config::parse("[output]\nsystem = \"mks\"\n", dir).unwrap_err(),
+                   "Expected si, cgs, imperial, customary or natural for system, got mks");
+        assert_eq!(Config::parse("[units]\nsystem = \"si\"\n", dir).unwrap_err(),
+                   "Unknown setting units.system");
+    }
+}
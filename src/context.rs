@@ -5,10 +5,15 @@
 use std::collections::{BTreeMap, BTreeSet};
 use number::{Dim, Number, Unit};
 use num::Num;
-use ast::{Expr, DatePattern};
+use ast::{Expr, DatePattern, FunctionDef, NonlinearDef, TableDef};
 use search;
 use substance::Substance;
 use reply::NotFoundError;
+use value::Value;
+use logarithmic::LogScale;
+use std::rc::Rc;
+use system::UnitSystem;
+use std::cell::{Cell, RefCell};
 
 /// The evaluation context that contains unit definitions.
 #[derive(Debug)]
@@ -27,8 +32,41 @@ pub struct Context {
     pub substances: BTreeMap<String, Substance>,
     pub substance_symbols: BTreeMap<String, String>,
     pub temporaries: BTreeMap<String, Number>,
+    pub variables: BTreeMap<String, Value>,
+    pub functions: BTreeMap<String, FunctionDef>,
+    pub nonlinear: BTreeMap<String, NonlinearDef>,
+    pub table_units: BTreeMap<String, TableDef>,
+    pub log_units: BTreeMap<String, Rc<LogScale>>,
+    pub history: Vec<Value>,
     pub short_output: bool,
+    /// Leave out the quantities of results, and exact fractions when
+    /// there is an approximate value, as in `rink --short`.
+    pub brief_output: bool,
     pub use_humanize: bool,
+    /// The number of significant digits results are shown with when
+    /// the query doesn't ask for a number of digits.
+    pub significant_digits: u64,
+    /// The unit system results are shown in when the query doesn't
+    /// convert them to a unit.
+    pub unit_system: UnitSystem,
+    /// The unit names the query looked up so far, while it is being
+    /// explained.
+    pub trace: RefCell<Option<Vec<String>>>,
+    /// Set while the parts of a nonlinear or table unit are evaluated,
+    /// so that variables can't shadow the units they refer to.
+    pub in_definition: Cell<bool>,
+}
+
+/// How a unit name was found by `Context::resolve()`.
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    /// The prefix that was split off the name, with its value.
+    pub prefix: Option<(String, Number)>,
+    /// The unit the rest of the name refers to.
+    pub unit: String,
+    /// Whether a plural s was removed.
+    pub plural: bool,
+    pub value: Number,
 }
 
 impl Context {
@@ -49,8 +87,19 @@ impl Context {
             substances: BTreeMap::new(),
             substance_symbols: BTreeMap::new(),
             temporaries: BTreeMap::new(),
+            variables: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            nonlinear: BTreeMap::new(),
+            table_units: BTreeMap::new(),
+            log_units: BTreeMap::new(),
+            history: Vec::new(),
             short_output: false,
+            brief_output: false,
             use_humanize: true,
+            significant_digits: ::number::DEFAULT_PRECISION,
+            unit_system: UnitSystem::SI,
+            trace: RefCell::new(None),
+            in_definition: Cell::new(false),
         }
     }
 
@@ -61,6 +110,24 @@ impl Context {
     /// Given a unit name, returns its value if it exists. Supports SI
     /// prefixes, plurals, bare dimensions like length, and quantities.
     pub fn lookup(&self, name: &str) -> Option<Number> {
+        self.resolve(name).map(|x| x.value)
+    }
+
+    /// Like `lookup()`, but records the name in `trace` while a query
+    /// is being explained. Used for the names a query refers to, as
+    /// opposed to ones looked up to show the result.
+    pub fn lookup_traced(&self, name: &str) -> Option<Number> {
+        let res = self.lookup(name);
+        if res.is_some() {
+            if let Some(ref mut trace) = *self.trace.borrow_mut() {
+                trace.push(name.to_owned());
+            }
+        }
+        res
+    }
+
+    /// Like `lookup()`, but also tells how the name was found.
+    pub fn resolve(&self, name: &str) -> Option<Resolved> {
         fn inner(ctx: &Context, name: &str) -> Option<Number> {
             if let Some(v) = ctx.temporaries.get(name).cloned() {
                 return Some(v)
@@ -81,29 +148,35 @@ impl Context {
             }
             None
         }
-        if let Some(v) = inner(self, name) {
-            return Some(v)
-        }
-        for &(ref pre, ref value) in &self.prefixes {
-            if name.starts_with(pre) {
-                if let Some(v) = inner(self, &name[pre.len()..]) {
-                    return Some((&v * &value).unwrap())
-                }
-            }
-        }
-        // after so that "ks" is kiloseconds
-        if name.ends_with("s") {
-            let name = &name[0..name.len()-1];
-            if let Some(v) = inner(self, name) {
-                return Some(v)
+        fn with_prefix(ctx: &Context, name: &str, plural: bool) -> Option<Resolved> {
+            if let Some(v) = inner(ctx, name) {
+                return Some(Resolved {
+                    prefix: None,
+                    unit: name.to_owned(),
+                    plural: plural,
+                    value: v,
+                })
             }
-            for &(ref pre, ref value) in &self.prefixes {
+            for &(ref pre, ref value) in &ctx.prefixes {
                 if name.starts_with(pre) {
-                    if let Some(v) = inner(self, &name[pre.len()..]) {
-                        return Some((&v * &value).unwrap())
+                    if let Some(v) = inner(ctx, &name[pre.len()..]) {
+                        return Some(Resolved {
+                            prefix: Some((pre.clone(), value.clone())),
+                            unit: name[pre.len()..].to_owned(),
+                            plural: plural,
+                            value: (&v * &value).unwrap(),
+                        })
                     }
                 }
             }
+            None
+        }
+        if let Some(v) = with_prefix(self, name, false) {
+            return Some(v)
+        }
+        // after so that "ks" is kiloseconds
+        if name.ends_with("s") {
+            return with_prefix(self, &name[0..name.len()-1], true)
         }
         None
     }
@@ -118,7 +191,7 @@ impl Context {
                 return Some((*k.0).clone())
             }
             if let Some(v) = ctx.definitions.get(name) {
-                if let Expr::Unit(ref name) = *v {
+                if let Expr::Unit(ref name, _) = *v {
                     if let Some(r) = ctx.canonicalize(&*name) {
                         return Some(r)
                     } else {
@@ -249,6 +322,81 @@ impl Context {
         (recip, String::from_utf8(buf).unwrap())
     }
 
+    /// Returns true if the name is a nonlinear or piecewise linear
+    /// unit, which are used like functions.
+    pub fn is_nonlinear(&self, name: &str) -> bool {
+        self.nonlinear.contains_key(name) || self.table_units.contains_key(name)
+    }
+
+    /// The names of user functions and nonlinear units, which a query
+    /// calls rather than multiplies when they are followed by `(`.
+    pub fn function_names(&self) -> BTreeSet<String> {
+        self.functions.keys()
+            .chain(self.nonlinear.keys())
+            .chain(self.table_units.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns true if the name refers to something defined by the
+    /// units database, as opposed to a user variable.
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.lookup(name).is_some() ||
+            self.substances.contains_key(name) ||
+            self.is_nonlinear(name) ||
+            self.log_units.contains_key(name) ||
+            self.definitions.contains_key(name)
+    }
+
+    /// Returns true if the units database has a definition with
+    /// exactly this name, not counting prefixes and plurals.
+    pub fn has_definition(&self, name: &str) -> bool {
+        self.units.contains_key(name) ||
+            self.definitions.contains_key(name) ||
+            self.substances.contains_key(name) ||
+            self.is_nonlinear(name) ||
+            self.log_units.contains_key(name) ||
+            self.functions.contains_key(name)
+    }
+
+    /// Defines a prefix, replacing an existing one with the same name
+    /// so that it keeps its place in the search order.
+    pub fn set_prefix(&mut self, name: &str, value: Number) {
+        match self.prefixes.iter().position(|x| x.0 == name) {
+            Some(i) => self.prefixes[i].1 = value,
+            None => self.prefixes.push((name.to_owned(), value)),
+        }
+    }
+
+    /// Returns true if evaluating the expression would call the named
+    /// function, either directly or through other user functions.
+    pub fn calls_function(&self, expr: &Expr, name: &str) -> bool {
+        match *expr {
+            Expr::Call(ref func, ref args, _) =>
+                func == name ||
+                args.iter().any(|x| self.calls_function(x, name)) ||
+                self.functions.get(func)
+                    .map(|f| self.calls_function(&f.body, name))
+                    .unwrap_or(false),
+            Expr::Mul(ref exprs) | Expr::Vector(ref exprs) =>
+                exprs.iter().any(|x| self.calls_function(x, name)),
+            Expr::Frac(ref left, ref right) |
+            Expr::Pow(ref left, ref right) |
+            Expr::Add(ref left, ref right) |
+            Expr::Sub(ref left, ref right) |
+            Expr::PlusMinus(ref left, ref right) |
+            Expr::Equals(ref left, ref right) |
+            Expr::Compare(_, ref left, ref right) =>
+                self.calls_function(left, name) || self.calls_function(right, name),
+            Expr::Neg(ref expr) | Expr::Plus(ref expr) |
+            Expr::Suffix(_, ref expr) | Expr::Of(_, ref expr) =>
+                self.calls_function(expr, name),
+            Expr::OfAt(_, ref expr, ref at) =>
+                self.calls_function(expr, name) || self.calls_function(at, name),
+            _ => false,
+        }
+    }
+
     pub fn typo_dym<'a>(&'a self, what: &str) -> Option<&'a str> {
         search::search(self, what, 1).into_iter().next()
     }
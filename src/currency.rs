@@ -42,12 +42,13 @@ pub fn parse(f: File) -> Result<Defs, String> {
                             name: currency.to_owned(),
                             def: Rc::new(Def::Unit(
                                 Expr::Mul(vec![
-                                    Expr::Frac(Box::new(Expr::Const(Num::one())),
-                                               Box::new(Expr::Const(num))),
-                                    Expr::Unit("EUR".to_string())
+                                    Expr::Frac(Box::new(Expr::Const(Num::one(), None)),
+                                               Box::new(Expr::Const(num, None))),
+                                    Expr::Unit("EUR".to_string(), None)
                                 ]))),
                             doc: Some(format!("Sourced from European Central Bank.")),
                             category: Some("currencies".to_owned()),
+                            location: None,
                         });
                     }
                 }
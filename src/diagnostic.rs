@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Problems found in units files and scripts.
+
+use std::fmt;
+use std::rc::Rc;
+
+/// Where a definition starts in a units file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: Rc<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem in a units file or a failed assertion in a script.
+/// Definitions that don't come from a file, like downloaded currency
+/// rates, have an empty file and line 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(location: Option<&Location>, severity: Severity, message: String) -> Diagnostic {
+        match location {
+            Some(location) => Diagnostic {
+                file: (*location.file).clone(),
+                line: location.line,
+                column: location.column,
+                severity: severity,
+                message: message,
+            },
+            None => Diagnostic {
+                file: String::new(),
+                line: 0,
+                column: 0,
+                severity: severity,
+                message: message,
+            },
+        }
+    }
+
+    pub fn error(location: Option<&Location>, message: String) -> Diagnostic {
+        Diagnostic::new(location, Severity::Error, message)
+    }
+
+    pub fn warning(location: Option<&Location>, message: String) -> Diagnostic {
+        Diagnostic::new(location, Severity::Warning, message)
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(fmt, "warning"),
+            Severity::Error => write!(fmt, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            try!(write!(fmt, "{}:{}:{}: ", self.file, self.line, self.column));
+        } else if self.file.len() > 0 {
+            try!(write!(fmt, "{}: ", self.file));
+        }
+        write!(fmt, "{}: {}", self.severity, self.message)
+    }
+}
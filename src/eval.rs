@@ -2,29 +2,59 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use gmp::mpq::Mpq;
+use gmp::mpz::Mpz;
 use number::{Number, Dim, NumberParts, pow};
 use num::{Num, Int};
 use date;
-use ast::{Expr, SuffixOp, Query, Conversion, Digits};
+use ast::{
+    Expr, SuffixOp, CompareOp, Query, Conversion, Digits, FunctionDef, Bounds, substitute, unit_names
+};
 use std::rc::Rc;
-use factorize::{factorize, Factors};
+use factorize::{factorize, best_units, Factors};
 use value::{Value, Show};
 use reply::{
     DefReply, ConversionReply, FactorizeReply, UnitsForReply,
     QueryReply, ConformanceError, QueryError, UnitListReply,
     DurationReply, SearchReply, DateReply, ExprReply,
-    UnitsInCategory
+    UnitsInCategory, AssignReply, VariableReply, VariablesReply,
+    UnsetReply, FunctionReply, LogarithmicReply, IntervalReply, PolarReply,
+    CompositionReply, ElementReply, ReactionReply, SpeciesReply, AlternativesReply,
+    ExplainReply, ExplainStep, ExplainKind, BoolReply
 };
 use search;
 use context::Context;
 use substance::SubstanceGetError;
-use formula::substance_from_formula;
+use logarithmic::Logarithmic;
+use uncertain::Uncertain;
+use complex::Complex;
+use vector::Vector;
+use interval::{Interval, format_bound};
+use formula::{substance_from_formula, parse_formula, composition};
+
+/// Builds an expression that evaluates to the given number, so that
+/// it can be substituted into the definition of a nonlinear unit.
+fn number_expr(num: &Number) -> Expr {
+    let mut exprs = vec![Expr::Const(num.value.clone(), None)];
+    for (dim, &pow) in &num.unit {
+        exprs.push(Expr::Pow(
+            Box::new(Expr::Quote((*dim.0).clone())),
+            Box::new(Expr::Const(Num::from(pow), None))
+        ));
+    }
+    Expr::Mul(exprs)
+}
 
 impl Context {
     /// Evaluates an expression to compute its value, *excluding* `->`
-    /// conversions.
+    /// conversions. Errors point at the innermost expression that
+    /// failed.
     pub fn eval(&self, expr: &Expr) -> Result<Value, QueryError> {
+        self.eval_unspanned(expr).map_err(|e| e.at(expr.span()))
+    }
+
+    fn eval_unspanned(&self, expr: &Expr) -> Result<Value, QueryError> {
         use std::ops::*;
         macro_rules! operator {
             ($left:ident $op:ident $opname:tt $right:ident) => {{
@@ -57,19 +87,44 @@ impl Context {
                         left.show(self)
                     )))
                 } else {
-                    let left = (&left * &self.lookup($scale).expect(
+                    let left = (&left * &self.lookup_traced($scale).expect(
                         &*format!("Missing {} unit", $scale))).unwrap();
-                    Ok(Value::Number((&left + &self.lookup($base)
+                    Ok(Value::Number((&left + &self.lookup_traced($base)
                                       .expect(&*format!("Missing {} constant", $base))).unwrap()))
                 }
             }}
         }
 
         match *expr {
-            Expr::Unit(ref name) if name == "now" =>
+            Expr::Unit(ref name, _) if name == "now" =>
                 Ok(Value::DateTime(date::GenericDateTime::Fixed(date::now()))),
-            Expr::Unit(ref name) =>
-                self.lookup(name).map(Value::Number)
+            Expr::Unit(ref name, _) if name == "ans" || name == "_" =>
+                self.history.last().cloned().ok_or_else(|| QueryError::Generic(format!(
+                    "There is no previous result for {} to refer to", name
+                ))),
+            Expr::Unit(ref name, _) if name.starts_with("$") => {
+                use std::str::FromStr;
+                match usize::from_str(&name[1..]) {
+                    Ok(n) if n >= 1 && n <= self.history.len() =>
+                        Ok(self.history[n - 1].clone()),
+                    Ok(n) => Err(QueryError::Generic(format!(
+                        "No such result ${}, there are {} results so far",
+                        n, self.history.len()
+                    ))),
+                    Err(_) => Err(QueryError::Generic(format!(
+                        "Malformed result reference {}, expected $ followed \
+                         by a result number", name
+                    ))),
+                }
+            },
+            Expr::Unit(ref name, _) if self.variable(name).is_some() =>
+                Ok(self.variables[name].clone()),
+            Expr::Unit(ref name, _) if self.log_units.contains_key(name) =>
+                Ok(Value::Logarithmic(Logarithmic::new(
+                    Num::one(), self.log_units[name].clone()
+                ))),
+            Expr::Unit(ref name, _) =>
+                self.lookup_traced(name).map(Value::Number)
                 .or_else(||
                     self.substances.get(name)
                         .cloned().map(Value::Substance)
@@ -82,8 +137,10 @@ impl Context {
                     self.unknown_unit_err(name)
                 )),
             Expr::Quote(ref name) => Ok(Value::Number(Number::one_unit(Dim::new(&**name)))),
-            Expr::Const(ref num) =>
+            Expr::Const(ref num, _) =>
                 Ok(Value::Number(Number::new(num.clone()))),
+            Expr::Imaginary(ref num) =>
+                Ok(Complex::imaginary(num.clone()).into_value()),
             Expr::Date(ref date) => match date::try_decode(date, self) {
                 Ok(date) => Ok(Value::DateTime(date)),
                 Err(e) => Err(QueryError::Generic(e))
@@ -97,11 +154,26 @@ impl Context {
             Expr::Add(ref left, ref right)  => operator!(left add + right),
             Expr::Sub(ref left, ref right)  => operator!(left sub - right),
             Expr::Pow(ref left, ref right)  => operator!(left pow ^ right),
+            Expr::PlusMinus(ref left, ref right) => {
+                let value = try!(self.eval(left));
+                let error = try!(self.eval(right));
+                let res = match (&value, &error) {
+                    (&Value::Number(ref value), &Value::Number(ref error)) =>
+                        Uncertain::new(value, error).map(Value::Uncertain),
+                    _ => Err(format!("Operation is not defined")),
+                };
+                res.map_err(|e| QueryError::Generic(format!(
+                    "{}: <{}> ± <{}>",
+                    e, value.show(self), error.show(self)
+                )))
+            },
 
+            // °C and °F are the nonlinear units tempC and tempF,
+            // the other scales have no definitions of their own
             Expr::Suffix(SuffixOp::Celsius, ref left) =>
-                temperature!(left, "C", "zerocelsius", "kelvin"),
+                self.eval_temperature(left, "C", "tempC"),
             Expr::Suffix(SuffixOp::Fahrenheit, ref left) =>
-                temperature!(left, "F", "zerofahrenheit", "degrankine"),
+                self.eval_temperature(left, "F", "tempF"),
             Expr::Suffix(SuffixOp::Reaumur, ref left) =>
                 temperature!(left, "Ré", "zerocelsius", "reaumur_absolute"),
             Expr::Suffix(SuffixOp::Romer, ref left) =>
@@ -122,7 +194,7 @@ impl Context {
             }),
             Expr::Equals(ref left, ref right) => {
                 match **left {
-                    Expr::Unit(_) => (),
+                    Expr::Unit(_, _) => (),
                     ref x => return Err(QueryError::Generic(format!(
                         "= is currently only used for inline unit definitions: \
                          expected unit, got {}", x
@@ -130,10 +202,18 @@ impl Context {
                 };
                 self.eval(right)
             },
+            Expr::Compare(op, ref left, ref right) => {
+                let left = try!(self.eval(left));
+                let right = try!(self.eval(right));
+                self.compare(op, &left, &right).map(Value::Bool)
+            },
             Expr::Of(ref field, ref val) => {
                 let val = try!(self.eval(val));
                 let val = match val {
                     Value::Substance(sub) => sub,
+                    Value::Mixture(mix) => return mix.get(&**field)
+                        .map(Value::Number)
+                        .map_err(QueryError::Generic),
                     x => return Err(QueryError::Generic(format!(
                         "Not defined: {} of <{}>",
                         field, x.show(self)
@@ -150,209 +230,710 @@ impl Context {
                     }
                 })
             },
-            Expr::Call(ref name, ref args) => {
+            Expr::OfAt(ref field, ref val, ref at) => {
+                let val = match try!(self.eval(val)) {
+                    Value::Substance(sub) => sub,
+                    x => return Err(QueryError::Generic(format!(
+                        "Not defined: {} of <{}>",
+                        field, x.show(self)
+                    )))
+                };
+                let at = match try!(self.eval(at)) {
+                    Value::Number(num) => num,
+                    x => return Err(QueryError::Generic(format!(
+                        "Expected a number after at, got <{}>",
+                        x.show(self)
+                    )))
+                };
+                val.get_at(&**field, &at).map(Value::Number).map_err(|e| {
+                    match e {
+                        SubstanceGetError::Generic(s) =>
+                            QueryError::Generic(s),
+                        SubstanceGetError::Conformance(l, r) =>
+                            QueryError::Conformance(
+                                self.conformance_err(&l, &r)
+                            ),
+                    }
+                })
+            },
+            Expr::Call(ref name, ref args, _) if self.is_nonlinear(name) => {
+                if args.len() != 1 {
+                    return Err(QueryError::Generic(format!(
+                        "Argument number mismatch for {}: Expected 1, got {}",
+                        name, args.len()
+                    )))
+                }
+                match try!(self.eval(&args[0])) {
+                    Value::Number(ref num) if self.table_units.contains_key(name) =>
+                        self.eval_table(name, num).map(Value::Number),
+                    Value::Number(ref num) => self.eval_nonlinear(name, num).map(Value::Number),
+                    ref x => Err(QueryError::Generic(format!(
+                        "Expected Number, got <{}>", x.show(self)
+                    )))
+                }
+            },
+            Expr::Call(ref name, ref args, _) if self.functions.contains_key(name) => {
+                let func = &self.functions[name];
+                if args.len() != func.params.len() {
+                    return Err(QueryError::Generic(format!(
+                        "Argument number mismatch for {}: Expected {}, got {}",
+                        name, func.params.len(), args.len()
+                    )))
+                }
+                let res = try!(self.eval(&func.apply(args)));
+                if let Some(ref result) = func.result {
+                    let result = try!(self.eval(result));
+                    // a vector can be declared with one unit for all
+                    // of its components
+                    let conforms = match (res.units(), result.units()) {
+                        (Some(ref left), Some(ref right)) if right.len() == 1 =>
+                            left.iter().all(|x| *x == right[0]),
+                        (Some(left), Some(right)) => left == right,
+                        _ => false,
+                    };
+                    if !conforms {
+                        return Err(QueryError::Generic(format!(
+                            "Conformance error in result of {}: {} != {}",
+                            name, res.show(self), result.show(self)
+                        )))
+                    }
+                }
+                Ok(res)
+            },
+            // only the branch that is taken is evaluated
+            Expr::Call(ref name, ref args, _) if name == "if" => {
+                if args.len() != 3 {
+                    return Err(QueryError::Generic(format!(
+                        "Argument number mismatch for if: Expected 3, got {}", args.len()
+                    )))
+                }
+                match try!(self.eval(&args[0])) {
+                    Value::Bool(true) => self.eval(&args[1]),
+                    Value::Bool(false) => self.eval(&args[2]),
+                    x => Err(QueryError::Generic(format!(
+                        "Expected a comparison as the condition of if, got <{}>", x.show(self)
+                    )))
+                }
+            },
+            Expr::Call(ref name, ref exprs, _) => {
                 let args = try!(
-                    args.iter()
+                    exprs.iter()
                         .map(|x| self.eval(x))
                         .collect::<Result<Vec<_>, _>>());
+                self.call_builtin(name, &args)
+            },
+            Expr::Vector(ref exprs) => {
+                let values = try!(exprs.iter().map(|x| self.eval(x))
+                                  .collect::<Result<Vec<_>, _>>());
+                Vector::from_values(values).map(Value::Vector).map_err(|e| {
+                    QueryError::Generic(format!("{}: {}", e, expr))
+                })
+            },
+            Expr::Error(ref e, _) => Err(QueryError::Generic(e.clone())),
+        }
+    }
 
-                macro_rules! func {
-                    (fn $fname:ident($($name:ident : $ty:ident),*) $block:block) => {{
-                        let mut iter = args.iter();
-                        let mut count = 0;
-                        $( count += 1; let _ = stringify!($name); )*;
-                        $(
-                            let $name = match iter.next() {
-                                Some(&Value::$ty(ref v)) => v,
-                                Some(x) => return Err(QueryError::Generic(
-                                    format!(
-                                        "Expected {}, got <{}>",
-                                        stringify!($ty), x.show(self)
-                                    )
-                                )),
-                                None => return Err(QueryError::Generic(format!(
-                                    "Argument number mismatch for {}: \
-                                     Expected {}, got {}",
-                                    stringify!($fname), count, args.len()
-                                )))
-                            };
-                        )*;
-                        if iter.next().is_some() {
-                            return Err(QueryError::Generic(format!(
-                                "Argument number mismatch for {}: \
-                                 Expected {}, got {}",
-                                stringify!($fname), count, args.len()
-                            )));
-                        }
-                        let res: Result<Value, String> = {
-                            $block
-                        };
-                        res.map_err(|e| {
-                            QueryError::Generic(format!(
-                                "{}: {}({})",
-                                e, stringify!($fname),
-                                args.iter()
-                                    .map(|x| x.show(self))
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ))
-                        })
+    /// Calls a builtin function with arguments that have already been
+    /// evaluated.
+    fn call_builtin(&self, name: &str, args: &[Value]) -> Result<Value, QueryError> {
+        macro_rules! func {
+            (fn $fname:ident($($name:ident : $ty:ident),*) $block:block) => {{
+                let mut iter = args.iter();
+                let mut count = 0;
+                $( count += 1; let _ = stringify!($name); )*;
+                $(
+                    let $name = match iter.next() {
+                        Some(&Value::$ty(ref v)) => v,
+                        Some(x) => return Err(QueryError::Generic(
+                            format!(
+                                "Expected {}, got <{}>",
+                                stringify!($ty), x.show(self)
+                            )
+                        )),
+                        None => return Err(QueryError::Generic(format!(
+                            "Argument number mismatch for {}: \
+                             Expected {}, got {}",
+                            stringify!($fname), count, args.len()
+                        )))
+                    };
+                )*;
+                if iter.next().is_some() {
+                    return Err(QueryError::Generic(format!(
+                        "Argument number mismatch for {}: \
+                         Expected {}, got {}",
+                        stringify!($fname), count, args.len()
+                    )));
+                }
+                let res: Result<Value, String> = {
+                    $block
+                };
+                res.map_err(|e| {
+                    QueryError::Generic(format!(
+                        "{}: {}({})",
+                        e, stringify!($fname),
+                        args.iter()
+                            .map(|x| x.show(self))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                })
+            }
+        }}
+
+        let uncertain = args.iter().any(|x| match *x {
+            Value::Uncertain(_) => true,
+            _ => false,
+        });
+
+        let complex = args.iter().any(|x| match *x {
+            Value::Complex(_) => true,
+            _ => false,
+        });
+
+        match name {
+            "sqrt" if complex => func!(fn sqrt(num: Complex) {
+                num.sqrt().map(Complex::into_value)
+            }),
+            "exp" if complex => func!(fn exp(num: Complex) {
+                num.exp().map(Complex::into_value)
+            }),
+            "ln" if complex => func!(fn ln(num: Complex) {
+                Ok(num.ln().into_value())
+            }),
+            "abs" if complex => func!(fn abs(num: Complex) {
+                Ok(Value::Number(num.abs()))
+            }),
+            "arg" if complex => func!(fn arg(num: Complex) {
+                Ok(Value::Number(num.arg()))
+            }),
+            "conj" if complex => func!(fn conj(num: Complex) {
+                Ok(Value::Complex(num.conj()))
+            }),
+            "re" if complex => func!(fn re(num: Complex) {
+                Ok(Value::Number(num.re()))
+            }),
+            "im" if complex => func!(fn im(num: Complex) {
+                Ok(Value::Number(num.im()))
+            }),
+            "sqrt" if uncertain => func!(fn sqrt(num: Uncertain) {
+                num.sqrt().map(Value::Uncertain)
+            }),
+            "exp" if uncertain => func!(fn exp(num: Uncertain) {
+                Ok(Value::Uncertain(num.apply(f64::exp, f64::exp)))
+            }),
+            "ln" if uncertain => func!(fn ln(num: Uncertain) {
+                Ok(Value::Uncertain(num.apply(f64::ln, |x| 1.0 / x)))
+            }),
+            "log10" if uncertain => func!(fn log10(num: Uncertain) {
+                Ok(Value::Uncertain(num.apply(
+                    f64::log10, |x| 1.0 / (x * ::std::f64::consts::LN_10))))
+            }),
+            "sin" if uncertain => func!(fn sin(num: Uncertain) {
+                Ok(Value::Uncertain(num.apply(f64::sin, f64::cos)))
+            }),
+            "cos" if uncertain => func!(fn cos(num: Uncertain) {
+                Ok(Value::Uncertain(num.apply(f64::cos, |x| -x.sin())))
+            }),
+            "tan" if uncertain => func!(fn tan(num: Uncertain) {
+                Ok(Value::Uncertain(num.apply(f64::tan, |x| 1.0 / (x.cos() * x.cos()))))
+            }),
+            "sqrt" => func!(fn sqrt(num: Number) {
+                if num.value < Num::zero() {
+                    Complex::from_number(num).sqrt().map(Complex::into_value)
+                } else {
+                    num.root(2).map(Value::Number)
+                }
+            }),
+            "exp" => func!(fn exp(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().exp()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "ln" => func!(fn ln(num: Number) {
+                if num.value < Num::zero() {
+                    Ok(Complex::from_number(num).ln().into_value())
+                } else {
+                    Ok(Value::Number(Number {
+                        value: Num::Float(num.value.to_f64().ln()),
+                        unit: num.unit.clone(),
+                    }))
+                }
+            }),
+            "log" => func!(fn log(num: Number, base: Number) {
+                if base.unit.len() > 0 {
+                    Err(format!(
+                        "Base must be dimensionless"
+                    ))
+                } else {
+                    Ok(Value::Number(Number {
+                        value: Num::Float(num.value.to_f64()
+                                          .log(base.value.to_f64())),
+                        unit: num.unit.clone(),
+                    }))
+                }
+            }),
+            "log2" => func!(fn log2(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().log2()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "log10" => func!(fn ln(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().log10()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "hypot" => func!(fn hypot(x: Number, y: Number) {
+                if x.unit != y.unit {
+                    Err(format!(
+                        "Arguments to hypot must have matching \
+                         dimensionality"
+                    ))
+                } else {
+                    Ok(Value::Number(Number {
+                        value: Num::Float(x.value.to_f64().hypot(y.value.to_f64())),
+                        unit: x.unit.clone(),
+                    }))
+                }
+            }),
+            "sin" => func!(fn sin(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().sin()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "cos" => func!(fn cos(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().cos()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "tan" => func!(fn tan(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().tan()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "asin" => func!(fn asin(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().asin()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "acos" => func!(fn acos(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().acos()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "atan" => func!(fn atan(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().atan()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "atan2" => func!(fn atan2(x: Number, y: Number) {
+                if x.unit != y.unit {
+                    Err(format!(
+                        "Arguments to atan2 must have matching \
+                         dimensionality"
+                    ))
+                } else {
+                    Ok(Value::Number(Number {
+                        value: Num::Float(x.value.to_f64()
+                                          .atan2(y.value.to_f64())),
+                        unit: x.unit.clone(),
+                    }))
+                }
+            }),
+            "sinh" => func!(fn sinh(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().sinh()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "cosh" => func!(fn cosh(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().cosh()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "tanh" => func!(fn tanh(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().tanh()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "asinh" => func!(fn asinh(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().asinh()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "acosh" => func!(fn acosh(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().acosh()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "atanh" => func!(fn atanh(num: Number) {
+                Ok(Value::Number(Number {
+                    value: Num::Float(num.value.to_f64().atanh()),
+                    unit: num.unit.clone(),
+                }))
+            }),
+            "abs" => func!(fn abs(num: Number) {
+                Ok(Value::Number(Complex::from_number(num).abs()))
+            }),
+            "arg" => func!(fn arg(num: Number) {
+                Ok(Value::Number(Complex::from_number(num).arg()))
+            }),
+            "conj" => func!(fn conj(num: Number) {
+                Ok(Value::Number(num.clone()))
+            }),
+            "re" => func!(fn re(num: Number) {
+                Ok(Value::Number(num.clone()))
+            }),
+            "im" => func!(fn im(num: Number) {
+                Ok(Value::Number(Complex::from_number(num).im()))
+            }),
+            "dot" => func!(fn dot(left: Vector, right: Vector) {
+                left.dot(right).map(Value::Number)
+            }),
+            "cross" => func!(fn cross(left: Vector, right: Vector) {
+                left.cross(right).map(Value::Vector)
+            }),
+            "norm" => func!(fn norm(vec: Vector) {
+                vec.norm().map(Value::Number)
+            }),
+            "transpose" => func!(fn transpose(mat: Vector) {
+                Ok(Value::Vector(mat.transpose()))
+            }),
+            _ => Err(QueryError::Generic(format!(
+                "Function not found: {}", name
+            )))
+        }
+    }
+
+    /// Evaluates an expression using interval arithmetic, so that the
+    /// result is guaranteed to contain the exact value. Parts that have
+    /// no interval counterpart are errors rather than guesses.
+    pub fn eval_interval(&self, expr: &Expr) -> Result<Interval, QueryError> {
+        macro_rules! operator {
+            ($left:ident $op:ident $opname:tt $right:ident) => {{
+                let left = try!(self.eval_interval(&**$left));
+                let right = try!(self.eval_interval(&**$right));
+                left.$op(&right).map_err(|e| {
+                    QueryError::Generic(format!(
+                        "{}: <{}> {} <{}>",
+                        e, $left, stringify!($opname), $right
+                    ))
+                })
+            }}
+        }
+
+        match *expr {
+            Expr::Const(ref num, _) => Ok(Interval::from_number(&Number::new(num.clone()))),
+            Expr::Neg(ref expr) => self.eval_interval(expr).map(|x| x.neg()),
+            Expr::Plus(ref expr) => self.eval_interval(expr),
+            Expr::Frac(ref left, ref right) => operator!(left div / right),
+            Expr::Add(ref left, ref right) => operator!(left add + right),
+            Expr::Sub(ref left, ref right) => operator!(left sub - right),
+            Expr::Pow(ref left, ref right) => operator!(left pow ^ right),
+            Expr::Mul(ref args) => args.iter().fold(Ok(Interval::one()), |a, b| {
+                a.and_then(|a| Ok(a.mul(&try!(self.eval_interval(b)))))
+            }),
+            Expr::Call(ref name, ref args, _) if self.functions.contains_key(name) => {
+                let func = &self.functions[name];
+                if args.len() != func.params.len() {
+                    return Err(QueryError::Generic(format!(
+                        "Argument number mismatch for {}: Expected {}, got {}",
+                        name, func.params.len(), args.len()
+                    )))
+                }
+                let res = try!(self.eval_interval(&func.apply(args)));
+                if let Some(ref result) = func.result {
+                    match try!(self.eval(result)) {
+                        Value::Number(ref result) if result.unit == res.unit => (),
+                        result => return Err(QueryError::Generic(format!(
+                            "Conformance error in result of {}: {} != {}",
+                            name, Number::unit_to_string(&res.unit), result.show(self)
+                        )))
                     }
-                }}
+                }
+                Ok(res)
+            },
+            Expr::Call(ref name, ref args, _) if name == "if" && args.len() == 3 =>
+                match try!(self.eval(&args[0])) {
+                    Value::Bool(true) => self.eval_interval(&args[1]),
+                    Value::Bool(false) => self.eval_interval(&args[2]),
+                    x => Err(QueryError::Generic(format!(
+                        "Expected a comparison as the condition of if, got <{}>", x.show(self)
+                    ))),
+                },
+            Expr::Call(ref name, ref args, _) if ::text_query::is_func(name) &&
+                !self.is_nonlinear(name) => {
+                if args.len() != 1 {
+                    return Err(QueryError::Generic(format!(
+                        "Interval arithmetic is not implemented for {}", name
+                    )))
+                }
+                let arg = try!(self.eval_interval(&args[0]));
+                arg.call(name).map_err(|e| QueryError::Generic(format!(
+                    "{}: {}({})", e, name, args[0]
+                )))
+            },
+            Expr::Unit(ref name, _) if self.variable(name).is_some() ||
+                self.temporaries.contains_key(name) =>
+                self.exact_interval(expr),
+            Expr::Unit(ref name, _) if self.units.contains_key(name) &&
+                (name == "π" || name == "ℯ") => {
+                // the fixed point computations carry guard digits, so
+                // these are much closer than 10^-40 to the constants
+                let value = if name == "π" {
+                    ::precise::pi(40)
+                } else {
+                    try!(::precise::exp(&Mpq::one(), 40).map_err(QueryError::Generic))
+                };
+                let error = Mpq::ratio(&Mpz::one(), &Mpz::from(10).pow(40));
+                Ok(Interval::around(&value, &error))
+            },
+            Expr::Unit(ref name, _) if self.units.contains_key(name) &&
+                self.definitions.contains_key(name) => {
+                // the loaded value may have been rounded, so only
+                // definitions that can't be re-evaluated use it
+                match self.eval_interval(&self.definitions[name]) {
+                    Ok(res) if res.unit == self.units[name].unit => Ok(res),
+                    _ => self.exact_interval(expr),
+                }
+            },
+            Expr::Unit(_, _) | Expr::Quote(_) | Expr::Error(_, _) => self.exact_interval(expr),
+            _ => Err(QueryError::Generic(format!(
+                "Interval arithmetic is not implemented for <{}>", expr
+            ))),
+        }
+    }
 
-                match &**name {
-                    "sqrt" => func!(fn sqrt(num: Number) {
-                        num.root(2).map(Value::Number)
-                    }),
-                    "exp" => func!(fn exp(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().exp()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "ln" => func!(fn ln(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().ln()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "log" => func!(fn log(num: Number, base: Number) {
-                        if base.unit.len() > 0 {
-                            Err(format!(
-                                "Base must be dimensionless"
-                            ))
-                        } else {
-                            Ok(Value::Number(Number {
-                                value: Num::Float(num.value.to_f64()
-                                                  .log(base.value.to_f64())),
-                                unit: num.unit.clone(),
-                            }))
-                        }
-                    }),
-                    "log2" => func!(fn log2(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().log2()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "log10" => func!(fn ln(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().log10()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "hypot" => func!(fn hypot(x: Number, y: Number) {
-                        if x.unit != y.unit {
-                            Err(format!(
-                                "Arguments to hypot must have matching \
-                                 dimensionality"
-                            ))
-                        } else {
-                            Ok(Value::Number(Number {
-                                value: Num::Float(x.value.to_f64().hypot(y.value.to_f64())),
-                                unit: x.unit.clone(),
-                            }))
-                        }
-                    }),
-                    "sin" => func!(fn sin(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().sin()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "cos" => func!(fn cos(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().cos()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "tan" => func!(fn tan(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().tan()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "asin" => func!(fn asin(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().asin()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "acos" => func!(fn acos(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().acos()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "atan" => func!(fn atan(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().atan()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "atan2" => func!(fn atan2(x: Number, y: Number) {
-                        if x.unit != y.unit {
-                            Err(format!(
-                                "Arguments to atan2 must have matching \
-                                 dimensionality"
-                            ))
-                        } else {
-                            Ok(Value::Number(Number {
-                                value: Num::Float(x.value.to_f64()
-                                                  .atan2(y.value.to_f64())),
-                                unit: x.unit.clone(),
-                            }))
-                        }
-                    }),
-                    "sinh" => func!(fn sinh(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().sinh()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "cosh" => func!(fn cosh(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().cosh()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "tanh" => func!(fn tanh(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().tanh()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "asinh" => func!(fn asinh(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().asinh()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "acosh" => func!(fn acosh(num: Number) {
-                        Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().acosh()),
-                            unit: num.unit.clone(),
-                        }))
-                    }),
-                    "atanh" => func!(fn atanh(num: Number) {
+    /// Evaluates an expression normally, for an interval containing
+    /// only its value. Fails for values that were computed with floats,
+    /// since nothing bounds their error.
+    fn exact_interval(&self, expr: &Expr) -> Result<Interval, QueryError> {
+        match try!(self.eval(expr)) {
+            Value::Number(ref num) => match num.value {
+                Num::Mpq(_) => Ok(Interval::from_number(num)),
+                Num::Float(_) => Err(QueryError::Generic(format!(
+                    "<{}> is only known approximately, so it has no guaranteed bounds", expr
+                ))),
+            },
+            x => Err(QueryError::Generic(format!(
+                "Interval arithmetic is not defined for <{}>", x.show(self)
+            ))),
+        }
+    }
+
+    /// Evaluates an expression like `eval`, but computes builtin
+    /// functions, fractional powers, π and ℯ to the given number of
+    /// digits rather than with floats. Units are re-evaluated from
+    /// their definitions, so that units defined in terms of π are as
+    /// precise.
+    pub fn eval_precise(&self, expr: &Expr, digits: u32) -> Result<Value, QueryError> {
+        use std::ops::*;
+        macro_rules! operator {
+            ($left:ident $op:ident $opname:tt $right:ident) => {{
+                let left = try!(self.eval_precise(&**$left, digits));
+                let right = try!(self.eval_precise(&**$right, digits));
+                ((&left).$op(&right)).map_err(|e| {
+                    QueryError::Generic(format!(
+                        "{}: <{}> {} <{}>",
+                        e, left.show(self),
+                        stringify!($opname),
+                        right.show(self)
+                    ))
+                })
+            }}
+        }
+
+        match *expr {
+            Expr::Unit(ref name, _) if self.variable(name).is_some() ||
+                self.temporaries.contains_key(name) =>
+                self.eval(expr),
+            Expr::Unit(ref name, _) if self.units.contains_key(name) &&
+                (name == "π" || name == "ℯ") => {
+                let value = if name == "π" {
+                    ::precise::pi(digits)
+                } else {
+                    try!(::precise::exp(&Mpq::one(), digits).map_err(QueryError::Generic))
+                };
+                Ok(Value::Number(Number::new(Num::Mpq(value))))
+            },
+            Expr::Unit(ref name, _) if self.units.contains_key(name) &&
+                self.definitions.contains_key(name) => {
+                // definitions that can't be re-evaluated, or that now
+                // give a different unit, keep their loaded value
+                match self.eval_precise(&self.definitions[name], digits) {
+                    Ok(Value::Number(num)) if num.unit == self.units[name].unit =>
+                        Ok(Value::Number(num)),
+                    _ => self.eval(expr),
+                }
+            },
+            Expr::Neg(ref expr) => {
+                let value = try!(self.eval_precise(expr, digits));
+                (-&value).map_err(|e| QueryError::Generic(format!(
+                    "{}: - <{}>", e, value.show(self)
+                )))
+            },
+            Expr::Plus(ref expr) => self.eval_precise(expr, digits),
+            Expr::Frac(ref left, ref right) => operator!(left div / right),
+            Expr::Add(ref left, ref right) => operator!(left add + right),
+            Expr::Sub(ref left, ref right) => operator!(left sub - right),
+            Expr::Pow(ref left, ref right) => {
+                let left = try!(self.eval_precise(left, digits));
+                let right = try!(self.eval_precise(right, digits));
+                let res = try!(left.pow(&right).map_err(|e| QueryError::Generic(format!(
+                    "{}: <{}> ^ <{}>", e, left.show(self), right.show(self)
+                ))));
+                match (res, &left, &right) {
+                    // the unit comes from the usual computation, and
+                    // the value is replaced if it wasn't exact
+                    (Value::Number(res), &Value::Number(ref left), &Value::Number(ref right)) => {
+                        let value = try!(::precise::pow(
+                            &left.value.to_mpq(), &right.value.to_mpq(), digits
+                        ).map_err(|e| QueryError::Generic(format!(
+                            "{}: <{}> ^ <{}>", e, left.show(self), right.show(self)
+                        ))));
                         Ok(Value::Number(Number {
-                            value: Num::Float(num.value.to_f64().atanh()),
-                            unit: num.unit.clone(),
+                            value: Num::Mpq(value),
+                            unit: res.unit,
                         }))
-                    }),
-                    _ => Err(QueryError::Generic(format!(
-                        "Function not found: {}", name
+                    },
+                    (res, _, _) => Ok(res),
+                }
+            },
+            Expr::Mul(ref args) => args.iter().fold(Ok(Value::Number(Number::one())), |a, b| {
+                a.and_then(|a| {
+                    let b = try!(self.eval_precise(b, digits));
+                    (&a * &b).map_err(|e| QueryError::Generic(format!(
+                        "{}: <{}> * <{}>",
+                        e, a.show(self), b.show(self)
+                    )))
+                })
+            }),
+            Expr::Call(ref name, ref args, _) if self.functions.contains_key(name) => {
+                let func = &self.functions[name];
+                if args.len() != func.params.len() {
+                    return Err(QueryError::Generic(format!(
+                        "Argument number mismatch for {}: Expected {}, got {}",
+                        name, func.params.len(), args.len()
                     )))
                 }
+                let res = try!(self.eval_precise(&func.apply(args), digits));
+                if let Some(ref result) = func.result {
+                    let result = try!(self.eval(result));
+                    // a vector can be declared with one unit for all
+                    // of its components
+                    let conforms = match (res.units(), result.units()) {
+                        (Some(ref left), Some(ref right)) if right.len() == 1 =>
+                            left.iter().all(|x| *x == right[0]),
+                        (Some(left), Some(right)) => left == right,
+                        _ => false,
+                    };
+                    if !conforms {
+                        return Err(QueryError::Generic(format!(
+                            "Conformance error in result of {}: {} != {}",
+                            name, res.show(self), result.show(self)
+                        )))
+                    }
+                }
+                Ok(res)
             },
-            Expr::Error(ref e) => Err(QueryError::Generic(e.clone())),
+            Expr::Call(ref name, ref args, _) if name == "if" && args.len() == 3 =>
+                match try!(self.eval(&args[0])) {
+                    Value::Bool(true) => self.eval_precise(&args[1], digits),
+                    Value::Bool(false) => self.eval_precise(&args[2], digits),
+                    x => Err(QueryError::Generic(format!(
+                        "Expected a comparison as the condition of if, got <{}>", x.show(self)
+                    ))),
+                },
+            Expr::Call(ref name, ref exprs, _) if ::text_query::is_func(name) &&
+                !self.is_nonlinear(name) => {
+                let args = try!(
+                    exprs.iter()
+                        .map(|x| self.eval_precise(x, digits))
+                        .collect::<Result<Vec<_>, _>>());
+                let reals = args.iter().map(|x| match *x {
+                    Value::Number(ref num) => Some(num.value.to_mpq()),
+                    _ => None,
+                }).collect::<Option<Vec<_>>>();
+                // the square roots of negative numbers are imaginary,
+                // and ln(-x) = ln(x) + πi
+                let complex = match reals {
+                    Some(ref reals) if name == "sqrt" && reals.len() == 1 &&
+                        reals[0] < Mpq::zero() =>
+                        Some((Mpq::zero(), ::precise::root(&-&reals[0], 2, digits))),
+                    Some(ref reals) if name == "ln" && reals.len() == 1 &&
+                        reals[0] < Mpq::zero() => {
+                        let re = try!(::precise::ln(&-&reals[0], digits).map_err(|e| {
+                            QueryError::Generic(format!("{}: ln({})", e, exprs[0]))
+                        }));
+                        Some((re, ::precise::pi(digits)))
+                    },
+                    _ => None,
+                };
+                let value = match reals {
+                    Some(ref reals) if complex.is_none() =>
+                        match ::precise::call(name, reals, digits) {
+                            Some(value) => Some(try!(value.map_err(|e| QueryError::Generic(format!(
+                                "{}: {}({})", e, name, exprs.iter()
+                                    .map(|x| format!("{}", x))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ))))),
+                            None => None,
+                        },
+                    _ => None,
+                };
+                // the usual computation checks the arguments and gives
+                // the unit of the result, and the value is replaced
+                Ok(match (try!(self.call_builtin(name, &args)), value, complex) {
+                    (Value::Number(res), Some(value), _) => Value::Number(Number {
+                        value: Num::Mpq(value),
+                        unit: res.unit,
+                    }),
+                    (Value::Complex(res), _, Some((re, im))) => Value::Complex(Complex {
+                        re: Num::Mpq(re),
+                        im: Num::Mpq(im),
+                        unit: res.unit,
+                    }),
+                    (Value::Number(res), _, _) if name == "abs" => match args[0] {
+                        Value::Complex(Complex { re: Num::Mpq(ref re), im: Num::Mpq(ref im), .. }) =>
+                            Value::Number(Number {
+                                value: Num::Mpq(::precise::hypot(re, im, digits)),
+                                unit: res.unit,
+                            }),
+                        _ => Value::Number(res),
+                    },
+                    (res, _, _) => res,
+                })
+            },
+            _ => self.eval(expr),
+        }
+    }
+
+    /// Evaluates the expression of a conversion, precisely when a
+    /// number of digits is asked for.
+    fn eval_digits(&self, expr: &Expr, digits: Digits) -> Result<Value, QueryError> {
+        match digits {
+            Digits::Digits(n) => self.eval_precise(expr, n as u32),
+            _ => self.eval(expr),
         }
     }
 
     pub fn eval_unit_name(&self, expr: &Expr) -> Result<(BTreeMap<String, isize>, Num), QueryError> {
         match *expr {
             Expr::Equals(ref left, ref _right) => match **left {
-                Expr::Unit(ref name) => {
+                Expr::Unit(ref name, _) => {
                     let mut map = BTreeMap::new();
                     map.insert(name.clone(), 1);
                     Ok((map, Num::one()))
@@ -361,16 +942,16 @@ impl Context {
                     "Expected identifier, got {:?}", x
                 )))
             },
-            Expr::Call(_, _) => Err(QueryError::Generic(format!(
+            Expr::Call(_, _, _) => Err(QueryError::Generic(format!(
                 "Calls are not allowed in the right hand side of conversions"
             ))),
-            Expr::Unit(ref name) | Expr::Quote(ref name) => {
+            Expr::Unit(ref name, _) | Expr::Quote(ref name) => {
                 let mut map = BTreeMap::new();
                 map.insert(self.canonicalize(&**name)
                            .unwrap_or_else(|| name.clone()), 1);
                 Ok((map, Num::one()))
             },
-            Expr::Const(ref i) =>
+            Expr::Const(ref i, _) =>
                 Ok((BTreeMap::new(), i.clone())),
             Expr::Frac(ref left, ref right) => {
                 let (left, lv) = try!(self.eval_unit_name(left));
@@ -455,6 +1036,15 @@ impl Context {
                 }
                 Ok(left)
             },
+            Expr::PlusMinus(_, _) => Err(QueryError::Generic(format!(
+                "Uncertainties are not allowed in the right hand side of conversions"
+            ))),
+            Expr::Imaginary(_) => Err(QueryError::Generic(format!(
+                "Complex numbers are not allowed in the right hand side of conversions"
+            ))),
+            Expr::Vector(_) => Err(QueryError::Generic(format!(
+                "Vectors are not allowed in the right hand side of conversions"
+            ))),
             Expr::Neg(ref v) => self.eval_unit_name(v).map(|(u, v)| (u, -&v)),
             Expr::Plus(ref v) => self.eval_unit_name(v),
             Expr::Suffix(_, _) =>
@@ -464,7 +1054,13 @@ impl Context {
             Expr::Date(_) => Err(QueryError::Generic(format!(
                 "Dates are not allowed in the right hand side of conversions"
             ))),
-            Expr::Error(ref e) => Err(QueryError::Generic(e.clone())),
+            Expr::OfAt(_, _, _) => Err(QueryError::Generic(format!(
+                "Tabulated properties are not allowed in the right hand side of conversions"
+            ))),
+            Expr::Compare(_, _, _) => Err(QueryError::Generic(format!(
+                "Comparisons are not allowed in the right hand side of conversions"
+            ))),
+            Expr::Error(ref e, _) => Err(QueryError::Generic(e.clone())),
         }
     }
 
@@ -493,106 +1089,677 @@ impl Context {
             suggestions.push(format!("{word} right side by {}", desc.trim(), word=word));
         }
 
-        ConformanceError {
-            left: top.to_parts(self),
-            right: bottom.to_parts(self),
-            suggestions: suggestions,
+        ConformanceError {
+            left: top.to_parts(self),
+            right: bottom.to_parts(self),
+            suggestions: suggestions,
+        }
+    }
+
+    pub fn show(
+        &self,
+        raw: &Number,
+        bottom: &Number,
+        bottom_name: BTreeMap<String, isize>,
+        bottom_const: Num,
+        base: u8,
+        digits: Digits,
+    ) -> ConversionReply {
+        let (exact, approx) = raw.numeric_value(base, digits);
+        let bottom_name = bottom_name.into_iter().map(
+            |(a,b)| (Dim::new(&*a), b as i64)).collect();
+        let (num, den) = bottom_const.to_rational();
+        ConversionReply {
+            value: NumberParts {
+                exact_value: exact,
+                approx_value: approx,
+                factor: if num != Int::one() {
+                    Some(format!("{}", num))
+                } else {
+                    None
+                },
+                divfactor: if den != Int::one() {
+                    Some(format!("{}", den))
+                } else {
+                    None
+                },
+                unit: Some(Number::unit_to_string(&bottom_name)),
+                raw_unit: Some(bottom_name),
+                ..bottom.to_parts(self)
+            },
+        }
+    }
+
+    fn to_list(
+        &self, top: &Number, list: &[&str]
+    ) -> Result<Vec<NumberParts>, QueryError> {
+        let units = try!(list.iter().map(|x| {
+            self.lookup_traced(x).ok_or_else(|| self.unknown_unit_err(x))
+        }).collect::<Result<Vec<Number>, _>>());
+        {
+            let first = try!(units.first().ok_or(
+                format!("Expected non-empty unit list")));
+            try!(units.iter().skip(1).map(|x| {
+                if first.unit != x.unit {
+                    Err(format!(
+                        "Units in unit list must conform: <{}> ; <{}>",
+                        first.show(self), x.show(self)))
+                } else {
+                    Ok(())
+                }
+            }).collect::<Result<Vec<()>, _>>());
+            if top.unit != first.unit {
+                return Err(QueryError::Conformance(
+                    self.conformance_err(&top, &first)))
+            }
+        }
+        let mut value = top.value.clone();
+        let mut out = vec![];
+        let len = units.len();
+        for (i, unit) in units.into_iter().enumerate() {
+            if i == len-1 {
+                out.push(&value / &unit.value);
+            } else {
+                let (div, rem) = value.div_rem(&unit.value);
+                out.push(div);
+                value = rem;
+            }
+        }
+        Ok(list.iter().zip(out.into_iter()).map(|(name, value)| {
+            let pretty = Number {
+                value: value,
+                unit: Number::one_unit(Dim::new(name)).unit
+            }.to_parts(self);
+            let unit: String = pretty.unit.or(pretty.dimensions)
+                .map(|x| self.canonicalize(&*x).unwrap_or(x))
+                .expect("to_parts returned no dimensions");
+            let mut raw = BTreeMap::new();
+            raw.insert(Dim::new(&unit), 1);
+            NumberParts {
+                unit: Some(unit),
+                raw_unit: Some(raw),
+                exact_value: Some(pretty.approx_value.or(pretty.exact_value)
+                                  .expect("to_parts returned neither exact nor approx value")),
+                ..Default::default()
+            }
+        }).collect())
+    }
+
+    /// Converts a value into the reply used to display it when it is
+    /// the result of a query.
+    pub fn value_to_reply(&self, val: Value) -> Result<QueryReply, QueryError> {
+        match val {
+            Value::Number(ref n) if n.unit == Number::one_unit(Dim::new("s")).unit => {
+                let units = &["year", "week", "day", "hour", "minute", "second"];
+                let list = try!(self.to_list(&n, units));
+                let mut list = list.into_iter();
+                Ok(QueryReply::Duration(DurationReply {
+                    raw: n.to_parts(self),
+                    years: list.next().expect("Unexpected end of iterator"),
+                    //months: list.next().expect("Unexpected end of iterator"),
+                    months: NumberParts {
+                        exact_value: Some("0".to_owned()),
+                        unit: Some("month".to_owned()),
+                        raw_unit: Some({
+                            let mut raw = BTreeMap::new();
+                            raw.insert(Dim::new("month"), 1);
+                            raw
+                        }),
+                        ..Default::default()
+                    },
+                    weeks: list.next().expect("Unexpected end of iterator"),
+                    days: list.next().expect("Unexpected end of iterator"),
+                    hours: list.next().expect("Unexpected end of iterator"),
+                    minutes: list.next().expect("Unexpected end of iterator"),
+                    seconds: list.next().expect("Unexpected end of iterator"),
+                }))
+            },
+            Value::Number(n) => Ok(QueryReply::Number(n.to_parts(self))),
+            Value::DateTime(d) => match d {
+                date::GenericDateTime::Fixed(d) => Ok(QueryReply::Date(DateReply::new(self, d))),
+                date::GenericDateTime::Timezone(d) => Ok(QueryReply::Date(DateReply::new(self, d))),
+            },
+            Value::Substance(s) => Ok(QueryReply::Substance(
+                try!(s.to_reply(self).map_err(QueryError::Generic))
+            )),
+            Value::Logarithmic(l) => Ok(QueryReply::Logarithmic(
+                self.log_reply(&l, 10, Digits::Default)
+            )),
+            Value::Uncertain(u) => Ok(QueryReply::Number(u.to_parts(self))),
+            Value::Complex(c) => Ok(QueryReply::Number(c.to_parts(self))),
+            Value::Vector(v) => Ok(QueryReply::Number(v.to_parts(self))),
+            Value::Mixture(m) => Ok(QueryReply::Substance(
+                try!(m.to_reply(self).map_err(QueryError::Generic))
+            )),
+            Value::Bool(v) => Ok(QueryReply::Bool(BoolReply { value: v })),
+        }
+    }
+
+    /// Compares two values with the same dimensions, by the sign of
+    /// their difference so that dates can be compared too. Uncertain
+    /// values compare by their central values, except that `~=` holds
+    /// within their uncertainty. Other values are approximately equal
+    /// when they agree to the significant digits of results.
+    fn compare(&self, op: CompareOp, left: &Value, right: &Value) -> Result<bool, QueryError> {
+        use std::cmp::Ordering;
+
+        let undefined = || QueryError::Generic(format!(
+            "Comparison is not defined: <{}> {} <{}>",
+            left.show(self), op, right.show(self)
+        ));
+        match (left, right) {
+            (&Value::Number(ref l), &Value::Number(ref r)) if l.unit != r.unit =>
+                return Err(QueryError::Conformance(self.conformance_err(l, r))),
+            (&Value::Bool(l), &Value::Bool(r)) => return match op {
+                CompareOp::Eq | CompareOp::Approx => Ok(l == r),
+                CompareOp::NotEq => Ok(l != r),
+                _ => Err(undefined()),
+            },
+            _ => ()
+        }
+        let diff = try!((left - right).map_err(|_| undefined()));
+        let (diff, tolerance) = match (left, right, diff) {
+            (_, _, Value::Uncertain(diff)) => (diff.value.value, diff.error),
+            (&Value::Number(ref l), &Value::Number(ref r), Value::Number(diff)) => {
+                let largest = l.value.to_f64().abs().max(r.value.to_f64().abs());
+                let scale = 10f64.powi(-(self.significant_digits as i32));
+                (diff.value, Num::Float(largest * scale * 5.0))
+            },
+            // the difference of dates is a duration
+            (_, _, Value::Number(diff)) => (diff.value, Num::zero()),
+            _ => return Err(undefined())
+        };
+        let ordering = try!(diff.partial_cmp(&Num::zero()).ok_or_else(undefined));
+        Ok(match op {
+            CompareOp::Less => ordering == Ordering::Less,
+            CompareOp::LessEq => ordering != Ordering::Greater,
+            CompareOp::Greater => ordering == Ordering::Greater,
+            CompareOp::GreaterEq => ordering != Ordering::Less,
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::NotEq => ordering != Ordering::Equal,
+            CompareOp::Approx => diff.abs() <= tolerance,
+        })
+    }
+
+    /// Checks an assertion like `assert 1 mile == 1609 m within 0.1%`.
+    /// A dimensionless tolerance is relative to the right side, one
+    /// with units is the largest difference allowed.
+    fn eval_assert(&self, expr: &Expr, within: Option<&Expr>) -> Result<QueryReply, QueryError> {
+        let (op, left, right) = match *expr {
+            Expr::Compare(op, ref left, ref right) =>
+                (op, try!(self.eval(left)), try!(self.eval(right))),
+            _ => return match (try!(self.eval(expr)), within) {
+                (Value::Bool(true), None) => Ok(QueryReply::Bool(BoolReply { value: true })),
+                (Value::Bool(false), None) => Err(QueryError::Generic(format!(
+                    "Assertion failed: {}", expr))),
+                (_, Some(_)) => Err(QueryError::Generic(format!(
+                    "Expected `==` before within, got {}", expr))),
+                (x, None) => Err(QueryError::Generic(format!(
+                    "Expected a comparison after assert, got <{}>", x.show(self)))),
+            }
+        };
+        let passed = match within {
+            None => try!(self.compare(op, &left, &right)),
+            Some(tolerance) if op == CompareOp::Eq => {
+                let tolerance = match try!(self.eval(tolerance)) {
+                    Value::Number(n) => n,
+                    x => return Err(QueryError::Generic(format!(
+                        "Expected a number after within, got <{}>", x.show(self))))
+                };
+                let (l, r) = match (&left, &right) {
+                    (&Value::Number(ref l), &Value::Number(ref r)) => (l, r),
+                    _ => return Err(QueryError::Generic(format!(
+                        "Tolerances are only defined for numbers, got <{}> == <{}>",
+                        left.show(self), right.show(self))))
+                };
+                if l.unit != r.unit {
+                    return Err(QueryError::Conformance(self.conformance_err(l, r)))
+                }
+                let allowed = if tolerance.dimless() {
+                    &r.value.abs() * &tolerance.value
+                } else if tolerance.unit == l.unit {
+                    tolerance.value.abs()
+                } else {
+                    return Err(QueryError::Conformance(self.conformance_err(l, &tolerance)))
+                };
+                (&l.value - &r.value).abs() <= allowed
+            },
+            Some(_) => return Err(QueryError::Generic(format!(
+                "Expected `==` before within, got `{}`", op))),
+        };
+        if passed {
+            return Ok(QueryReply::Bool(BoolReply { value: true }))
+        }
+        let within = match within {
+            Some(tolerance) => format!(" within {}", tolerance),
+            None => String::new(),
+        };
+        Err(QueryError::Generic(format!(
+            "Assertion failed: <{}> {} <{}>{}",
+            left.show(self), op, right.show(self), within
+        )))
+    }
+
+    fn log_reply(&self, log: &Logarithmic, base: u8, digits: Digits) -> LogarithmicReply {
+        LogarithmicReply {
+            level: log.to_string(base, digits),
+            linear: if log.is_absolute() {
+                Some(log.to_linear().to_parts(self))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Evaluates a query, including ones that modify the session:
+    /// variable assignments (`x = 5 kg`) and `unset x`. Results of
+    /// expressions and conversions are appended to the history, where
+    /// they can be referred to as `ans`, `_`, `$1`, `$2`, ...
+    pub fn eval_query(&mut self, query: &Query) -> Result<QueryReply, QueryError> {
+        self.eval_query_unspanned(query).map_err(|e| e.at(query.span()))
+    }
+
+    fn eval_query_unspanned(&mut self, query: &Query) -> Result<QueryReply, QueryError> {
+        match *query {
+            Query::Expr(Expr::Equals(ref left, ref right)) => {
+                let name = match **left {
+                    Expr::Unit(ref name, _) => name.clone(),
+                    ref x => return Err(QueryError::Generic(format!(
+                        "Expected variable name on left side of =, got {}", x
+                    )))
+                };
+                if name == "now" || name == "ans" || name == "_" || name.starts_with("$") {
+                    return Err(QueryError::Generic(format!(
+                        "Cannot assign to {}, it is a builtin", name
+                    )))
+                }
+                let value = try!(self.eval(right));
+                let reply = try!(self.value_to_reply(value.clone()));
+                let shadows = self.is_defined(&name);
+                self.variables.insert(name.clone(), value.clone());
+                self.history.push(value);
+                Ok(QueryReply::Assign(AssignReply {
+                    name: name,
+                    value: Box::new(reply),
+                    shadows: shadows,
+                }))
+            },
+            Query::Unset(ref name) => {
+                let variable = self.variables.remove(name).is_some();
+                let function = self.functions.remove(name).is_some();
+                if variable || function {
+                    Ok(QueryReply::Unset(UnsetReply {
+                        name: name.clone(),
+                        variable: variable,
+                        function: function,
+                    }))
+                } else {
+                    Err(QueryError::Generic(format!(
+                        "No such variable or function {}", name
+                    )))
+                }
+            },
+            Query::Function(ref name, ref def) => {
+                try!(self.check_function(name, def));
+                self.functions.insert(name.clone(), def.clone());
+                Ok(QueryReply::Function(FunctionReply {
+                    name: name.clone(),
+                    params: def.params.clone(),
+                    body: def.body.to_string(),
+                    result: def.result.as_ref().map(|x| x.to_string()),
+                }))
+            },
+            _ => {
+                let mut value = None;
+                let reply = try!(self.eval_outer_with(query, &mut value));
+                if let Some(value) = value {
+                    self.history.push(value);
+                }
+                Ok(reply)
+            },
+        }
+    }
+
+    /// The exact value of a step of an explanation, in base units.
+    fn explain_parts(&self, value: &Number) -> NumberParts {
+        NumberParts {
+            quantity: self.quantities.get(&value.unit).cloned(),
+            ..value.to_parts_simple()
+        }
+    }
+
+    /// Adds the steps that derive a unit to an explanation, after the
+    /// ones for the units it is defined in terms of.
+    fn explain_unit(&self, name: &str, steps: &mut Vec<ExplainStep>,
+                    seen: &mut BTreeSet<String>) {
+        if !seen.insert(name.to_owned()) {
+            return
+        }
+        let resolved = match self.resolve(name) {
+            Some(resolved) => resolved,
+            None => return
+        };
+        let value = self.explain_parts(&resolved.value);
+        if resolved.prefix.is_some() || resolved.plural {
+            self.explain_unit(&resolved.unit, steps, seen);
+            let singular = match resolved.prefix {
+                Some((ref prefix, _)) => {
+                    let singular = format!("{}{}", prefix, resolved.unit);
+                    steps.push(ExplainStep {
+                        kind: ExplainKind::Prefix,
+                        name: singular.clone(),
+                        detail: Some(format!("{} {}", prefix, resolved.unit)),
+                        value: value.clone(),
+                    });
+                    singular
+                },
+                None => resolved.unit.clone(),
+            };
+            if resolved.plural {
+                steps.push(ExplainStep {
+                    kind: ExplainKind::Plural,
+                    name: name.to_owned(),
+                    detail: Some(singular),
+                    value: value,
+                });
+            }
+        } else if self.dimensions.contains(name) {
+            steps.push(ExplainStep {
+                kind: ExplainKind::BaseUnit,
+                name: name.to_owned(),
+                detail: None,
+                value: value,
+            });
+        } else if let Some(def) = self.definitions.get(name) {
+            let mut names = vec![];
+            unit_names(def, &mut names);
+            for unit in &names {
+                self.explain_unit(unit, steps, seen);
+            }
+            steps.push(ExplainStep {
+                kind: ExplainKind::Definition,
+                name: name.to_owned(),
+                detail: Some(def.to_string()),
+                value: value,
+            });
+        } else {
+            steps.push(ExplainStep {
+                kind: ExplainKind::Value,
+                name: name.to_owned(),
+                detail: None,
+                value: value,
+            });
+        }
+    }
+
+    /// Adds a step with the value of a part of the query, unless it is
+    /// a unit that already has one.
+    fn explain_expr(&self, expr: &Expr, steps: &mut Vec<ExplainStep>) -> Option<Number> {
+        let value = match self.eval(expr) {
+            Ok(Value::Number(value)) => value,
+            _ => return None
+        };
+        if let Expr::Unit(_, _) = *expr {
+            return Some(value)
+        }
+        steps.push(ExplainStep {
+            kind: ExplainKind::Value,
+            name: expr.to_string(),
+            detail: None,
+            value: self.explain_parts(&value),
+        });
+        Some(value)
+    }
+
+    /// The value of a session variable, unless a definition is being
+    /// evaluated.
+    fn variable(&self, name: &str) -> Option<&Value> {
+        if self.in_definition.get() {
+            None
+        } else {
+            self.variables.get(name)
+        }
+    }
+
+    /// Evaluates part of a nonlinear or table unit, which only refers
+    /// to units and not to the variables of the session.
+    fn eval_definition(&self, expr: &Expr) -> Result<Value, QueryError> {
+        let outer = self.in_definition.replace(true);
+        let res = self.eval(expr);
+        self.in_definition.set(outer);
+        res
+    }
+
+    /// Divides a value by the unit of a nonlinear or table unit.
+    fn in_unit_of(&self, name: &str, value: &Number, unit: &Number) -> Result<Number, QueryError> {
+        (value / unit).ok_or_else(|| QueryError::Generic(format!(
+            "The unit of {} is zero", name
+        )))
+    }
+
+    fn nonlinear_unit(&self, unit: &Option<Expr>) -> Result<Number, QueryError> {
+        match *unit {
+            Some(ref unit) => match try!(self.eval_definition(unit)) {
+                Value::Number(num) => Ok(num),
+                x => Err(QueryError::Generic(format!(
+                    "Expected Number, got <{}>", x.show(self)
+                )))
+            },
+            None => Ok(Number::one()),
+        }
+    }
+
+    fn check_bounds(
+        &self, name: &str, what: &str, value: &Number, unit: &Number, bounds: &Bounds
+    ) -> Result<(), QueryError> {
+        let scaled = try!(self.in_unit_of(name, value, unit)).value;
+        let bound = |x: &Expr| -> Result<Num, QueryError> {
+            match try!(self.eval_definition(x)) {
+                Value::Number(ref num) if num.unit.len() == 0 => Ok(num.value.clone()),
+                x => Err(QueryError::Generic(format!(
+                    "Expected dimensionless bound, got <{}>", x.show(self)
+                )))
+            }
+        };
+        let above = match bounds.lower {
+            Some((ref x, true)) => scaled >= try!(bound(x)),
+            Some((ref x, false)) => scaled > try!(bound(x)),
+            None => true,
+        };
+        let below = match bounds.upper {
+            Some((ref x, true)) => scaled <= try!(bound(x)),
+            Some((ref x, false)) => scaled < try!(bound(x)),
+            None => true,
+        };
+        if above && below {
+            Ok(())
+        } else {
+            Err(QueryError::Generic(format!(
+                "<{}> is outside the {} of {}, which is {}",
+                value.show(self), what, name, bounds
+            )))
+        }
+    }
+
+    /// Checks that the nonlinear unit behind a temperature suffix like
+    /// °C is defined.
+    fn temperature_unit(&self, suffix: &str, name: &str) -> Result<(), QueryError> {
+        if self.nonlinear.contains_key(name) {
+            Ok(())
+        } else {
+            Err(QueryError::Generic(format!(
+                "°{} is not available, since {} is not defined", suffix, name
+            )))
         }
     }
 
-    pub fn show(
-        &self,
-        raw: &Number,
-        bottom: &Number,
-        bottom_name: BTreeMap<String, isize>,
-        bottom_const: Num,
-        base: u8,
-        digits: Digits,
-    ) -> ConversionReply {
-        let (exact, approx) = raw.numeric_value(base, digits);
-        let bottom_name = bottom_name.into_iter().map(
-            |(a,b)| (Dim::new(&*a), b as i64)).collect();
-        let (num, den) = bottom_const.to_rational();
-        ConversionReply {
-            value: NumberParts {
-                exact_value: exact,
-                approx_value: approx,
-                factor: if num != Int::one() {
-                    Some(format!("{}", num))
-                } else {
-                    None
-                },
-                divfactor: if den != Int::one() {
-                    Some(format!("{}", den))
-                } else {
-                    None
-                },
-                unit: Some(Number::unit_to_string(&bottom_name)),
-                raw_unit: Some(bottom_name),
-                ..bottom.to_parts(self)
-            },
+    /// Evaluates a temperature like `20 °C` with the nonlinear unit
+    /// behind its suffix.
+    fn eval_temperature(&self, left: &Expr, suffix: &str, name: &str) -> Result<Value, QueryError> {
+        let left = match try!(self.eval(left)) {
+            Value::Number(left) => left,
+            left => return Err(QueryError::Generic(format!(
+                "Expected number, got: <{}> °{}", left.show(self), suffix
+            )))
+        };
+        if left.unit != BTreeMap::new() {
+            return Err(QueryError::Generic(format!(
+                "Expected dimensionless, got: <{}>", left.show(self)
+            )))
         }
+        try!(self.temperature_unit(suffix, name));
+        self.eval_nonlinear(name, &left).map(Value::Number)
     }
 
-    fn to_list(
-        &self, top: &Number, list: &[&str]
-    ) -> Result<Vec<NumberParts>, QueryError> {
-        let units = try!(list.iter().map(|x| {
-            self.lookup(x).ok_or_else(|| self.unknown_unit_err(x))
-        }).collect::<Result<Vec<Number>, _>>());
-        {
-            let first = try!(units.first().ok_or(
-                format!("Expected non-empty unit list")));
-            try!(units.iter().skip(1).map(|x| {
-                if first.unit != x.unit {
-                    Err(format!(
-                        "Units in unit list must conform: <{}> ; <{}>",
-                        first.show(self), x.show(self)))
-                } else {
-                    Ok(())
-                }
-            }).collect::<Result<Vec<()>, _>>());
-            if top.unit != first.unit {
-                return Err(QueryError::Conformance(
-                    self.conformance_err(&top, &first)))
-            }
+    /// Evaluates a nonlinear unit, as in `wiregauge(12)`.
+    pub fn eval_nonlinear(&self, name: &str, arg: &Number) -> Result<Number, QueryError> {
+        let def = &self.nonlinear[name];
+        let input = try!(self.nonlinear_unit(&def.input));
+        if arg.unit != input.unit {
+            return Err(QueryError::Conformance(self.conformance_err(arg, &input)))
         }
-        let mut value = top.value.clone();
-        let mut out = vec![];
-        let len = units.len();
-        for (i, unit) in units.into_iter().enumerate() {
-            if i == len-1 {
-                out.push(&value / &unit.value);
-            } else {
-                let (div, rem) = value.div_rem(&unit.value);
-                out.push(div);
-                value = rem;
+        if let Some(ref domain) = def.domain {
+            try!(self.check_bounds(name, "domain", arg, &input, domain));
+        }
+        let expr = substitute(&def.forward, &[def.param.clone()], &[number_expr(arg)]);
+        let res = match try!(self.eval_definition(&expr)) {
+            Value::Number(num) => num,
+            x => return Err(QueryError::Generic(format!(
+                "Expected Number, got <{}>", x.show(self)
+            )))
+        };
+        let output = try!(self.nonlinear_unit(&def.output));
+        if def.output.is_some() && res.unit != output.unit {
+            return Err(QueryError::Conformance(self.conformance_err(&res, &output)))
+        }
+        Ok(res)
+    }
+
+    /// Converts a value into a nonlinear unit using its inverse, as in
+    /// `0.5 mm -> wiregauge`. The result is in the input units of the
+    /// nonlinear unit.
+    pub fn eval_nonlinear_inverse(&self, name: &str, top: &Number) -> Result<Number, QueryError> {
+        let def = &self.nonlinear[name];
+        let inverse = match def.inverse {
+            Some(ref inverse) => inverse,
+            None => return Err(QueryError::Generic(format!(
+                "{} has no inverse, so it can't be converted to", name
+            )))
+        };
+        let output = try!(self.nonlinear_unit(&def.output));
+        if top.unit != output.unit {
+            return Err(QueryError::Conformance(self.conformance_err(top, &output)))
+        }
+        if let Some(ref range) = def.range {
+            try!(self.check_bounds(name, "range", top, &output, range));
+        }
+        let expr = substitute(inverse, &[def.name.clone()], &[number_expr(top)]);
+        let res = match try!(self.eval_definition(&expr)) {
+            Value::Number(num) => num,
+            x => return Err(QueryError::Generic(format!(
+                "Expected Number, got <{}>", x.show(self)
+            )))
+        };
+        let input = try!(self.nonlinear_unit(&def.input));
+        if res.unit != input.unit {
+            return Err(QueryError::Conformance(self.conformance_err(&res, &input)))
+        }
+        self.in_unit_of(name, &res, &input)
+    }
+
+    /// Evaluates a piecewise linear unit, as in `zincgauge(10)`.
+    pub fn eval_table(&self, name: &str, arg: &Number) -> Result<Number, QueryError> {
+        let def = &self.table_units[name];
+        let input = try!(self.nonlinear_unit(&def.input));
+        if arg.unit != input.unit {
+            return Err(QueryError::Conformance(self.conformance_err(arg, &input)))
+        }
+        let output = try!(self.nonlinear_unit(&def.output));
+        let x = try!(self.in_unit_of(name, arg, &input)).value;
+        let res = try!(def.table.eval(&x).ok_or_else(|| {
+            let (lower, upper) = def.table.range();
+            QueryError::Generic(format!(
+                "<{}> is outside the table of {}, which goes from {} to {}",
+                arg.show(self), name,
+                (&Number::new(lower.clone()) * &input).unwrap().show(self),
+                (&Number::new(upper.clone()) * &input).unwrap().show(self)
+            ))
+        }));
+        Ok((&Number::new(res) * &output).unwrap())
+    }
+
+    /// Converts a value into a piecewise linear unit, as in
+    /// `0.03 in -> zincgauge`, which needs the table to be monotonic.
+    /// The result is in the input units of the table.
+    pub fn eval_table_inverse(&self, name: &str, top: &Number) -> Result<Number, QueryError> {
+        let def = &self.table_units[name];
+        if !def.table.is_monotonic() {
+            return Err(QueryError::Generic(format!(
+                "The table of {} is not monotonic, so it can't be converted to", name
+            )))
+        }
+        let output = try!(self.nonlinear_unit(&def.output));
+        if top.unit != output.unit {
+            return Err(QueryError::Conformance(self.conformance_err(top, &output)))
+        }
+        let y = try!(self.in_unit_of(name, top, &output)).value;
+        let res = try!(def.table.invert(&y).ok_or_else(|| {
+            let (lower, upper) = def.table.value_range();
+            QueryError::Generic(format!(
+                "<{}> is outside the range of {}, which goes from {} to {}",
+                top.show(self), name,
+                (&Number::new(lower.clone()) * &output).unwrap().show(self),
+                (&Number::new(upper.clone()) * &output).unwrap().show(self)
+            ))
+        }));
+        Ok(Number::new(res))
+    }
+
+    /// Checks that a function definition can be added: builtins can't
+    /// be redefined, parameter names must be distinct, the body must
+    /// not end up calling the function itself, and the declared unit
+    /// of the result must exist.
+    pub fn check_function(&self, name: &str, def: &FunctionDef) -> Result<(), QueryError> {
+        if ::text_query::is_func(name) {
+            return Err(QueryError::Generic(format!(
+                "Cannot redefine {}, it is a builtin function", name
+            )))
+        }
+        for (i, param) in def.params.iter().enumerate() {
+            if def.params[..i].contains(param) {
+                return Err(QueryError::Generic(format!(
+                    "Parameter {} of {} is declared more than once", param, name
+                )))
             }
         }
-        Ok(list.iter().zip(out.into_iter()).map(|(name, value)| {
-            let pretty = Number {
-                value: value,
-                unit: Number::one_unit(Dim::new(name)).unit
-            }.to_parts(self);
-            let unit: String = pretty.unit.or(pretty.dimensions)
-                .map(|x| self.canonicalize(&*x).unwrap_or(x))
-                .expect("to_parts returned no dimensions");
-            let mut raw = BTreeMap::new();
-            raw.insert(Dim::new(&unit), 1);
-            NumberParts {
-                unit: Some(unit),
-                raw_unit: Some(raw),
-                exact_value: Some(pretty.approx_value.or(pretty.exact_value)
-                                  .expect("to_parts returned neither exact nor approx value")),
-                ..Default::default()
+        if self.calls_function(&def.body, name) {
+            return Err(QueryError::Generic(format!(
+                "Function {} cannot call itself", name
+            )))
+        }
+        if let Some(ref result) = def.result {
+            match try!(self.eval(result)) {
+                Value::Number(_) => (),
+                x => return Err(QueryError::Generic(format!(
+                    "The result of {} must be a unit, got <{}>", name, x.show(self)
+                )))
             }
-        }).collect())
+        }
+        Ok(())
     }
 
     /// Evaluates an expression, include `->` conversions.
     pub fn eval_outer(&self, expr: &Query) -> Result<QueryReply, QueryError> {
+        self.eval_outer_with(expr, &mut None)
+    }
+
+    /// Like `eval_outer`, but also sets `value` to the result of
+    /// expressions and conversions, for the history.
+    fn eval_outer_with(
+        &self, expr: &Query, value: &mut Option<Value>
+    ) -> Result<QueryReply, QueryError> {
         match *expr {
-            Query::Expr(Expr::Unit(ref name)) if {
+            Query::Expr(Expr::Unit(ref name, _)) if !self.variables.contains_key(name) && {
                 let a = self.definitions.contains_key(name);
                 let b = self.canonicalize(name)
                     .map(|x| self.definitions.contains_key(&*x))
@@ -605,7 +1772,7 @@ impl Context {
             } => {
                 let mut name = name.clone();
                 let mut canon = self.canonicalize(&name).unwrap_or_else(|| name.clone());
-                while let Some(&Expr::Unit(ref unit)) = {
+                while let Some(&Expr::Unit(ref unit, _)) = {
                     self.definitions.get(&name).or_else(|| self.definitions.get(&*canon))
                 } {
                     if self.dimensions.contains(&*name) {
@@ -638,8 +1805,10 @@ impl Context {
                         canon = unit_canon.clone();
                     }
                 }
+                let num = self.lookup(&name);
+                *value = num.clone().map(Value::Number);
                 let (def, def_expr, res) = if self.dimensions.contains(&*name) {
-                    let parts = self.lookup(&name)
+                    let parts = num
                         .expect("Lookup of base unit failed")
                         .to_parts(self);
                     let def = if let Some(ref q) = parts.quantity {
@@ -652,7 +1821,7 @@ impl Context {
                     let def = self.definitions.get(&name);
                     (def.as_ref().map(|x| format!("{}", x)),
                      def,
-                     self.lookup(&name).map(|x| x.to_parts(self)))
+                     num.map(|x| x.to_parts(self)))
                 };
                 Ok(QueryReply::Def(DefReply {
                     canon_name: canon,
@@ -663,12 +1832,13 @@ impl Context {
                 }))
             },
             Query::Convert(ref top, Conversion::None, Some(base), digits) => {
-                let top = try!(self.eval(top));
+                let top = try!(self.eval_digits(top, digits));
                 let top = match top {
                     Value::Number(top) => top,
                     _ => return Err(QueryError::Generic(format!(
                         "<{}> in base {} is not defined", top.show(self), base)))
                 };
+                *value = Some(Value::Number(top.clone()));
                 let (exact, approx) = top.numeric_value(base, digits);
                 let parts = NumberParts {
                     exact_value: exact,
@@ -681,9 +1851,15 @@ impl Context {
             },
             Query::Convert(ref top, Conversion::None, base, digits @ Digits::Digits(_)) |
             Query::Convert(ref top, Conversion::None, base, digits @ Digits::FullInt) => {
-                let top = try!(self.eval(top));
+                let top = try!(self.eval_digits(top, digits));
                 let top = match top {
                     Value::Number(top) => top,
+                    Value::Complex(top) => {
+                        *value = Some(Value::Complex(top.clone()));
+                        return Ok(QueryReply::Conversion(ConversionReply {
+                            value: top.to_parts_digits(self, base.unwrap_or(10), digits)
+                        }))
+                    },
                     _ => return Err(QueryError::Generic(format!(
                         "<{}> to {} is not defined",
                         top.show(self),
@@ -694,6 +1870,7 @@ impl Context {
                         }
                     )))
                 };
+                *value = Some(Value::Number(top.clone()));
                 let (exact, approx) = top.numeric_value(
                     base.unwrap_or(10), digits
                 );
@@ -706,69 +1883,304 @@ impl Context {
                     value: parts
                 }))
             },
-            Query::Convert(ref top, Conversion::Expr(ref bottom), base, digits) => match
-                (self.eval(top), self.eval(bottom), self.eval_unit_name(bottom))
-            {
-                (Ok(Value::Number(top)), Ok(Value::Number(bottom)),
-                 Ok((bottom_name, bottom_const))) => {
-                    if top.unit == bottom.unit {
-                        let raw = match &top / &bottom {
-                            Some(raw) => raw,
-                            None => return Err(QueryError::Generic(format!(
-                                "Division by zero: {} / {}",
-                                top.show(self), bottom.show(self))))
-                        };
-                        Ok(QueryReply::Conversion(self.show(
-                            &raw, &bottom,
+            Query::Convert(ref top, Conversion::Expr(Expr::Unit(ref name, _)), None, digits)
+                if self.is_nonlinear(name) => {
+                let top = match try!(self.eval(top)) {
+                    Value::Number(num) => num,
+                    x => return Err(QueryError::Generic(format!(
+                        "Cannot convert <{}> to {}", x.show(self), name
+                    )))
+                };
+                *value = Some(Value::Number(top.clone()));
+                let res = if self.table_units.contains_key(name) {
+                    try!(self.eval_table_inverse(name, &top))
+                } else {
+                    try!(self.eval_nonlinear_inverse(name, &top))
+                };
+                let mut unit_name = BTreeMap::new();
+                unit_name.insert(name.clone(), 1);
+                Ok(QueryReply::Conversion(self.show(
+                    &res, &top,
+                    unit_name, Num::one(),
+                    10,
+                    digits
+                )))
+            },
+            Query::Convert(ref top, Conversion::Expr(Expr::Unit(ref name, _)), base, digits)
+                if self.log_units.contains_key(name) => {
+                let scale = self.log_units[name].clone();
+                let res = match try!(self.eval(top)) {
+                    Value::Number(ref num) => Logarithmic::from_linear(num, scale, self),
+                    Value::Logarithmic(ref log) => log.convert(scale, self),
+                    ref x => return Err(QueryError::Generic(format!(
+                        "Cannot convert <{}> to {}", x.show(self), name
+                    )))
+                };
+                let res = try!(res.map_err(QueryError::Generic));
+                *value = Some(Value::Logarithmic(res.clone()));
+                Ok(QueryReply::Logarithmic(self.log_reply(&res, base.unwrap_or(10), digits)))
+            },
+            Query::Convert(ref top, Conversion::Expr(ref bottom), base, digits) => {
+                // levels are converted to other units through their linear value
+                let top = self.eval_digits(top, digits).map(|top| match top {
+                    Value::Logarithmic(ref log) => Value::Number(log.to_linear()),
+                    top => top,
+                });
+                *value = top.as_ref().ok().cloned();
+                match (top, self.eval_digits(bottom, digits), self.eval_unit_name(bottom)) {
+                    (Ok(Value::Number(top)), Ok(Value::Number(bottom)),
+                     Ok((bottom_name, bottom_const))) => {
+                        if top.unit == bottom.unit {
+                            let raw = match &top / &bottom {
+                                Some(raw) => raw,
+                                None => return Err(QueryError::Generic(format!(
+                                    "Division by zero: {} / {}",
+                                    top.show(self), bottom.show(self))))
+                            };
+                            Ok(QueryReply::Conversion(self.show(
+                                &raw, &bottom,
+                                bottom_name, bottom_const,
+                                base.unwrap_or(10),
+                                digits
+                            )))
+                        } else {
+                            Err(QueryError::Conformance(self.conformance_err(
+                                &top, &bottom)))
+                        }
+                    },
+                    (Ok(Value::Complex(top)), Ok(Value::Number(bottom)),
+                     Ok((bottom_name, bottom_const))) => {
+                        if top.unit != bottom.unit {
+                            return Err(QueryError::Conformance(self.conformance_err(
+                                &top.abs(), &bottom)))
+                        }
+                        let raw = try!(top.div(&Complex::from_number(&bottom)).map_err(|e| {
+                            QueryError::Generic(format!(
+                                "{}: {} / {}", e, top.show(self), bottom.show(self)))
+                        }));
+                        let mut reply = self.show(
+                            &raw.abs(), &bottom,
                             bottom_name, bottom_const,
                             base.unwrap_or(10),
                             digits
-                        )))
-                    } else {
-                        Err(QueryError::Conformance(self.conformance_err(
-                            &top, &bottom)))
+                        );
+                        reply.value = raw.fill_parts(
+                            &Num::one(), base.unwrap_or(10), digits, reply.value
+                        );
+                        Ok(QueryReply::Conversion(reply))
+                    },
+                    (Ok(Value::Vector(top)), Ok(Value::Number(bottom)),
+                     Ok((bottom_name, bottom_const))) => {
+                        // every component is converted on its own
+                        if let Some(elem) = top.elems.iter().find(|x| x.unit != bottom.unit) {
+                            return Err(QueryError::Conformance(self.conformance_err(
+                                elem, &bottom)))
+                        }
+                        let raw = try!(top.div(&bottom).map_err(|e| {
+                            QueryError::Generic(format!(
+                                "{}: {} / {}", e, top.show(self), bottom.show(self)))
+                        }));
+                        let mut reply = self.show(
+                            &raw.elems[0], &bottom,
+                            bottom_name, bottom_const,
+                            base.unwrap_or(10),
+                            digits
+                        );
+                        reply.value = raw.fill_parts(
+                            &Num::one(), base.unwrap_or(10), digits, reply.value
+                        );
+                        Ok(QueryReply::Conversion(reply))
+                    },
+                    (Ok(Value::Uncertain(top)), Ok(Value::Number(bottom)),
+                     Ok((bottom_name, bottom_const))) => {
+                        if top.value.unit != bottom.unit {
+                            return Err(QueryError::Conformance(self.conformance_err(
+                                &top.value, &bottom)))
+                        }
+                        let raw = try!(top.div(&Uncertain::exact(&bottom)).map_err(|e| {
+                            QueryError::Generic(format!(
+                                "{}: {} / {}", e, top.show(self), bottom.show(self)))
+                        }));
+                        let mut reply = self.show(
+                            &raw.value, &bottom,
+                            bottom_name, bottom_const,
+                            base.unwrap_or(10),
+                            digits
+                        );
+                        reply.value = raw.fill_parts(1.0, reply.value);
+                        Ok(QueryReply::Conversion(reply))
+                    },
+                    (Ok(Value::Substance(sub)), Ok(Value::Number(bottom)),
+                     Ok((bottom_name, bottom_const))) => {
+                        sub.get_in_unit(
+                            bottom,
+                            self,
+                            bottom_name,
+                            bottom_const,
+                            base.unwrap_or(10),
+                            digits
+                        ).map_err(
+                            QueryError::Generic
+                        ).map(
+                            QueryReply::Substance
+                        )
+                    },
+                    (Ok(Value::Number(top)), Ok(Value::Substance(mut sub)),
+                     Ok((bottom_name, bottom_const))) => {
+                        let unit = sub.amount.clone();
+                        sub.amount = top;
+                        sub.get_in_unit(
+                            unit,
+                            self,
+                            bottom_name,
+                            bottom_const,
+                            base.unwrap_or(10),
+                            digits
+                        ).map_err(
+                            QueryError::Generic
+                        ).map(
+                            QueryReply::Substance
+                        )
+                    },
+                    (Ok(x), Ok(y), Ok(_)) => Err(QueryError::Generic(format!(
+                        "Operation is not defined: <{}> -> <{}>",
+                        x.show(self),
+                        y.show(self)
+                    ))),
+                    (Err(e), _, _) => Err(e),
+                    (_, Err(e), _) => Err(e),
+                    (_, _, Err(e)) => Err(e),
+                }
+            },
+            Query::Convert(ref top, Conversion::Interval, None, Digits::Default) => {
+                let res = try!(self.eval_interval(top));
+                if let Some(num) = res.to_number() {
+                    *value = Some(Value::Number(num.clone()));
+                    return Ok(QueryReply::Number(num.to_parts(self)))
+                }
+                let certified = res.certified_digits();
+                // show a few digits beyond the certified ones, so that
+                // it is visible where the bounds start to differ
+                let digits = certified.as_ref().map(|&(n, _)| n as i32 + 2).unwrap_or(6);
+                let parts = Number {
+                    value: Num::one(),
+                    unit: res.unit.clone(),
+                }.to_parts(self);
+                Ok(QueryReply::Interval(IntervalReply {
+                    lower: format_bound(&res.lower, digits, false),
+                    upper: format_bound(&res.upper, digits, true),
+                    digits: certified.as_ref().map(|&(n, _)| n),
+                    value: certified.map(|(_, value)| value),
+                    of: NumberParts {
+                        unit: parts.unit,
+                        dimensions: parts.dimensions,
+                        quantity: parts.quantity,
+                        ..Default::default()
+                    },
+                }))
+            },
+            Query::Convert(ref top, Conversion::Composition, None, Digits::Default) => {
+                let name = match try!(self.eval(top)) {
+                    Value::Substance(sub) => {
+                        let name = sub.properties.name.clone();
+                        *value = Some(Value::Substance(sub));
+                        name
+                    },
+                    x => return Err(QueryError::Generic(format!(
+                        "Composition is only defined for chemical formulas, got <{}>",
+                        x.show(self)
+                    )))
+                };
+                let formula = try!(parse_formula(&name).map_err(|_| QueryError::Generic(format!(
+                    "Composition is only defined for chemical formulas, got {}", name
+                ))));
+                let parts = try!(composition(
+                    &formula, &self.substance_symbols, &self.substances
+                ).ok_or_else(|| QueryError::Generic(format!(
+                    "Unknown element or isotope in {}", name
+                ))));
+                let total = parts.iter().skip(1).fold(parts[0].2.clone(), |acc, x| {
+                    (&acc + &x.2).expect("Bug: molar masses have the same unit")
+                });
+                let molar_mass = try!(substance_from_formula(
+                    &name, &self.substance_symbols, &self.substances
+                ).and_then(|sub| sub.get("molar_mass").ok()).ok_or_else(|| {
+                    QueryError::Generic(format!("Unknown element or isotope in {}", name))
+                }));
+                Ok(QueryReply::Composition(CompositionReply {
+                    formula: name.clone(),
+                    molar_mass: molar_mass.to_parts(self),
+                    charge: formula.charge,
+                    elements: parts.iter().map(|&(ref atom, count, ref mass)| {
+                        let percent = &(&mass.value / &total.value) * &Num::from(100);
+                        ElementReply {
+                            symbol: format!("{}", atom),
+                            name: self.substance_symbols.get(&atom.symbol).cloned(),
+                            count: count,
+                            mass_percent: ::number::to_string(&percent, 10, Digits::Default).1,
+                        }
+                    }).collect(),
+                }))
+            },
+            Query::Convert(ref top, Conversion::Polar, None, Digits::Default) => {
+                let top = try!(self.eval(top));
+                *value = Some(top.clone());
+                let top = match top {
+                    Value::Complex(top) => top,
+                    Value::Number(top) => Complex::from_number(&top),
+                    x => return Err(QueryError::Generic(format!(
+                        "Polar form is not defined for <{}>", x.show(self)
+                    )))
+                };
+                let angle = Num::Float(top.arg().value.to_f64().to_degrees());
+                Ok(QueryReply::Polar(PolarReply {
+                    magnitude: top.abs().to_parts(self),
+                    angle: ::number::to_string(&angle, 10, Digits::Default).1,
+                }))
+            },
+            Query::Convert(ref top, Conversion::System(system), None, Digits::Default) => {
+                match try!(self.eval(top)) {
+                    Value::Number(top) => {
+                        *value = Some(Value::Number(top.clone()));
+                        Ok(QueryReply::Number(top.to_parts_in(self, system)))
+                    },
+                    x => Err(QueryError::Generic(format!(
+                        "Cannot convert <{}> to {} units", x.show(self), system
+                    )))
+                }
+            },
+            Query::Convert(ref top, Conversion::Alternatives, None, Digits::Default) => {
+                let top = match try!(self.eval(top)) {
+                    Value::Number(top) => top,
+                    x => return Err(QueryError::Generic(format!(
+                        "Alternatives are only defined for numbers, got <{}>", x.show(self)
+                    )))
+                };
+                *value = Some(Value::Number(top.clone()));
+                let alternatives = best_units(&top, self, 5).into_iter().map(|num| {
+                    let (exact, approx) = num.numeric_value_with_precision(
+                        10, Digits::Default, self.significant_digits);
+                    NumberParts {
+                        exact_value: exact,
+                        approx_value: approx,
+                        unit: Some(Number::unit_to_string(&num.unit)),
+                        raw_unit: Some(num.unit),
+                        ..Default::default()
                     }
-                },
-                (Ok(Value::Substance(sub)), Ok(Value::Number(bottom)),
-                 Ok((bottom_name, bottom_const))) => {
-                    sub.get_in_unit(
-                        bottom,
-                        self,
-                        bottom_name,
-                        bottom_const,
-                        base.unwrap_or(10),
-                        digits
-                    ).map_err(
-                        QueryError::Generic
-                    ).map(
-                        QueryReply::Substance
-                    )
-                },
-                (Ok(Value::Number(top)), Ok(Value::Substance(mut sub)),
-                 Ok((bottom_name, bottom_const))) => {
-                    let unit = sub.amount.clone();
-                    sub.amount = top;
-                    sub.get_in_unit(
-                        unit,
-                        self,
-                        bottom_name,
-                        bottom_const,
-                        base.unwrap_or(10),
-                        digits
-                    ).map_err(
-                        QueryError::Generic
-                    ).map(
-                        QueryReply::Substance
-                    )
-                },
-                (Ok(x), Ok(y), Ok(_)) => Err(QueryError::Generic(format!(
-                    "Operation is not defined: <{}> -> <{}>",
-                    x.show(self),
-                    y.show(self)
-                ))),
-                (Err(e), _, _) => Err(e),
-                (_, Err(e), _) => Err(e),
-                (_, _, Err(e)) => Err(e),
+                }).collect();
+                let parts = Number {
+                    value: Num::one(),
+                    unit: top.unit.clone(),
+                }.to_parts(self);
+                Ok(QueryReply::Alternatives(AlternativesReply {
+                    alternatives: alternatives,
+                    of: NumberParts {
+                        unit: parts.unit,
+                        dimensions: parts.dimensions,
+                        quantity: parts.quantity,
+                        ..Default::default()
+                    },
+                }))
             },
             Query::Convert(ref top, Conversion::List(ref list), None, Digits::Default) => {
                 let top = try!(self.eval(top));
@@ -777,6 +2189,7 @@ impl Context {
                     _ => return Err(QueryError::Generic(format!(
                         "Cannot convert <{}> to {:?}", top.show(self), list)))
                 };
+                *value = Some(Value::Number(top.clone()));
                 self.to_list(
                     &top,
                     &list.iter()
@@ -796,6 +2209,7 @@ impl Context {
                 use chrono::FixedOffset;
 
                 let top = try!(self.eval(top));
+                *value = Some(top.clone());
                 let top = match top {
                     Value::DateTime(date) => date,
                     _ => return Err(QueryError::Generic(format!(
@@ -806,6 +2220,7 @@ impl Context {
             },
             Query::Convert(ref top, Conversion::Timezone(tz), None, Digits::Default) => {
                 let top = try!(self.eval(top));
+                *value = Some(top.clone());
                 let top = match top {
                     Value::DateTime(date) => date,
                     _ => return Err(QueryError::Generic(format!(
@@ -821,6 +2236,7 @@ impl Context {
             Query::Convert(ref top, ref which @ Conversion::DegRo, None, digits) |
             Query::Convert(ref top, ref which @ Conversion::DegDe, None, digits) => {
                 let top = try!(self.eval(top));
+                *value = Some(top.clone());
                 macro_rules! temperature {
                     ($name:expr, $base:expr, $scale:expr) => {{
                         let top = match top {
@@ -828,13 +2244,13 @@ impl Context {
                             _ => return Err(QueryError::Generic(format!(
                                 "Cannot convert <{}> to °{}", top.show(self), $name)))
                         };
-                        let bottom = self.lookup($scale)
+                        let bottom = self.lookup_traced($scale)
                             .expect(&*format!("Unit {} missing", $scale));
                         if top.unit != bottom.unit {
                             Err(QueryError::Conformance(
                                 self.conformance_err(&top, &bottom)))
                         } else {
-                            let res = (top - &self.lookup($base)
+                            let res = (top - &self.lookup_traced($base)
                                        .expect(&*format!("Constant {} missing", $base))).unwrap();
                             let res = (&res / &bottom).unwrap();
                             let mut name = BTreeMap::new();
@@ -849,9 +2265,29 @@ impl Context {
                     }}
                 }
 
+                macro_rules! nonlinear_temperature {
+                    ($name:expr, $unit:expr) => {{
+                        let top = match top {
+                            Value::Number(ref num) => num,
+                            _ => return Err(QueryError::Generic(format!(
+                                "Cannot convert <{}> to °{}", top.show(self), $name)))
+                        };
+                        try!(self.temperature_unit($name, $unit));
+                        let res = try!(self.eval_nonlinear_inverse($unit, top));
+                        let mut name = BTreeMap::new();
+                        name.insert(format!("°{}", $name), 1);
+                        Ok(QueryReply::Conversion(self.show(
+                            &res, top,
+                            name, Num::one(),
+                            10,
+                            digits
+                        )))
+                    }}
+                }
+
                 match *which {
-                    Conversion::DegC => temperature!("C", "zerocelsius", "kelvin"),
-                    Conversion::DegF => temperature!("F", "zerofahrenheit", "degrankine"),
+                    Conversion::DegC => nonlinear_temperature!("C", "tempC"),
+                    Conversion::DegF => nonlinear_temperature!("F", "tempF"),
                     Conversion::DegRe => temperature!("Ré", "zerocelsius", "reaumur_absolute"),
                     Conversion::DegRo => temperature!("Rø", "zeroromer", "romer_absolute"),
                     Conversion::DegDe => temperature!("De", "zerodelisle", "delisle_absolute"),
@@ -878,7 +2314,7 @@ impl Context {
             },
             Query::Factorize(ref expr) => {
                 let mut val = None;
-                if let Expr::Unit(ref name) = *expr {
+                if let Expr::Unit(ref name, _) = *expr {
                     for (u, k) in &self.quantities {
                         if name == k {
                             val = Some(Number {
@@ -920,7 +2356,7 @@ impl Context {
             },
             Query::UnitsFor(ref expr) => {
                 let mut val = None;
-                if let Expr::Unit(ref name) = *expr {
+                if let Expr::Unit(ref name, _) = *expr {
                     for (u, k) in &self.quantities {
                         if name == k {
                             val = Some(Number {
@@ -946,7 +2382,7 @@ impl Context {
                 let dim_name;
                 let mut out = vec![];
                 for (name, unit) in self.units.iter() {
-                    if let Some(&Expr::Unit(_)) = self.definitions.get(name) {
+                    if let Some(&Expr::Unit(_, _)) = self.definitions.get(name) {
                         continue
                     }
                     let category = self.categories.get(name);
@@ -996,6 +2432,7 @@ impl Context {
                 Ok(QueryReply::UnitsFor(UnitsForReply {
                     units: categories,
                     of: NumberParts {
+                        unit: parts.unit,
                         dimensions: parts.dimensions,
                         quantity: parts.quantity,
                         ..Default::default()
@@ -1033,43 +2470,144 @@ impl Context {
             Query::Expr(ref expr) |
             Query::Convert(ref expr, Conversion::None, None, Digits::Default) => {
                 let val = try!(self.eval(expr));
-                match val {
-                    Value::Number(ref n) if n.unit == Number::one_unit(Dim::new("s")).unit => {
-                        let units = &["year", "week", "day", "hour", "minute", "second"];
-                        let list = try!(self.to_list(&n, units));
-                        let mut list = list.into_iter();
-                        Ok(QueryReply::Duration(DurationReply {
-                            raw: n.to_parts(self),
-                            years: list.next().expect("Unexpected end of iterator"),
-                            //months: list.next().expect("Unexpected end of iterator"),
-                            months: NumberParts {
-                                exact_value: Some("0".to_owned()),
-                                unit: Some("month".to_owned()),
-                                raw_unit: Some({
-                                    let mut raw = BTreeMap::new();
-                                    raw.insert(Dim::new("month"), 1);
-                                    raw
-                                }),
-                                ..Default::default()
-                            },
-                            weeks: list.next().expect("Unexpected end of iterator"),
-                            days: list.next().expect("Unexpected end of iterator"),
-                            hours: list.next().expect("Unexpected end of iterator"),
-                            minutes: list.next().expect("Unexpected end of iterator"),
-                            seconds: list.next().expect("Unexpected end of iterator"),
-                        }))
+                *value = Some(val.clone());
+                self.value_to_reply(val)
+            },
+            Query::Vars => {
+                Ok(QueryReply::Variables(VariablesReply {
+                    variables: self.variables.iter().map(|(name, value)| {
+                        VariableReply {
+                            name: name.clone(),
+                            value: value.show(self),
+                        }
+                    }).collect(),
+                }))
+            },
+            Query::Unset(ref name) => Err(QueryError::Generic(format!(
+                "Cannot unset {}: variables can only be changed within a session",
+                name
+            ))),
+            Query::Function(ref name, _) => Err(QueryError::Generic(format!(
+                "Cannot define {}: functions can only be defined within a session",
+                name
+            ))),
+            Query::Balance(ref reaction) => {
+                let coefficients = try!(::reaction::balance(reaction).map_err(QueryError::Generic));
+                let mut species = reaction.formulas().into_iter()
+                    .zip(coefficients.into_iter())
+                    .map(|(formula, coefficient)| SpeciesReply {
+                        coefficient: coefficient,
+                        formula: formula.to_owned(),
+                    })
+                    .collect::<Vec<_>>();
+                let products = species.split_off(reaction.reactants.len());
+                Ok(QueryReply::Reaction(ReactionReply {
+                    reactants: species,
+                    products: products,
+                }))
+            },
+            Query::Stoichiometry(ref given, ref reaction, ref wanted) => {
+                let coefficients = try!(
+                    ::reaction::coefficients(reaction).map_err(QueryError::Generic));
+                let formulas = reaction.formulas();
+                let molar_mass = |name: &str| substance_from_formula(
+                    name, &self.substance_symbols, &self.substances
+                ).and_then(|sub| sub.get("molar_mass").ok()).ok_or_else(|| {
+                    QueryError::Generic(format!("Unknown element or isotope in {}", name))
+                });
+                let position = |name: &str| formulas.iter().position(|x| *x == name)
+                    .ok_or_else(|| QueryError::Generic(format!(
+                        "{} is not part of {}", name, reaction
+                    )));
+
+                let given = match try!(self.eval(given)) {
+                    Value::Substance(sub) => sub,
+                    x => return Err(QueryError::Generic(format!(
+                        "Expected an amount of a formula in the reaction, got <{}>",
+                        x.show(self)
+                    )))
+                };
+                let given_name = given.properties.name.clone();
+                let given_index = try!(position(&given_name));
+                let mol = Number::one_unit(Dim::new("mol"));
+                let moles = if given.amount.unit == mol.unit {
+                    given.amount.clone()
+                } else {
+                    let moles = (&given.amount / &try!(molar_mass(&given_name)))
+                        .expect("Bug: molar masses are non-zero");
+                    if moles.unit != mol.unit {
+                        return Err(QueryError::Generic(format!(
+                            "Expected an amount or mass of {}, got <{}>",
+                            given_name, given.amount.show(self)
+                        )))
+                    }
+                    moles
+                };
+
+                let (field, wanted_name) = match *wanted {
+                    Expr::Of(ref field, ref name) => match **name {
+                        Expr::Unit(ref name, _) => (field, name),
+                        ref x => return Err(QueryError::Generic(format!(
+                            "Expected a formula in the reaction, got {}", x
+                        )))
+                    },
+                    ref x => return Err(QueryError::Generic(format!(
+                        "Expected `mass of` or `amount of` a formula in the reaction, got {}", x
+                    )))
+                };
+                let wanted_index = try!(position(wanted_name));
+                let ratio = Number::new(
+                    &Num::from(coefficients[wanted_index] as i64) /
+                        &Num::from(coefficients[given_index] as i64)
+                );
+                let wanted_moles = (&moles * &ratio).expect("Multiplication of numbers should not fail");
+                let res = match &**field {
+                    "amount" => wanted_moles,
+                    "mass" => (&wanted_moles * &try!(molar_mass(wanted_name)))
+                        .expect("Multiplication of numbers should not fail"),
+                    _ => return Err(QueryError::Generic(format!(
+                        "Expected `mass of` or `amount of` a formula in the reaction, got {}", wanted
+                    )))
+                };
+                Ok(QueryReply::Number(res.to_parts(self)))
+            },
+            Query::Explain(ref query) => {
+                *self.trace.borrow_mut() = Some(vec![]);
+                let result = self.eval_outer(query);
+                let names = self.trace.borrow_mut().take().unwrap_or_else(Vec::new);
+                let result = try!(result);
+                let mut steps = vec![];
+                let mut seen = BTreeSet::new();
+                for name in &names {
+                    self.explain_unit(name, &mut steps, &mut seen);
+                }
+                match **query {
+                    Query::Convert(ref top, Conversion::Expr(ref bottom), _, _) => {
+                        let top_value = self.explain_expr(top, &mut steps);
+                        let bottom_value = self.explain_expr(bottom, &mut steps);
+                        if let (Some(top_value), Some(bottom_value)) = (top_value, bottom_value) {
+                            if let Some(factor) = &top_value / &bottom_value {
+                                steps.push(ExplainStep {
+                                    kind: ExplainKind::Factor,
+                                    name: format!("({}) / ({})", top, bottom),
+                                    detail: None,
+                                    value: self.explain_parts(&factor),
+                                });
+                            }
+                        }
                     },
-                    Value::Number(n) => Ok(QueryReply::Number(n.to_parts(self))),
-                    Value::DateTime(d) => match d {
-                        date::GenericDateTime::Fixed(d) => Ok(QueryReply::Date(DateReply::new(self, d))),
-                        date::GenericDateTime::Timezone(d) => Ok(QueryReply::Date(DateReply::new(self, d))),
+                    Query::Expr(ref expr) | Query::Convert(ref expr, _, _, _) => {
+                        self.explain_expr(expr, &mut steps);
                     },
-                    Value::Substance(s) => Ok(QueryReply::Substance(
-                        try!(s.to_reply(self).map_err(QueryError::Generic))
-                    )),
+                    _ => (),
                 }
+                Ok(QueryReply::Explain(ExplainReply {
+                    steps: steps,
+                    result: Box::new(result),
+                }))
             },
-            Query::Error(ref e) => Err(QueryError::Generic(e.clone())),
+            Query::Assert(ref expr, ref within) => self.eval_assert(expr, within.as_ref()),
+            Query::Error(ref e, _) => Err(QueryError::Generic(e.clone())),
         }
     }
 }
@@ -6,6 +6,7 @@ use std::collections::{BTreeMap, BinaryHeap};
 use std::rc::Rc;
 use number::{Number, Unit, Dim};
 use num::Num;
+use context::Context;
 use std::cmp;
 
 #[derive(PartialEq, Eq, Debug)]
@@ -23,16 +24,22 @@ impl cmp::Ord for Factors {
     }
 }
 
+/// Whether dividing by the unit would introduce base units that the
+/// value doesn't have.
+fn adds_dims(value: &Number, unit: &Unit) -> bool {
+    unit.iter().any(|(dim, pow)| {
+        let vpow = value.unit.get(dim).cloned().unwrap_or(0);
+        let snum = (vpow - pow).signum();
+        snum != 0 && snum != vpow.signum()
+    })
+}
+
 pub fn fast_decompose(value: &Number, quantities: &BTreeMap<Unit, String>) -> Unit {
     let mut best = None;
-    'outer: for (unit, name) in quantities.iter() {
+    for (unit, name) in quantities.iter() {
         // make sure we aren't doing something weird like introducing new base units
-        for (dim, pow) in unit {
-            let vpow = value.unit.get(dim).cloned().unwrap_or(0);
-            let snum = (vpow - pow).signum();
-            if snum != 0 && snum != vpow.signum() {
-                continue 'outer
-            }
+        if adds_dims(value, unit) {
+            continue
         }
         let num = Number {
             value: Num::one(),
@@ -95,3 +102,78 @@ pub fn factorize(value: &Number, quantities: &BTreeMap<Unit, Rc<String>>)
     assert!(candidates.len() <= 10);
     candidates
 }
+
+/// Ways of writing a number in named units, simplest first, each with
+/// a readable prefix. The candidates are the units `prettify()` would
+/// use, one of the units in `Context::reverse` times what remains in
+/// base units, and factorizations into named and base units.
+pub fn best_units(value: &Number, context: &Context, count: usize) -> Vec<Number> {
+    let mut res = unit_candidates(value, context, true);
+    res.truncate(count);
+    res.into_iter().map(|x| x.with_readable_prefix(context)).collect()
+}
+
+/// The units `best_units()` ranks, simplest first and without
+/// prefixes. Factorizations are only tried if `factor` is set, since
+/// they are too slow to run for every number that is shown.
+pub fn unit_candidates(value: &Number, context: &Context, factor: bool) -> Vec<Number> {
+    let mut candidates = vec![fast_decompose(value, &context.reverse)];
+    for (unit, name) in &context.reverse {
+        let num = Number {
+            value: Num::one(),
+            unit: unit.clone(),
+        };
+        for &i in [-1, 1, 2].iter() {
+            let mut res = (value / &num.powi(i)).unwrap().unit;
+            res.insert(Dim::new(&**name), i as i64);
+            candidates.push(res);
+        }
+    }
+    if factor {
+        let mut named = context.reverse.iter()
+            .map(|(unit, name)| (unit.clone(), Rc::new(name.clone())))
+            .collect::<BTreeMap<_, _>>();
+        for dim in value.unit.keys() {
+            let mut unit = Unit::new();
+            unit.insert(dim.clone(), 1);
+            named.insert(unit, dim.0.clone());
+        }
+        for Factors(_, names) in factorize(value, &named).into_sorted_vec() {
+            let mut unit = Unit::new();
+            for name in names {
+                *unit.entry(Dim(name)).or_insert(0) += 1;
+            }
+            candidates.push(unit);
+        }
+    }
+    candidates.push(value.unit.clone());
+
+    let mut res: Vec<(i64, Number)> = vec![];
+    for unit in candidates {
+        let score = readability_score(&unit, context);
+        let unit = unit.into_iter().map(|(dim, pow)| {
+            (context.canonicalizations.get(&*dim.0).map(|x| Dim::new(x)).unwrap_or(dim), pow)
+        }).collect::<Unit>();
+        if res.iter().any(|x| x.1.unit == unit) {
+            continue
+        }
+        res.push((score, Number {
+            value: value.value.clone(),
+            unit: unit,
+        }));
+    }
+    // the sort is stable, so ties keep the order above
+    res.sort_by_key(|x| x.0);
+    res.into_iter().map(|x| x.1).collect()
+}
+
+/// Like `Number::complexity_score()`, but powers above 2 are hard to
+/// read and count triple, and each named unit costs one more than a
+/// base unit. This way `W/m^2` wins over `kg/s^3`, but `m/s^2` still
+/// wins over `N/kg`.
+fn readability_score(unit: &Unit, context: &Context) -> i64 {
+    unit.iter().map(|(dim, &pow)| {
+        let named = if context.dimensions.contains(dim) { 0 } else { 1 };
+        1 + pow.abs() + 2 * cmp::max(pow.abs() - 2, 0) + named
+    }).sum()
+}
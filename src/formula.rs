@@ -2,94 +2,397 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::str::{Chars, FromStr};
-use std::iter::Peekable;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::rc::Rc;
 use num::Num;
 use number::{Number, Dim};
 use substance::{Property, Properties, Substance};
 
-enum Token {
-    Symbol(String),
-    Count(u32),
-    Error
+/// The symbols of all elements, used to tell formulas like `Ca(OH)2`
+/// apart from function calls while tokenizing.
+const ELEMENTS: &'static [&'static str] = &[
+    "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg", "Al",
+    "Si", "P", "S", "Cl", "Ar", "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe",
+    "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As", "Se", "Br", "Kr", "Rb", "Sr",
+    "Y", "Zr", "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd", "In", "Sn",
+    "Sb", "Te", "I", "Xe", "Cs", "Ba", "La", "Ce", "Pr", "Nd", "Pm", "Sm",
+    "Eu", "Gd", "Tb", "Dy", "Ho", "Er", "Tm", "Yb", "Lu", "Hf", "Ta", "W",
+    "Re", "Os", "Ir", "Pt", "Au", "Hg", "Tl", "Pb", "Bi", "Po", "At", "Rn",
+    "Fr", "Ra", "Ac", "Th", "Pa", "U", "Np", "Pu", "Am", "Cm", "Bk", "Cf",
+    "Es", "Fm", "Md", "No", "Lr", "Rf", "Db", "Sg", "Bh", "Hs", "Mt", "Ds",
+    "Rg", "Cn", "Nh", "Fl", "Mc", "Lv", "Ts", "Og",
+];
+
+/// Atomic masses of common isotopes in daltons, as integer and
+/// fractional digits.
+const ISOTOPES: &'static [(&'static str, u32, &'static str, &'static str)] = &[
+    ("H", 1, "1", "00782503223"),
+    ("H", 2, "2", "01410177812"),
+    ("H", 3, "3", "0160492779"),
+    ("C", 12, "12", "0"),
+    ("C", 13, "13", "00335483507"),
+    ("C", 14, "14", "0032419884"),
+    ("N", 14, "14", "00307400443"),
+    ("N", 15, "15", "00010889888"),
+    ("O", 16, "15", "99491461957"),
+    ("O", 17, "16", "99913175650"),
+    ("O", 18, "17", "99915961286"),
+    ("S", 32, "31", "9720711744"),
+    ("S", 34, "33", "967867004"),
+    ("Cl", 35, "34", "968852682"),
+    ("Cl", 37, "36", "965902602"),
+    ("U", 235, "235", "0439301"),
+    ("U", 238, "238", "0507884"),
+];
+
+/// The molar mass of the electron in kg/mol, which is removed for
+/// every positive charge of an ion.
+const ELECTRON_MOLAR_MASS: (&'static str, &'static str, &'static str) =
+    ("5", "48579909070", "-7");
+
+/// An atom in a formula, with the mass number for isotope labels like
+/// `¹³C`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atom {
+    pub symbol: String,
+    pub mass_number: Option<u32>,
 }
 
-#[derive(Clone)]
-struct TokenIterator<'a>(Peekable<Chars<'a>>);
+/// A chemical formula like `CuSO4·5H2O` or `[Fe(CN)6]3-`.
+#[derive(Debug, Clone)]
+pub struct Formula {
+    /// The number of atoms of each kind, in order of first appearance.
+    pub atoms: Vec<(Atom, u32)>,
+    /// The charge, in elementary charges.
+    pub charge: i64,
+}
 
-impl<'a> TokenIterator<'a> {
-    pub fn new(input: &'a str) -> TokenIterator<'a> {
-        TokenIterator(input.chars().peekable())
+fn superscript_digit(c: char) -> Option<u32> {
+    match c {
+        '⁰' => Some(0),
+        '¹' => Some(1),
+        '²' => Some(2),
+        '³' => Some(3),
+        '⁴'..='⁹' => Some(c as u32 - '⁴' as u32 + 4),
+        _ => None
     }
 }
 
-impl<'a> Iterator for TokenIterator<'a> {
-    type Item = Token;
+fn subscript_digit(c: char) -> Option<u32> {
+    match c {
+        '₀'..='₉' => Some(c as u32 - '₀' as u32),
+        _ => None
+    }
+}
+
+fn ascii_digit(c: char) -> Option<u32> {
+    c.to_digit(10)
+}
+
+/// Adds `count` times the atoms of a group to the atoms of a formula.
+fn merge(atoms: &mut Vec<(Atom, u32)>, group: Vec<(Atom, u32)>, count: u32) {
+    for (atom, n) in group {
+        if let Some(entry) = atoms.iter_mut().find(|x| x.0 == atom) {
+            entry.1 += n * count;
+            continue
+        }
+        atoms.push((atom, n * count));
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    /// Reads a run of digits, if there is one.
+    fn number(&mut self, digit: fn(char) -> Option<u32>) -> Result<Option<u32>, String> {
+        let mut res: Option<u32> = None;
+        while let Some(d) = self.peek().and_then(digit) {
+            self.pos += 1;
+            res = Some(try!(res.unwrap_or(0).checked_mul(10)
+                            .and_then(|x| x.checked_add(d))
+                            .ok_or(format!("Count is too large"))));
+        }
+        Ok(res)
+    }
 
-    fn next(&mut self) -> Option<Token> {
-        if self.0.peek() == None {
-            return None
+    /// Reads the count after an atom or group, which is 1 if omitted.
+    fn count(&mut self) -> Result<u32, String> {
+        let count = match try!(self.number(ascii_digit)) {
+            Some(count) => Some(count),
+            None => try!(self.number(subscript_digit)),
+        };
+        match count {
+            Some(0) => Err(format!("Counts must be positive")),
+            Some(count) => Ok(count),
+            None => Ok(1),
         }
-        let res = match self.0.next().unwrap() {
-            letter @ 'A'..='Z' => {
-                let mut symbol = String::new();
-                symbol.push(letter);
-                match self.0.peek().cloned() {
-                    Some('a'..='z') => symbol.push(self.0.next().unwrap()),
-                    _ => ()
-                }
-                Token::Symbol(symbol)
+    }
+
+    fn atom(&mut self, after_open: bool) -> Result<Atom, String> {
+        // isotope labels are superscript, or follow an opening bracket
+        // as in `[13C]O2`, where they can't be confused with counts
+        let mass_number = match try!(self.number(superscript_digit)) {
+            Some(n) => Some(n),
+            None if after_open => try!(self.number(ascii_digit)),
+            None => None,
+        };
+        let mut symbol = String::new();
+        match self.peek() {
+            Some(c @ 'A'..='Z') => symbol.push(c),
+            Some(c) => return Err(format!("Expected element symbol, got `{}`", c)),
+            None => return Err(format!("Expected element symbol, got end of formula")),
+        }
+        self.pos += 1;
+        if let Some(c @ 'a'..='z') = self.peek() {
+            symbol.push(c);
+            self.pos += 1;
+        }
+        Ok(Atom {
+            symbol: symbol,
+            mass_number: mass_number,
+        })
+    }
+
+    /// Reads atoms and bracketed groups up to the closing bracket, or
+    /// up to a hydrate dot at the top level.
+    fn groups(&mut self, close: Option<char>) -> Result<Vec<(Atom, u32)>, String> {
+        let mut atoms = vec![];
+        loop {
+            let after_open = self.pos > 0 && match self.chars[self.pos - 1] {
+                '(' | '[' => true,
+                _ => false
+            };
+            match self.peek() {
+                None if close.is_none() => break,
+                None => return Err(format!("Missing `{}`", close.unwrap())),
+                Some(c) if Some(c) == close => {
+                    self.pos += 1;
+                    break
+                },
+                Some('·') | Some('⋅') if close.is_none() => break,
+                Some(open @ '(') | Some(open @ '[') => {
+                    self.pos += 1;
+                    let inner = try!(self.groups(Some(if open == '(' { ')' } else { ']' })));
+                    let count = try!(self.count());
+                    merge(&mut atoms, inner, count);
+                },
+                Some(_) => {
+                    let atom = try!(self.atom(after_open));
+                    let count = try!(self.count());
+                    merge(&mut atoms, vec![(atom, 1)], count);
+                },
             }
-            digit @ '0'..='9' => {
-                let mut integer = String::new();
-                integer.push(digit);
-                while let Some('0'..='9') = self.0.peek().cloned() {
-                    integer.push(self.0.next().unwrap())
-                }
-                Token::Count(u32::from_str(&integer).unwrap())
+        }
+        if atoms.len() == 0 {
+            return Err(format!("Formulas and groups must contain atoms"))
+        }
+        Ok(atoms)
+    }
+}
+
+/// Whether a bare digit before the sign is the charge rather than a
+/// count: for single atoms like `Ca2+`, and for complexes in brackets
+/// like `[Fe(CN)6]3-`.
+fn charge_digit(chars: &[char]) -> bool {
+    if chars.last() == Some(&']') {
+        return true
+    }
+    let mut parser = Parser {
+        chars: chars.to_owned(),
+        pos: 0,
+    };
+    match parser.groups(None) {
+        Ok(ref atoms) => parser.pos == chars.len() && atoms.len() == 1 && atoms[0].1 == 1,
+        Err(_) => false,
+    }
+}
+
+/// Removes the charge from the end of a formula, as in `Ca2+`, `NO3-`,
+/// `[Fe(CN)6]3-`, `SO4^2-` or `SO₄²⁻`. Without `^`, only a single
+/// digit is taken as the charge, and only where it can't be a count.
+fn take_charge(chars: &mut Vec<char>) -> i64 {
+    let sign = match chars.last().cloned() {
+        Some('+') | Some('⁺') => 1,
+        Some('-') | Some('⁻') => -1,
+        _ => return 0
+    };
+    let superscript = match chars.pop() {
+        Some('⁺') | Some('⁻') => true,
+        _ => false
+    };
+    let mut digits = vec![];
+    while let Some(&c) = chars.last() {
+        let digit = if superscript { superscript_digit(c) } else { ascii_digit(c) };
+        match digit {
+            Some(d) => {
+                digits.insert(0, d);
+                chars.pop();
+            },
+            None => break
+        }
+    }
+    if !superscript && chars.last() == Some(&'^') {
+        chars.pop();
+    } else if !superscript && digits.len() > 1 {
+        // `SO42-` is SO4 with a charge of 2-
+        let count = digits.len() - 1;
+        for d in digits.drain(..count) {
+            chars.push(::std::char::from_digit(d, 10).unwrap());
+        }
+    } else if !superscript && digits.len() == 1 && !charge_digit(chars) {
+        // `NO3-` is NO3 with a charge of 1-
+        chars.push(::std::char::from_digit(digits[0], 10).unwrap());
+        digits.clear();
+    }
+    let magnitude = if digits.len() == 0 {
+        1
+    } else {
+        digits.iter().fold(0i64, |acc, &d| acc.saturating_mul(10).saturating_add(d as i64))
+    };
+    sign * magnitude
+}
+
+/// Parses a chemical formula with nested groups, hydrate dots, isotope
+/// labels and charges. The element symbols are not checked.
+pub fn parse_formula(formula: &str) -> Result<Formula, String> {
+    let mut chars = formula.chars().collect::<Vec<char>>();
+    let charge = take_charge(&mut chars);
+    let mut parser = Parser {
+        chars: chars,
+        pos: 0,
+    };
+    let mut atoms = vec![];
+    loop {
+        // parts after a hydrate dot, like the `5H2O` in `CuSO4·5H2O`,
+        // can have a coefficient
+        let coefficient = if parser.pos > 0 {
+            match try!(parser.number(ascii_digit)) {
+                Some(0) => return Err(format!("Counts must be positive")),
+                Some(n) => n,
+                None => 1,
             }
-            _ => Token::Error
+        } else {
+            1
         };
-        Some(res)
+        let part = try!(parser.groups(None));
+        merge(&mut atoms, part, coefficient);
+        match parser.peek() {
+            Some('·') | Some('⋅') => parser.pos += 1,
+            None => break,
+            Some(c) => return Err(format!("Unexpected `{}`", c)),
+        }
+    }
+    Ok(Formula {
+        atoms: atoms,
+        charge: charge,
+    })
+}
+
+/// Whether the string is a formula made of known element symbols.
+pub fn is_formula(formula: &str) -> bool {
+    match parse_formula(formula) {
+        Ok(formula) => formula.atoms.iter().all(|&(ref atom, _)| {
+            ELEMENTS.contains(&&*atom.symbol)
+        }),
+        Err(_) => false,
+    }
+}
+
+fn molar_mass_unit() -> BTreeMap<Dim, i64> {
+    let mut unit = BTreeMap::new();
+    unit.insert(Dim::new("kg"), 1);
+    unit.insert(Dim::new("mol"), -1);
+    unit
+}
+
+fn decimal(integer: &str, frac: &str, exp: Option<&str>) -> Num {
+    Number::from_parts(integer, Some(frac), exp).expect("Bug: Invalid constant")
+}
+
+impl Atom {
+    /// The molar mass of the element, from its `!symbol` substance, or
+    /// of the isotope.
+    pub fn molar_mass(&self,
+                      symbols: &BTreeMap<String, String>,
+                      substances: &BTreeMap<String, Substance>) -> Option<Number> {
+        match self.mass_number {
+            Some(mass_number) => ISOTOPES.iter()
+                .find(|x| x.0 == self.symbol && x.1 == mass_number)
+                .map(|&(_, _, integer, frac)| Number {
+                    value: &decimal(integer, frac, None) / &Num::from(1000),
+                    unit: molar_mass_unit(),
+                }),
+            None => symbols.get(&self.symbol)
+                .and_then(|name| substances.get(name))
+                .and_then(|subst| subst.get("molar_mass").ok()),
+        }
     }
 }
 
+impl fmt::Display for Atom {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(mass_number) = self.mass_number {
+            for c in format!("{}", mass_number).chars() {
+                try!(write!(fmt, "{}", match c {
+                    '1' => '¹',
+                    '2' => '²',
+                    '3' => '³',
+                    c => ::std::char::from_u32(
+                        c as u32 - '0' as u32 + '⁰' as u32
+                    ).unwrap(),
+                }));
+            }
+        }
+        write!(fmt, "{}", self.symbol)
+    }
+}
+
+/// The mass of each kind of atom in one mole of the formula, in order
+/// of first appearance.
+pub fn composition(formula: &Formula,
+                   symbols: &BTreeMap<String, String>,
+                   substances: &BTreeMap<String, Substance>) -> Option<Vec<(Atom, u32, Number)>> {
+    formula.atoms.iter().map(|&(ref atom, count)| {
+        atom.molar_mass(symbols, substances).map(|mass| {
+            let count_num = Number::new(Num::from(count as i64));
+            (atom.clone(), count, (&mass * &count_num).unwrap())
+        })
+    }).collect()
+}
+
 /**
  * Compute the molar mass of a compound given its chemical formula.
  */
 pub fn substance_from_formula(formula: &str,
                               symbols: &BTreeMap<String, String>,
                               substances: &BTreeMap<String, Substance>) -> Option<Substance> {
-    let mut molar_mass_unit = BTreeMap::new();
-    molar_mass_unit.insert(Dim::new("kg"), 1);
-    molar_mass_unit.insert(Dim::new("mol"), -1);
-    let mut total_molar_mass = Number { value: Num::from(0), unit: molar_mass_unit };
-
-    let mut iter = TokenIterator::new(formula).peekable();
-    while let Some(token) = iter.next() {
-        match token {
-            Token::Symbol(ref sym) if symbols.contains_key(sym) => {
-                let count = match iter.peek() {
-                    Some(&Token::Count(n)) => {
-                        iter.next().unwrap();
-                        Number::new(Num::from(n as i64))
-                    }
-                    _ => Number::one()
-                };
-
-                let subst = substances.get(symbols.get(sym).unwrap()).unwrap();
-                match subst.get("molar_mass") {
-                    Ok(subst_molar_mass) => {
-                        let subst_molar_mass = (&subst_molar_mass * &count).unwrap();
-                        total_molar_mass = (&total_molar_mass + &subst_molar_mass).unwrap();
-                    }
-                    Err(_) => return None
-                }
-            }
-            _ => return None
-        }
+    let parsed = match parse_formula(formula) {
+        Ok(parsed) => parsed,
+        Err(_) => return None
+    };
+    let parts = match composition(&parsed, symbols, substances) {
+        Some(parts) => parts,
+        None => return None
+    };
+    let mut total_molar_mass = Number { value: Num::from(0), unit: molar_mass_unit() };
+    for (_, _, mass) in parts {
+        total_molar_mass = (&total_molar_mass + &mass).unwrap();
+    }
+    if parsed.charge != 0 {
+        let (integer, frac, exp) = ELECTRON_MOLAR_MASS;
+        let electrons = Number {
+            value: &decimal(integer, frac, Some(exp)) * &Num::from(parsed.charge),
+            unit: molar_mass_unit(),
+        };
+        total_molar_mass = (&total_molar_mass - &electrons).unwrap();
     }
 
     let mut props = BTreeMap::new();
@@ -105,6 +408,7 @@ pub fn substance_from_formula(formula: &str,
         properties: Rc::new(Properties {
             name: formula.to_owned(),
             properties: props,
+            tables: BTreeMap::new(),
         })
     })
 }
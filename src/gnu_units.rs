@@ -5,9 +5,15 @@
 use std::str::Chars;
 use std::iter::Peekable;
 use std::rc::Rc;
+use std::cell::Cell;
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use ast::*;
 use num::Num;
+use table::{Interpolation, Table};
+use diagnostic::{Diagnostic, Location};
 
 #[derive(Debug, Clone)]
 pub enum Token {
@@ -15,7 +21,10 @@ pub enum Token {
     Newline,
     Doc(String),
     Ident(String),
+    /// An identifier immediately followed by `(`.
+    Call(String),
     Number(String, Option<String>, Option<String>),
+    Semicolon,
     LPar,
     RPar,
     Bang,
@@ -32,11 +41,42 @@ pub enum Token {
 }
 
 #[derive(Clone)]
-pub struct TokenIterator<'a>(Peekable<Chars<'a>>);
+pub struct TokenIterator<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+    /// The line and column of the last token returned by `next()`,
+    /// shared so that it can be read through a `Peekable`.
+    start: Rc<Cell<(usize, usize)>>,
+}
 
 impl<'a> TokenIterator<'a> {
     pub fn new(input: &'a str) -> TokenIterator<'a> {
-        TokenIterator(input.chars().peekable())
+        TokenIterator {
+            chars: input.chars().peekable(),
+            line: 1,
+            column: 1,
+            start: Rc::new(Cell::new((1, 1))),
+        }
+    }
+
+    /// The line and column where the most recently read token starts.
+    /// After peeking, this is the position of the peeked token.
+    pub fn position(&self) -> Rc<Cell<(usize, usize)>> {
+        self.start.clone()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        match c {
+            Some('\n') => {
+                self.line += 1;
+                self.column = 1;
+            },
+            Some(_) => self.column += 1,
+            None => (),
+        }
+        c
     }
 }
 
@@ -44,7 +84,7 @@ fn is_ident(c: char) -> bool {
     match c {
         //c if c.is_alphabetic() => true,
         //'_' | '$' | '-' | '\'' | '"' | '%' | ',' => true,
-        ' ' | '\t' | '\n' | '\r' | '(' | ')' | '/' | '|' | '^' | '+' | '*' | '\\' | '#' => false,
+        ' ' | '\t' | '\n' | '\r' | '(' | ')' | '/' | '|' | '^' | '+' | '*' | '\\' | '#' | ';' => false,
         _ => true
     }
 }
@@ -53,19 +93,21 @@ impl<'a> Iterator for TokenIterator<'a> {
     type Item = Token;
 
     fn next(&mut self) -> Option<Token> {
-        if self.0.peek() == None {
+        self.start.set((self.line, self.column));
+        if self.chars.peek() == None {
             return Some(Token::Eof)
         }
-        let res = match self.0.next().unwrap() {
+        let res = match self.bump().unwrap() {
             ' ' | '\t' => return self.next(),
-            '\r' => if self.0.peek() == Some(&'\n') {
-                self.0.next();
+            '\r' => if self.chars.peek() == Some(&'\n') {
+                self.bump();
                 Token::Newline
             } else {
                 Token::Newline
             },
             '\n' => Token::Newline,
             '!' => Token::Bang,
+            ';' => Token::Semicolon,
             '(' => Token::LPar,
             ')' => Token::RPar,
             '/' => Token::Slash,
@@ -76,10 +118,10 @@ impl<'a> Iterator for TokenIterator<'a> {
             '*' => Token::Asterisk,
             '{' => Token::LeftBrace,
             '}' => Token::RightBrace,
-            '?' => if self.0.peek() == Some(&'?') {
-                self.0.next();
+            '?' => if self.chars.peek() == Some(&'?') {
+                self.bump();
                 let mut out = String::new();
-                loop { match self.0.next() {
+                loop { match self.bump() {
                     Some('\n') | None => break,
                     Some(x) => out.push(x),
                 }}
@@ -87,8 +129,8 @@ impl<'a> Iterator for TokenIterator<'a> {
             } else {
                 Token::Question
             },
-            '\\' => match self.0.next() {
-                Some('\r') => match self.0.next() {
+            '\\' => match self.bump() {
+                Some('\r') => match self.bump() {
                     Some('\n') => self.next().unwrap(),
                     _ => Token::Error(format!("Expected LF or CRLF line endings"))
                 },
@@ -97,7 +139,7 @@ impl<'a> Iterator for TokenIterator<'a> {
                 None => Token::Error(format!("Unexpected EOF")),
             },
             '#' => {
-                while let Some(c) = self.0.next() {
+                while let Some(c) = self.bump() {
                     match c {
                         '\n' => break,
                         _ => ()
@@ -113,9 +155,9 @@ impl<'a> Iterator for TokenIterator<'a> {
                 // integer component
                 if x != '.' {
                     integer.push(x);
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.chars.peek().cloned() {
                         match c {
-                            '0'..='9' => integer.push(self.0.next().unwrap()),
+                            '0'..='9' => integer.push(self.bump().unwrap()),
                             _ => break
                         }
                     }
@@ -123,14 +165,14 @@ impl<'a> Iterator for TokenIterator<'a> {
                     integer.push('0');
                 }
                 // fractional component
-                if x == '.' || Some('.') == self.0.peek().cloned() {
+                if x == '.' || Some('.') == self.chars.peek().cloned() {
                     let mut buf = String::new();
                     if x != '.' {
-                        self.0.next();
+                        self.bump();
                     }
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.chars.peek().cloned() {
                         match c {
-                            '0'..='9' => buf.push(self.0.next().unwrap()),
+                            '0'..='9' => buf.push(self.bump().unwrap()),
                             _ => break
                         }
                     }
@@ -139,23 +181,23 @@ impl<'a> Iterator for TokenIterator<'a> {
                     }
                 }
                 // exponent
-                if let Some('e') = self.0.peek().cloned().map(|x| x.to_ascii_lowercase()) {
+                if let Some('e') = self.chars.peek().cloned().map(|x| x.to_ascii_lowercase()) {
                     let mut buf = String::new();
-                    self.0.next();
-                    if let Some(c) = self.0.peek().cloned() {
+                    self.bump();
+                    if let Some(c) = self.chars.peek().cloned() {
                         match c {
                             '-' => {
-                                buf.push(self.0.next().unwrap());
+                                buf.push(self.bump().unwrap());
                             },
                             '+' => {
-                                self.0.next();
+                                self.bump();
                             },
                             _ => ()
                         }
                     }
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.chars.peek().cloned() {
                         match c {
-                            '0'..='9' => buf.push(self.0.next().unwrap()),
+                            '0'..='9' => buf.push(self.bump().unwrap()),
                             _ => break
                         }
                     }
@@ -167,9 +209,9 @@ impl<'a> Iterator for TokenIterator<'a> {
             },
             '"' => {
                 let mut buf = String::new();
-                while let Some(c) = self.0.next() {
+                while let Some(c) = self.bump() {
                     if c == '\\' {
-                        if let Some(c) = self.0.next() {
+                        if let Some(c) = self.bump() {
                             buf.push(c);
                         }
                     } else if c == '"' {
@@ -183,14 +225,20 @@ impl<'a> Iterator for TokenIterator<'a> {
             x if is_ident(x) => {
                 let mut buf = String::new();
                 buf.push(x);
-                while let Some(c) = self.0.peek().cloned() {
+                while let Some(c) = self.chars.peek().cloned() {
                     if is_ident(c) || c.is_numeric() {
-                        buf.push(self.0.next().unwrap());
+                        buf.push(self.bump().unwrap());
                     } else {
                         break;
                     }
                 }
                 match &*buf {
+                    // the units of a table unit can start with a
+                    // parenthesis, as in `plategauge[(oz/ft^2)/...]`
+                    _ if self.chars.peek() == Some(&'(') && !buf.contains('[') => {
+                        self.bump();
+                        Token::Call(buf)
+                    },
                     _ => Token::Ident(buf)
                 }
             },
@@ -209,25 +257,36 @@ fn parse_term(iter: &mut Iter) -> Expr {
                 iter.next();
                 Expr::Of(name, Box::new(parse_mul(iter)))
             },
-            _ => Expr::Unit(name)
+            _ => Expr::Unit(name, None)
+        },
+        Token::Call(name) => {
+            if let Some(&Token::RPar) = iter.peek() {
+                iter.next();
+                return Expr::Call(name, vec![], None)
+            }
+            let arg = parse_expr(iter);
+            match iter.next().unwrap() {
+                Token::RPar => Expr::Call(name, vec![arg], None),
+                x => Expr::Error(format!("Expected ), got {:?}", x), None)
+            }
         },
         Token::Number(num, frac, exp) =>
             ::number::Number::from_parts(&*num, frac.as_ref().map(|x| &**x), exp.as_ref().map(|x| &**x))
-            .map(Expr::Const)
-            .unwrap_or_else(|e| Expr::Error(format!("{}", e))),
+            .map(|x| Expr::Const(x, None))
+            .unwrap_or_else(|e| Expr::Error(format!("{}", e), None)),
         Token::Plus => Expr::Plus(Box::new(parse_term(iter))),
         Token::Dash => Expr::Neg(Box::new(parse_term(iter))),
         Token::Slash => Expr::Frac(
-            Box::new(Expr::Const(Num::one())),
+            Box::new(Expr::Const(Num::one(), None)),
             Box::new(parse_term(iter))),
         Token::LPar => {
             let res = parse_expr(iter);
             match iter.next().unwrap() {
                 Token::RPar => res,
-                x => Expr::Error(format!("Expected ), got {:?}", x))
+                x => Expr::Error(format!("Expected ), got {:?}", x), None)
             }
         },
-        x => Expr::Error(format!("Expected term, got {:?}", x))
+        x => Expr::Error(format!("Expected term, got {:?}", x), None)
     }
 }
 
@@ -251,7 +310,8 @@ fn parse_pow(iter: &mut Iter) -> Expr {
 fn parse_mul(iter: &mut Iter) -> Expr {
     let mut terms = vec![parse_pow(iter)];
     loop { match iter.peek().cloned().unwrap() {
-        Token::Slash | Token::Plus | Token::Dash | Token::RPar | Token::Newline | Token::Eof =>
+        Token::Slash | Token::Plus | Token::Dash | Token::RPar | Token::Newline |
+        Token::Semicolon | Token::Eof =>
             break,
         Token::Asterisk => {
             iter.next();
@@ -299,15 +359,337 @@ pub fn parse_expr(iter: &mut Iter) -> Expr {
     parse_add(iter)
 }
 
-pub fn parse(iter: &mut Iter) -> Defs {
+/// Parses the rest of a `!function name(x, y) body` directive, which
+/// can declare the unit of its result as in `!function name(x, y)
+/// units=[m/s] body`. Commas are identifier characters in this syntax,
+/// so the parameter list is put back together and split again.
+fn parse_function(iter: &mut Iter) -> Option<(String, FunctionDef)> {
+    let name = match iter.next().unwrap() {
+        Token::Call(name) => name,
+        _ => return None
+    };
+    let mut params = String::new();
+    loop {
+        match iter.next().unwrap() {
+            Token::Ident(ref s) => {
+                params.push_str(s);
+                params.push(' ');
+            },
+            Token::RPar => break,
+            _ => return None
+        }
+    }
+    let params = params
+        .split(|c| c == ',' || c == ' ')
+        .filter(|x| x.len() > 0)
+        .map(|x| x.to_owned())
+        .collect::<Vec<_>>();
+    let units = match iter.peek().cloned().unwrap() {
+        Token::Ident(ref s) if s.starts_with("units=") => Some(s.clone()),
+        Token::Call(ref s) if s.starts_with("units=") => Some(format!("{}(", s)),
+        _ => None
+    };
+    let result = match units {
+        Some(units) => {
+            iter.next();
+            let units = parse_option(units, iter);
+            let value = &units["units=".len()..];
+            if !value.starts_with("[") || !value.ends_with("]") {
+                return None
+            }
+            match parse_str(&value[1..value.len() - 1]) {
+                Some(result) => Some(result),
+                None => return None,
+            }
+        },
+        None => None
+    };
+    let body = parse_expr(iter);
+    Some((name, FunctionDef {
+        params: params,
+        body: body,
+        result: result,
+    }))
+}
+
+/// Reassembles an option of a nonlinear unit, such as `units=[in;out]`
+/// or `domain=[a,b)`, from the tokens it was split into.
+fn parse_option(first: String, iter: &mut Iter) -> String {
+    fn depth(s: &str) -> i32 {
+        s.chars().fold(0, |d, c| match c {
+            '[' | '(' => d + 1,
+            ']' | ')' => d - 1,
+            _ => d
+        })
+    }
+
+    let mut buf = first;
+    while depth(&buf) > 0 {
+        let text = match iter.peek().cloned().unwrap() {
+            Token::Ident(s) => s,
+            Token::Call(s) => format!("{}(", s),
+            Token::Number(int, frac, exp) => format!(
+                "{}{}{}", int,
+                frac.map(|x| format!(".{}", x)).unwrap_or_default(),
+                exp.map(|x| format!("e{}", x)).unwrap_or_default()),
+            Token::Semicolon => ";".to_owned(),
+            Token::LPar => "(".to_owned(),
+            Token::RPar => ")".to_owned(),
+            Token::Slash => "/".to_owned(),
+            Token::Pipe => "|".to_owned(),
+            Token::Caret => "^".to_owned(),
+            Token::Plus => "+".to_owned(),
+            Token::Dash => "-".to_owned(),
+            Token::Asterisk => "*".to_owned(),
+            _ => break
+        };
+        iter.next();
+        buf.push_str(&text);
+    }
+    buf
+}
+
+fn parse_str(input: &str) -> Option<Expr> {
+    if input.trim().len() == 0 {
+        None
+    } else {
+        Some(parse_expr(&mut TokenIterator::new(input).peekable()))
+    }
+}
+
+fn parse_bounds(input: &str) -> Option<Bounds> {
+    let lower = match input.chars().next() {
+        Some('[') => true,
+        Some('(') => false,
+        _ => return None
+    };
+    let upper = match input.chars().last() {
+        Some(']') => true,
+        Some(')') => false,
+        _ => return None
+    };
+    let inner = &input[1..input.len() - 1];
+    let mut parts = inner.split(',');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(b), None) => Some(Bounds {
+            lower: parse_str(a).map(|x| (x, lower)),
+            upper: parse_str(b).map(|x| (x, upper)),
+        }),
+        _ => None
+    }
+}
+
+/// Parses the rest of a nonlinear unit definition after `name(`.
+fn parse_nonlinear(name: &str, iter: &mut Iter) -> Result<NonlinearDef, String> {
+    let param = match iter.next().unwrap() {
+        Token::Ident(param) => param,
+        x => return Err(format!("Expected parameter name, got {:?}", x))
+    };
+    match iter.next().unwrap() {
+        Token::RPar => (),
+        x => return Err(format!("Expected ), got {:?}", x))
+    }
+    let mut def = NonlinearDef {
+        name: name.to_owned(),
+        param: param,
+        input: None,
+        output: None,
+        domain: None,
+        range: None,
+        forward: Expr::Error(format!("Missing definition"), None),
+        inverse: None,
+    };
+    loop {
+        let is_option = |s: &str| {
+            s.starts_with("units=") || s.starts_with("domain=") || s.starts_with("range=")
+        };
+        let option = match iter.peek().cloned().unwrap() {
+            Token::Ident(ref s) if is_option(s) => s.clone(),
+            Token::Call(ref s) if is_option(s) => format!("{}(", s),
+            Token::Ident(ref s) if s == "noerror" => {
+                iter.next();
+                continue
+            },
+            _ => break
+        };
+        iter.next();
+        let option = parse_option(option, iter);
+        let eq = option.find('=').unwrap();
+        let (key, value) = (&option[..eq], &option[eq + 1..]);
+        match key {
+            "units" => {
+                if !value.starts_with("[") || !value.ends_with("]") {
+                    return Err(format!("Malformed units option: {}", value))
+                }
+                let mut parts = value[1..value.len() - 1].split(';');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(input), Some(output), None) => {
+                        def.input = parse_str(input);
+                        def.output = parse_str(output);
+                    },
+                    _ => return Err(format!("Malformed units option: {}", value))
+                }
+            },
+            _ => {
+                let bounds = match parse_bounds(value) {
+                    Some(bounds) => bounds,
+                    None => return Err(format!("Malformed {} option: {}", key, value))
+                };
+                if key == "domain" {
+                    def.domain = Some(bounds);
+                } else {
+                    def.range = Some(bounds);
+                }
+            },
+        }
+    }
+    def.forward = parse_expr(iter);
+    if let Some(&Token::Semicolon) = iter.peek() {
+        iter.next();
+        def.inverse = Some(parse_expr(iter));
+    }
+    Ok(def)
+}
+
+/// Parses the entries of a table up to the end of the line, as pairs
+/// of numbers which may be separated by commas.
+fn parse_points(iter: &mut Iter) -> Result<Vec<(Num, Num)>, String> {
+    let mut values = vec![];
+    loop {
+        let negative = match iter.peek().cloned().unwrap() {
+            Token::Newline | Token::Eof => break,
+            Token::Ident(ref s) if s == "," && values.len() % 2 == 0 => {
+                iter.next();
+                continue
+            },
+            Token::Dash => {
+                iter.next();
+                true
+            },
+            _ => false
+        };
+        let value = match iter.next().unwrap() {
+            Token::Number(num, frac, exp) => try!(::number::Number::from_parts(
+                &*num, frac.as_ref().map(|x| &**x), exp.as_ref().map(|x| &**x)
+            ).map_err(|e| format!("{}", e))),
+            x => return Err(format!("Expected number in table, got {:?}", x))
+        };
+        values.push(if negative { -&value } else { value });
+    }
+    if values.len() % 2 != 0 {
+        return Err(format!("The last entry of the table has no value"))
+    }
+    Ok(values.chunks(2).map(|x| (x[0].clone(), x[1].clone())).collect())
+}
+
+/// Parses the rest of a tabulated substance property after `name
+/// table`, which is `param_name param_unit unit [linear|spline]`
+/// followed by pairs of parameter and value, usually continued over
+/// several lines.
+fn parse_table(name: String, iter: &mut Iter) -> Result<PropertyTable, String> {
+    let param_name = match iter.next().unwrap() {
+        Token::Ident(param_name) => param_name,
+        x => return Err(format!("Expected table parameter name, got {:?}", x))
+    };
+    let param_unit = parse_term(iter);
+    let unit = parse_term(iter);
+    let interpolation = match iter.peek().cloned().unwrap() {
+        Token::Ident(ref s) if s == "linear" => Interpolation::Linear,
+        Token::Ident(ref s) if s == "spline" => Interpolation::Spline,
+        _ => return Err(format!("Expected linear or spline interpolation for table {}", name))
+    };
+    iter.next();
+    let points = try!(parse_points(iter).map_err(|e| format!("Table {}: {}", name, e)));
+    Ok(PropertyTable {
+        name: name,
+        param_name: param_name,
+        param_unit: param_unit,
+        unit: unit,
+        interpolation: interpolation,
+        points: points,
+        doc: None,
+    })
+}
+
+/// Parses a piecewise linear unit, `name[in;out] x1 y1, x2 y2, ...`,
+/// where `first` is the token with the name and the start of the
+/// units.
+fn parse_table_unit(first: &str, iter: &mut Iter) -> Result<TableDef, String> {
+    let start = first.find('[').unwrap();
+    let units = parse_option(first[start..].to_owned(), iter);
+    if !units.ends_with("]") {
+        return Err(format!("Malformed units: {}", units))
+    }
+    let mut parts = units[1..units.len() - 1].split(';');
+    let (input, output) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(output), None, None) => (None, parse_str(output)),
+        (Some(input), Some(output), None) => (parse_str(input), parse_str(output)),
+        _ => return Err(format!("Malformed units: {}", units))
+    };
+    let points = try!(parse_points(iter));
+    let table = try!(Table::new(points, Interpolation::Linear));
+    Ok(TableDef {
+        name: first[..start].to_owned(),
+        input: input,
+        output: output,
+        table: table,
+    })
+}
+
+/// Parses a units file, returning the definitions that could be
+/// parsed along with the problems found in the others. `file` is the
+/// name the diagnostics are reported with, and the path that
+/// `!include` directives are relative to.
+pub fn parse(file: &str, input: &str) -> (Defs, Vec<Diagnostic>) {
+    let mut stack = vec![];
+    if let Ok(path) = Path::new(file).canonicalize() {
+        stack.push(path);
+    }
+    parse_nested(file, input, &mut stack)
+}
+
+/// Reads and parses a file named by an `!include` directive. `stack`
+/// has the files that are being parsed, to catch files that include
+/// themselves.
+fn include(path: &Path, stack: &mut Vec<PathBuf>)
+           -> Result<(Defs, Vec<Diagnostic>), String> {
+    let error = |e: ::std::io::Error| format!("Could not include {}: {}", path.display(), e);
+    let canonical = try!(path.canonicalize().map_err(&error));
+    if stack.contains(&canonical) {
+        return Err(format!("{} includes itself", path.display()))
+    }
+    let mut input = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut input)).map_err(&error));
+    stack.push(canonical);
+    let res = parse_nested(&*path.display().to_string(), &*input, stack);
+    stack.pop();
+    Ok(res)
+}
+
+fn parse_nested(file: &str, input: &str, stack: &mut Vec<PathBuf>) -> (Defs, Vec<Diagnostic>) {
+    let tokens = TokenIterator::new(input);
+    let position = tokens.position();
+    let iter = &mut tokens.peekable();
+    let file = Rc::new(file.to_owned());
+    let here = || {
+        let (line, column) = position.get();
+        Location {
+            file: file.clone(),
+            line: line,
+            column: column,
+        }
+    };
     let mut map = vec![];
-    let mut line = 1;
+    let mut diagnostics = vec![];
     let mut doc = None;
     let mut category = None;
     let mut symbols = BTreeMap::new();
+    let mut nonlinear = BTreeMap::new();
     loop {
-        match iter.next().unwrap() {
-            Token::Newline => line += 1,
+        let token = iter.next().unwrap();
+        let location = here();
+        match token {
+            Token::Newline => (),
             Token::Eof => break,
             Token::Bang => {
                 match iter.next().unwrap() {
@@ -318,16 +700,19 @@ pub fn parse(iter: &mut Iter) -> Defs {
                                     name: s.clone(),
                                     def: Rc::new(Def::Category(d)),
                                     doc: None,
-                                    category: None
+                                    category: None,
+                                    location: Some(location.clone()),
                                 });
                                 category = Some(s);
                             },
-                            _ => println!("Malformed category directive"),
+                            _ => diagnostics.push(Diagnostic::error(
+                                Some(&location), format!("Malformed category directive"))),
                         }
                     },
                     Token::Ident(ref s) if s == "endcategory" => {
                         if category.is_none() {
-                            println!("Stray endcategory directive");
+                            diagnostics.push(Diagnostic::warning(
+                                Some(&location), format!("Stray endcategory directive")));
                         }
                         category = None
                     },
@@ -336,9 +721,88 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             (Token::Ident(subst), Token::Ident(sym)) => {
                                 symbols.insert(subst, sym);
                             }
-                            _ => println!("Malformed symbol directive"),
+                            _ => diagnostics.push(Diagnostic::error(
+                                Some(&location), format!("Malformed symbol directive"))),
                         }
                     }
+                    Token::Ident(ref s) if s == "logunit" => {
+                        let name = match iter.next().unwrap() {
+                            Token::Ident(name) => name,
+                            x => {
+                                diagnostics.push(Diagnostic::error(Some(&location), format!(
+                                    "Malformed logunit directive: expected name, got {:?}", x)));
+                                continue
+                            }
+                        };
+                        let step = parse_expr(iter);
+                        let mut field = false;
+                        let mut reference = None;
+                        if let Some(&Token::Semicolon) = iter.peek() {
+                            iter.next();
+                            if let Some(Token::Ident(ref s)) = iter.peek().cloned() {
+                                if s == "field" {
+                                    iter.next();
+                                    field = true;
+                                }
+                            }
+                            reference = Some(parse_expr(iter));
+                        }
+                        map.push(DefEntry {
+                            name: name,
+                            def: Rc::new(Def::Logarithmic {
+                                step: step,
+                                field: field,
+                                reference: reference,
+                            }),
+                            doc: doc.take(),
+                            category: category.clone(),
+                            location: Some(location.clone()),
+                        });
+                    },
+                    Token::Ident(ref s) if s == "include" => {
+                        // paths aren't tokens, so they're read from the
+                        // line itself
+                        let (line, column) = position.get();
+                        let path = input.lines().nth(line - 1).unwrap_or("")
+                            .chars().skip(column - 1 + s.len())
+                            .take_while(|&c| c != '#')
+                            .collect::<String>();
+                        loop {
+                            match iter.peek().cloned().unwrap() {
+                                Token::Newline | Token::Eof => break,
+                                _ => {
+                                    iter.next();
+                                }
+                            }
+                        }
+                        let path = path.trim().trim_matches('"');
+                        if path.len() == 0 {
+                            diagnostics.push(Diagnostic::error(
+                                Some(&location), format!("Malformed include directive")));
+                            continue
+                        }
+                        let dir = Path::new(&**file).parent().unwrap_or(Path::new(""));
+                        match include(&dir.join(path), stack) {
+                            Ok((mut defs, mut errors)) => {
+                                map.append(&mut defs.defs);
+                                diagnostics.append(&mut errors);
+                            },
+                            Err(e) => diagnostics.push(Diagnostic::error(Some(&location), e)),
+                        }
+                    },
+                    Token::Ident(ref s) if s == "function" => {
+                        match parse_function(iter) {
+                            Some((name, def)) => map.push(DefEntry {
+                                name: name,
+                                def: Rc::new(Def::Function(def)),
+                                doc: doc.take(),
+                                category: category.clone(),
+                                location: Some(location.clone()),
+                            }),
+                            None => diagnostics.push(Diagnostic::error(
+                                Some(&location), format!("Malformed function directive"))),
+                        }
+                    },
                     _ => loop {
                         match iter.peek().cloned().unwrap() {
                             Token::Newline | Token::Eof => break,
@@ -349,6 +813,42 @@ pub fn parse(iter: &mut Iter) -> Defs {
                     },
                 }
             },
+            Token::Call(name) => {
+                if let Some(&Token::RPar) = iter.peek() {
+                    // `name() other` is another name for a nonlinear unit
+                    iter.next();
+                    let def = match iter.next().unwrap() {
+                        Token::Ident(ref other) => nonlinear.get(other).cloned(),
+                        _ => None,
+                    };
+                    match def {
+                        Some(def) => map.push(DefEntry {
+                            name: name,
+                            def: Rc::new(Def::Nonlinear(def)),
+                            doc: doc.take(),
+                            category: category.clone(),
+                            location: Some(location.clone()),
+                        }),
+                        None => diagnostics.push(Diagnostic::error(Some(&location), format!(
+                            "Malformed nonlinear unit alias {}", name))),
+                    }
+                    continue
+                }
+                match parse_nonlinear(&name, iter) {
+                    Ok(def) => {
+                        nonlinear.insert(name.clone(), def.clone());
+                        map.push(DefEntry {
+                            name: name,
+                            def: Rc::new(Def::Nonlinear(def)),
+                            doc: doc.take(),
+                            category: category.clone(),
+                            location: Some(location.clone()),
+                        });
+                    },
+                    Err(e) => diagnostics.push(Diagnostic::error(Some(&location), format!(
+                        "Nonlinear unit {} is malformed: {}", name, e))),
+                }
+            },
             Token::Doc(line) => {
                 doc = match doc.take() {
                     None => Some(line.trim().to_owned()),
@@ -356,7 +856,32 @@ pub fn parse(iter: &mut Iter) -> Defs {
                 };
             },
             Token::Ident(name) => {
-                if name.ends_with("-") {
+                if name.contains('[') {
+                    // piecewise linear unit
+                    match parse_table_unit(&name, iter) {
+                        Ok(def) => map.push(DefEntry {
+                            name: def.name.clone(),
+                            def: Rc::new(Def::Table(def)),
+                            doc: doc.take(),
+                            category: category.clone(),
+                            location: Some(location.clone()),
+                        }),
+                        Err(e) => {
+                            diagnostics.push(Diagnostic::error(Some(&location), format!(
+                                "Table unit {} is malformed: {}",
+                                name.split('[').next().unwrap(), e)));
+                            // skip the rest of the entries
+                            loop {
+                                match iter.peek().cloned().unwrap() {
+                                    Token::Newline | Token::Eof => break,
+                                    _ => {
+                                        iter.next();
+                                    }
+                                }
+                            }
+                        },
+                    }
+                } else if name.ends_with("-") {
                     // prefix
                     let expr = parse_expr(iter);
                     let mut name = name;
@@ -368,6 +893,7 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             def: Rc::new(Def::Prefix(expr)),
                             doc: doc.take(),
                             category: category.clone(),
+                            location: Some(location.clone()),
                         });
                     } else {
                         map.push(DefEntry {
@@ -375,6 +901,7 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             def: Rc::new(Def::SPrefix(expr)),
                             doc: doc.take(),
                             category: category.clone(),
+                            location: Some(location.clone()),
                         });
                     }
                 } else {
@@ -389,12 +916,14 @@ pub fn parse(iter: &mut Iter) -> Defs {
                                 def: Rc::new(Def::Dimension),
                                 doc: doc.take(),
                                 category: category.clone(),
+                                location: Some(location.clone()),
                             });
                             map.push(DefEntry {
                                 name: long.clone(),
                                 def: Rc::new(Def::Canonicalization(name.clone())),
                                 doc: doc.take(),
                                 category: category.clone(),
+                                location: Some(location.clone()),
                             });
                         } else {
                             map.push(DefEntry {
@@ -402,6 +931,7 @@ pub fn parse(iter: &mut Iter) -> Defs {
                                 def: Rc::new(Def::Dimension),
                                 doc: doc.take(),
                                 category: category.clone(),
+                                location: Some(location.clone()),
                             });
                         }
                     } else if let Some(&Token::Question) = iter.peek() {
@@ -413,19 +943,18 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             def: Rc::new(Def::Quantity(expr)),
                             doc: doc.take(),
                             category: category.clone(),
+                            location: Some(location.clone()),
                         });
                     } else if let Some(&Token::LeftBrace) = iter.peek() {
                         // substance
                         iter.next();
                         let mut props = vec![];
+                        let mut tables = vec![];
                         let mut prop_doc = None;
                         loop {
                             let name = match iter.next().unwrap() {
                                 Token::Ident(name) => name,
-                                Token::Newline => {
-                                    line += 1;
-                                    continue
-                                },
+                                Token::Newline => continue,
                                 Token::Eof => break,
                                 Token::Doc(line) => {
                                     prop_doc = match prop_doc.take() {
@@ -438,17 +967,33 @@ pub fn parse(iter: &mut Iter) -> Defs {
                                 Token::RightBrace =>
                                     break,
                                 x => {
-                                    println!("Expected property, got {:?}", x);
+                                    diagnostics.push(Diagnostic::error(Some(&here()), format!(
+                                        "Expected property, got {:?}", x)));
                                     break
                                 },
                             };
                             let output_name = match iter.next().unwrap() {
+                                Token::Ident(ref s) if s == "table" => {
+                                    match parse_table(name, iter) {
+                                        Ok(mut table) => {
+                                            table.doc = prop_doc.take();
+                                            tables.push(table);
+                                        },
+                                        Err(e) => {
+                                            diagnostics.push(Diagnostic::error(Some(&here()), e));
+                                            break
+                                        },
+                                    }
+                                    continue
+                                },
                                 Token::Ident(ref s) if s == "const" => {
                                     let input_name = match iter.next().unwrap() {
                                         Token::Ident(name) => name,
                                         x => {
-                                            println!("Expected property input \
-                                                      name, got {:?}", x);
+                                            diagnostics.push(Diagnostic::error(
+                                                Some(&here()), format!(
+                                                    "Expected property input \
+                                                     name, got {:?}", x)));
                                             break
                                         },
                                     };
@@ -456,7 +1001,7 @@ pub fn parse(iter: &mut Iter) -> Defs {
                                     props.push(Property {
                                         output_name: name.clone(),
                                         name: name,
-                                        input: Expr::Const(Num::one()),
+                                        input: Expr::Const(Num::one(), None),
                                         input_name: input_name,
                                         output: output,
                                         doc: prop_doc.take()
@@ -465,7 +1010,8 @@ pub fn parse(iter: &mut Iter) -> Defs {
                                 },
                                 Token::Ident(name) => name,
                                 x => {
-                                    println!("Expected property input name, got {:?}", x);
+                                    diagnostics.push(Diagnostic::error(Some(&here()), format!(
+                                        "Expected property input name, got {:?}", x)));
                                     break
                                 },
                             };
@@ -473,14 +1019,16 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             match iter.next().unwrap() {
                                 Token::Slash => (),
                                 x => {
-                                    println!("Expected /, got {:?}", x);
+                                    diagnostics.push(Diagnostic::error(Some(&here()), format!(
+                                        "Expected /, got {:?}", x)));
                                     break
                                 }
                             }
                             let input_name = match iter.next().unwrap() {
                                 Token::Ident(name) => name,
                                 x => {
-                                    println!("Expected property input name, got {:?}", x);
+                                    diagnostics.push(Diagnostic::error(Some(&here()), format!(
+                                        "Expected property input name, got {:?}", x)));
                                     break
                                 },
                             };
@@ -498,10 +1046,12 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             name: name,
                             def: Rc::new(Def::Substance {
                                 symbol: None,
-                                properties: props
+                                properties: props,
+                                tables: tables,
                             }),
                             doc: doc.take(),
                             category: category.clone(),
+                            location: Some(location.clone()),
                         });
                     } else {
                         // derived
@@ -511,26 +1061,31 @@ pub fn parse(iter: &mut Iter) -> Defs {
                             def: Rc::new(Def::Unit(expr)),
                             doc: doc.take(),
                             category: category.clone(),
+                            location: Some(location.clone()),
                         });
                     }
                 }
             },
-            x => println!("Expected definition on line {}, got {:?}", line, x),
+            x => diagnostics.push(Diagnostic::error(Some(&location), format!(
+                "Expected definition, got {:?}", x))),
         };
     }
 
     for entry in map.iter_mut() {
         match Rc::get_mut(&mut entry.def).unwrap() {
             &mut Def::Substance { ref mut symbol, .. } => {
-                *symbol = symbols.get(&entry.name).map(|x| x.to_owned())
+                // substances from included files keep their own symbols
+                if let Some(x) = symbols.get(&entry.name) {
+                    *symbol = Some(x.to_owned())
+                }
             }
             _ => ()
         }
     }
 
-    Defs {
+    (Defs {
         defs: map
-    }
+    }, diagnostics)
 }
 
 pub fn tokens(iter: &mut Iter) -> Vec<Token> {
@@ -548,6 +1103,7 @@ pub fn tokens(iter: &mut Iter) -> Vec<Token> {
 mod tests {
     use super::*;
     use ast::Expr;
+    use diagnostic::Severity;
 
     fn do_parse(s: &str) -> Expr {
         let mut iter = TokenIterator::new(s).peekable();
@@ -557,7 +1113,7 @@ mod tests {
     macro_rules! expect {
         ($expr:expr, $pattern:path, $expected:expr) => {
             match do_parse($expr) {
-                $pattern(s) => assert_eq!(s, $expected),
+                $pattern(s, _) => assert_eq!(s, $expected),
                 x => panic!("{}", x),
             }
         };
@@ -568,7 +1124,7 @@ mod tests {
         let expr = do_parse("+1");
 
         if let Expr::Plus(x) = expr {
-            if let Expr::Const(x) = *x {
+            if let Expr::Const(x, _) = *x {
                 if x != 1.into() {
                     panic!("number != 1");
                 }
@@ -583,7 +1139,7 @@ mod tests {
     #[test]
     fn test_missing_bracket() {
         match do_parse("(") {
-            Expr::Error(ref s) => assert_eq!(s, "Expected ), got Eof"),
+            Expr::Error(ref s, _) => assert_eq!(s, "Expected ), got Eof"),
             x => panic!("Wrong result: {}", x),
         }
     }
@@ -622,4 +1178,170 @@ mod tests {
     fn test_escaped_quotes() {
         expect!("\"ab\\\"\"", Expr::Unit, "ab\"")
     }
+
+    #[test]
+    fn test_function_directive() {
+        let (defs, _) = parse("test.units",
+            "!function reynolds(rho,v, L , mu) rho v L / mu\n\
+             !function speed(d, t) units=[m/s] d / t\n");
+        assert_eq!(defs.defs.len(), 2);
+        assert_eq!(defs.defs[0].name, "reynolds");
+        match *defs.defs[0].def {
+            Def::Function(ref def) => {
+                assert_eq!(def.params, vec!["rho", "v", "L", "mu"]);
+                assert_eq!(def.body.to_string(), "rho v L / mu");
+                assert!(def.result.is_none());
+            },
+            ref x => panic!("Expected function, got {:?}", x),
+        }
+        match *defs.defs[1].def {
+            Def::Function(ref def) => {
+                assert_eq!(def.body.to_string(), "d / t");
+                assert_eq!(def.result.as_ref().unwrap().to_string(), "m / s");
+            },
+            ref x => panic!("Expected function, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_nonlinear() {
+        let (defs, _) = parse("test.units",
+            "wiregauge(g) units=[1;m] range=(0,) \\\n\
+             1|200 92^((36+(-g))/39) in; 36+(-39)ln(200 wiregauge/in)/ln(92)\n\
+             awg() wiregauge\n\
+             shoe (3+11|12) inch\n");
+        assert_eq!(defs.defs.len(), 3);
+        match *defs.defs[1].def {
+            Def::Nonlinear(ref def) => {
+                assert_eq!(defs.defs[1].name, "awg");
+                assert_eq!(def.param, "g");
+                assert_eq!(def.input.as_ref().unwrap().to_string(), "1");
+                assert_eq!(def.output.as_ref().unwrap().to_string(), "m");
+                assert_eq!(def.range.as_ref().unwrap().to_string(), "(0, )");
+                assert_eq!(def.forward.to_string(), "(1 / 200) 92^((36 + -g) / 39) in");
+                assert_eq!(def.inverse.as_ref().unwrap().to_string(),
+                           "36 + -39 ln(200 wiregauge / in) / ln(92)");
+            },
+            ref x => panic!("Expected nonlinear unit, got {:?}", x),
+        }
+        match *defs.defs[2].def {
+            Def::Unit(_) => (),
+            ref x => panic!("Expected unit, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_logunit_directive() {
+        let (defs, _) = parse("test.units",
+            "!logunit Np 20 / ln(10)\n\
+             !logunit dBV 1 ; field V\n");
+        assert_eq!(defs.defs.len(), 2);
+        match *defs.defs[0].def {
+            Def::Logarithmic { ref step, field: false, reference: None } =>
+                assert_eq!(step.to_string(), "20 / ln(10)"),
+            ref x => panic!("Expected relative logarithmic unit, got {:?}", x),
+        }
+        match *defs.defs[1].def {
+            Def::Logarithmic { field: true, reference: Some(ref reference), .. } =>
+                assert_eq!(reference.to_string(), "V"),
+            ref x => panic!("Expected field logarithmic unit, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_table_unit() {
+        let (defs, _) = parse("test.units",
+            "zincgauge[in] 1 0.002, 10 0.02, \\\n\
+             15 0.04\n\
+             brix[0.99717g/cm^3] 0 1.00000 1 1.00390\n\
+             plategauge[1;(oz/ft^2)/(480*lb/ft^3)] -5 300 1 180\n\
+             stdgauge[(oz/ft^2)/(501.84*lb/ft^3)] -5 300 1 180\n\
+             typo[in] 1 0.002, 10 0.0O2\n\
+             backwards[in] 10 0.02 1 0.002\n\
+             shoe (3+11|12) inch\n");
+        assert_eq!(defs.defs.len(), 5);
+        match *defs.defs[0].def {
+            Def::Table(ref def) => {
+                assert_eq!(def.name, "zincgauge");
+                assert!(def.input.is_none());
+                assert_eq!(def.output.as_ref().unwrap().to_string(), "in");
+                assert_eq!(def.table.points.len(), 3);
+            },
+            ref x => panic!("Expected table unit, got {:?}", x),
+        }
+        match *defs.defs[1].def {
+            Def::Table(ref def) =>
+                assert_eq!(def.output.as_ref().unwrap().to_string(), "0.99717 g / cm^3"),
+            ref x => panic!("Expected table unit, got {:?}", x),
+        }
+        match *defs.defs[2].def {
+            Def::Table(ref def) => {
+                assert_eq!(def.input.as_ref().unwrap().to_string(), "1");
+                assert_eq!(def.table.points[0].0, Num::from(-5));
+            },
+            ref x => panic!("Expected table unit, got {:?}", x),
+        }
+        match *defs.defs[3].def {
+            Def::Table(ref def) => {
+                assert_eq!(def.name, "stdgauge");
+                assert!(def.input.is_none());
+                assert_eq!(def.output.as_ref().unwrap().to_string(),
+                           "(oz / ft^2) / 501.84 lb / ft^3");
+            },
+            ref x => panic!("Expected table unit, got {:?}", x),
+        }
+        // the malformed tables are left out
+        assert_eq!(defs.defs[4].name, "shoe");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (defs, diagnostics) = parse("test.units",
+            "foot 12 inch \\\n\
+             # a comment\n\
+             !symbol x\n\
+             \x20 yard 3 foot\n\
+             typo[in] 1 0.002, 10 0.0O2\n\
+             )\n");
+        assert_eq!(defs.defs.len(), 2);
+        let location = defs.defs[1].location.as_ref().unwrap();
+        assert_eq!((location.line, location.column), (4, 3));
+        let positions = diagnostics.iter().map(|x| {
+            (x.line, x.column, x.severity)
+        }).collect::<Vec<_>>();
+        assert_eq!(positions, vec![
+            (3, 1, Severity::Error),
+            (5, 1, Severity::Error),
+            (6, 1, Severity::Error),
+        ]);
+        assert_eq!(diagnostics[1].to_string(),
+                   "test.units:5:1: error: Table unit typo is malformed: \
+                    Expected number in table, got Ident(\"O2\")");
+    }
+
+    #[test]
+    fn test_substance_table() {
+        let (defs, _) = parse("test.units",
+            "water {\n\
+             density mass gram / volume cm^3\n\
+             density table temperature K (g/cm^3) spline \\\n\
+             273.15 0.99984 283.15 0.99970 293.15 0.99821\n\
+             vapor_pressure table temperature K kPa linear 273.15 0.6113 -1\n\
+             }\n");
+        assert_eq!(defs.defs.len(), 1);
+        match *defs.defs[0].def {
+            Def::Substance { ref properties, ref tables, .. } => {
+                assert_eq!(properties.len(), 1);
+                // the vapor pressure has a parameter without a value
+                assert_eq!(tables.len(), 1);
+                assert_eq!(tables[0].name, "density");
+                assert_eq!(tables[0].param_name, "temperature");
+                assert_eq!(tables[0].param_unit.to_string(), "K");
+                assert_eq!(tables[0].unit.to_string(), "g / cm^3");
+                assert_eq!(tables[0].interpolation, Interpolation::Spline);
+                assert_eq!(tables[0].points.len(), 3);
+            },
+            ref x => panic!("Expected substance, got {:?}", x),
+        }
+    }
 }
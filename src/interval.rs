@@ -0,0 +1,454 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use gmp::mpq::Mpq;
+use gmp::mpz::Mpz;
+use number::{Number, Unit};
+use num::Num;
+use std::f64::consts::PI;
+
+/// A number known to lie between two bounds. The bounds are exact
+/// rationals, so arithmetic on them is exact; only the results of
+/// transcendental functions are computed with floats, and those are
+/// rounded outward by a few units in the last place.
+#[derive(Debug, Clone)]
+pub struct Interval {
+    pub lower: Mpq,
+    pub upper: Mpq,
+    pub unit: Unit,
+}
+
+/// How far the results of functions from the math library are widened,
+/// in units in the last place. The library is accurate to within one.
+const LIBM_ULPS: usize = 2;
+
+fn next_up(x: f64) -> f64 {
+    if x.is_nan() || x == ::std::f64::INFINITY {
+        x
+    } else if x == 0.0 {
+        f64::from_bits(1)
+    } else if x > 0.0 {
+        f64::from_bits(x.to_bits() + 1)
+    } else {
+        f64::from_bits(x.to_bits() - 1)
+    }
+}
+
+fn next_down(x: f64) -> f64 {
+    -next_up(-x)
+}
+
+fn to_mpq(x: f64) -> Mpq {
+    let mut res = Mpq::zero();
+    res.set_d(x);
+    res
+}
+
+/// The largest float that is not above the value.
+fn float_down(x: &Mpq) -> f64 {
+    let res: f64 = x.clone().into();
+    if to_mpq(res) > *x { next_down(res) } else { res }
+}
+
+/// The smallest float that is not below the value.
+fn float_up(x: &Mpq) -> f64 {
+    let res: f64 = x.clone().into();
+    if to_mpq(res) < *x { next_up(res) } else { res }
+}
+
+fn widen_down(mut x: f64) -> f64 {
+    for _ in 0..LIBM_ULPS {
+        x = next_down(x);
+    }
+    x
+}
+
+fn widen_up(mut x: f64) -> f64 {
+    for _ in 0..LIBM_ULPS {
+        x = next_up(x);
+    }
+    x
+}
+
+fn min(a: Mpq, b: Mpq) -> Mpq {
+    if a < b { a } else { b }
+}
+
+fn max(a: Mpq, b: Mpq) -> Mpq {
+    if a > b { a } else { b }
+}
+
+/// Rounds a rational to an integer, either down or up.
+fn round(x: &Mpq, up: bool) -> Mpz {
+    let num = x.get_num();
+    let den = x.get_den();
+    let trunc = &num / &den;
+    if &trunc * &den == num {
+        trunc
+    } else if up && num > Mpz::from(0) {
+        &trunc + &Mpz::one()
+    } else if !up && num < Mpz::from(0) {
+        &trunc - &Mpz::one()
+    } else {
+        trunc
+    }
+}
+
+fn power_of_ten(exp: i32) -> Mpq {
+    let res = Mpz::from(10).pow(exp.abs() as u32);
+    if exp < 0 {
+        Mpq::ratio(&Mpz::one(), &res)
+    } else {
+        Mpq::ratio(&res, &Mpz::one())
+    }
+}
+
+/// The decimal exponent of the leading digit of a nonzero value. May
+/// be one off for values very close to a power of ten.
+fn exponent(x: &Mpq) -> i32 {
+    let x: f64 = x.abs().into();
+    x.log10().floor() as i32
+}
+
+/// Formats a value to the given number of significant digits, rounding
+/// down for lower bounds and up for upper ones.
+pub fn format_bound(x: &Mpq, digits: i32, up: bool) -> String {
+    if *x == Mpq::zero() {
+        return "0".to_owned()
+    }
+    let scale = digits - 1 - exponent(x);
+    let scaled = round(&(x * &power_of_ten(scale)), up);
+    let sign = if scaled < Mpz::from(0) { "-" } else { "" };
+    let mut mantissa = format!("{}", scaled.abs());
+    let exp = mantissa.len() as i32 - 1 - scale;
+    if exp >= 10 || exp < -5 {
+        while mantissa.len() > 1 && mantissa.ends_with('0') {
+            mantissa.pop();
+        }
+        if mantissa.len() > 1 {
+            mantissa.insert(1, '.');
+        }
+        format!("{}{}e{}", sign, mantissa, exp)
+    } else if scale <= 0 {
+        format!("{}{}{}", sign, mantissa, "0".repeat((-scale) as usize))
+    } else if scale as usize >= mantissa.len() {
+        format!("{}0.{}{}", sign,
+                "0".repeat(scale as usize - mantissa.len()), mantissa)
+    } else {
+        let point = mantissa.len() - scale as usize;
+        mantissa.insert(point, '.');
+        format!("{}{}", sign, mantissa)
+    }
+}
+
+impl Interval {
+    pub fn one() -> Interval {
+        Interval {
+            lower: Mpq::one(),
+            upper: Mpq::one(),
+            unit: Unit::new(),
+        }
+    }
+
+    /// Creates an interval containing a number. Rationals are exact,
+    /// while floats are trusted to within a few units in the last
+    /// place.
+    pub fn from_number(num: &Number) -> Interval {
+        let (lower, upper) = match num.value {
+            Num::Mpq(ref mpq) => (mpq.clone(), mpq.clone()),
+            Num::Float(f) => (to_mpq(widen_down(f)), to_mpq(widen_up(f))),
+        };
+        Interval {
+            lower: lower,
+            upper: upper,
+            unit: num.unit.clone(),
+        }
+    }
+
+    /// Creates a dimensionless interval of the values within `error`
+    /// of `value`.
+    pub fn around(value: &Mpq, error: &Mpq) -> Interval {
+        Interval {
+            lower: value - error,
+            upper: value + error,
+            unit: Unit::new(),
+        }
+    }
+
+    /// Returns the number if the interval is a single point.
+    pub fn to_number(&self) -> Option<Number> {
+        if self.lower == self.upper {
+            Some(Number {
+                value: Num::Mpq(self.lower.clone()),
+                unit: self.unit.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn dimless(&self) -> bool {
+        self.unit.len() == 0
+    }
+
+    fn contains_zero(&self) -> bool {
+        self.lower <= Mpq::zero() && self.upper >= Mpq::zero()
+    }
+
+    pub fn add(&self, other: &Interval) -> Result<Interval, String> {
+        if self.unit != other.unit {
+            return Err(format!("Addition of units with mismatched units is not meaningful"))
+        }
+        Ok(Interval {
+            lower: &self.lower + &other.lower,
+            upper: &self.upper + &other.upper,
+            unit: self.unit.clone(),
+        })
+    }
+
+    pub fn sub(&self, other: &Interval) -> Result<Interval, String> {
+        if self.unit != other.unit {
+            return Err(format!("Subtraction of units with mismatched units is not meaningful"))
+        }
+        Ok(Interval {
+            lower: &self.lower - &other.upper,
+            upper: &self.upper - &other.lower,
+            unit: self.unit.clone(),
+        })
+    }
+
+    pub fn neg(&self) -> Interval {
+        Interval {
+            lower: -&self.upper,
+            upper: -&self.lower,
+            unit: self.unit.clone(),
+        }
+    }
+
+    pub fn mul(&self, other: &Interval) -> Interval {
+        let a = &self.lower * &other.lower;
+        let b = &self.lower * &other.upper;
+        let c = &self.upper * &other.lower;
+        let d = &self.upper * &other.upper;
+        Interval {
+            lower: min(min(a.clone(), b.clone()), min(c.clone(), d.clone())),
+            upper: max(max(a, b), max(c, d)),
+            unit: ::btree_merge(&self.unit, &other.unit, |a, b| {
+                if a+b != 0 { Some(a + b) } else { None }
+            }),
+        }
+    }
+
+    pub fn div(&self, other: &Interval) -> Result<Interval, String> {
+        if other.contains_zero() {
+            return Err(format!("Division by an interval containing zero"))
+        }
+        let inverse = Interval {
+            lower: &Mpq::one() / &other.upper,
+            upper: &Mpq::one() / &other.lower,
+            unit: other.unit.iter()
+                .map(|(k, &power)| (k.clone(), -power))
+                .collect::<Unit>(),
+        };
+        Ok(self.mul(&inverse))
+    }
+
+    pub fn powi(&self, exp: i64) -> Result<Interval, String> {
+        if exp < 0 {
+            return Interval::one().div(&try!(self.powi(-exp)))
+        }
+        let unit = if exp == 0 {
+            Unit::new()
+        } else {
+            self.unit.iter()
+                .map(|(k, &power)| (k.clone(), power * exp))
+                .collect::<Unit>()
+        };
+        let exp = exp as u32;
+        let pow = |x: &Mpq| Mpq::ratio(&x.get_num().pow(exp), &x.get_den().pow(exp));
+        let (a, b) = (pow(&self.lower), pow(&self.upper));
+        let (lower, upper) = if exp % 2 == 1 {
+            (a, b)
+        } else if self.contains_zero() {
+            (Mpq::zero(), max(a, b))
+        } else {
+            (min(a.clone(), b.clone()), max(a, b))
+        };
+        Ok(Interval {
+            lower: lower,
+            upper: upper,
+            unit: unit,
+        })
+    }
+
+    /// Raises the interval to an exact power. Fractional powers need a
+    /// non-negative base.
+    pub fn pow(&self, exp: &Interval) -> Result<Interval, String> {
+        if !exp.dimless() {
+            return Err(format!("Exponent must be dimensionless"))
+        }
+        if exp.lower != exp.upper {
+            return Err(format!("Exponent must be exact in interval arithmetic"))
+        }
+        let num = exp.lower.get_num();
+        let den = exp.lower.get_den();
+        if den == Mpz::one() {
+            let num: Option<i64> = (&num).into();
+            return match num {
+                Some(num) if num.abs() < 1 << 31 => self.powi(num),
+                _ => Err(format!("Exponent is too large")),
+            }
+        }
+        if self.lower < Mpq::zero() {
+            return Err(format!("Fractional powers of negative values are not defined"))
+        }
+        let (num, den): (Option<i64>, Option<i64>) = ((&num).into(), (&den).into());
+        let (num, den) = match (num, den) {
+            (Some(num), Some(den)) => (num, den),
+            _ => return Err(format!("Exponent is too large")),
+        };
+        let mut unit = Unit::new();
+        for (dim, &power) in &self.unit {
+            if (power * num) % den != 0 {
+                return Err(format!("Exponentiation must result in integer dimensions"))
+            }
+            unit.insert(dim.clone(), power * num / den);
+        }
+        let exp = num as f64 / den as f64;
+        let (below, above) = if to_mpq(exp) == Mpq::ratio(&Mpz::from(num), &Mpz::from(den)) {
+            (exp, exp)
+        } else {
+            (next_down(exp), next_up(exp))
+        };
+        // x^e grows with e when x >= 1, and shrinks with it otherwise
+        let bound = |x: f64, up: bool| x.powf(if (x >= 1.0) == up { above } else { below });
+        let a = float_down(&self.lower);
+        let b = float_up(&self.upper);
+        let (lower, upper) = if exp > 0.0 {
+            (bound(a, false), bound(b, true))
+        } else {
+            (bound(b, false), bound(a, true))
+        };
+        Interval::checked("pow", widen_down(lower), widen_up(upper), unit)
+    }
+
+    fn checked(name: &str, lower: f64, upper: f64, unit: Unit) -> Result<Interval, String> {
+        if !lower.is_finite() || !upper.is_finite() {
+            return Err(format!(
+                "{} is undefined or unbounded on part of the interval", name
+            ))
+        }
+        Ok(Interval {
+            lower: to_mpq(lower),
+            upper: to_mpq(upper),
+            unit: unit,
+        })
+    }
+
+    /// Applies a monotonic function to the bounds.
+    fn monotonic<F>(&self, name: &str, func: F, increasing: bool) -> Result<Interval, String>
+    where F: Fn(f64) -> f64 {
+        let a = func(float_down(&self.lower));
+        let b = func(float_up(&self.upper));
+        let (lower, upper) = if increasing { (a, b) } else { (b, a) };
+        Interval::checked(name, widen_down(lower), widen_up(upper), self.unit.clone())
+    }
+
+    /// Applies a function with period 2π, which has its maximum at
+    /// `peak` and its minimum half a period later.
+    fn periodic(&self, name: &str, func: fn(f64) -> f64, peak: f64) -> Result<Interval, String> {
+        let a = float_down(&self.lower);
+        let b = float_up(&self.upper);
+        if !a.is_finite() || !b.is_finite() {
+            return Err(format!("{} is undefined or unbounded on part of the interval", name))
+        }
+        let contains = |at: f64| {
+            let k = ((a - at) / (2.0 * PI)).ceil();
+            at + k * 2.0 * PI <= b
+        };
+        let (fa, fb) = (func(a), func(b));
+        let lower = if b - a >= 2.0 * PI || contains(peak - PI) {
+            -1.0
+        } else {
+            widen_down(fa.min(fb)).max(-1.0)
+        };
+        let upper = if b - a >= 2.0 * PI || contains(peak) {
+            1.0
+        } else {
+            widen_up(fa.max(fb)).min(1.0)
+        };
+        Interval::checked(name, lower, upper, self.unit.clone())
+    }
+
+    /// Applies one of the builtin functions. Like their regular
+    /// counterparts, these keep the units of their argument.
+    pub fn call(&self, name: &str) -> Result<Interval, String> {
+        match name {
+            "sqrt" => self.pow(&Interval {
+                lower: Mpq::ratio(&Mpz::one(), &Mpz::from(2)),
+                upper: Mpq::ratio(&Mpz::one(), &Mpz::from(2)),
+                unit: Unit::new(),
+            }),
+            "exp" => self.monotonic(name, f64::exp, true),
+            "ln" => self.monotonic(name, f64::ln, true),
+            "log2" => self.monotonic(name, f64::log2, true),
+            "log10" => self.monotonic(name, f64::log10, true),
+            "sin" => self.periodic(name, f64::sin, PI / 2.0),
+            "cos" => self.periodic(name, f64::cos, 0.0),
+            "tan" => {
+                let a = float_down(&self.lower);
+                let k = ((a - PI / 2.0) / PI).ceil();
+                if PI / 2.0 + k * PI <= float_up(&self.upper) {
+                    return Err(format!("tan is unbounded on the interval"))
+                }
+                self.monotonic(name, f64::tan, true)
+            },
+            "asin" => self.monotonic(name, f64::asin, true),
+            "acos" => self.monotonic(name, f64::acos, false),
+            "atan" => self.monotonic(name, f64::atan, true),
+            "sinh" => self.monotonic(name, f64::sinh, true),
+            "cosh" => if self.contains_zero() {
+                let a = float_down(&self.lower).cosh();
+                let b = float_up(&self.upper).cosh();
+                Interval::checked(name, 1.0, widen_up(a.max(b)), self.unit.clone())
+            } else if self.lower > Mpq::zero() {
+                self.monotonic(name, f64::cosh, true)
+            } else {
+                self.monotonic(name, f64::cosh, false)
+            },
+            "tanh" => self.monotonic(name, f64::tanh, true),
+            "asinh" => self.monotonic(name, f64::asinh, true),
+            "acosh" => self.monotonic(name, f64::acosh, true),
+            "atanh" => self.monotonic(name, f64::atanh, true),
+            _ => Err(format!("Interval arithmetic is not implemented for {}", name)),
+        }
+    }
+
+    /// Returns the number of significant digits both bounds agree on,
+    /// along with those digits.
+    pub fn certified_digits(&self) -> Option<(usize, String)> {
+        let zero = Mpq::zero();
+        if self.lower <= zero && self.upper >= zero {
+            return None
+        }
+        let (small, large) = if self.lower > zero {
+            (self.lower.clone(), self.upper.clone())
+        } else {
+            (self.upper.abs(), self.lower.abs())
+        };
+        let exp = exponent(&large);
+        let mut res = None;
+        for digits in 1..41 {
+            let scale = power_of_ten(digits - 1 - exp);
+            if round(&(&small * &scale), false) != round(&(&large * &scale), false) {
+                break
+            }
+            res = Some(digits);
+        }
+        res.map(|digits| {
+            let value = if self.lower > zero { large } else { -&large };
+            (digits as usize, format_bound(&value, digits, self.lower < zero))
+        })
+    }
+}
@@ -0,0 +1,162 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A stable JSON form of query results, for programs that use Rink
+//! through `rink --json` or `rink --server-stdio`. Each reply is one
+//! object:
+//!
+//! ```json
+//! {"version": 1, "ok": true, "reply": {"type": "number", "text": "3 meter (length)", "number": {...}}}
+//! {"version": 1, "ok": false, "error": {"type": "not_found", "message": "...", "got": "fooo", "suggestion": "foot"}, "span": {"start": 8, "end": 12}}
+//! ```
+//!
+//! Every reply has a `type` and the `text` that Rink would print.
+//! Numbers, dates, booleans and errors also come with their parts.
+//! Spans are byte offsets into the trimmed query. New fields may be
+//! added within a version, `VERSION` changes when existing ones do.
+
+use json::JsonValue;
+use number::NumberParts;
+use reply::{DateReply, QueryError, QueryReply};
+use text_query::Span;
+
+pub const VERSION: u32 = 1;
+
+fn opt(value: &Option<String>) -> JsonValue {
+    match *value {
+        Some(ref value) => value.clone().into(),
+        None => JsonValue::Null,
+    }
+}
+
+pub fn number_json(parts: &NumberParts) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    res["exact_value"] = opt(&parts.exact_value);
+    res["approx_value"] = opt(&parts.approx_value);
+    res["uncertainty"] = opt(&parts.uncertainty);
+    res["concise_value"] = opt(&parts.concise_value);
+    res["factor"] = opt(&parts.factor);
+    res["divfactor"] = opt(&parts.divfactor);
+    res["unit"] = opt(&parts.unit);
+    res["quantity"] = opt(&parts.quantity);
+    res["dimensions"] = opt(&parts.dimensions);
+    res
+}
+
+pub fn date_json(date: &DateReply) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    res["year"] = date.year.into();
+    res["month"] = date.month.into();
+    res["day"] = date.day.into();
+    res["hour"] = date.hour.into();
+    res["minute"] = date.minute.into();
+    res["second"] = date.second.into();
+    res["nanosecond"] = date.nanosecond.into();
+    res["human"] = opt(&date.human);
+    res["string"] = date.string.clone().into();
+    res
+}
+
+fn reply_type(reply: &QueryReply) -> &'static str {
+    match *reply {
+        QueryReply::Number(_) => "number",
+        QueryReply::Date(_) => "date",
+        QueryReply::Substance(_) => "substance",
+        QueryReply::Duration(_) => "duration",
+        QueryReply::Def(_) => "definition",
+        QueryReply::Conversion(_) => "conversion",
+        QueryReply::Factorize(_) => "factorize",
+        QueryReply::UnitsFor(_) => "units_for",
+        QueryReply::UnitList(_) => "unit_list",
+        QueryReply::Search(_) => "search",
+        QueryReply::Assign(_) => "assign",
+        QueryReply::Variables(_) => "variables",
+        QueryReply::Unset(_) => "unset",
+        QueryReply::Function(_) => "function",
+        QueryReply::Logarithmic(_) => "logarithmic",
+        QueryReply::Interval(_) => "interval",
+        QueryReply::Polar(_) => "polar",
+        QueryReply::Composition(_) => "composition",
+        QueryReply::Reaction(_) => "reaction",
+        QueryReply::Alternatives(_) => "alternatives",
+        QueryReply::Explain(_) => "explain",
+        QueryReply::Bool(_) => "bool",
+    }
+}
+
+pub fn reply_json(reply: &QueryReply) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    res["type"] = reply_type(reply).into();
+    res["text"] = reply.to_string().into();
+    match *reply {
+        QueryReply::Number(ref parts) => res["number"] = number_json(parts),
+        QueryReply::Conversion(ref conv) => res["number"] = number_json(&conv.value),
+        QueryReply::Duration(ref duration) => res["number"] = number_json(&duration.raw),
+        QueryReply::Date(ref date) => res["date"] = date_json(date),
+        QueryReply::UnitList(ref list) =>
+            res["list"] = JsonValue::Array(list.list.iter().map(number_json).collect()),
+        QueryReply::Alternatives(ref alt) =>
+            res["alternatives"] = JsonValue::Array(alt.alternatives.iter().map(number_json).collect()),
+        QueryReply::Bool(ref b) => res["value"] = b.value.into(),
+        QueryReply::Assign(ref assign) => {
+            res["name"] = assign.name.clone().into();
+            res["value"] = reply_json(&assign.value);
+        },
+        QueryReply::Explain(ref explain) => res["result"] = reply_json(&explain.result),
+        _ => ()
+    }
+    res
+}
+
+pub fn error_json(error: &QueryError) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    match *error {
+        QueryError::Conformance(ref e) => {
+            res["type"] = "conformance".into();
+            res["message"] = error.to_string().into();
+            res["left"] = number_json(&e.left);
+            res["right"] = number_json(&e.right);
+            res["suggestions"] = JsonValue::Array(
+                e.suggestions.iter().map(|x| x.clone().into()).collect());
+        },
+        QueryError::NotFound(ref e) => {
+            res["type"] = "not_found".into();
+            res["message"] = error.to_string().into();
+            res["got"] = e.got.clone().into();
+            res["suggestion"] = opt(&e.suggestion);
+        },
+        QueryError::Generic(ref message) => {
+            res["type"] = "generic".into();
+            res["message"] = message.clone().into();
+        },
+        QueryError::Spanned(ref error, _) => return error_json(error),
+    }
+    res
+}
+
+/// The object written for the result of one query.
+pub fn to_json(result: &Result<QueryReply, (QueryError, Option<Span>)>) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    res["version"] = VERSION.into();
+    match *result {
+        Ok(ref reply) => {
+            res["ok"] = true.into();
+            res["reply"] = reply_json(reply);
+        },
+        Err((ref error, ref span)) => {
+            res["ok"] = false.into();
+            res["error"] = error_json(error);
+            res["span"] = match *span {
+                Some(span) => {
+                    let mut obj = JsonValue::new_object();
+                    obj["start"] = span.start.into();
+                    obj["end"] = span.end.into();
+                    obj
+                },
+                None => JsonValue::Null,
+            };
+        },
+    }
+    res
+}
@@ -36,6 +36,7 @@ extern crate gmp;
 extern crate chrono;
 extern crate strsim;
 extern crate chrono_tz;
+extern crate toml;
 #[cfg(feature = "chrono-humanize")]
 extern crate chrono_humanize;
 #[cfg(feature = "sandbox")]
@@ -46,7 +47,6 @@ extern crate ipc_channel;
 extern crate reqwest;
 #[cfg(feature = "currency")]
 extern crate xml;
-#[cfg(feature = "currency")]
 extern crate json;
 #[cfg(feature = "nightly")]
 extern crate serde;
@@ -68,7 +68,21 @@ pub mod reply;
 pub mod search;
 pub mod load;
 pub mod substance;
+pub mod mixture;
+pub mod logarithmic;
+pub mod uncertain;
+pub mod interval;
+pub mod precise;
+pub mod complex;
+pub mod vector;
 pub mod formula;
+pub mod reaction;
+pub mod table;
+pub mod diagnostic;
+pub mod config;
+pub mod system;
+pub mod script;
+pub mod json_reply;
 #[cfg(feature = "currency")]
 pub mod currency;
 #[cfg(feature = "currency")]
@@ -77,6 +91,7 @@ pub mod btc;
 pub use number::Number;
 pub use context::Context;
 pub use value::Value;
+pub use diagnostic::Diagnostic;
 
 use std::env;
 use std::convert::From;
@@ -147,25 +162,62 @@ static DEFAULT_FILE: Option<&'static str> = None;
 static DATES_FILE: &'static str = include_str!("../datepatterns.txt");
 static CURRENCY_FILE: &'static str = include_str!("../currency.units");
 
-/// Creates a context by searching standard directories for definitions.units.
+/// Creates a context by searching standard directories for
+/// definitions.units. Problems in the units files are ignored, use
+/// `load_with_diagnostics()` to get them instead.
 pub fn load() -> Result<Context, String> {
+    load_with_diagnostics().map(|(ctx, _)| ctx)
+}
+
+/// The extra units files that are loaded after the standard ones, in
+/// order. These are the files listed in the `RINK_UNITS_PATH`
+/// environment variable, separated like `PATH`, followed by
+/// `.rink/units` in the current directory if it exists.
+pub fn units_path() -> Vec<PathBuf> {
+    let mut out = vec![];
+    if let Some(paths) = env::var_os("RINK_UNITS_PATH") {
+        out.extend(env::split_paths(&paths).filter(|x| x.as_os_str().len() > 0));
+    }
+    let project = PathBuf::from(".rink/units");
+    if project.is_file() {
+        out.push(project);
+    }
+    out
+}
+
+/// Creates a context by searching standard directories for
+/// definitions.units, along with the problems found in the units
+/// files. The files from `units_path()` are not loaded, pass them to
+/// `load_with_units()` for that.
+pub fn load_with_diagnostics() -> Result<(Context, Vec<Diagnostic>), String> {
+    load_with_units(&[], true)
+}
+
+/// Creates a context from the standard units files followed by
+/// `files`. Definitions in later files override earlier ones, which
+/// is reported as a warning. Exchange rates are downloaded only if
+/// `fetch_currency` is set.
+pub fn load_with_units(files: &[PathBuf], fetch_currency: bool)
+                       -> Result<(Context, Vec<Diagnostic>), String> {
     use std::io::Read;
     use std::path::Path;
 
     let mut path = try!(config_dir());
     path.push("rink/");
-    let load = |name| {
-        File::open(name)
+    let load = |name: PathBuf| {
+        File::open(&name)
         .and_then(|mut f| {
             let mut buf = vec![];
             try!(f.read_to_end(&mut buf));
-            Ok(String::from_utf8_lossy(&*buf).into_owned())
+            Ok((name.display().to_string(), String::from_utf8_lossy(&*buf).into_owned()))
         })
     };
     let units =
         load(Path::new("definitions.units").to_path_buf())
         .or_else(|_| load(path.join("definitions.units")))
-        .or_else(|_| DEFAULT_FILE.map(|x| x.to_owned()).ok_or(format!(
+        .or_else(|_| DEFAULT_FILE.map(|x| {
+            ("<builtin definitions.units>".to_owned(), x.to_owned())
+        }).ok_or(format!(
             "Did not exist in search path and binary is not compiled with `gpl` feature")))
         .map_err(|e| format!(
             "Failed to open definitions.units: {}\n\
@@ -175,23 +227,27 @@ pub fn load() -> Result<Context, String> {
              {}\n\
              \n",
             e, &path, DATA_FILE_URL));
-    let units = try!(units);
-    let dates =
+    let (units_file, units) = try!(units);
+    let (_, dates) =
         load(Path::new("datepatterns.txt").to_path_buf())
         .or_else(|_| load(path.join("datepatterns.txt")))
-        .unwrap_or_else(|_| DATES_FILE.to_owned());
+        .unwrap_or_else(|_| ("<builtin datepatterns.txt>".to_owned(), DATES_FILE.to_owned()));
 
-    let mut iter = gnu_units::TokenIterator::new(&*units).peekable();
-    let units = gnu_units::parse(&mut iter);
+    let mut diagnostics = vec![];
+    let (units, mut errors) = gnu_units::parse(&*units_file, &*units);
+    diagnostics.append(&mut errors);
     let dates = date::parse_datefile(&*dates);
-    let ecb = load_currency();
-    let btc = load_btc();
+    let (ecb, btc) = if fetch_currency {
+        (load_currency(), load_btc())
+    } else {
+        (None, None)
+    };
     let currency_defs = {
-        let defs = load(Path::new("currency.units").to_path_buf())
+        let (file, defs) = load(Path::new("currency.units").to_path_buf())
             .or_else(|_| load(path.join("currency.units")))
-            .unwrap_or_else(|_| CURRENCY_FILE.to_owned());
-        let mut iter = gnu_units::TokenIterator::new(&*defs).peekable();
-        let currency = gnu_units::parse(&mut iter);
+            .unwrap_or_else(|_| ("<builtin currency.units>".to_owned(), CURRENCY_FILE.to_owned()));
+        let (currency, mut errors) = gnu_units::parse(&*file, &*defs);
+        diagnostics.append(&mut errors);
         currency
     };
     let currency = {
@@ -199,12 +255,14 @@ pub fn load() -> Result<Context, String> {
         if let Some(Ok(mut ecb)) = ecb {
             defs.append(&mut ecb.defs)
         } else if let Some(Err(e)) = ecb {
-            println!("Failed to load ECB currency data: {}", e);
+            diagnostics.push(Diagnostic::warning(None, format!(
+                "Failed to load ECB currency data: {}", e)));
         }
         if let Some(Ok(mut btc)) = btc {
             defs.append(&mut btc.defs)
         } else if let Some(Err(e)) = btc {
-            println!("Failed to load BTC currency data: {}", e);
+            diagnostics.push(Diagnostic::warning(None, format!(
+                "Failed to load BTC currency data: {}", e)));
         }
         let mut currency_defs = currency_defs;
         defs.append(&mut currency_defs.defs);
@@ -213,19 +271,75 @@ pub fn load() -> Result<Context, String> {
         }
     };
 
+    let mut extra = vec![];
+    for file in files {
+        match load(file.clone()) {
+            Ok((name, input)) => {
+                let (mut defs, mut errors) = gnu_units::parse(&*name, &*input);
+                extra.append(&mut defs.defs);
+                diagnostics.append(&mut errors);
+            },
+            Err(e) => diagnostics.push(Diagnostic::error(None, format!(
+                "Could not open units file {}: {}", file.display(), e))),
+        }
+    }
+
     let mut ctx = context::Context::new();
-    ctx.load(units);
+    diagnostics.append(&mut ctx.load(units));
     ctx.load_dates(dates);
-    ctx.load(currency);
-    Ok(ctx)
+    diagnostics.append(&mut ctx.load(currency));
+    // loaded last so that they can refer to currencies
+    diagnostics.append(&mut ctx.load(ast::Defs {
+        defs: extra
+    }));
+    Ok((ctx, diagnostics))
+}
+
+/// Lints a units file named `file` with the contents `input`. Files
+/// that define base units, like definitions.units, are checked on
+/// their own, others are loaded on top of the standard definitions.
+/// The files from `units_path()` are left out, since the checked file
+/// is usually one of them. Exchange rates are downloaded only if
+/// `fetch_currency` is set.
+pub fn check(file: &str, input: &str, fetch_currency: bool)
+             -> Result<Vec<Diagnostic>, String> {
+    let (defs, mut diagnostics) = gnu_units::parse(file, input);
+    let standalone = defs.defs.iter().any(|x| match *x.def {
+        ast::Def::Dimension => true,
+        _ => false,
+    });
+    let mut ctx = if standalone {
+        Context::new()
+    } else {
+        try!(load_with_units(&[], fetch_currency)).0
+    };
+    diagnostics.append(&mut ctx.load(defs));
+    Ok(diagnostics)
 }
 
 /// Evaluates a single line within a context.
 pub fn one_line(ctx: &mut Context, line: &str) -> Result<String, String> {
-    let mut iter = text_query::TokenIterator::new(line.trim()).peekable();
+    one_line_spanned(ctx, line).map_err(|(e, _)| e)
+}
+
+/// Evaluates a single line within a context. Errors come with the
+/// part of the trimmed line that they are about, when it is known.
+pub fn one_line_spanned(
+    ctx: &mut Context, line: &str
+) -> Result<String, (String, Option<text_query::Span>)> {
+    eval_spanned(ctx, line)
+        .map(|reply| reply.to_string())
+        .map_err(|(e, span)| (e.to_string(), span))
+}
+
+/// Like `one_line_spanned`, but keeps the structure of the reply.
+pub fn eval_spanned(
+    ctx: &mut Context, line: &str
+) -> Result<reply::QueryReply, (reply::QueryError, Option<text_query::Span>)> {
+    let mut iter = text_query::TokenStream::new(line.trim())
+        .with_functions(ctx.function_names());
     let expr = text_query::parse_query(&mut iter);
-    let res = ctx.eval_outer(&expr);
-    res.as_ref().map(ToString::to_string).map_err(ToString::to_string)
+    ctx.eval_query(&expr).map_err(|e| e.split())
 }
 
 #[cfg(feature = "sandbox")]
@@ -6,15 +6,19 @@ use std::collections::{BTreeMap, BTreeSet};
 use number::{Number, Dim};
 use num::Num;
 use ast::{Expr, Def, Defs, DefEntry};
-use substance::{Substance, Property, Properties};
+use substance::{Substance, Property, Properties, TabulatedProperty};
+use table::Table;
+use logarithmic::LogScale;
 use std::rc::Rc;
 use value::Value;
+use diagnostic::{Diagnostic, Location};
 use Context;
 
 impl Context {
-    /// Takes a parsed definitions.units from
-    /// `gnu_units::parse()`. Prints if there are errors in the file.
-    pub fn load(&mut self, defs: Defs) {
+    /// Takes a parsed definitions.units from `gnu_units::parse()`.
+    /// Definitions with errors are left out, and the problems are
+    /// returned.
+    pub fn load(&mut self, defs: Defs) -> Vec<Diagnostic> {
         #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
         enum Name {
             Unit(Rc<String>),
@@ -31,6 +35,15 @@ impl Context {
             temp_marks: BTreeSet<Name>,
             docs: BTreeMap<Name, String>,
             categories: BTreeMap<Name, String>,
+            locations: BTreeMap<Name, Location>,
+            diagnostics: Vec<Diagnostic>,
+        }
+
+        fn name_str(name: &Name) -> &str {
+            match *name {
+                Name::Unit(ref name) | Name::Prefix(ref name) |
+                Name::Quantity(ref name) | Name::Category(ref name) => &**name,
+            }
         }
 
         impl Resolver {
@@ -108,21 +121,28 @@ impl Context {
 
             fn eval(&mut self, expr: &Expr) {
                 match *expr {
-                    Expr::Unit(ref name) => {
+                    Expr::Unit(ref name, _) => {
                         let name = self.intern(name);
                         let _ = self.lookup(&name);
                     },
                     Expr::Frac(ref left, ref right) |
                     Expr::Pow(ref left, ref right) |
                     Expr::Add(ref left, ref right) |
-                    Expr::Sub(ref left, ref right) => {
+                    Expr::Sub(ref left, ref right) |
+                    Expr::PlusMinus(ref left, ref right) |
+                    Expr::Compare(_, ref left, ref right) => {
                         self.eval(left);
                         self.eval(right);
                     },
                     Expr::Neg(ref expr) | Expr::Plus(ref expr) |
                     Expr::Suffix(_, ref expr) | Expr::Of(_, ref expr) =>
                         self.eval(expr),
-                    Expr::Mul(ref exprs) | Expr::Call(_, ref exprs) => for expr in exprs {
+                    Expr::OfAt(_, ref expr, ref at) => {
+                        self.eval(expr);
+                        self.eval(at);
+                    },
+                    Expr::Mul(ref exprs) | Expr::Call(_, ref exprs, _) |
+                    Expr::Vector(ref exprs) => for expr in exprs {
                         self.eval(expr);
                     },
                     _ => ()
@@ -131,7 +151,10 @@ impl Context {
 
             fn visit(&mut self, name: &Name) {
                 if self.temp_marks.get(name).is_some() {
-                    println!("Unit {:?} has a dependency cycle", name);
+                    let diagnostic = Diagnostic::error(
+                        self.locations.get(name),
+                        format!("{} has a dependency cycle", name_str(name)));
+                    self.diagnostics.push(diagnostic);
                     return;
                 }
                 if self.unmarked.get(name).is_some() {
@@ -144,11 +167,26 @@ impl Context {
                             Def::Canonicalization(ref e) => {
                                 self.lookup(&Rc::new(e.clone()));
                             },
-                            Def::Substance { ref properties, .. } => {
+                            Def::Logarithmic { ref step, ref reference, .. } => {
+                                self.eval(step);
+                                if let Some(ref reference) = *reference {
+                                    self.eval(reference);
+                                }
+                            },
+                            Def::Function(ref def) => {
+                                if let Some(ref result) = def.result {
+                                    self.eval(result);
+                                }
+                            },
+                            Def::Substance { ref properties, ref tables, .. } => {
                                 for prop in properties {
                                     self.eval(&prop.input);
                                     self.eval(&prop.output);
                                 }
+                                for table in tables {
+                                    self.eval(&table.param_unit);
+                                    self.eval(&table.unit);
+                                }
                             },
                             _ => (),
                         }
@@ -168,8 +206,21 @@ impl Context {
             temp_marks: BTreeSet::new(),
             docs: BTreeMap::new(),
             categories: BTreeMap::new(),
+            locations: BTreeMap::new(),
+            diagnostics: vec![],
         };
-        for DefEntry { name, def, doc, category } in defs.defs.into_iter() {
+        for DefEntry { name, def, doc, category, location } in defs.defs.into_iter() {
+            // names from earlier calls, like the standard definitions
+            // under a user's units file
+            let overrides = match *def {
+                Def::Prefix(_) | Def::SPrefix(_) => self.prefixes.iter().any(|x| x.0 == name),
+                Def::Category(_) | Def::Quantity(_) => false,
+                _ => self.has_definition(&name),
+            };
+            if overrides {
+                resolver.diagnostics.push(Diagnostic::warning(
+                    location.as_ref(), format!("{} overrides an earlier definition", name)));
+            }
             let name = resolver.intern(&name);
             let unit = match *def {
                 Def::Prefix(_) | Def::SPrefix(_) => Name::Prefix(name),
@@ -191,9 +242,18 @@ impl Context {
                     Name::Category(ref name) => ("category", name),
                 };
                 if ty != "category" {
-                    println!("warning: multiple {} named {}", ty, name);
+                    let message = match resolver.locations.get(&unit) {
+                        Some(old) => format!(
+                            "Multiple {} named {}, overriding the one at {}:{}:{}",
+                            ty, name, old.file, old.line, old.column),
+                        None => format!("Multiple {} named {}", ty, name),
+                    };
+                    resolver.diagnostics.push(Diagnostic::warning(location.as_ref(), message));
                 }
             }
+            if let Some(location) = location {
+                resolver.locations.insert(unit.clone(), location);
+            }
             resolver.unmarked.insert(unit);
         }
 
@@ -201,6 +261,8 @@ impl Context {
             resolver.visit(&name)
         }
         let sorted = resolver.sorted;
+        let locations = resolver.locations;
+        let mut diagnostics = resolver.diagnostics;
         //println!("{:#?}", sorted);
         let mut input = resolver.input;
         let udefs = sorted.into_iter().map(move |name| {
@@ -227,6 +289,7 @@ impl Context {
         reverse.insert("katal");
 
         for (name, def) in udefs {
+            let location = locations.get(&name);
             let name = match name {
                 Name::Unit(name) => (*name).clone(),
                 Name::Prefix(name) => (*name).clone(),
@@ -241,10 +304,11 @@ impl Context {
                     self.canonicalizations.insert(of.clone(), name.clone());
                     match self.lookup(of) {
                         Some(v) => {
-                            self.definitions.insert(name.clone(), Expr::Unit(of.clone()));
+                            self.definitions.insert(name.clone(), Expr::Unit(of.clone(), None));
                             self.units.insert(name.clone(), v);
                         },
-                        None => println!("Canonicalization {} is malformed: {} not found", name, of)
+                        None => diagnostics.push(Diagnostic::error(location, format!(
+                            "Canonicalization {} is malformed: {} not found", name, of))),
                     }
                 },
                 Def::Unit(ref expr) => match self.eval(expr) {
@@ -262,26 +326,33 @@ impl Context {
                             sub
                         };
                         if self.substances.insert(name.clone(), sub).is_some() {
-                            println!("Warning: Conflicting substances for {}", name);
+                            diagnostics.push(Diagnostic::warning(
+                                location, format!("Conflicting substances for {}", name)));
                         }
                     },
-                    Ok(_) => println!("Unit {} is not a number", name),
-                    Err(e) => println!("Unit {} is malformed: {}", name, e)
+                    Ok(_) => diagnostics.push(Diagnostic::error(
+                        location, format!("Unit {} is not a number", name))),
+                    Err(e) => diagnostics.push(Diagnostic::error(
+                        location, format!("Unit {} is malformed: {}", name, e)))
                 },
                 Def::Prefix(ref expr) => match self.eval(expr) {
                     Ok(Value::Number(v)) => {
-                        self.prefixes.push((name.clone(), v));
+                        self.set_prefix(&name, v);
                     },
-                    Ok(_) => println!("Prefix {} is not a number", name),
-                    Err(e) => println!("Prefix {} is malformed: {}", name, e)
+                    Ok(_) => diagnostics.push(Diagnostic::error(
+                        location, format!("Prefix {} is not a number", name))),
+                    Err(e) => diagnostics.push(Diagnostic::error(
+                        location, format!("Prefix {} is malformed: {}", name, e)))
                 },
                 Def::SPrefix(ref expr) => match self.eval(expr) {
                     Ok(Value::Number(v)) => {
-                        self.prefixes.push((name.clone(), v.clone()));
+                        self.set_prefix(&name, v.clone());
                         self.units.insert(name.clone(), v);
                     },
-                    Ok(_) => println!("Prefix {} is not a number", name),
-                    Err(e) => println!("Prefix {} is malformed: {}", name, e)
+                    Ok(_) => diagnostics.push(Diagnostic::error(
+                        location, format!("Prefix {} is not a number", name))),
+                    Err(e) => diagnostics.push(Diagnostic::error(
+                        location, format!("Prefix {} is malformed: {}", name, e)))
                 },
                 Def::Quantity(ref expr) => match self.eval(expr) {
                     Ok(Value::Number(v)) => {
@@ -289,14 +360,19 @@ impl Context {
                         if !self.definitions.contains_key(&name) {
                             self.definitions.insert(name.clone(), expr.clone());
                         }
-                        if let Some(old) = res {
-                            println!("Warning: Conflicting quantities {} and {}", name, old);
+                        match res {
+                            Some(ref old) if *old != name => diagnostics.push(
+                                Diagnostic::warning(location, format!(
+                                    "Conflicting quantities {} and {}", name, old))),
+                            _ => (),
                         }
                     },
-                    Ok(_) => println!("Quantity {} is not a number", name),
-                    Err(e) => println!("Quantity {} is malformed: {}", name, e)
+                    Ok(_) => diagnostics.push(Diagnostic::error(
+                        location, format!("Quantity {} is not a number", name))),
+                    Err(e) => diagnostics.push(Diagnostic::error(
+                        location, format!("Quantity {} is malformed: {}", name, e)))
                 },
-                Def::Substance { ref properties, ref symbol } => {
+                Def::Substance { ref properties, ref tables, ref symbol } => {
                     let mut prev = BTreeMap::new();
                     let res = properties.iter().map(|prop| {
                         let input = match self.eval(&prop.input) {
@@ -326,11 +402,10 @@ impl Context {
                             .unit;
                         let existing = prev.entry(unit).or_insert(BTreeSet::new());
                         for conflict in existing.intersection(&unique) {
-                            println!(
-                                "Warning: conflicting \
-                                 properties for {} of {}",
+                            diagnostics.push(Diagnostic::warning(location, format!(
+                                "Conflicting properties for {} of {}",
                                 conflict, name
-                            );
+                            )));
                         }
                         existing.append(&mut unique);
                         self.temporaries.insert(
@@ -359,30 +434,114 @@ impl Context {
                         }))
                     }).collect::<Result<BTreeMap<_,_>, _>>();
                     self.temporaries.clear();
-                    match res {
-                        Ok(res) => {
+                    let tables = tables.iter().map(|table| {
+                        let param_unit = match self.eval(&table.param_unit) {
+                            Ok(Value::Number(v)) => v,
+                            Ok(x) => return Err(format!(
+                                "Expected number for parameter of \
+                                 table {}, got {:?}", table.name, x)),
+                            Err(e) => return Err(format!(
+                                "Malformed parameter of table {}: {}",
+                                table.name, e)),
+                        };
+                        let unit = match self.eval(&table.unit) {
+                            Ok(Value::Number(v)) => v,
+                            Ok(x) => return Err(format!(
+                                "Expected number for unit of \
+                                 table {}, got {:?}", table.name, x)),
+                            Err(e) => return Err(format!(
+                                "Malformed unit of table {}: {}",
+                                table.name, e)),
+                        };
+                        let values = try!(Table::new(
+                            table.points.clone(), table.interpolation
+                        ).map_err(|e| format!("Malformed table {}: {}", table.name, e)));
+                        Ok((table.name.clone(), TabulatedProperty {
+                            param_name: table.param_name.clone(),
+                            param_unit: param_unit,
+                            unit: unit,
+                            table: values,
+                            doc: table.doc.clone(),
+                        }))
+                    }).collect::<Result<BTreeMap<_,_>, _>>();
+                    match (res, tables) {
+                        (Ok(res), Ok(tables)) => {
                             self.substances.insert(name.clone(), Substance {
                                 amount: Number::one(),
                                 properties: Rc::new(Properties {
                                     name: name.clone(),
                                     properties: res,
+                                    tables: tables,
                                 }),
                             });
                             if let &Some(ref symbol) = symbol {
                                 self.substance_symbols.insert(symbol.clone(), name.clone());
                             }
                         },
-                        Err(e) => println!("Substance {} is malformed: {}", name, e),
+                        (Err(e), _) | (_, Err(e)) => diagnostics.push(Diagnostic::error(
+                            location, format!("Substance {} is malformed: {}", name, e))),
                     }
                 },
                 Def::Category(ref desc) => {
                     self.category_names.insert(name.clone(), desc.clone());
                 },
-                Def::Error(ref err) => println!("Def {}: {}", name, err),
+                Def::Logarithmic { ref step, field, ref reference } => {
+                    let step = match self.eval(step) {
+                        Ok(Value::Number(ref num)) if num.dimless() => num.value.clone(),
+                        Ok(_) => {
+                            diagnostics.push(Diagnostic::error(location, format!(
+                                "Step of logarithmic unit {} must be dimensionless", name)));
+                            continue
+                        },
+                        Err(e) => {
+                            diagnostics.push(Diagnostic::error(location, format!(
+                                "Logarithmic unit {} is malformed: {}", name, e)));
+                            continue
+                        },
+                    };
+                    let reference = match *reference {
+                        Some(ref reference) => match self.eval(reference) {
+                            Ok(Value::Number(num)) => Some(num),
+                            Ok(_) => {
+                                diagnostics.push(Diagnostic::error(location, format!(
+                                    "Reference of logarithmic unit {} must be a number", name)));
+                                continue
+                            },
+                            Err(e) => {
+                                diagnostics.push(Diagnostic::error(location, format!(
+                                "Logarithmic unit {} is malformed: {}", name, e)));
+                                continue
+                            },
+                        },
+                        None => None,
+                    };
+                    self.log_units.insert(name.clone(), Rc::new(LogScale {
+                        name: name.clone(),
+                        step: step,
+                        field: field,
+                        reference: reference,
+                    }));
+                },
+                Def::Nonlinear(ref def) => {
+                    self.nonlinear.insert(name.clone(), def.clone());
+                },
+                Def::Table(ref def) => {
+                    self.table_units.insert(name.clone(), def.clone());
+                },
+                Def::Function(ref def) => match self.check_function(&name, def) {
+                    Ok(()) => {
+                        self.functions.insert(name.clone(), def.clone());
+                    },
+                    Err(e) => diagnostics.push(Diagnostic::error(
+                        location, format!("Function {} is malformed: {}", name, e))),
+                },
+                Def::Error(ref err) => diagnostics.push(Diagnostic::error(
+                    location, format!("Def {}: {}", name, err))),
             };
         }
 
         for (name, val) in resolver.docs {
+            let location = locations.get(&name);
             let name = match name {
                 Name::Unit(name) => (*name).clone(),
                 Name::Prefix(name) => (*name).clone(),
@@ -390,11 +549,13 @@ impl Context {
                 Name::Category(name) => (*name).clone(),
             };
             if self.docs.insert(name.clone(), val).is_some() {
-                println!("Doc conflict for {}", name);
+                diagnostics.push(Diagnostic::warning(
+                    location, format!("Doc conflict for {}", name)));
             }
         }
 
         for (name, val) in resolver.categories {
+            let location = locations.get(&name);
             let name = match name {
                 Name::Unit(name) => (*name).clone(),
                 Name::Prefix(name) => (*name).clone(),
@@ -402,8 +563,11 @@ impl Context {
                 Name::Category(name) => (*name).clone(),
             };
             if self.categories.insert(name.clone(), val).is_some() {
-                println!("Category conflict for {}", name);
+                diagnostics.push(Diagnostic::warning(
+                    location, format!("Category conflict for {}", name)));
             }
         }
+
+        diagnostics
     }
 }
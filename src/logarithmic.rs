@@ -0,0 +1,226 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use context::Context;
+use number::Number;
+use num::Num;
+use value::Show;
+use ast::Digits;
+use std::rc::Rc;
+
+/// A logarithmic scale, such as dB, Np, dBm or pH. Steps are measured
+/// in decibels of power, so a bel is 10 and a neper is 20/ln(10).
+#[derive(Debug, Clone)]
+pub struct LogScale {
+    pub name: String,
+    pub step: Num,
+    /// Whether the reference is a field (root-power) quantity like a
+    /// voltage or a pressure, which goes with the square root of power.
+    pub field: bool,
+    /// The level corresponding to 0 on this scale. Relative scales like
+    /// dB describe a ratio and have none.
+    pub reference: Option<Number>,
+}
+
+/// A level on a logarithmic scale, like `30 dBm`.
+#[derive(Debug, Clone)]
+pub struct Logarithmic {
+    pub level: Num,
+    pub scale: Rc<LogScale>,
+}
+
+impl LogScale {
+    fn relative(&self) -> Rc<LogScale> {
+        Rc::new(LogScale {
+            name: if self.step == Num::one() {
+                "dB".to_owned()
+            } else {
+                self.name.clone()
+            },
+            step: self.step.clone(),
+            field: false,
+            reference: None,
+        })
+    }
+}
+
+impl Logarithmic {
+    pub fn new(level: Num, scale: Rc<LogScale>) -> Logarithmic {
+        Logarithmic {
+            level: level,
+            scale: scale,
+        }
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.scale.reference.is_some()
+    }
+
+    fn decibels(&self) -> f64 {
+        self.level.to_f64() * self.scale.step.to_f64()
+    }
+
+    fn with_decibels(db: f64, scale: Rc<LogScale>) -> Logarithmic {
+        Logarithmic::new(Num::Float(db / scale.step.to_f64()), scale)
+    }
+
+    /// Returns the linear value of the level: the reference quantity
+    /// scaled accordingly for absolute levels, and the power ratio for
+    /// relative ones.
+    pub fn to_linear(&self) -> Number {
+        match self.scale.reference {
+            Some(ref reference) => {
+                let power = if self.scale.field { 20.0 } else { 10.0 };
+                let ratio = Number::new(Num::Float(10f64.powf(self.decibels() / power)));
+                (reference * &ratio).expect("Bug: Mul should not fail")
+            },
+            None => Number::new(Num::Float(10f64.powf(self.decibels() / 10.0))),
+        }
+    }
+
+    /// Expresses a linear value as a level on the given scale.
+    pub fn from_linear(
+        value: &Number, scale: Rc<LogScale>, context: &Context
+    ) -> Result<Logarithmic, String> {
+        let ratio = match scale.reference {
+            Some(ref reference) => {
+                if value.unit != reference.unit {
+                    return Err(format!(
+                        "Conformance error: <{}> is not a level of {}, \
+                         which is relative to {}",
+                        value.show(context), scale.name,
+                        reference.show(context)
+                    ))
+                }
+                (value / reference).expect("Reference of logarithmic unit is zero")
+            },
+            None => {
+                if !value.dimless() {
+                    return Err(format!(
+                        "Conformance error: {} is relative and can only express \
+                         dimensionless ratios",
+                        scale.name
+                    ))
+                }
+                value.clone()
+            },
+        };
+        let ratio = ratio.value.to_f64();
+        if ratio <= 0.0 {
+            return Err(format!(
+                "Logarithm of a non-positive value is undefined: {}", ratio
+            ))
+        }
+        let power = if scale.field { 20.0 } else { 10.0 };
+        Ok(Logarithmic::with_decibels(power * ratio.log10(), scale))
+    }
+
+    /// Converts the level to another logarithmic scale. Both scales
+    /// have to be either relative or absolute.
+    pub fn convert(
+        &self, scale: Rc<LogScale>, context: &Context
+    ) -> Result<Logarithmic, String> {
+        match (self.scale.reference.is_some(), scale.reference.is_some()) {
+            (false, false) => Ok(Logarithmic::with_decibels(self.decibels(), scale)),
+            (true, true) => Logarithmic::from_linear(&self.to_linear(), scale, context),
+            (true, false) => Err(format!(
+                "Cannot convert the absolute level {} to the relative scale {}",
+                self, scale.name
+            )),
+            (false, true) => Err(format!(
+                "Cannot convert the relative level {} to the absolute scale {}",
+                self, scale.name
+            )),
+        }
+    }
+
+    /// Returns the level measured in steps of the given size, keeping
+    /// it exact when the step doesn't change.
+    fn level_in(&self, step: &Num) -> Num {
+        if *step == self.scale.step {
+            self.level.clone()
+        } else {
+            Num::Float(self.decibels() / step.to_f64())
+        }
+    }
+
+    /// Adds two levels. Relative levels are gains, which can be added
+    /// to each other and to absolute levels. Two absolute levels can't
+    /// be added without deciding whether the signals are coherent, so
+    /// that is an error.
+    pub fn add(&self, other: &Logarithmic) -> Result<Logarithmic, String> {
+        match (self.is_absolute(), other.is_absolute()) {
+            (_, false) => Ok(Logarithmic::new(
+                &self.level + &other.level_in(&self.scale.step),
+                self.scale.clone())),
+            (false, true) => Ok(Logarithmic::new(
+                &self.level_in(&other.scale.step) + &other.level,
+                other.scale.clone())),
+            (true, true) => Err(format!(
+                "Adding the absolute levels {} and {} is ambiguous, \
+                 convert them to linear units first",
+                self, other
+            )),
+        }
+    }
+
+    /// Subtracts two levels. The difference of two absolute levels is
+    /// the relative level between them.
+    pub fn sub(&self, other: &Logarithmic) -> Result<Logarithmic, String> {
+        match (self.is_absolute(), other.is_absolute()) {
+            (_, false) => Ok(Logarithmic::new(
+                &self.level - &other.level_in(&self.scale.step),
+                self.scale.clone())),
+            (false, true) => Err(format!(
+                "Cannot subtract the absolute level {} from the relative level {}",
+                other, self
+            )),
+            (true, true) if Rc::ptr_eq(&self.scale, &other.scale) =>
+                Ok(Logarithmic::new(&self.level - &other.level, self.scale.relative())),
+            (true, true) => {
+                let ratio = match &self.to_linear() / &other.to_linear() {
+                    Some(ref ratio) if ratio.dimless() => ratio.value.to_f64(),
+                    _ => return Err(format!(
+                        "Cannot subtract {} from {}, they are levels of \
+                         different quantities",
+                        other, self
+                    ))
+                };
+                let power = if self.scale.field { 20.0 } else { 10.0 };
+                Ok(Logarithmic::with_decibels(power * ratio.log10(), self.scale.relative()))
+            },
+        }
+    }
+
+    /// Multiplies the level by a dimensionless number, which is how
+    /// `30 dBm` is formed from `30` and `dBm`.
+    pub fn mul(&self, other: &Number) -> Result<Logarithmic, String> {
+        if !other.dimless() {
+            return Err(format!(
+                "Logarithmic levels can only be multiplied by dimensionless numbers"
+            ))
+        }
+        Ok(Logarithmic::new(&self.level * &other.value, self.scale.clone()))
+    }
+
+    pub fn to_string(&self, base: u8, digits: Digits) -> String {
+        let (exact, approx) = ::number::to_string(&self.level, base, digits);
+        match (exact, approx) {
+            (true, value) => format!("{} {}", value, self.scale.name),
+            (false, value) => format!("approx. {} {}", value, self.scale.name),
+        }
+    }
+}
+
+impl ::std::fmt::Display for Logarithmic {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "{}", self.to_string(10, Digits::Default))
+    }
+}
+
+impl Show for Logarithmic {
+    fn show(&self, _context: &Context) -> String {
+        format!("{}", self)
+    }
+}
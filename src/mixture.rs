@@ -0,0 +1,218 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Mixtures of substances, like `100 ml water + 5 g NaCl`, and the
+//! concentrations of their solutes.
+
+use std::collections::BTreeMap;
+use context::Context;
+use number::{Number, Dim};
+use num::Num;
+use substance::Substance;
+use value::Show;
+use reply::{PropertyReply, SubstanceReply};
+
+#[derive(Debug, Clone)]
+pub struct Mixture {
+    /// The substances with the amounts they were added in. The first
+    /// one is the solvent, the others are solutes.
+    pub components: Vec<Substance>,
+}
+
+/// The properties that are computed for each solute.
+const CONCENTRATIONS: &'static [&'static str] = &[
+    "molarity", "molality", "mass_fraction", "mole_fraction",
+];
+
+fn unit(name: &str, power: i64) -> BTreeMap<Dim, i64> {
+    let mut unit = BTreeMap::new();
+    unit.insert(Dim::new(name), power);
+    unit
+}
+
+fn mul(left: &Number, right: &Number) -> Number {
+    (left * right).expect("Multiplication of numbers should not fail")
+}
+
+fn div(left: &Number, right: &Number) -> Result<Number, String> {
+    (left / right).ok_or_else(|| format!("Division by zero"))
+}
+
+fn sum(numbers: &[Number]) -> Number {
+    numbers.iter().skip(1).fold(numbers[0].clone(), |acc, x| {
+        (&acc + x).expect("Bug: summands have the same unit")
+    })
+}
+
+/// The value of a property for a unit amount of the substance, like
+/// its density or molar mass.
+fn property(sub: &Substance, name: &str) -> Option<Number> {
+    Substance {
+        amount: Number::one(),
+        properties: sub.properties.clone(),
+    }.get(name).ok()
+}
+
+fn mass(sub: &Substance) -> Result<Number, String> {
+    let amount = &sub.amount;
+    let known = if amount.unit == unit("kg", 1) {
+        Some(amount.clone())
+    } else if amount.unit == unit("mol", 1) {
+        property(sub, "molar_mass").map(|x| mul(amount, &x))
+    } else if amount.unit == unit("m", 3) {
+        property(sub, "density").map(|x| mul(amount, &x))
+    } else {
+        return Err(format!(
+            "The amount of {} in a mixture must be a mass, volume or amount \
+             of substance, got {}",
+            sub.properties.name, amount.to_parts_simple().format("n u")
+        ))
+    };
+    known.ok_or_else(|| format!(
+        "Cannot find the mass of {} {}: it needs a {}",
+        amount.to_parts_simple().format("n u"),
+        sub.properties.name,
+        if amount.unit == unit("mol", 1) { "molar_mass" } else { "density" }
+    ))
+}
+
+fn volume(sub: &Substance) -> Option<Number> {
+    if sub.amount.unit == unit("m", 3) {
+        return Some(sub.amount.clone())
+    }
+    match (mass(sub), property(sub, "density")) {
+        (Ok(mass), Some(density)) => div(&mass, &density).ok(),
+        _ => None
+    }
+}
+
+fn moles(sub: &Substance) -> Result<Number, String> {
+    if sub.amount.unit == unit("mol", 1) {
+        return Ok(sub.amount.clone())
+    }
+    let molar_mass = try!(property(sub, "molar_mass").ok_or_else(|| format!(
+        "{} has no molar_mass", sub.properties.name
+    )));
+    div(&try!(mass(sub)), &molar_mass)
+}
+
+impl Mixture {
+    /// Creates a mixture, checking that the mass of every component is
+    /// known.
+    pub fn new(components: Vec<Substance>) -> Result<Mixture, String> {
+        for sub in &components {
+            try!(mass(sub));
+        }
+        Ok(Mixture {
+            components: components,
+        })
+    }
+
+    pub fn name(&self, context: &Context) -> String {
+        self.components.iter().map(|sub| format!(
+            "{} {}", sub.amount.to_parts(context).format("n u"), sub.properties.name
+        )).collect::<Vec<_>>().join(" + ")
+    }
+
+    pub fn mass(&self) -> Number {
+        sum(&self.components.iter().map(|x| {
+            mass(x).expect("Checked in Mixture::new")
+        }).collect::<Vec<_>>())
+    }
+
+    /// The volume of the mixture, assuming ideal mixing of the
+    /// components that have a volume, and that the others, like salts,
+    /// dissolve without changing it.
+    pub fn volume(&self) -> Result<Number, String> {
+        let volumes = self.components.iter().filter_map(volume).collect::<Vec<_>>();
+        if volumes.len() == 0 {
+            return Err(format!(
+                "Cannot find the volume of the mixture: {} has no density",
+                self.components[0].properties.name
+            ))
+        }
+        Ok(sum(&volumes))
+    }
+
+    /// The only solute, for properties like `molarity of` that refer
+    /// to one.
+    fn solute(&self) -> Result<&Substance, String> {
+        if self.components.len() == 2 {
+            Ok(&self.components[1])
+        } else {
+            Err(format!(
+                "The mixture has more than one solute, the concentration of \
+                 each is listed when it is shown"
+            ))
+        }
+    }
+
+    /// The concentration of a solute, as molarity, molality,
+    /// mass_fraction, mole_fraction or ppm.
+    pub fn concentration(&self, name: &str, solute: &Substance) -> Result<Number, String> {
+        match name {
+            "molarity" => div(&try!(moles(solute)), &try!(self.volume())),
+            "molality" => div(&try!(moles(solute)), &try!(mass(&self.components[0]))),
+            "mass_fraction" => div(&try!(mass(solute)), &self.mass()),
+            "ppm" => {
+                let fraction = try!(div(&try!(mass(solute)), &self.mass()));
+                Ok(mul(&fraction, &Number::new(Num::from(1000000))))
+            },
+            "mole_fraction" => {
+                let total = try!(self.components.iter().map(moles)
+                                 .collect::<Result<Vec<_>, _>>());
+                div(&try!(moles(solute)), &sum(&total))
+            },
+            _ => Err(format!("No such concentration {}", name))
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Number, String> {
+        match name {
+            "mass" => Ok(self.mass()),
+            "volume" => self.volume(),
+            "density" => div(&self.mass(), &try!(self.volume())),
+            _ if name == "ppm" || CONCENTRATIONS.contains(&name) =>
+                self.concentration(name, try!(self.solute())),
+            _ => Err(format!("No such property {} of the mixture", name))
+        }
+    }
+
+    pub fn to_reply(&self, context: &Context) -> Result<SubstanceReply, String> {
+        let reply = |name: String, value: Number| PropertyReply {
+            name: name,
+            value: value.to_parts(context),
+            doc: None,
+        };
+        let mass = self.mass();
+        let mut properties = vec![reply("mass".to_owned(), mass.clone())];
+        if let Ok(volume) = self.volume() {
+            properties.push(reply("volume".to_owned(), volume.clone()));
+            properties.push(reply("density".to_owned(), try!(div(&mass, &volume))));
+        }
+        for solute in self.components.iter().skip(1) {
+            for name in CONCENTRATIONS {
+                // concentrations that need a missing density or
+                // molar_mass are left out
+                if let Ok(value) = self.concentration(name, solute) {
+                    properties.push(reply(
+                        format!("{} of {}", name, solute.properties.name), value
+                    ));
+                }
+            }
+        }
+        Ok(SubstanceReply {
+            name: self.name(context),
+            doc: None,
+            amount: mass.to_parts(context),
+            properties: properties,
+        })
+    }
+}
+
+impl Show for Mixture {
+    fn show(&self, context: &Context) -> String {
+        self.name(context)
+    }
+}
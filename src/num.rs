@@ -119,6 +119,19 @@ impl Num {
     pub fn to_f64(&self) -> f64 {
         self.into()
     }
+
+    /// Converts to a rational, exactly. Unlike `to_rational`, floats
+    /// are not approximated by a nearby fraction.
+    pub fn to_mpq(&self) -> Mpq {
+        match *self {
+            Num::Mpq(ref mpq) => mpq.clone(),
+            Num::Float(f) => {
+                let mut res = Mpq::zero();
+                res.set_d(f);
+                res
+            },
+        }
+    }
 }
 
 impl From<Mpq> for Num {
@@ -13,6 +13,7 @@ use std::borrow::Borrow;
 use context::Context;
 use num::*;
 use ast::Digits;
+use system::UnitSystem;
 
 /// Alias for the primary representation of dimensionality.
 pub type Unit = BTreeMap<Dim, i64>;
@@ -71,7 +72,36 @@ pub fn pow(left: &Num, exp: i32) -> Num {
     }
 }
 
+/// Finds the SI prefix that brings a value of a unit to the power
+/// `exp` between 1 and 1000, and returns it with the scaled value.
+pub fn si_prefix<'a>(value: &Num, exp: i64, context: &'a Context) -> Option<(&'a str, Num)> {
+    let prefixes = [
+        "milli", "micro", "nano", "pico", "femto", "atto", "zepto", "yocto",
+        "kilo", "mega", "giga", "tera", "peta", "exa", "zetta", "yotta"];
+    let abs = value.abs();
+    for &(ref p, ref v) in &context.prefixes {
+        if !prefixes.contains(&&**p) {
+            continue;
+        }
+        if { abs >= pow(&v.value, exp as i32) &&
+             abs < pow(&(&v.value * &Num::from(1000)), exp as i32) } {
+            return Some((&**p, value / &pow(&v.value, exp as i32)))
+        }
+    }
+    None
+}
+
+/// The number of significant digits numbers are shown with by default.
+pub const DEFAULT_PRECISION: u64 = 7;
+
 pub fn to_string(rational: &Num, base: u8, digits: Digits) -> (bool, String) {
+    to_string_with_precision(rational, base, digits, DEFAULT_PRECISION)
+}
+
+/// Like `to_string()`, but with `precision` significant digits
+/// instead of the default when `digits` is `Digits::Default`.
+pub fn to_string_with_precision(rational: &Num, base: u8, digits: Digits, precision: u64)
+                                -> (bool, String) {
     use std::char::from_digit;
 
     let sign = *rational < Num::zero();
@@ -109,7 +139,7 @@ pub fn to_string(rational: &Num, base: u8, digits: Digits) -> (bool, String) {
         };
         let placed_ints = n >= intdigits;
         let ndigits = match digits {
-            Digits::Default | Digits::FullInt => 6,
+            Digits::Default | Digits::FullInt => precision as i32 - 1,
             Digits::Digits(n) => intdigits as i32 + n as i32
         };
         let bail =
@@ -165,6 +195,12 @@ pub struct NumberParts {
     /// Present if the number can't be exactly concisely represented
     /// in decimal or scientific notation.
     pub approx_value: Option<String>,
+    /// Present if the number has an uncertainty, which is then
+    /// rounded along with `approx_value`.
+    pub uncertainty: Option<String>,
+    /// The value with its uncertainty in concise notation, as in
+    /// `5.00(20)`, if it has one.
+    pub concise_value: Option<String>,
     /// Numerator factor by which the value is multiplied, if not one.
     pub factor: Option<String>,
     /// Divisor factor, if not one.
@@ -184,6 +220,8 @@ impl Default for NumberParts {
         NumberParts {
             exact_value: None,
             approx_value: None,
+            uncertainty: None,
+            concise_value: None,
             factor: None,
             divfactor: None,
             raw_unit: None,
@@ -199,7 +237,9 @@ impl NumberParts {
     ///
     /// - `a`: Approximate numerical value, if exists.
     /// - `e`: Exact numerical value, if exists.
-    /// - `n`: Exact and approximate values.
+    /// - `n`: Exact and approximate values, or the value and its
+    ///   uncertainty.
+    /// - `c`: Value with uncertainty in concise notation, if exists.
     /// - `u`: Unit.
     /// - `q`: Quantity, if exists.
     /// - `w`: Quantity in parentheses, if exists.
@@ -228,6 +268,16 @@ impl NumberParts {
                 } else {
                     continue
                 },
+                'n' if self.uncertainty.is_some() => write!(
+                    out, "{} ± {}",
+                    self.approx_value.as_ref().map(|x| &**x).unwrap_or("?"),
+                    self.uncertainty.as_ref().unwrap()
+                ).unwrap(),
+                'c' => if let Some(c) = self.concise_value.as_ref() {
+                    write!(out, "{}", c).unwrap();
+                } else {
+                    continue
+                },
                 'n' => match (self.exact_value.as_ref(), self.approx_value.as_ref()) {
                     (Some(ex), Some(ap)) => write!(out, "{}, approx. {}", ex, ap).unwrap(),
                     (Some(ex), None) => write!(out, "{}", ex).unwrap(),
@@ -489,12 +539,17 @@ impl Number {
     }
 
     pub fn numeric_value(&self, base: u8, digits: Digits) -> (Option<String>, Option<String>) {
+        self.numeric_value_with_precision(base, digits, DEFAULT_PRECISION)
+    }
+
+    pub fn numeric_value_with_precision(&self, base: u8, digits: Digits, precision: u64)
+                                        -> (Option<String>, Option<String>) {
         match self.value {
             Num::Mpq(ref mpq) => {
                 let num = mpq.get_num();
                 let den = mpq.get_den();
 
-                match to_string(&self.value, base, digits) {
+                match to_string_with_precision(&self.value, base, digits, precision) {
                     (true, v) => (Some(v), None),
                     (false, v) => if {den > Mpz::from(1_000) ||
                                       num > Mpz::from(1_000_000u64)} {
@@ -505,7 +560,7 @@ impl Number {
                 }
             },
             Num::Float(_f) => {
-                (None, Some(to_string(&self.value, base, digits).1))
+                (None, Some(to_string_with_precision(&self.value, base, digits, precision).1))
             },
         }
     }
@@ -523,13 +578,17 @@ impl Number {
     /// Convert the units of the number from base units to display
     /// units, and possibly apply SI prefixes.
     pub fn prettify(&self, context: &Context) -> Number {
+        self.prettify_in(context, context.unit_system)
+    }
+
+    /// Like `prettify()`, but with the display units of a given unit
+    /// system instead of the context's.
+    pub fn prettify_in(&self, context: &Context, system: UnitSystem) -> Number {
+        if let Some(res) = system.convert(self, context) {
+            return res
+        }
         let unit = self.pretty_unit(context);
         if unit.len() == 1 {
-            use std::collections::HashSet;
-            let prefixes = [
-                "milli", "micro", "nano", "pico", "femto", "atto", "zepto", "yocto",
-                "kilo", "mega", "giga", "tera", "peta", "exa", "zetta", "yotta"]
-                .iter().cloned().collect::<HashSet<&'static str>>();
             let orig = unit.iter().next().unwrap();
             // kg special case
             let (val, orig) = if &**(orig.0).0 == "kg" || &**(orig.0).0 == "kilogram" {
@@ -538,27 +597,18 @@ impl Number {
             } else {
                 (self.value.clone(), (orig.0.clone(), orig.1))
             };
-            for &(ref p, ref v) in &context.prefixes {
-                if !prefixes.contains(&**p) {
-                    continue;
-                }
-                let abs = val.abs();
-                if { abs >= pow(&v.value, (*orig.1) as i32) &&
-                     abs < pow(&(&v.value * &Num::from(1000)),
-                               (*orig.1) as i32) } {
-                    let res = &val / &pow(&v.value, (*orig.1) as i32);
-                    // tonne special case
-                    let unit = if &**(orig.0).0 == "gram" && p == "mega" {
-                        format!("tonne")
-                    } else {
-                        format!("{}{}", p, orig.0)
-                    };
-                    let mut map = BTreeMap::new();
-                    map.insert(Dim::new(&*unit), *orig.1);
-                    return Number {
-                        value: res,
-                        unit: map,
-                    }
+            if let Some((p, res)) = si_prefix(&val, *orig.1, context) {
+                // tonne special case
+                let unit = if &**(orig.0).0 == "gram" && p == "mega" {
+                    format!("tonne")
+                } else {
+                    format!("{}{}", p, orig.0)
+                };
+                let mut map = BTreeMap::new();
+                map.insert(Dim::new(&*unit), *orig.1);
+                return Number {
+                    value: res,
+                    unit: map,
                 }
             }
             let mut map = BTreeMap::new();
@@ -571,13 +621,61 @@ impl Number {
             Number {
                 value: self.value.clone(),
                 unit: unit,
-            }
+            }.with_readable_prefix(context)
+        }
+    }
+
+    /// Applies an SI prefix to the first unit with a positive power,
+    /// if that makes the number at least a few digits shorter, as in
+    /// `1.2 megawatt / meter^2` instead of `1200000 watt / meter^2`.
+    pub fn with_readable_prefix(&self, context: &Context) -> Number {
+        let (lead, exp) = match self.unit.iter().find(|&(_, &exp)| exp > 0) {
+            Some((dim, &exp)) => (dim.clone(), exp),
+            None => return self.clone()
+        };
+        // kg special case
+        let (val, name) = if &**lead.0 == "kg" || &**lead.0 == "kilogram" {
+            (&self.value * &pow(&Num::from(1000), exp as i32), "gram".to_owned())
+        } else {
+            (self.value.clone(), (*lead.0).clone())
+        };
+        let (prefix, res) = match si_prefix(&val, exp, context) {
+            Some(x) => x,
+            None => return self.clone()
+        };
+        let len = |x: &Num| to_string_with_precision(
+            x, 10, Digits::Default, context.significant_digits).1.len();
+        if len(&res) + 4 > len(&self.value) {
+            return self.clone()
+        }
+        // tonne special case
+        let name = if name == "gram" && prefix == "mega" {
+            format!("tonne")
+        } else {
+            format!("{}{}", prefix, name)
+        };
+        let mut unit = self.unit.clone();
+        unit.remove(&lead);
+        unit.insert(Dim::new(&*name), exp);
+        Number {
+            value: res,
+            unit: unit,
         }
     }
 
     pub fn to_parts(&self, context: &Context) -> NumberParts {
-        let value = self.prettify(context);
-        let (exact, approx) = value.numeric_value(10, Digits::Default);
+        self.to_parts_in(context, context.unit_system)
+    }
+
+    /// Like `to_parts()`, but shown in the units of a given unit
+    /// system.
+    pub fn to_parts_in(&self, context: &Context, system: UnitSystem) -> NumberParts {
+        let value = self.prettify_in(context, system);
+        let (exact, approx) = value.numeric_value_with_precision(
+            10, Digits::Default, context.significant_digits);
+        // brief output leaves out fractions that are only
+        // approximate, and the quantity
+        let exact = if context.brief_output && approx.is_some() { None } else { exact };
 
         let quantity = context.quantities.get(&self.unit).cloned().or_else(|| {
             if self.unit.len() == 1 {
@@ -591,7 +689,7 @@ impl Number {
             } else {
                 None
             }
-        });
+        }).and_then(|x| if context.brief_output { None } else { Some(x) });
 
         NumberParts {
             exact_value: exact,
@@ -637,12 +735,10 @@ impl Number {
         String::from_utf8(out).unwrap()
     }
 
+    /// The simplest way to write the unit, as ranked by
+    /// `best_units()`.
     fn pretty_unit(&self, context: &Context) -> Unit {
-        let pretty = ::factorize::fast_decompose(self, &context.reverse);
-        let pretty = pretty.into_iter()
-            .map(|(k, p)| (context.canonicalizations.get(&*k.0).map(|x| Dim::new(x)).unwrap_or(k), p))
-            .collect::<BTreeMap<_, _>>();
-        pretty
+        ::factorize::unit_candidates(self, context, false).swap_remove(0).unit
     }
 
     pub fn complexity_score(&self) -> i64 {
@@ -0,0 +1,584 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Elementary functions computed to any number of digits, for
+//! conversions like `-> digits 50`. The functions work on fixed point
+//! integers scaled by a power of ten, and carry a few guard digits
+//! beyond the requested ones, so that the rounding errors of the
+//! intermediate steps don't reach the digits that are shown.
+//!
+//! The number of digits counts decimal places for results of at least
+//! one, and significant digits for smaller results, which matches how
+//! `-> digits` output is printed.
+
+use gmp::mpq::Mpq;
+use gmp::mpz::Mpz;
+use std::cmp;
+use std::f64::consts::{LN_2, LN_10};
+
+const GUARD: u32 = 10;
+
+/// Fixed point numbers with a given number of decimal places.
+struct Scale {
+    one: Mpz,
+}
+
+impl Scale {
+    fn new(places: u32) -> Scale {
+        Scale {
+            one: Mpz::from(10).pow(places),
+        }
+    }
+
+    fn fixed(&self, x: &Mpq) -> Mpz {
+        &(&x.get_num() * &self.one) / &x.get_den()
+    }
+
+    fn rational(&self, x: &Mpz) -> Mpq {
+        Mpq::ratio(x, &self.one)
+    }
+
+    fn mul(&self, a: &Mpz, b: &Mpz) -> Mpz {
+        &(a * b) / &self.one
+    }
+
+    fn div(&self, a: &Mpz, b: &Mpz) -> Mpz {
+        &(a * &self.one) / b
+    }
+}
+
+/// The decimal exponent of a float estimate, or zero if there is none.
+fn exponent(x: f64) -> i64 {
+    if x.is_finite() && x != 0.0 {
+        x.abs().log10().floor() as i64
+    } else {
+        0
+    }
+}
+
+/// The decimal exponent of a rational, give or take one.
+fn magnitude(x: &Mpq) -> i64 {
+    if *x == Mpq::zero() {
+        0
+    } else {
+        x.get_num().abs().size_in_base(10) as i64 - x.get_den().size_in_base(10) as i64
+    }
+}
+
+/// How many decimal places to work with for a result with the given
+/// decimal exponent.
+fn places(digits: u32, exponent: i64) -> u32 {
+    digits + GUARD + cmp::max(0, -exponent) as u32
+}
+
+fn digits_of(x: i64) -> u32 {
+    x.abs().to_string().len() as u32
+}
+
+fn to_f64(x: &Mpq) -> f64 {
+    x.clone().into()
+}
+
+fn int(x: &Mpz) -> Mpq {
+    Mpq::ratio(x, &Mpz::one())
+}
+
+fn round(x: &Mpq, places: u32) -> Mpq {
+    let scale = Scale::new(places);
+    scale.rational(&scale.fixed(x))
+}
+
+fn powi(x: &Mpq, exp: i64) -> Mpq {
+    let num = x.get_num().pow(exp.abs() as u32);
+    let den = x.get_den().pow(exp.abs() as u32);
+    if exp < 0 {
+        Mpq::ratio(&den, &num)
+    } else {
+        Mpq::ratio(&num, &den)
+    }
+}
+
+fn floor_div(a: &Mpz, b: &Mpz) -> Mpz {
+    let res = a / b;
+    if &(&res * b) > a {
+        &res - &Mpz::one()
+    } else {
+        res
+    }
+}
+
+/// The integer part of the kth root of a nonnegative integer.
+fn iroot(n: &Mpz, k: u32) -> Mpz {
+    if *n == Mpz::zero() {
+        return Mpz::zero()
+    }
+    // Newton's method converges from above, so start with a power of
+    // ten at least as large as the root
+    let size = n.size_in_base(10) as u32;
+    let mut x = Mpz::from(10).pow((size + k - 1) / k);
+    let pow = k - 1;
+    let k1 = Mpz::from(pow as u64);
+    let k = Mpz::from(k as u64);
+    loop {
+        let next = &(&(&k1 * &x) + &(n / &x.pow(pow))) / &k;
+        if next >= x {
+            return x
+        }
+        x = next;
+    }
+}
+
+fn sqrt_fixed(scale: &Scale, x: &Mpz) -> Mpz {
+    iroot(&(x * &scale.one), 2)
+}
+
+/// Sums the series for atan(1/n), or atanh(1/n) if not alternating.
+fn arctan_inv(scale: &Scale, n: u64, alternating: bool) -> Mpz {
+    let n = Mpz::from(n);
+    let n2 = &n * &n;
+    let mut power = &scale.one / &n;
+    let mut sum = power.clone();
+    let mut k = 1u64;
+    loop {
+        power = &power / &n2;
+        let term = &power / &Mpz::from(2 * k + 1);
+        if term == Mpz::zero() {
+            return sum
+        }
+        sum = if alternating && k % 2 == 1 {
+            &sum - &term
+        } else {
+            &sum + &term
+        };
+        k += 1;
+    }
+}
+
+/// Sums the series for atan(x), or atanh(x) if not alternating. Only
+/// converges quickly for small x.
+fn arctan_series(scale: &Scale, x: &Mpz, alternating: bool) -> Mpz {
+    let x2 = scale.mul(x, x);
+    let mut power = x.clone();
+    let mut sum = x.clone();
+    let mut k = 1u64;
+    loop {
+        power = scale.mul(&power, &x2);
+        let term = &power / &Mpz::from(2 * k + 1);
+        if term == Mpz::zero() {
+            return sum
+        }
+        sum = if alternating && k % 2 == 1 {
+            &sum - &term
+        } else {
+            &sum + &term
+        };
+        k += 1;
+    }
+}
+
+fn pi_fixed(scale: &Scale) -> Mpz {
+    // Machin's formula
+    &(&arctan_inv(scale, 5, true) * &Mpz::from(16)) -
+        &(&arctan_inv(scale, 239, true) * &Mpz::from(4))
+}
+
+fn ln2_fixed(scale: &Scale) -> Mpz {
+    &arctan_inv(scale, 3, false) * &Mpz::from(2)
+}
+
+/// Computes exp(x) for an x of at most about one.
+fn exp_fixed(scale: &Scale, x: &Mpz) -> Mpz {
+    // exp(x) = exp(x / 256)^256, where the series for the smaller
+    // argument converges much faster
+    let x = x / &Mpz::from(256);
+    let mut term = scale.one.clone();
+    let mut sum = scale.one.clone();
+    let mut n = 1u64;
+    loop {
+        term = &scale.mul(&term, &x) / &Mpz::from(n);
+        if term == Mpz::zero() {
+            break
+        }
+        sum = &sum + &term;
+        n += 1;
+    }
+    for _ in 0..8 {
+        sum = scale.mul(&sum, &sum);
+    }
+    sum
+}
+
+/// Sums the series for sin(x), or cos(x) if `cos` is set. Only
+/// converges quickly for x between -pi and pi.
+fn sin_series(scale: &Scale, x: &Mpz, cos: bool) -> Mpz {
+    let x2 = scale.mul(x, x);
+    let mut term = if cos { scale.one.clone() } else { x.clone() };
+    let mut sum = term.clone();
+    let mut n = if cos { 0u64 } else { 1u64 };
+    loop {
+        term = &(&Mpz::zero() - &scale.mul(&term, &x2)) / &Mpz::from((n + 1) * (n + 2));
+        if term == Mpz::zero() {
+            return sum
+        }
+        sum = &sum + &term;
+        n += 2;
+    }
+}
+
+/// Reduces an angle to between -pi and pi. The scale needs as many
+/// extra places as the angle has digits before the decimal point.
+fn reduce_angle(scale: &Scale, x: &Mpq) -> Mpz {
+    let x = scale.fixed(x);
+    let two_pi = &pi_fixed(scale) * &Mpz::from(2);
+    let turns = floor_div(&(&(&x * &Mpz::from(2)) + &two_pi), &(&two_pi * &Mpz::from(2)));
+    &x - &(&two_pi * &turns)
+}
+
+fn atan_fixed(scale: &Scale, x: &Mpz) -> Mpz {
+    if *x < Mpz::zero() {
+        return &Mpz::zero() - &atan_fixed(scale, &(&Mpz::zero() - x))
+    }
+    if *x > scale.one {
+        let half_pi = &pi_fixed(scale) / &Mpz::from(2);
+        return &half_pi - &atan_fixed(scale, &scale.div(&scale.one, x))
+    }
+    // atan(x) = 2 atan(x / (1 + sqrt(1 + x^2))), applied three times
+    // so that the series converges quickly
+    let mut x = x.clone();
+    for _ in 0..3 {
+        let root = sqrt_fixed(scale, &(&scale.one + &scale.mul(&x, &x)));
+        x = scale.div(&x, &(&scale.one + &root));
+    }
+    &arctan_series(scale, &x, true) * &Mpz::from(8)
+}
+
+/// Computes asin(x) for x between -1 and 1.
+fn asin_fixed(scale: &Scale, x: &Mpq) -> Mpz {
+    let one = Mpq::one();
+    if x.abs() == one {
+        let half_pi = &pi_fixed(scale) / &Mpz::from(2);
+        return if *x < Mpq::zero() {
+            &Mpz::zero() - &half_pi
+        } else {
+            half_pi
+        }
+    }
+    let cos = sqrt_fixed(scale, &scale.fixed(&(&one - &(x * x))));
+    atan_fixed(scale, &scale.div(&scale.fixed(x), &cos))
+}
+
+pub fn pi(digits: u32) -> Mpq {
+    let scale = Scale::new(digits + GUARD);
+    scale.rational(&pi_fixed(&scale))
+}
+
+/// The kth root of a nonnegative number, if it is rational.
+pub fn exact_root(x: &Mpq, k: u32) -> Option<Mpq> {
+    let num = x.get_num();
+    let den = x.get_den();
+    let num_root = iroot(&num, k);
+    let den_root = iroot(&den, k);
+    if num_root.pow(k) == num && den_root.pow(k) == den {
+        Some(Mpq::ratio(&num_root, &den_root))
+    } else {
+        None
+    }
+}
+
+/// Computes the kth root of a nonnegative number, exactly if the
+/// result is rational.
+pub fn root(x: &Mpq, k: u32, digits: u32) -> Mpq {
+    if let Some(res) = exact_root(x, k) {
+        return res
+    }
+    let num = x.get_num();
+    let den = x.get_den();
+    let scale = Scale::new(places(digits, magnitude(x) / k as i64));
+    // root(num / den) = root(num den^(k - 1)) / den
+    let radicand = &(&num * &den.pow(k - 1)) * &scale.one.pow(k);
+    Mpq::ratio(&iroot(&radicand, k), &(&den * &scale.one))
+}
+
+pub fn exp(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    let approx = to_f64(x);
+    if approx.abs() > 1e5 {
+        return Err(format!("Exponent is too large"))
+    }
+    // exp(x) = 2^k exp(x - k ln 2)
+    let k = (approx / LN_2).round() as i64;
+    // large results need more places because of the multiplication by
+    // 2^k, and small ones because of the division
+    let exponent = (approx / LN_10).floor() as i64;
+    let scale = Scale::new(places(digits, 0) + exponent.abs() as u32 + digits_of(k));
+    let reduced = &scale.fixed(x) - &(&ln2_fixed(&scale) * &Mpz::from(k));
+    let res = scale.rational(&exp_fixed(&scale, &reduced));
+    let two = int(&Mpz::from(2).pow(k.abs() as u32));
+    Ok(if k < 0 { &res / &two } else { &res * &two })
+}
+
+pub fn ln(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    let one = Mpq::one();
+    if *x <= Mpq::zero() {
+        return Err(format!("Logarithm of a nonpositive number is undefined"))
+    }
+    if *x == one {
+        return Ok(Mpq::zero())
+    }
+    // results close to zero need more places
+    let near_one = x - &one;
+    let exponent = if near_one.abs() < Mpq::ratio(&Mpz::one(), &Mpz::from(2)) {
+        magnitude(&near_one)
+    } else {
+        exponent(to_f64(x).ln())
+    };
+    // ln(x) = k ln 2 + ln(y) = k ln 2 + 2 atanh((y - 1) / (y + 1)),
+    // where y = x / 2^k lies between 1/2 and 2
+    let k = x.get_num().size_in_base(2) as i64 - x.get_den().size_in_base(2) as i64;
+    let two = int(&Mpz::from(2).pow(k.abs() as u32));
+    let y = if k < 0 { x * &two } else { x / &two };
+    let z = &(&y - &one) / &(&y + &one);
+    let scale = Scale::new(places(digits, exponent) + digits_of(k));
+    let res = &(&ln2_fixed(&scale) * &Mpz::from(k)) +
+        &(&arctan_series(&scale, &scale.fixed(&z), false) * &Mpz::from(2));
+    Ok(scale.rational(&res))
+}
+
+/// Finds an integer k such that base^k = x, if there is a small one.
+fn exact_log(x: &Mpq, base: &Mpq) -> Option<i64> {
+    let approx = to_f64(x).ln() / to_f64(base).ln();
+    let k = approx.round();
+    if !approx.is_finite() || (approx - k).abs() > 1e-6 || k.abs() > 1e4 {
+        return None
+    }
+    if powi(base, k as i64) == *x {
+        Some(k as i64)
+    } else {
+        None
+    }
+}
+
+pub fn log(x: &Mpq, base: &Mpq, digits: u32) -> Result<Mpq, String> {
+    if *base <= Mpq::zero() || *base == Mpq::one() {
+        return Err(format!("Logarithm base must be positive and not one"))
+    }
+    if *x > Mpq::zero() {
+        if let Some(k) = exact_log(x, base) {
+            return Ok(int(&Mpz::from(k)))
+        }
+    }
+    let top = try!(ln(x, digits + GUARD));
+    let bottom = try!(ln(base, digits + GUARD));
+    let res = &top / &bottom;
+    Ok(round(&res, places(digits, magnitude(&res))))
+}
+
+pub fn pow(x: &Mpq, power: &Mpq, digits: u32) -> Result<Mpq, String> {
+    let num = power.get_num();
+    let den = power.get_den();
+    let num_small: Option<i64> = (&num).into();
+    let den_small: Option<i64> = (&den).into();
+    if den == Mpz::one() {
+        if let Some(num) = num_small {
+            return Ok(powi(x, num))
+        }
+    }
+    if *x == Mpq::zero() {
+        return if *power > Mpq::zero() {
+            Ok(Mpq::zero())
+        } else {
+            Err(format!("Division by zero"))
+        }
+    }
+    if *x < Mpq::zero() {
+        return Err(format!("Complex numbers are not implemented"))
+    }
+    let exponent = (to_f64(power) * magnitude(x) as f64) as i64;
+    let digits = digits + cmp::max(0, exponent) as u32;
+    match (num_small, den_small) {
+        (Some(num), Some(den)) if num.abs() < 1000 && den < 1000 => {
+            let root = root(x, den as u32, digits + digits_of(num));
+            Ok(round(&powi(&root, num), places(digits, exponent)))
+        },
+        _ => {
+            let log = try!(ln(x, digits + digits_of(to_f64(power) as i64) + 1));
+            exp(&(power * &log), digits)
+        },
+    }
+}
+
+pub fn hypot(x: &Mpq, y: &Mpq, digits: u32) -> Mpq {
+    root(&(&(x * x) + &(y * y)), 2, digits)
+}
+
+pub fn sin(x: &Mpq, digits: u32) -> Mpq {
+    if *x == Mpq::zero() {
+        return Mpq::zero()
+    }
+    let scale = Scale::new(places(digits, exponent(to_f64(x).sin())) +
+                           cmp::max(0, magnitude(x)) as u32);
+    scale.rational(&sin_series(&scale, &reduce_angle(&scale, x), false))
+}
+
+pub fn cos(x: &Mpq, digits: u32) -> Mpq {
+    let scale = Scale::new(places(digits, exponent(to_f64(x).cos())) +
+                           cmp::max(0, magnitude(x)) as u32);
+    scale.rational(&sin_series(&scale, &reduce_angle(&scale, x), true))
+}
+
+pub fn tan(x: &Mpq, digits: u32) -> Mpq {
+    if *x == Mpq::zero() {
+        return Mpq::zero()
+    }
+    // large results come from small cosines, which need more places
+    let approx = to_f64(x).tan();
+    let scale = Scale::new(places(digits, exponent(approx)) +
+                           cmp::max(0, exponent(approx)) as u32 +
+                           cmp::max(0, magnitude(x)) as u32);
+    let x = reduce_angle(&scale, x);
+    let sin = sin_series(&scale, &x, false);
+    let cos = sin_series(&scale, &x, true);
+    scale.rational(&scale.div(&sin, &cos))
+}
+
+pub fn asin(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    if x.abs() > Mpq::one() {
+        return Err(format!("asin is only defined between -1 and 1"))
+    }
+    // arguments close to one need more places
+    let near_one = cmp::max(0, -magnitude(&(&Mpq::one() - &x.abs())));
+    let scale = Scale::new(places(digits, exponent(to_f64(x).asin())) + near_one as u32);
+    Ok(scale.rational(&asin_fixed(&scale, x)))
+}
+
+pub fn acos(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    if x.abs() > Mpq::one() {
+        return Err(format!("acos is only defined between -1 and 1"))
+    }
+    let near_one = cmp::max(0, -magnitude(&(&Mpq::one() - &x.abs())));
+    let scale = Scale::new(places(digits, exponent(to_f64(x).acos())) + near_one as u32);
+    let half_pi = &pi_fixed(&scale) / &Mpz::from(2);
+    Ok(scale.rational(&(&half_pi - &asin_fixed(&scale, x))))
+}
+
+pub fn atan(x: &Mpq, digits: u32) -> Mpq {
+    let scale = Scale::new(places(digits, exponent(to_f64(x).atan())));
+    scale.rational(&atan_fixed(&scale, &scale.fixed(x)))
+}
+
+/// Computes the angle of the point (x, y), in the same argument order
+/// as `f64::atan2`.
+pub fn atan2(y: &Mpq, x: &Mpq, digits: u32) -> Mpq {
+    let zero = Mpq::zero();
+    let scale = Scale::new(places(digits, exponent(to_f64(y).atan2(to_f64(x)))));
+    let pi = pi_fixed(&scale);
+    let res = if *x == zero {
+        if *y == zero {
+            Mpz::zero()
+        } else if *y < zero {
+            &Mpz::zero() - &(&pi / &Mpz::from(2))
+        } else {
+            &pi / &Mpz::from(2)
+        }
+    } else {
+        let angle = atan_fixed(&scale, &scale.fixed(&(y / x)));
+        if *x > zero {
+            angle
+        } else if *y < zero {
+            &angle - &pi
+        } else {
+            &angle + &pi
+        }
+    };
+    scale.rational(&res)
+}
+
+/// The extra digits needed for results that are about as small as
+/// the argument, when the argument is small.
+fn small(x: &Mpq) -> u32 {
+    cmp::max(0, -magnitude(x)) as u32
+}
+
+pub fn sinh(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    if *x == Mpq::zero() {
+        return Ok(Mpq::zero())
+    }
+    let exp = try!(exp(x, digits + small(x)));
+    let res = &(&exp - &exp.invert()) / &int(&Mpz::from(2));
+    Ok(round(&res, places(digits, magnitude(&res))))
+}
+
+pub fn cosh(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    let exp = try!(exp(x, digits));
+    let res = &(&exp + &exp.invert()) / &int(&Mpz::from(2));
+    Ok(round(&res, places(digits, 0)))
+}
+
+pub fn tanh(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    if *x == Mpq::zero() {
+        return Ok(Mpq::zero())
+    }
+    let exp = try!(exp(x, digits + small(x)));
+    let exp2 = &exp * &exp;
+    let one = Mpq::one();
+    let res = &(&exp2 - &one) / &(&exp2 + &one);
+    Ok(round(&res, places(digits, magnitude(&res))))
+}
+
+pub fn asinh(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    if *x == Mpq::zero() {
+        return Ok(Mpq::zero())
+    }
+    let abs = x.abs();
+    let root = root(&(&(&abs * &abs) + &Mpq::one()), 2, digits + small(x));
+    let res = try!(ln(&(&abs + &root), digits));
+    Ok(if *x < Mpq::zero() { &Mpq::zero() - &res } else { res })
+}
+
+pub fn acosh(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    let one = Mpq::one();
+    if *x < one {
+        return Err(format!("acosh is only defined from 1 onwards"))
+    }
+    let root = root(&(&(x * x) - &one), 2, digits + small(&(x - &one)));
+    ln(&(x + &root), digits)
+}
+
+pub fn atanh(x: &Mpq, digits: u32) -> Result<Mpq, String> {
+    let one = Mpq::one();
+    if x.abs() >= one {
+        return Err(format!("atanh is only defined between -1 and 1, exclusive"))
+    }
+    let res = try!(ln(&(&(&one + x) / &(&one - x)), digits));
+    Ok(&res / &int(&Mpz::from(2)))
+}
+
+/// Computes a builtin function to the given number of digits. Returns
+/// None for functions that aren't implemented here.
+pub fn call(name: &str, args: &[Mpq], digits: u32) -> Option<Result<Mpq, String>> {
+    let two = int(&Mpz::from(2));
+    let ten = int(&Mpz::from(10));
+    Some(match (name, args.len()) {
+        ("sqrt", 1) => Ok(root(&args[0], 2, digits)),
+        ("exp", 1) => exp(&args[0], digits),
+        ("ln", 1) => ln(&args[0], digits),
+        ("log", 2) => log(&args[0], &args[1], digits),
+        ("log2", 1) => log(&args[0], &two, digits),
+        ("log10", 1) => log(&args[0], &ten, digits),
+        ("hypot", 2) => Ok(hypot(&args[0], &args[1], digits)),
+        ("sin", 1) => Ok(sin(&args[0], digits)),
+        ("cos", 1) => Ok(cos(&args[0], digits)),
+        ("tan", 1) => Ok(tan(&args[0], digits)),
+        ("asin", 1) => asin(&args[0], digits),
+        ("acos", 1) => acos(&args[0], digits),
+        ("atan", 1) => Ok(atan(&args[0], digits)),
+        ("atan2", 2) => Ok(atan2(&args[0], &args[1], digits)),
+        ("sinh", 1) => sinh(&args[0], digits),
+        ("cosh", 1) => cosh(&args[0], digits),
+        ("tanh", 1) => tanh(&args[0], digits),
+        ("asinh", 1) => asinh(&args[0], digits),
+        ("acosh", 1) => acosh(&args[0], digits),
+        ("atanh", 1) => atanh(&args[0], digits),
+        _ => return None
+    })
+}
@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Balancing chemical reactions. The coefficients are found by
+//! integer Gaussian elimination on the matrix of atom counts, with a
+//! column for each formula and a row for each kind of atom, plus one
+//! for the charge of ions.
+
+use ast::Reaction;
+use formula::{Atom, Formula, parse_formula};
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a
+}
+
+/// Divides a row by the greatest common divisor of its entries.
+fn reduce(row: &mut [i64]) {
+    let divisor = row.iter().fold(0, |acc, &x| gcd(acc, x));
+    if divisor > 1 {
+        for x in row.iter_mut() {
+            *x /= divisor;
+        }
+    }
+}
+
+fn formulas(reaction: &Reaction) -> Result<Vec<Formula>, String> {
+    reaction.formulas().into_iter().map(|name| {
+        parse_formula(name).map_err(|e| format!("Failed to parse {}: {}", name, e))
+    }).collect()
+}
+
+/// The number of each kind of atom in each formula, negated for the
+/// products, so that balanced coefficients make every row sum to zero.
+fn matrix(formulas: &[Formula], reactants: usize) -> Vec<Vec<i64>> {
+    let mut atoms: Vec<&Atom> = vec![];
+    for formula in formulas {
+        for &(ref atom, _) in &formula.atoms {
+            if !atoms.contains(&atom) {
+                atoms.push(atom);
+            }
+        }
+    }
+    let sign = |i: usize| if i < reactants { 1 } else { -1 };
+    let mut rows = atoms.iter().map(|atom| {
+        formulas.iter().enumerate().map(|(i, formula)| {
+            sign(i) * formula.atoms.iter()
+                .find(|x| x.0 == **atom)
+                .map(|x| x.1 as i64)
+                .unwrap_or(0)
+        }).collect::<Vec<i64>>()
+    }).collect::<Vec<_>>();
+    if formulas.iter().any(|x| x.charge != 0) {
+        rows.push(formulas.iter().enumerate().map(|(i, formula)| {
+            sign(i) * formula.charge
+        }).collect());
+    }
+    rows
+}
+
+/// Finds the smallest positive integer coefficients that balance the
+/// atoms and charges of a reaction, ignoring the coefficients it was
+/// written with.
+pub fn balance(reaction: &Reaction) -> Result<Vec<u64>, String> {
+    let formulas = try!(formulas(reaction));
+    let mut matrix = matrix(&formulas, reaction.reactants.len());
+    let columns = formulas.len();
+    let overflow = || format!("The coefficients of {} are too large", reaction);
+
+    let mut pivots = vec![];
+    for col in 0..columns {
+        let rank = pivots.len();
+        let row = match (rank..matrix.len()).find(|&i| matrix[i][col] != 0) {
+            Some(row) => row,
+            None => continue
+        };
+        matrix.swap(rank, row);
+        let pivot = matrix[rank].clone();
+        for row in matrix.iter_mut().enumerate().filter(|x| x.0 != rank).map(|x| x.1) {
+            let factor = row[col];
+            if factor == 0 {
+                continue
+            }
+            for (x, &p) in row.iter_mut().zip(pivot.iter()) {
+                *x = try!(x.checked_mul(pivot[col])
+                          .and_then(|x| p.checked_mul(factor).and_then(|y| x.checked_sub(y)))
+                          .ok_or_else(&overflow));
+            }
+            reduce(row);
+        }
+        pivots.push(col);
+    }
+
+    let free = (0..columns).filter(|x| !pivots.contains(x)).collect::<Vec<_>>();
+    if free.len() == 0 {
+        return Err(format!("{} cannot be balanced", reaction))
+    }
+    if free.len() > 1 {
+        return Err(format!(
+            "{} can be balanced in more than one way, give the coefficients instead",
+            reaction
+        ))
+    }
+    let free = free[0];
+
+    // every pivot row reads `a x_pivot + b x_free = 0`
+    let mut multiple = 1i64;
+    for (row, &col) in pivots.iter().enumerate() {
+        let a = matrix[row][col].abs();
+        multiple = try!((multiple / gcd(multiple, a)).checked_mul(a).ok_or_else(&overflow));
+    }
+    let mut solution = vec![0i64; columns];
+    solution[free] = multiple;
+    for (row, &col) in pivots.iter().enumerate() {
+        solution[col] = try!((-matrix[row][free])
+                             .checked_mul(multiple / matrix[row][col])
+                             .ok_or_else(&overflow));
+    }
+    reduce(&mut solution);
+    if solution.iter().all(|&x| x <= 0) {
+        for x in solution.iter_mut() {
+            *x = -*x;
+        }
+    }
+    if solution.iter().any(|&x| x <= 0) {
+        return Err(format!("{} cannot be balanced", reaction))
+    }
+    Ok(solution.into_iter().map(|x| x as u64).collect())
+}
+
+/// The coefficients of the reactants followed by those of the
+/// products. Reactions written without any coefficients are balanced,
+/// otherwise the coefficients given are checked, with 1 for those left
+/// out.
+pub fn coefficients(reaction: &Reaction) -> Result<Vec<u64>, String> {
+    let given = reaction.reactants.iter().chain(reaction.products.iter())
+        .map(|x| x.0)
+        .collect::<Vec<_>>();
+    if given.iter().all(|x| x.is_none()) {
+        return balance(reaction)
+    }
+    let given = given.into_iter().map(|x| x.unwrap_or(1)).collect::<Vec<u64>>();
+    let formulas = try!(formulas(reaction));
+    let balanced = matrix(&formulas, reaction.reactants.len()).iter().all(|row| {
+        row.iter().zip(given.iter()).fold(Some(0i64), |acc, (&x, &n)| {
+            acc.and_then(|acc| x.checked_mul(n as i64).and_then(|x| acc.checked_add(x)))
+        }) == Some(0)
+    });
+    if balanced {
+        Ok(given)
+    } else {
+        Err(format!("{} is not balanced", reaction))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reaction(reactants: &[&str], products: &[&str]) -> Reaction {
+        let side = |x: &[&str]| -> Vec<(Option<u64>, String)> {
+            x.iter().map(|x| (None, x.to_string())).collect()
+        };
+        Reaction {
+            reactants: side(reactants),
+            products: side(products),
+        }
+    }
+
+    #[test]
+    fn test_balance() {
+        assert_eq!(balance(&reaction(&["CH4", "O2"], &["CO2", "H2O"])),
+                   Ok(vec![1, 2, 1, 2]));
+        assert_eq!(balance(&reaction(&["Fe", "O2"], &["Fe2O3"])),
+                   Ok(vec![4, 3, 2]));
+        assert_eq!(balance(&reaction(&["C6H12O6", "O2"], &["CO2", "H2O"])),
+                   Ok(vec![1, 6, 6, 6]));
+        assert_eq!(balance(&reaction(&["Cu", "Ag+"], &["Cu2+", "Ag"])),
+                   Ok(vec![1, 2, 1, 2]));
+        assert_eq!(balance(&reaction(&["H2O"], &["CO2"])),
+                   Err("H2O -> CO2 cannot be balanced".to_owned()));
+    }
+}
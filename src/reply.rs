@@ -7,6 +7,7 @@ use std::fmt::Result as FmtResult;
 use chrono::{DateTime, TimeZone};
 use std::iter::once;
 use ast::{Expr, Digits};
+use text_query::Span;
 
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
@@ -118,6 +119,171 @@ pub struct DateReply {
     pub string: String,
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct AssignReply {
+    pub name: String,
+    pub value: Box<QueryReply>,
+    /// True if the variable hides a unit or substance of the same name.
+    pub shadows: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct VariableReply {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct VariablesReply {
+    pub variables: Vec<VariableReply>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct UnsetReply {
+    pub name: String,
+    /// Whether a variable with the name was removed.
+    pub variable: bool,
+    /// Whether a function with the name was removed.
+    pub function: bool,
+}
+
+/// The result of a comparison like `1 mile > 1600 m`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct BoolReply {
+    pub value: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct LogarithmicReply {
+    /// The level, such as `30 dBm`.
+    pub level: String,
+    /// The equivalent linear value, for absolute levels.
+    pub linear: Option<NumberParts>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct ElementReply {
+    /// The element symbol, with the mass number of isotopes.
+    pub symbol: String,
+    pub name: Option<String>,
+    /// The number of atoms in the formula.
+    pub count: u32,
+    /// The percentage of the molar mass.
+    pub mass_percent: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct CompositionReply {
+    pub formula: String,
+    pub molar_mass: NumberParts,
+    /// The charge, in elementary charges.
+    pub charge: i64,
+    pub elements: Vec<ElementReply>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct SpeciesReply {
+    pub coefficient: u64,
+    pub formula: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct ReactionReply {
+    pub reactants: Vec<SpeciesReply>,
+    pub products: Vec<SpeciesReply>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct PolarReply {
+    /// The magnitude, with its unit.
+    pub magnitude: NumberParts,
+    /// The angle from the positive real axis, in degrees.
+    pub angle: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct AlternativesReply {
+    /// The same value in different units, simplest first.
+    pub alternatives: Vec<NumberParts>,
+    /// The dimensions and quantity of the value.
+    pub of: NumberParts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub enum ExplainKind {
+    /// A base unit, which isn't defined in terms of others.
+    BaseUnit,
+    /// A unit from the units files, with its definition.
+    Definition,
+    /// A unit name made of a prefix and another unit.
+    Prefix,
+    /// The plural of another unit name.
+    Plural,
+    /// Any other name, like a quantity, or a part of the query.
+    Value,
+    /// The factor between the two sides of a conversion.
+    Factor,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct ExplainStep {
+    pub kind: ExplainKind,
+    /// The unit or expression the step is about.
+    pub name: String,
+    /// Where the value comes from: the definition of a unit, or the
+    /// prefix and unit a name is split into.
+    pub detail: Option<String>,
+    /// The exact value, in base units.
+    pub value: NumberParts,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct ExplainReply {
+    /// The steps of the derivation, where each only depends on the
+    /// ones before it.
+    pub steps: Vec<ExplainStep>,
+    pub result: Box<QueryReply>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct IntervalReply {
+    /// The lower bound, rounded down.
+    pub lower: String,
+    /// The upper bound, rounded up.
+    pub upper: String,
+    /// The number of significant digits both bounds agree on.
+    pub digits: Option<usize>,
+    /// The digits both bounds agree on.
+    pub value: Option<String>,
+    /// The unit, dimensions and quantity of the bounds.
+    pub of: NumberParts,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct FunctionReply {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: String,
+    pub result: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
 pub enum QueryReply {
@@ -131,6 +297,18 @@ pub enum QueryReply {
     UnitsFor(UnitsForReply),
     UnitList(UnitListReply),
     Search(SearchReply),
+    Assign(AssignReply),
+    Variables(VariablesReply),
+    Unset(UnsetReply),
+    Function(FunctionReply),
+    Logarithmic(LogarithmicReply),
+    Interval(IntervalReply),
+    Polar(PolarReply),
+    Composition(CompositionReply),
+    Reaction(ReactionReply),
+    Alternatives(AlternativesReply),
+    Explain(ExplainReply),
+    Bool(BoolReply),
 }
 
 #[derive(Debug, Clone)]
@@ -154,6 +332,36 @@ pub enum QueryError {
     Conformance(ConformanceError),
     NotFound(NotFoundError),
     Generic(String),
+    /// An error along with the part of the query it is about.
+    Spanned(Box<QueryError>, Span),
+}
+
+impl QueryError {
+    /// The part of the query that the error is about, if it is known.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            QueryError::Spanned(_, span) => Some(span),
+            _ => None
+        }
+    }
+
+    /// Attaches the span of the expression that failed, unless the
+    /// error already points at a part of it.
+    pub fn at(self, span: Option<Span>) -> QueryError {
+        match (self, span) {
+            (QueryError::Spanned(e, span), _) => QueryError::Spanned(e, span),
+            (e, Some(span)) => QueryError::Spanned(Box::new(e), span),
+            (e, None) => e,
+        }
+    }
+
+    /// Separates the error from its span.
+    pub fn split(self) -> (QueryError, Option<Span>) {
+        match self {
+            QueryError::Spanned(e, span) => (*e, Some(span)),
+            e => (e, None),
+        }
+    }
 }
 
 impl ExprReply {
@@ -185,12 +393,20 @@ impl ExprReply {
                 }}
             }
             match *expr {
-                Expr::Unit(ref name) => parts.push(ExprParts::Unit(name.clone())),
+                Expr::Unit(ref name, _) => parts.push(ExprParts::Unit(name.clone())),
                 Expr::Quote(ref name) => literal!(format!("'{}'", name)),
-                Expr::Const(ref num) => {
+                Expr::Const(ref num, _) => {
                     let (_exact, val) = ::number::to_string(num, 10, Digits::Default);
                     literal!(format!("{}", val))
                 },
+                Expr::Imaginary(ref num) => {
+                    let (_exact, val) = ::number::to_string(num, 10, Digits::Default);
+                    if val == "1" {
+                        literal!("i")
+                    } else {
+                        literal!(format!("{}i", val))
+                    }
+                },
                 Expr::Date(ref _date) => literal!("NYI: date expr to expr parts"),
                 Expr::Mul(ref exprs) => {
                     if prec < Prec::Mul {
@@ -203,7 +419,7 @@ impl ExprReply {
                         literal!(")");
                     }
                 },
-                Expr::Call(ref name, ref args) => {
+                Expr::Call(ref name, ref args, _) => {
                     literal!(format!("{}(", name));
                     if let Some(first) = args.first() {
                         recurse(first, parts, Prec::Equals);
@@ -214,10 +430,23 @@ impl ExprReply {
                     }
                     literal!(")")
                 },
+                Expr::Vector(ref elems) => {
+                    literal!("[");
+                    if let Some(first) = elems.first() {
+                        recurse(first, parts, Prec::Equals);
+                    }
+                    for elem in elems.iter().skip(1) {
+                        literal!(",");
+                        recurse(elem, parts, Prec::Equals);
+                    }
+                    literal!("]")
+                },
                 Expr::Pow(ref left, ref right) => binop!(left, right, Prec::Pow, Prec::Term, "^"),
                 Expr::Frac(ref left, ref right) => binop!(left, right, Prec::Div, Prec::Mul, " / "),
                 Expr::Add(ref left, ref right) => binop!(left, right, Prec::Add, Prec::Div, " + "),
                 Expr::Sub(ref left, ref right) => binop!(left, right, Prec::Add, Prec::Div, " - "),
+                Expr::PlusMinus(ref left, ref right) =>
+                    binop!(left, right, Prec::Add, Prec::Div, " ± "),
                 Expr::Plus(ref expr) => {
                     literal!("+");
                     recurse(expr, parts, Prec::Plus)
@@ -227,6 +456,8 @@ impl ExprReply {
                     recurse(expr, parts, Prec::Plus)
                 },
                 Expr::Equals(ref left, ref right) => binop!(left, right, Prec::Equals, Prec::Add, " = "),
+                Expr::Compare(op, ref left, ref right) =>
+                    binop!(left, right, Prec::Equals, Prec::Add, format!(" {} ", op)),
                 Expr::Suffix(ref op, ref expr) => {
                     if prec < Prec::Mul {
                         literal!("(");
@@ -251,7 +482,23 @@ impl ExprReply {
                         literal!(")");
                     }
                 },
-                Expr::Error(ref err) => parts.push(ExprParts::Error(err.to_owned()))
+                Expr::OfAt(ref field, ref expr, ref at) => {
+                    if prec < Prec::Add {
+                        literal!("(");
+                    }
+                    let mut sub = vec![];
+                    recurse(expr, &mut sub, Prec::Div);
+                    parts.push(ExprParts::Property(
+                        field.to_owned(),
+                        sub
+                    ));
+                    literal!("at");
+                    recurse(at, parts, Prec::Div);
+                    if prec < Prec::Add {
+                        literal!(")");
+                    }
+                },
+                Expr::Error(ref err, _) => parts.push(ExprParts::Error(err.to_owned()))
             }
         }
 
@@ -288,6 +535,18 @@ impl Display for QueryReply {
             QueryReply::UnitsFor(ref v) => write!(fmt, "{}", v),
             QueryReply::UnitList(ref v) => write!(fmt, "{}", v),
             QueryReply::Search(ref v) => write!(fmt, "{}", v),
+            QueryReply::Assign(ref v) => write!(fmt, "{}", v),
+            QueryReply::Variables(ref v) => write!(fmt, "{}", v),
+            QueryReply::Unset(ref v) => write!(fmt, "{}", v),
+            QueryReply::Function(ref v) => write!(fmt, "{}", v),
+            QueryReply::Logarithmic(ref v) => write!(fmt, "{}", v),
+            QueryReply::Interval(ref v) => write!(fmt, "{}", v),
+            QueryReply::Polar(ref v) => write!(fmt, "{}", v),
+            QueryReply::Composition(ref v) => write!(fmt, "{}", v),
+            QueryReply::Reaction(ref v) => write!(fmt, "{}", v),
+            QueryReply::Alternatives(ref v) => write!(fmt, "{}", v),
+            QueryReply::Explain(ref v) => write!(fmt, "{}", v),
+            QueryReply::Bool(ref v) => write!(fmt, "{}", v),
         }
     }
 }
@@ -298,6 +557,7 @@ impl Display for QueryError {
             QueryError::Generic(ref v) => write!(fmt, "{}", v),
             QueryError::Conformance(ref v) => write!(fmt, "{}", v),
             QueryError::NotFound(ref v) => write!(fmt, "{}", v),
+            QueryError::Spanned(ref v, _) => write!(fmt, "{}", v),
         }
     }
 }
@@ -464,3 +724,166 @@ impl Display for SearchReply {
         )
     }
 }
+
+impl Display for AssignReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(fmt, "{} = {}", self.name, self.value));
+        if self.shadows {
+            try!(write!(fmt, "\nWarning: {} shadows an existing unit", self.name));
+        }
+        Ok(())
+    }
+}
+
+impl Display for VariablesReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        if self.variables.len() == 0 {
+            return write!(fmt, "No variables defined")
+        }
+        write!(
+            fmt, "Variables: {}",
+            self.variables.iter()
+                .map(|x| format!("{} = {}", x.name, x.value))
+                .collect::<Vec<_>>()
+                .join("; ")
+        )
+    }
+}
+
+impl Display for UnsetReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match (self.variable, self.function) {
+            (true, true) => write!(fmt, "Unset variable and function {}", self.name),
+            (false, true) => write!(fmt, "Unset function {}", self.name),
+            _ => write!(fmt, "Unset variable {}", self.name),
+        }
+    }
+}
+
+impl Display for BoolReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}", self.value)
+    }
+}
+
+impl Display for FunctionReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(fmt, "{}({}) := {}", self.name, self.params.join(", "), self.body));
+        if let Some(ref result) = self.result {
+            try!(write!(fmt, " -> {}", result));
+        }
+        Ok(())
+    }
+}
+
+impl Display for LogarithmicReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(fmt, "{}", self.level));
+        if let Some(ref linear) = self.linear {
+            try!(write!(fmt, " = {}", linear));
+        }
+        Ok(())
+    }
+}
+
+impl Display for CompositionReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(
+            fmt, "{}: {}",
+            self.formula,
+            self.elements.iter().map(|elem| format!(
+                "{} {} ({}%)", elem.symbol, elem.count, elem.mass_percent
+            )).collect::<Vec<_>>().join(", ")
+        ));
+        if self.charge != 0 {
+            try!(write!(fmt, "; charge = {}", self.charge));
+        }
+        write!(fmt, "; molar_mass = {}", self.molar_mass.format("n u"))
+    }
+}
+
+impl Display for SpeciesReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        if self.coefficient == 1 {
+            write!(fmt, "{}", self.formula)
+        } else {
+            write!(fmt, "{} {}", self.coefficient, self.formula)
+        }
+    }
+}
+
+impl Display for ReactionReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let side = |species: &[SpeciesReply]| {
+            species.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" + ")
+        };
+        write!(fmt, "{} -> {}", side(&self.reactants), side(&self.products))
+    }
+}
+
+impl Display for PolarReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(fmt, "{} ∠ {}°", self.magnitude.format("n u"), self.angle));
+        if let Some(ref quantity) = self.magnitude.quantity {
+            try!(write!(fmt, " ({})", quantity));
+        }
+        Ok(())
+    }
+}
+
+impl Display for IntervalReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(fmt, "[{}, {}] {}", self.lower, self.upper, self.of.format("u w")));
+        if let (Some(digits), Some(value)) = (self.digits, self.value.as_ref()) {
+            try!(write!(fmt, ", {} certified digits: {}", digits, value));
+        }
+        Ok(())
+    }
+}
+
+impl Display for AlternativesReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        try!(write!(fmt, "Alternatives"));
+        if let Some(ref quantity) = self.of.quantity {
+            try!(write!(fmt, " for {}", quantity));
+        }
+        write!(fmt, ": {}", self.alternatives.iter().map(|x| {
+            x.format("n u")
+        }).collect::<Vec<_>>().join("; "))
+    }
+}
+
+impl Display for ExplainStep {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let value = self.value.format("n u");
+        let detail = self.detail.as_ref().map(|x| &**x).unwrap_or("?");
+        match self.kind {
+            ExplainKind::BaseUnit => {
+                try!(write!(fmt, "{} is a base unit", self.name));
+                if let Some(ref quantity) = self.value.quantity {
+                    try!(write!(fmt, " of {}", quantity));
+                }
+                Ok(())
+            },
+            ExplainKind::Definition =>
+                write!(fmt, "{} := {} = {}", self.name, detail, value),
+            ExplainKind::Prefix =>
+                write!(fmt, "{} = {} = {}", self.name, detail, value),
+            ExplainKind::Plural =>
+                write!(fmt, "{} is the plural of {}", self.name, detail),
+            ExplainKind::Value =>
+                write!(fmt, "{} = {}", self.name, value),
+            ExplainKind::Factor =>
+                write!(fmt, "factor {} = {}", self.name, value),
+        }
+    }
+}
+
+impl Display for ExplainReply {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        for (i, step) in self.steps.iter().enumerate() {
+            try!(writeln!(fmt, "{}. {}", i + 1, step));
+        }
+        write!(fmt, "Result: {}", self.result)
+    }
+}
@@ -0,0 +1,69 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Scripts of queries that check their own results, for catching
+//! mistakes in calculations when definitions change:
+//!
+//! ```text
+//! // bike.rink
+//! wheel = 622 mm + 2 * 28 mm
+//! assert wheel pi == 2.13 m within 0.5%
+//! assert wheel < 700 mm
+//! ```
+
+use std::rc::Rc;
+use ast::Query;
+use context::Context;
+use diagnostic::{Diagnostic, Location};
+use text_query::{parse_query, TokenStream};
+
+#[derive(Debug, Clone)]
+pub struct ScriptReport {
+    /// The number of `assert` lines.
+    pub assertions: usize,
+    /// Failed assertions and lines that couldn't be evaluated.
+    pub failures: Vec<Diagnostic>,
+}
+
+impl ScriptReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Evaluates the lines of a script named `file` in order, skipping
+/// blank lines and `//` comments. Variables assigned by earlier lines
+/// are visible to later ones.
+pub fn run(ctx: &mut Context, file: &str, input: &str) -> ScriptReport {
+    let file = Rc::new(file.to_owned());
+    let mut report = ScriptReport {
+        assertions: 0,
+        failures: vec![],
+    };
+    for (i, line) in input.lines().enumerate() {
+        let query = line.trim();
+        if query.is_empty() || query.starts_with("//") {
+            continue
+        }
+        let mut iter = TokenStream::new(query).with_functions(ctx.function_names());
+        let parsed = parse_query(&mut iter);
+        if let Query::Assert(_, _) = parsed {
+            report.assertions += 1;
+        }
+        if let Err(e) = ctx.eval_query(&parsed) {
+            let indent = line.len() - line.trim_start().len();
+            let column = match e.span() {
+                Some(span) => line[..indent + span.start].chars().count() + 1,
+                None => indent + 1,
+            };
+            let location = Location {
+                file: file.clone(),
+                line: i + 1,
+                column: column,
+            };
+            report.failures.push(Diagnostic::error(Some(&location), e.to_string()));
+        }
+    }
+    report
+}
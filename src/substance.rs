@@ -12,6 +12,7 @@ use std::ops::{Mul, Div, Add};
 use std::iter::once;
 use std::rc::Rc;
 use ast::Digits;
+use table::Table;
 
 #[derive(Debug, Clone)]
 pub struct Property {
@@ -22,10 +23,23 @@ pub struct Property {
     pub doc: Option<String>,
 }
 
+/// A property that depends on a parameter, like the density of water
+/// at different temperatures. The table is in units of `param_unit`
+/// and `unit`.
+#[derive(Debug, Clone)]
+pub struct TabulatedProperty {
+    pub param_name: String,
+    pub param_unit: Number,
+    pub unit: Number,
+    pub table: Table,
+    pub doc: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Properties {
     pub name: String,
     pub properties: BTreeMap<String, Property>,
+    pub tables: BTreeMap<String, TabulatedProperty>,
 }
 
 #[derive(Debug, Clone)]
@@ -45,11 +59,47 @@ impl Substance {
             amount: self.amount,
             properties: Rc::new(Properties {
                 name: name,
-                properties: self.properties.properties.clone()
+                properties: self.properties.properties.clone(),
+                tables: self.properties.tables.clone(),
             })
         }
     }
 
+    /// The value of a tabulated property at the given value of its
+    /// parameter, as in `density of water at 60 °C`.
+    pub fn get_at(&self, name: &str, param: &Number) -> Result<Number, SubstanceGetError> {
+        let prop = try!(self.properties.tables.get(name).ok_or_else(|| {
+            SubstanceGetError::Generic(format!(
+                "{} of {} is not tabulated", name, self.properties.name
+            ))
+        }));
+        if !self.amount.dimless() {
+            return Err(SubstanceGetError::Generic(format!(
+                "{} of {} is tabulated per substance, not for an amount of it",
+                name, self.properties.name
+            )))
+        }
+        if param.unit != prop.param_unit.unit {
+            return Err(SubstanceGetError::Conformance(
+                param.clone(), prop.param_unit.clone()))
+        }
+        let x = (param / &prop.param_unit).expect("Non-zero table unit").value;
+        let show = |x: &Num| {
+            (&Number::new(x.clone()) * &prop.param_unit).unwrap()
+                .to_parts_simple().format("n u")
+        };
+        let y = try!(prop.table.eval(&x).ok_or_else(|| {
+            let (lower, upper) = prop.table.range();
+            SubstanceGetError::Generic(format!(
+                "The {} {} is outside the table of {} of {}, which goes from {} to {}",
+                prop.param_name, show(&x), name, self.properties.name,
+                show(lower), show(upper)
+            ))
+        }));
+        let value = (&Number::new(y) * &prop.unit).unwrap();
+        Ok((&self.amount * &value).unwrap())
+    }
+
     pub fn get(&self, name: &str) -> Result<Number, SubstanceGetError> {
         if self.amount.dimless() {
             self.properties.properties.get(name)
@@ -483,7 +533,8 @@ impl<'a, 'b> Add<&'b Substance> for &'a Substance {
                         output_name: prop1.output_name.clone(),
                         doc: None,
                     }))
-                }).collect()
+                }).collect(),
+                tables: BTreeMap::new(),
             })
         };
         if res.properties.properties.len() == 0 {
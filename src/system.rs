@@ -0,0 +1,192 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Systems of units that results can be shown in, like `-> cgs`.
+
+use std::fmt;
+use context::Context;
+use number::{Dim, Number};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitSystem {
+    /// SI units with prefixes, the default.
+    SI,
+    CGS,
+    /// British imperial units.
+    Imperial,
+    /// US customary units, which differ from imperial ones in volume.
+    Customary,
+    /// Energies in electronvolts, with other quantities expressed
+    /// through them and hbar, c and the Boltzmann constant.
+    Natural,
+}
+
+/// A product of units, like `foot lbf`.
+type Factors = &'static [(&'static str, i64)];
+
+/// Units for specific quantities, which are tried in order.
+/// Torque and energy have the same dimensions, so foot lbf is used
+/// for both in imperial and US customary units.
+const CGS_UNITS: &'static [Factors] = &[
+    &[("dyne", 1)],
+    &[("erg", 1)],
+    &[("erg", 1), ("s", -1)],
+    &[("barye", 1)],
+    &[("poise", 1)],
+    &[("stokes", 1)],
+    &[("galileo", 1)],
+    &[("centimeter", 3)],
+    &[("kayser", 1)],
+    &[("gauss", 1)],
+    &[("statcoulomb", 1)],
+    &[("kelvin", 1)],
+];
+
+const IMPERIAL_UNITS: &'static [Factors] = &[
+    &[("lbf", 1)],
+    &[("foot", 1), ("lbf", 1)],
+    &[("horsepower", 1)],
+    &[("psi", 1)],
+    &[("mph", 1)],
+    &[("brgallon", 1)],
+    &[("degrankine", 1)],
+];
+
+const CUSTOMARY_UNITS: &'static [Factors] = &[
+    &[("lbf", 1)],
+    &[("foot", 1), ("lbf", 1)],
+    &[("horsepower", 1)],
+    &[("psi", 1)],
+    &[("mph", 1)],
+    &[("usgallon", 1)],
+    &[("degrankine", 1)],
+];
+
+const NATURAL_UNITS: &'static [Factors] = &[
+    &[("electronvolt", 1)],
+    &[("electronvolt", 1), ("c", -2)],
+    &[("electronvolt", 1), ("c", -1)],
+    &[("c", 1), ("electronvolt", -1), ("hbar", 1)],
+    &[("c", 3), ("electronvolt", -3), ("hbar", 3)],
+    &[("electronvolt", -1), ("hbar", 1)],
+    &[("boltzmann", -1), ("electronvolt", 1)],
+];
+
+/// Replacements of SI base units, for the quantities that don't have
+/// a unit of their own. Imperial and US customary units share theirs.
+const CGS_BASE: &'static [(&'static str, &'static str)] = &[
+    ("m", "centimeter"),
+    ("kg", "gram"),
+];
+
+const IMPERIAL_BASE: &'static [(&'static str, &'static str)] = &[
+    ("m", "foot"),
+    ("kg", "pound"),
+    ("K", "degrankine"),
+];
+
+fn product(factors: &[(&str, i64)], context: &Context) -> Option<Number> {
+    factors.iter().fold(Some(Number::one()), |acc, &(name, pow)| {
+        match (acc, context.lookup(name)) {
+            (Some(acc), Some(unit)) => &acc * &unit.powi(pow as i32),
+            _ => None
+        }
+    })
+}
+
+fn in_units(value: &Number, factors: &[(&str, i64)], context: &Context) -> Option<Number> {
+    let scale = match product(factors, context) {
+        Some(ref scale) if scale.unit == value.unit => scale.clone(),
+        _ => return None
+    };
+    (value / &scale).map(|res| Number {
+        value: res.value,
+        unit: factors.iter().map(|&(name, pow)| (Dim::new(name), pow)).collect(),
+    })
+}
+
+impl UnitSystem {
+    pub fn parse(name: &str) -> Option<UnitSystem> {
+        match name {
+            "si" => Some(UnitSystem::SI),
+            "cgs" => Some(UnitSystem::CGS),
+            "imperial" => Some(UnitSystem::Imperial),
+            "customary" => Some(UnitSystem::Customary),
+            "natural" => Some(UnitSystem::Natural),
+            _ => None
+        }
+    }
+
+    fn units(&self) -> (&'static [Factors], &'static [(&'static str, &'static str)]) {
+        match *self {
+            UnitSystem::SI => (&[], &[]),
+            UnitSystem::CGS => (CGS_UNITS, CGS_BASE),
+            UnitSystem::Imperial => (IMPERIAL_UNITS, IMPERIAL_BASE),
+            UnitSystem::Customary => (CUSTOMARY_UNITS, IMPERIAL_BASE),
+            UnitSystem::Natural => (NATURAL_UNITS, &[]),
+        }
+    }
+
+    /// Shows a number in the units of the system, or returns None if
+    /// the system has nothing better than SI units for it.
+    pub fn convert(&self, value: &Number, context: &Context) -> Option<Number> {
+        let (units, base) = self.units();
+        for factors in units {
+            if let Some(res) = in_units(value, factors, context) {
+                return Some(match *self {
+                    UnitSystem::Natural => prefixed(res, "electronvolt", context),
+                    _ => res
+                })
+            }
+        }
+        let mut replaced = false;
+        let factors = value.unit.iter().map(|(dim, &pow)| {
+            match base.iter().find(|&&(from, _)| from == &**dim.0) {
+                Some(&(_, to)) => {
+                    replaced = true;
+                    (to, pow)
+                },
+                None => (&**dim.0, pow)
+            }
+        }).collect::<Vec<_>>();
+        if replaced {
+            in_units(value, &factors, context)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies an SI prefix to `name`, if it appears to the first power.
+fn prefixed(value: Number, name: &str, context: &Context) -> Number {
+    let dim = Dim::new(name);
+    if value.unit.get(&dim) != Some(&1) {
+        return value
+    }
+    match ::number::si_prefix(&value.value, 1, context) {
+        Some((prefix, res)) => {
+            let mut unit = value.unit.clone();
+            unit.remove(&dim);
+            unit.insert(Dim::new(&*format!("{}{}", prefix, name)), 1);
+            Number {
+                value: res,
+                unit: unit,
+            }
+        },
+        None => value
+    }
+}
+
+impl fmt::Display for UnitSystem {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnitSystem::SI => write!(fmt, "si"),
+            UnitSystem::CGS => write!(fmt, "cgs"),
+            UnitSystem::Imperial => write!(fmt, "imperial"),
+            UnitSystem::Customary => write!(fmt, "customary"),
+            UnitSystem::Natural => write!(fmt, "natural"),
+        }
+    }
+}
+
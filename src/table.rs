@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Interpolation in tables of measured values, like the density of
+//! water at different temperatures.
+
+use num::Num;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    /// A natural cubic spline through the points.
+    Spline,
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    /// The points of the table, in order of increasing x.
+    pub points: Vec<(Num, Num)>,
+    pub interpolation: Interpolation,
+    /// The second derivatives of the spline at each point.
+    curvature: Vec<Num>,
+}
+
+/// Solves for the second derivatives of the natural cubic spline
+/// through the points, which are zero at both ends.
+fn curvature(points: &[(Num, Num)]) -> Vec<Num> {
+    let n = points.len();
+    let mut res = vec![Num::zero(); n];
+    if n < 3 {
+        return res
+    }
+    let h = points.windows(2).map(|w| &w[1].0 - &w[0].0).collect::<Vec<_>>();
+    let slope = points.windows(2).zip(h.iter()).map(|(w, h)| {
+        &(&w[1].1 - &w[0].1) / h
+    }).collect::<Vec<_>>();
+    let two = Num::from(2);
+    let six = Num::from(6);
+    // forward sweep of the tridiagonal system, one row per inner point
+    let mut diag = vec![];
+    let mut rhs = vec![];
+    for i in 1..n - 1 {
+        let mut d = &two * &(&h[i - 1] + &h[i]);
+        let mut r = &six * &(&slope[i] - &slope[i - 1]);
+        if i > 1 {
+            let factor = &h[i - 1] / &diag[i - 2];
+            d = &d - &(&factor * &h[i - 1]);
+            r = &r - &(&factor * &rhs[i - 2]);
+        }
+        diag.push(d);
+        rhs.push(r);
+    }
+    for i in (1..n - 1).rev() {
+        let mut r = rhs[i - 1].clone();
+        if i < n - 2 {
+            r = &r - &(&h[i] * &res[i + 1]);
+        }
+        res[i] = &r / &diag[i - 1];
+    }
+    res
+}
+
+impl Table {
+    /// Creates a table from at least two points with strictly
+    /// increasing x.
+    pub fn new(points: Vec<(Num, Num)>, interpolation: Interpolation) -> Result<Table, String> {
+        if points.len() < 2 {
+            return Err(format!("A table needs at least 2 points, got {}", points.len()))
+        }
+        for w in points.windows(2) {
+            if !(w[0].0 < w[1].0) {
+                return Err(format!(
+                    "Table entries must be in increasing order, but {} comes after {}",
+                    w[1].0.to_f64(), w[0].0.to_f64()
+                ))
+            }
+        }
+        let curvature = match interpolation {
+            Interpolation::Spline => curvature(&points),
+            Interpolation::Linear => vec![],
+        };
+        Ok(Table {
+            points: points,
+            interpolation: interpolation,
+            curvature: curvature,
+        })
+    }
+
+    /// The smallest and largest x in the table.
+    pub fn range(&self) -> (&Num, &Num) {
+        (&self.points[0].0, &self.points[self.points.len() - 1].0)
+    }
+
+    /// Whether the values strictly increase or strictly decrease, so
+    /// that the table can be inverted.
+    pub fn is_monotonic(&self) -> bool {
+        let pairs = || self.points.windows(2);
+        pairs().all(|w| w[0].1 < w[1].1) || pairs().all(|w| w[0].1 > w[1].1)
+    }
+
+    /// The smallest and largest value in the table, for monotonic
+    /// tables.
+    pub fn value_range(&self) -> (&Num, &Num) {
+        let first = &self.points[0].1;
+        let last = &self.points[self.points.len() - 1].1;
+        if first < last { (first, last) } else { (last, first) }
+    }
+
+    /// Finds the x where a monotonic table has the value y, by linear
+    /// interpolation, or returns None if y is outside of it.
+    pub fn invert(&self, y: &Num) -> Option<Num> {
+        self.points.windows(2).find(|w| {
+            (w[0].1 <= *y && *y <= w[1].1) || (w[1].1 <= *y && *y <= w[0].1)
+        }).map(|w| {
+            let (ref x0, ref y0) = w[0];
+            let (ref x1, ref y1) = w[1];
+            x0 + &(&(&(y - y0) * &(x1 - x0)) / &(y1 - y0))
+        })
+    }
+
+    /// Interpolates the table at x, or returns None if x is outside
+    /// of it.
+    pub fn eval(&self, x: &Num) -> Option<Num> {
+        let (lower, upper) = self.range();
+        if x < lower || x > upper {
+            return None
+        }
+        let i = self.points.windows(2).position(|w| *x <= w[1].0).unwrap();
+        let (ref x0, ref y0) = self.points[i];
+        let (ref x1, ref y1) = self.points[i + 1];
+        let h = x1 - x0;
+        let a = &(x1 - x) / &h;
+        let b = &(x - x0) / &h;
+        let linear = &(&a * y0) + &(&b * y1);
+        match self.interpolation {
+            Interpolation::Linear => Some(linear),
+            Interpolation::Spline => {
+                let m0 = &self.curvature[i];
+                let m1 = &self.curvature[i + 1];
+                let cube = |t: &Num| &(&(t * t) * t) - t;
+                let bend = &(&(&cube(&a) * m0) + &(&cube(&b) * m1)) * &(&(&h * &h) / &Num::from(6));
+                Some(&linear + &bend)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table(points: &[(i64, i64)], interpolation: Interpolation) -> Table {
+        Table::new(points.iter().map(|&(x, y)| (Num::from(x), Num::from(y))).collect(),
+                   interpolation).unwrap()
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let linear = table(&[(0, 0), (2, 4), (4, 0)], Interpolation::Linear);
+        assert_eq!(linear.eval(&Num::from(1)), Some(Num::from(2)));
+        assert_eq!(linear.eval(&Num::from(3)), Some(Num::from(2)));
+        assert_eq!(linear.eval(&Num::from(5)), None);
+
+        // a spline reproduces points on a straight line exactly
+        let spline = table(&[(0, 0), (1, 2), (3, 6), (4, 8)], Interpolation::Spline);
+        assert_eq!(spline.eval(&Num::from(2)), Some(Num::from(4)));
+        let spline = table(&[(0, 0), (1, 1), (2, 0)], Interpolation::Spline);
+        assert_eq!(spline.eval(&Num::from(1)), Some(Num::from(1)));
+        let half = &Num::from(1) / &Num::from(2);
+        assert!(spline.eval(&half).unwrap() > half);
+
+        assert!(linear.invert(&Num::from(2)).is_some());
+        assert!(!linear.is_monotonic());
+        let decreasing = table(&[(0, 10), (5, 5), (10, 4)], Interpolation::Linear);
+        assert!(decreasing.is_monotonic());
+        assert_eq!(decreasing.invert(&Num::from(8)), Some(Num::from(2)));
+        assert_eq!(decreasing.invert(&Num::from(11)), None);
+
+        assert!(Table::new(vec![(Num::from(1), Num::zero()), (Num::from(1), Num::one())],
+                           Interpolation::Linear).is_err());
+    }
+}
@@ -2,13 +2,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::str::Chars;
-use std::iter::Peekable;
 use ast::*;
 use gmp::mpz::Mpz;
 use gmp::mpq::Mpq;
 use num::Num;
 use chrono_tz::Tz;
+use system::UnitSystem;
+use std::collections::BTreeSet;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum Token {
@@ -16,6 +17,7 @@ pub enum Token {
     Comment(usize),
     Ident(String),
     Decimal(String, Option<String>, Option<String>),
+    Imaginary(String, Option<String>, Option<String>),
     Hex(String),
     Oct(String),
     Bin(String),
@@ -28,8 +30,11 @@ pub enum Token {
     Eof,
     LPar,
     RPar,
+    LBracket,
+    RBracket,
     Plus,
     Minus,
+    PlusMinus,
     Asterisk,
     DashArrow,
     Colon,
@@ -42,6 +47,7 @@ pub enum Token {
     DegDe,
     DegN,
     Percent,
+    Compare(CompareOp),
     Error(String),
 }
 
@@ -50,6 +56,7 @@ fn describe(token: &Token) -> String {
         Token::Newline | Token::Comment(_) => "\\n".to_owned(),
         Token::Ident(_) => "ident".to_owned(),
         Token::Decimal(_, _, _) => "number".to_owned(),
+        Token::Imaginary(_, _, _) => "imaginary number".to_owned(),
         Token::Hex(_) => "hex".to_owned(),
         Token::Oct(_) => "octal".to_owned(),
         Token::Bin(_) => "binary".to_owned(),
@@ -62,8 +69,11 @@ fn describe(token: &Token) -> String {
         Token::Eof => "eof".to_owned(),
         Token::LPar => "`(`".to_owned(),
         Token::RPar => "`)`".to_owned(),
+        Token::LBracket => "`[`".to_owned(),
+        Token::RBracket => "`]`".to_owned(),
         Token::Plus => "`+`".to_owned(),
         Token::Minus => "`-`".to_owned(),
+        Token::PlusMinus => "`±`".to_owned(),
         Token::Asterisk => "`*`".to_owned(),
         Token::DashArrow => "`->`".to_owned(),
         Token::Colon => "`:`".to_owned(),
@@ -76,17 +86,188 @@ fn describe(token: &Token) -> String {
         Token::DegDe => "`°De`".to_owned(),
         Token::DegN => "`°N`".to_owned(),
         Token::Percent => "%".to_owned(),
+        Token::Compare(op) => format!("`{}`", op),
         Token::Error(ref e) => format!("<{}>", e)
     }
 }
 
+/// A range of bytes in a query, like the token an error is about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "nightly", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The span from the start of the earlier one to the end of the
+    /// later one.
+    pub fn join(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// A line with `^~~` under the span, for showing beneath `input`.
+    pub fn underline(&self, input: &str) -> String {
+        let start = self.start.min(input.len());
+        let end = self.end.min(input.len()).max(start);
+        let mut res = input[..start].chars().map(|c| match c {
+            '\t' => '\t',
+            _ => ' '
+        }).collect::<String>();
+        res.push('^');
+        for _ in 1..input[start..end].chars().count() {
+            res.push('~');
+        }
+        res
+    }
+}
+
+/// The characters of a query, along with the byte offset of the next
+/// one.
 #[derive(Clone)]
-pub struct TokenIterator<'a>(Peekable<Chars<'a>>);
+struct Cursor<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+}
+
+impl<'a> Iterator for Cursor<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.input[self.pos..].chars().next();
+        if let Some(c) = c {
+            self.pos += c.len_utf8();
+        }
+        c
+    }
+}
+
+/// The tokens of a query. The flag is set while reading a reaction,
+/// where formulas like `Ag+` are charged even when an operand
+/// follows.
+#[derive(Clone)]
+pub struct TokenIterator<'a>(Cursor<'a>, bool);
 
 impl<'a> TokenIterator<'a> {
     pub fn new(input: &'a str) -> TokenIterator<'a> {
-        TokenIterator(input.chars().peekable())
+        TokenIterator(Cursor {
+            input: input,
+            pos: 0,
+        }, false)
+    }
+
+    /// Reads the next token along with the bytes it was read from.
+    pub fn next_spanned(&mut self) -> (Token, Span) {
+        while let Some(c) = self.0.peek() {
+            if c != ' ' && c != '\t' {
+                break
+            }
+            self.0.next();
+        }
+        let start = self.0.pos;
+        let token = self.next().unwrap();
+        (token, Span {
+            start: start,
+            end: self.0.pos,
+        })
+    }
+
+    /// Extends the start of a token, like `Ca` or `[`, to a chemical
+    /// formula like `Ca(OH)2` or `[Fe(CN)6]3-` when the characters
+    /// that follow make one.
+    fn formula(&mut self, start: &str) -> Option<String> {
+        let mut chars = start.chars().collect::<Vec<char>>();
+        let len = chars.len();
+        let mut ahead = self.0.clone();
+        while let Some(c) = ahead.next() {
+            // `H2O->` is followed by an arrow, not charged
+            if c == '-' && ahead.peek() == Some('>') {
+                break
+            }
+            if c.is_alphanumeric() || "()[]·⋅^+-⁺⁻".contains(c) {
+                chars.push(c);
+            } else {
+                break
+            }
+        }
+        for end in (len + 1..chars.len() + 1).rev() {
+            // the formula must end the word, so that `H2O-NaCl` is
+            // still a subtraction
+            match chars.get(end) {
+                Some(&c) if c.is_alphanumeric() || c == '(' || c == '[' => continue,
+                _ => ()
+            }
+            // `(N)` is still a parenthesized newton
+            if start == "(" || start == "[" {
+                let mut depth = 0;
+                let close = chars[..end].iter().position(|&c| {
+                    match c {
+                        '(' | '[' => depth += 1,
+                        ')' | ']' => depth -= 1,
+                        _ => ()
+                    }
+                    depth == 0
+                });
+                if close == Some(end - 1) {
+                    continue
+                }
+            }
+            // outside of reactions, a bare charge as in `N+` is only
+            // read where it can't be an operator, so `2 N+ 3 N` is a sum
+            if !self.1 && bare_charge(&chars[..end]) && !self.ends_operand(end - len) {
+                continue
+            }
+            let candidate = chars[..end].iter().cloned().collect::<String>();
+            if ::formula::is_formula(&candidate) {
+                for _ in len..end {
+                    self.0.next();
+                }
+                return Some(candidate)
+            }
+        }
+        None
+    }
+
+    /// Whether skipping `count` characters leaves only the end of the
+    /// query or a `->`.
+    fn ends_operand(&self, count: usize) -> bool {
+        let mut ahead = self.0.clone();
+        for _ in 0..count {
+            ahead.next();
+        }
+        while let Some(c) = ahead.peek() {
+            if c != ' ' && c != '\t' {
+                break
+            }
+            ahead.next();
+        }
+        match ahead.next() {
+            None => true,
+            Some('-') => ahead.peek() == Some('>'),
+            Some(_) => false,
+        }
+    }
+}
+
+/// Whether a formula ends in a charge with an ASCII sign and without
+/// `^`, like the one of `Ca2+`.
+fn bare_charge(chars: &[char]) -> bool {
+    match chars.last() {
+        Some(&'+') | Some(&'-') => (),
+        _ => return false
     }
+    let rest = &chars[..chars.len() - 1];
+    let digits = rest.iter().rev().take_while(|c| c.is_digit(10)).count();
+    rest[..rest.len() - digits].last() != Some(&'^')
 }
 
 impl<'a> Iterator for TokenIterator<'a> {
@@ -99,24 +280,67 @@ impl<'a> Iterator for TokenIterator<'a> {
         let res = match self.0.next().unwrap() {
             ' ' | '\t' => return self.next(),
             '\n' => Token::Newline,
-            '(' => Token::LPar,
+            '(' => match self.formula("(") {
+                Some(formula) => Token::Ident(formula),
+                None => Token::LPar,
+            },
             ')' => Token::RPar,
-            '+' => Token::Plus,
+            '[' => match self.formula("[") {
+                Some(formula) => Token::Ident(formula),
+                None => Token::LBracket,
+            },
+            ']' => Token::RBracket,
+            '+' => if self.0.peek() == Some('-') {
+                self.0.next();
+                Token::PlusMinus
+            } else {
+                Token::Plus
+            },
+            '±' => Token::PlusMinus,
             ';' => Token::Semicolon,
             '%' => Token::Percent,
-            '=' => Token::Equals,
+            '=' => if self.0.peek() == Some('=') {
+                self.0.next();
+                Token::Compare(CompareOp::Eq)
+            } else {
+                Token::Equals
+            },
+            '<' => if self.0.peek() == Some('=') {
+                self.0.next();
+                Token::Compare(CompareOp::LessEq)
+            } else {
+                Token::Compare(CompareOp::Less)
+            },
+            '>' => if self.0.peek() == Some('=') {
+                self.0.next();
+                Token::Compare(CompareOp::GreaterEq)
+            } else {
+                Token::Compare(CompareOp::Greater)
+            },
+            '!' if self.0.peek() == Some('=') => {
+                self.0.next();
+                Token::Compare(CompareOp::NotEq)
+            },
+            '~' if self.0.peek() == Some('=') => {
+                self.0.next();
+                Token::Compare(CompareOp::Approx)
+            },
+            '≤' => Token::Compare(CompareOp::LessEq),
+            '≥' => Token::Compare(CompareOp::GreaterEq),
+            '≠' => Token::Compare(CompareOp::NotEq),
+            '≈' => Token::Compare(CompareOp::Approx),
             '^' => Token::Caret,
             ',' => Token::Comma,
             '|' => Token::Pipe,
             ':' => Token::Colon,
             '→' => Token::DashArrow,
-            '*' => if self.0.peek().cloned() == Some('*') {
+            '*' => if self.0.peek() == Some('*') {
                 self.0.next();
                 Token::Caret
             } else {
                 Token::Asterisk
             },
-            '-' => match self.0.peek().cloned() {
+            '-' => match self.0.peek() {
                 Some('>') => {
                     self.0.next();
                     Token::DashArrow
@@ -125,20 +349,20 @@ impl<'a> Iterator for TokenIterator<'a> {
             },
             '\u{2212}' => Token::Minus,
             '/' => match self.0.peek() {
-                Some(&'/') => loop {
+                Some('/') => loop {
                     match self.0.next() {
                         None | Some('\n') => return Some(Token::Comment(1)),
                         _ => ()
                     }
                 },
-                Some(&'*') => {
+                Some('*') => {
                     let mut lines = 0;
                     loop {
-                        if let Some(&'\n') = self.0.peek() {
+                        if let Some('\n') = self.0.peek() {
                             lines += 1;
                         }
                         if let Some('*') = self.0.next() {
-                            if let Some(&'/') = self.0.peek() {
+                            if let Some('/') = self.0.peek() {
                                 self.0.next();
                                 return Some(Token::Comment(lines))
                             }
@@ -151,11 +375,11 @@ impl<'a> Iterator for TokenIterator<'a> {
                 _ => Token::Slash
             },
             x @ '0'..='9' | x @ '.' => {
-                if x == '0' && self.0.peek() == Some(&'x') {
+                if x == '0' && self.0.peek() == Some('x') {
                     self.0.next();
                     let mut hex = String::new();
 
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         match c {
                             '0'..='9' | 'a'..='f' | 'A'..='F' =>
                                 hex.push(self.0.next().unwrap()),
@@ -172,11 +396,11 @@ impl<'a> Iterator for TokenIterator<'a> {
                     return Some(Token::Hex(hex))
                 }
 
-                if x == '0' && self.0.peek() == Some(&'o') {
+                if x == '0' && self.0.peek() == Some('o') {
                     self.0.next();
                     let mut oct = String::new();
 
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         match c {
                             '0'..='7' =>
                                 oct.push(self.0.next().unwrap()),
@@ -193,11 +417,11 @@ impl<'a> Iterator for TokenIterator<'a> {
                     return Some(Token::Oct(oct))
                 }
 
-                if x == '0' && self.0.peek() == Some(&'b') {
+                if x == '0' && self.0.peek() == Some('b') {
                     self.0.next();
                     let mut bin = String::new();
 
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         match c {
                             '0' | '1' =>
                                 bin.push(self.0.next().unwrap()),
@@ -221,7 +445,7 @@ impl<'a> Iterator for TokenIterator<'a> {
                 // integer component
                 if x != '.' {
                     integer.push(x);
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         match c {
                             '0'..='9' => integer.push(self.0.next().unwrap()),
                             '\u{2009}' | '_' => {
@@ -234,12 +458,12 @@ impl<'a> Iterator for TokenIterator<'a> {
                     integer.push('0');
                 }
                 // fractional component
-                if x == '.' || Some('.') == self.0.peek().cloned() {
+                if x == '.' || Some('.') == self.0.peek() {
                     let mut buf = String::new();
                     if x != '.' {
                         self.0.next();
                     }
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         match c {
                             '0'..='9' => buf.push(self.0.next().unwrap()),
                             '\u{2009}' | '_' => {
@@ -255,13 +479,13 @@ impl<'a> Iterator for TokenIterator<'a> {
                     frac = Some(buf)
                 }
                 // exponent
-                if let Some('e') = self.0.peek().cloned().map(|x| x.to_ascii_lowercase()) {
+                if let Some('e') = self.0.peek().map(|x| x.to_ascii_lowercase()) {
                     let mut buf = String::new();
                     self.0.next();
-                    if let Some('e') = self.0.peek().cloned().map(|x| x.to_ascii_lowercase()) {
+                    if let Some('e') = self.0.peek().map(|x| x.to_ascii_lowercase()) {
                         self.0.next();
                     }
-                    if let Some(c) = self.0.peek().cloned() {
+                    if let Some(c) = self.0.peek() {
                         match c {
                             '-' => {
                                 buf.push(self.0.next().unwrap());
@@ -272,7 +496,7 @@ impl<'a> Iterator for TokenIterator<'a> {
                             _ => ()
                         }
                     }
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         match c {
                             '0'..='9' => buf.push(self.0.next().unwrap()),
                             '\u{2009}' | '_' => {
@@ -287,12 +511,29 @@ impl<'a> Iterator for TokenIterator<'a> {
                     }
                     exp = Some(buf)
                 }
-                Token::Decimal(integer, frac, exp)
+                // imaginary literals like `4i`, but not `4in`
+                let imaginary = match self.0.peek() {
+                    Some('i') | Some('j') => {
+                        let mut ahead = self.0.clone();
+                        ahead.next();
+                        match ahead.peek() {
+                            Some(c) => !(c.is_alphanumeric() || c == '_' || c == '$'),
+                            None => true,
+                        }
+                    },
+                    _ => false
+                };
+                if imaginary {
+                    self.0.next();
+                    Token::Imaginary(integer, frac, exp)
+                } else {
+                    Token::Decimal(integer, frac, exp)
+                }
             },
             '\\' => match self.0.next() {
                 Some('u') => {
                     let mut buf = String::new();
-                    while let Some(c) = self.0.peek().cloned() {
+                    while let Some(c) = self.0.peek() {
                         if c.is_digit(16) {
                             buf.push(self.0.next().unwrap());
                         } else {
@@ -345,7 +586,7 @@ impl<'a> Iterator for TokenIterator<'a> {
                         x if x.is_digit(10) => {
                             let mut integer = String::new();
                             integer.push(x);
-                            while let Some(c) = self.0.peek().cloned() {
+                            while let Some(c) = self.0.peek() {
                                 if c.is_digit(10) {
                                     self.0.next();
                                     integer.push(c);
@@ -353,10 +594,10 @@ impl<'a> Iterator for TokenIterator<'a> {
                                     break;
                                 }
                             }
-                            let frac = if let Some('.') = self.0.peek().cloned() {
+                            let frac = if let Some('.') = self.0.peek() {
                                 let mut frac = String::new();
                                 self.0.next();
-                                while let Some(c) = self.0.peek().cloned() {
+                                while let Some(c) = self.0.peek() {
                                     if c.is_digit(10) {
                                         self.0.next();
                                         frac.push(c);
@@ -373,7 +614,7 @@ impl<'a> Iterator for TokenIterator<'a> {
                         x => {
                             let mut buf = String::new();
                             buf.push(x);
-                            while let Some(c) = self.0.peek().cloned() {
+                            while let Some(c) = self.0.peek() {
                                 if !"#:-+ ".contains(c) && !c.is_digit(10) {
                                     self.0.next();
                                     buf.push(c);
@@ -413,13 +654,20 @@ impl<'a> Iterator for TokenIterator<'a> {
             x => {
                 let mut buf = String::new();
                 buf.push(x);
-                while let Some(c) = self.0.peek().cloned() {
+                while let Some(c) = self.0.peek() {
                     if c.is_alphanumeric() || c == '_' || c == '$' {
                         buf.push(self.0.next().unwrap());
                     } else {
                         break;
                     }
                 }
+                let formula = match buf.chars().next() {
+                    Some('A'..='Z') => self.formula(&buf),
+                    _ => None
+                };
+                if let Some(formula) = formula {
+                    return Some(Token::Ident(formula))
+                }
                 match &*buf {
                     "degC" | "°C" | "celsius" | "℃" => Token::DegC,
                     "degF" | "°F" | "fahrenheit" | "℉" => Token::DegF,
@@ -429,6 +677,7 @@ impl<'a> Iterator for TokenIterator<'a> {
                     "degN" | "°N" | "degnewton" => Token::DegN,
                     "per" => Token::Slash,
                     "to" | "in" => Token::DashArrow,
+                    "i" | "j" => Token::Imaginary("1".to_owned(), None, None),
                     _ => Token::Ident(buf)
                 }
             }
@@ -437,9 +686,91 @@ impl<'a> Iterator for TokenIterator<'a> {
     }
 }
 
-pub type Iter<'a> = Peekable<TokenIterator<'a>>;
+/// The tokens of a query with one token of lookahead. It keeps track
+/// of where the tokens came from, so that errors can point at them.
+#[derive(Clone)]
+pub struct TokenStream<'a> {
+    tokens: TokenIterator<'a>,
+    peeked: Option<(Token, Span)>,
+    span: Span,
+    functions: Rc<BTreeSet<String>>,
+}
 
-fn is_func(name: &str) -> bool {
+impl<'a> TokenStream<'a> {
+    pub fn new(input: &'a str) -> TokenStream<'a> {
+        TokenStream {
+            tokens: TokenIterator::new(input),
+            peeked: None,
+            span: Span { start: 0, end: 0 },
+            functions: Rc::new(BTreeSet::new()),
+        }
+    }
+
+    /// Sets the names besides the builtin functions that are called
+    /// when followed by `(`, like user functions and nonlinear units.
+    /// Other names are multiplied with the parenthesized expression
+    /// instead, so `kg (2 + 3)` is 5 kg.
+    pub fn with_functions(mut self, functions: BTreeSet<String>) -> TokenStream<'a> {
+        self.functions = Rc::new(functions);
+        self
+    }
+
+    fn is_call(&self, name: &str) -> bool {
+        is_func(name) || self.functions.contains(name)
+    }
+
+    pub fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.tokens.next_spanned());
+        }
+        match self.peeked {
+            Some((ref token, span)) => {
+                self.span = span;
+                Some(token)
+            },
+            None => None
+        }
+    }
+
+    /// The span of the token that was read last, by either `next()`
+    /// or `peek()`.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// A syntax error at the token that was read last.
+    fn syntax_error(&self, message: String) -> (String, Span) {
+        (message, self.span)
+    }
+
+    /// An expression for a syntax error at the token that was read
+    /// last.
+    fn error(&self, message: String) -> Expr {
+        Expr::Error(message, Some(self.span))
+    }
+
+    /// A query for a syntax error at the token that was read last.
+    fn query_error(&self, message: String) -> Query {
+        Query::Error(message, Some(self.span))
+    }
+}
+
+impl<'a> Iterator for TokenStream<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let (token, span) = match self.peeked.take() {
+            Some(next) => next,
+            None => self.tokens.next_spanned(),
+        };
+        self.span = span;
+        Some(token)
+    }
+}
+
+pub type Iter<'a> = TokenStream<'a>;
+
+pub fn is_func(name: &str) -> bool {
     match name {
         "sqrt" => true,
         "exp" => true,
@@ -461,6 +792,16 @@ fn is_func(name: &str) -> bool {
         "asinh" => true,
         "acosh" => true,
         "atanh" => true,
+        "dot" => true,
+        "cross" => true,
+        "norm" => true,
+        "transpose" => true,
+        "abs" => true,
+        "arg" => true,
+        "conj" => true,
+        "re" => true,
+        "im" => true,
+        "if" => true,
         _ => false
     }
 }
@@ -484,31 +825,61 @@ fn is_attr(name: &str) -> Option<&'static str> {
     }
 }
 
-fn parse_term(iter: &mut Iter) -> Expr {
+/// Parses the arguments of a call to `name`, which was read from
+/// `span`.
+fn parse_call(name: &str, span: Span, iter: &mut Iter) -> Expr {
+    iter.next();
+    let mut args = vec![];
+    loop {
+        if let Some(&Token::RPar) = iter.peek() {
+            iter.next();
+            break;
+        }
+        args.push(parse_expr(iter));
+        match iter.peek().cloned().unwrap() {
+            Token::Comma => {
+                iter.next();
+            },
+            Token::RPar => (),
+            x => return iter.error(format!(
+                "Expected `,` or `)`, got {}", describe(&x)))
+        }
+    }
+    Expr::Call(name.to_owned(), args, Some(span.join(iter.span())))
+}
+
+/// Parses the parameter list of a function definition, `(x, y)`.
+fn parse_params(iter: &mut Iter) -> Option<Vec<String>> {
     match iter.next().unwrap() {
+        Token::LPar => (),
+        _ => return None
+    }
+    let mut params = vec![];
+    if let Some(&Token::RPar) = iter.peek() {
+        iter.next();
+        return Some(params)
+    }
+    loop {
+        match iter.next().unwrap() {
+            Token::Ident(name) => params.push(name),
+            _ => return None
+        }
+        match iter.next().unwrap() {
+            Token::Comma => (),
+            Token::RPar => return Some(params),
+            _ => return None
+        }
+    }
+}
+
+fn parse_term(iter: &mut Iter) -> Expr {
+    let token = iter.next().unwrap();
+    let span = iter.span();
+    match token {
         Token::Ident(ref name) if is_func(name) => {
             match iter.peek().cloned().unwrap() {
-                Token::LPar => {
-                    iter.next();
-                    let mut args = vec![];
-                    loop {
-                        if let Some(&Token::RPar) = iter.peek() {
-                            iter.next();
-                            break;
-                        }
-                        args.push(parse_expr(iter));
-                        match iter.peek().cloned().unwrap() {
-                            Token::Comma => {
-                                iter.next();
-                            },
-                            Token::RPar => (),
-                            x => return Expr::Error(format!("Expected `,` or `)`, got {}",
-                                                            describe(&x)))
-                        }
-                    }
-                    Expr::Call(name.clone(), args)
-                },
-                _ => Expr::Call(name.clone(), vec![parse_pow(iter)]),
+                Token::LPar => parse_call(name, span, iter),
+                _ => Expr::Call(name.clone(), vec![parse_pow(iter)], Some(span)),
             }
         },
         Token::Ident(ref attr) if is_attr(attr).is_some() => {
@@ -516,66 +887,121 @@ fn parse_term(iter: &mut Iter) -> Expr {
                 Token::Ident(ref name) => {
                     let attr = is_attr(attr).unwrap();
                     iter.next();
-                    Expr::Unit(format!("{}{}", attr, name))
+                    Expr::Unit(format!("{}{}", attr, name), Some(span.join(iter.span())))
                 },
-                x => Expr::Error(format!("Attribute must be followed by ident, got {}",
-                                         describe(&x)))
+                x => iter.error(format!(
+                    "Attribute must be followed by ident, got {}", describe(&x)))
             }
         },
         Token::Ident(name) => match iter.peek().cloned().unwrap() {
+            Token::LPar if iter.is_call(&name) => parse_call(&name, span, iter),
             Token::Ident(ref s) if s == "of" => {
                 iter.next();
-                Expr::Of(name.clone(), Box::new(parse_juxt(iter)))
+                let value = juxt(iter, true);
+                match iter.peek().cloned().unwrap() {
+                    Token::Ident(ref s) if s == "at" => {
+                        iter.next();
+                        Expr::OfAt(name.clone(), Box::new(value), Box::new(parse_juxt(iter)))
+                    },
+                    _ => Expr::Of(name.clone(), Box::new(value))
+                }
             },
-            _ => Expr::Unit(name)
+            _ => Expr::Unit(name, Some(span))
         },
         Token::Quote(name) => Expr::Quote(name),
         Token::Decimal(num, frac, exp) =>
             ::number::Number::from_parts(&*num, frac.as_ref().map(|x| &**x), exp.as_ref().map(|x| &**x))
-            .map(Expr::Const)
-            .unwrap_or_else(|e| Expr::Error(format!("{}", e))),
+            .map(|x| Expr::Const(x, Some(span)))
+            .unwrap_or_else(|e| iter.error(format!("{}", e))),
+        Token::Imaginary(num, frac, exp) =>
+            ::number::Number::from_parts(&*num, frac.as_ref().map(|x| &**x), exp.as_ref().map(|x| &**x))
+            .map(Expr::Imaginary)
+            .unwrap_or_else(|e| iter.error(format!("{}", e))),
         Token::Hex(num) =>
             Mpz::from_str_radix(&*num, 16)
             .map(|x| Mpq::ratio(&x, &Mpz::one()))
             .map(Num::Mpq)
-            .map(Expr::Const)
-            .unwrap_or_else(|_| Expr::Error(format!("Failed to parse hex"))),
+            .map(|x| Expr::Const(x, Some(span)))
+            .unwrap_or_else(|_| iter.error(format!("Failed to parse hex"))),
         Token::Oct(num) =>
             Mpz::from_str_radix(&*num, 8)
             .map(|x| Mpq::ratio(&x, &Mpz::one()))
             .map(Num::Mpq)
-            .map(Expr::Const)
-            .unwrap_or_else(|_| Expr::Error(format!("Failed to parse octal"))),
+            .map(|x| Expr::Const(x, Some(span)))
+            .unwrap_or_else(|_| iter.error(format!("Failed to parse octal"))),
         Token::Bin(num) =>
             Mpz::from_str_radix(&*num, 2)
             .map(|x| Mpq::ratio(&x, &Mpz::one()))
             .map(Num::Mpq)
-            .map(Expr::Const)
-            .unwrap_or_else(|_| Expr::Error(format!("Failed to parse binary"))),
+            .map(|x| Expr::Const(x, Some(span)))
+            .unwrap_or_else(|_| iter.error(format!("Failed to parse binary"))),
         Token::Plus => Expr::Plus(Box::new(parse_term(iter))),
         Token::Minus => Expr::Neg(Box::new(parse_term(iter))),
+        // `+-42` in prefix position is still a sign
+        Token::PlusMinus => Expr::Plus(Box::new(Expr::Neg(Box::new(parse_term(iter))))),
         Token::LPar => {
             let res = parse_expr(iter);
             match iter.next().unwrap() {
                 Token::RPar => res,
-                x => Expr::Error(format!("Expected `)`, got {}", describe(&x)))
+                x => iter.error(format!("Expected `)`, got {}", describe(&x)))
             }
         },
-        Token::Percent => Expr::Unit("percent".to_owned()),
+        Token::LBracket => {
+            let mut elems = vec![];
+            if let Some(&Token::RBracket) = iter.peek() {
+                iter.next();
+                return Expr::Vector(elems)
+            }
+            loop {
+                elems.push(parse_expr(iter));
+                match iter.next().unwrap() {
+                    Token::Comma => (),
+                    Token::RBracket => break,
+                    x => return iter.error(format!(
+                        "Expected `,` or `]`, got {}", describe(&x)))
+                }
+            }
+            Expr::Vector(elems)
+        },
+        Token::Percent => Expr::Unit("percent".to_owned(), Some(span)),
         Token::Date(toks) => Expr::Date(toks),
         Token::Comment(_) => parse_term(iter),
-        x => Expr::Error(format!("Expected term, got {}", describe(&x))),
+        x => iter.error(format!("Expected term, got {}", describe(&x))),
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Const(_, _) => true,
+        Expr::Neg(ref expr) | Expr::Plus(ref expr) => is_literal(expr),
+        _ => false
     }
 }
 
 fn parse_suffix(iter: &mut Iter) -> Expr {
     let left = parse_term(iter);
-    match *iter.peek().unwrap() {
+    match iter.peek().cloned().unwrap() {
+        // between two numbers, as in `5.0 ± 0.2 m`, the uncertainty
+        // binds tighter than the units. Otherwise, as in `5 m ± 2 cm`,
+        // it is parsed like addition.
+        Token::PlusMinus if is_literal(&left) && {
+            let mut ahead = iter.clone();
+            ahead.next();
+            match ahead.next().unwrap() {
+                Token::Decimal(_, _, _) | Token::Hex(_) |
+                Token::Oct(_) | Token::Bin(_) => true,
+                _ => false
+            }
+        } => {
+            iter.next();
+            let right = parse_term(iter);
+            Expr::PlusMinus(Box::new(left), Box::new(right))
+        },
         Token::Percent => {
             let mut left = left;
             while let Some(&Token::Percent) = iter.peek() {
                 iter.next();
-                left = Expr::Mul(vec![left, Expr::Unit("percent".to_owned())]);
+                left = Expr::Mul(vec![left, Expr::Unit("percent".to_owned(), Some(iter.span()))]);
             }
             left
         },
@@ -607,13 +1033,89 @@ fn parse_frac(iter: &mut Iter) -> Expr {
     }
 }
 
+/// Whether the next tokens are `in reaction`, which ends the amount
+/// in a query like `10 g CH4 in reaction CH4 + O2 -> CO2 + H2O -> mass of CO2`.
+/// `in` is lexed the same way as `->`.
+fn at_reaction(iter: &Iter) -> bool {
+    let mut ahead = iter.clone();
+    match (ahead.next(), ahead.next()) {
+        (Some(Token::DashArrow), Some(Token::Ident(ref b))) => b == "reaction",
+        _ => false
+    }
+}
+
+/// Parses a chemical reaction like `CH4 + 2 O2 -> CO2 + 2 H2O`, up to
+/// the end of the query or the next `->`.
+pub fn parse_reaction(iter: &mut Iter) -> Result<Reaction, (String, Span)> {
+    iter.tokens.1 = true;
+    let res = reaction(iter);
+    iter.tokens.1 = false;
+    res
+}
+
+fn reaction(iter: &mut Iter) -> Result<Reaction, (String, Span)> {
+    fn side(iter: &mut Iter) -> Result<Vec<(Option<u64>, String)>, (String, Span)> {
+        let mut species = vec![];
+        loop {
+            let coefficient = match iter.peek().cloned().unwrap() {
+                Token::Decimal(ref int, None, None) => {
+                    iter.next();
+                    match u64::from_str_radix(&*int, 10) {
+                        Ok(0) => return Err(iter.syntax_error(format!(
+                            "Coefficients must be positive"))),
+                        Ok(v) => Some(v),
+                        Err(e) => return Err(iter.syntax_error(format!(
+                            "Failed to parse coefficient: {}", e))),
+                    }
+                },
+                _ => None
+            };
+            match iter.next().unwrap() {
+                Token::Ident(ref name) if ::formula::is_formula(name) =>
+                    species.push((coefficient, name.clone())),
+                Token::Ident(ref name) =>
+                    return Err(iter.syntax_error(format!("{} is not a chemical formula", name))),
+                x => return Err(iter.syntax_error(format!(
+                    "Expected chemical formula, got {}", describe(&x)))),
+            }
+            match *iter.peek().unwrap() {
+                Token::Plus => {
+                    iter.next();
+                },
+                _ => return Ok(species)
+            }
+        }
+    }
+    let reactants = try!(side(iter));
+    match iter.next().unwrap() {
+        Token::DashArrow => (),
+        x => return Err(iter.syntax_error(format!(
+            "Expected `->` between reactants and products, got {}", describe(&x))))
+    }
+    let products = try!(side(iter));
+    Ok(Reaction {
+        reactants: reactants,
+        products: products,
+    })
+}
+
 fn parse_juxt(iter: &mut Iter) -> Expr {
+    juxt(iter, false)
+}
+
+/// Juxtaposition, which in the operand of `of` stops at `at`, as in
+/// `density of water at 60 °C`, instead of reading it as the
+/// technical atmosphere. `within` always ends it, for the tolerance
+/// of `assert 1 mile == 1609 m within 0.1%`.
+fn juxt(iter: &mut Iter, of: bool) -> Expr {
     let mut terms = vec![parse_frac(iter)];
     loop { match iter.peek().cloned().unwrap() {
         Token::Asterisk | Token::Slash | Token::Comma | Token::Equals |
-        Token::Plus | Token::Minus | Token::DashArrow |
-        Token::RPar | Token::Newline |
+        Token::Plus | Token::Minus | Token::PlusMinus | Token::DashArrow |
+        Token::RPar | Token::RBracket | Token::Newline | Token::Compare(_) |
         Token::Comment(_) | Token::Eof => break,
+        Token::Ident(ref s) if s == "at" && of => break,
+        Token::Ident(ref s) if s == "within" => break,
         Token::DegC => {
             iter.next();
             terms = vec![Expr::Suffix(SuffixOp::Celsius, Box::new(Expr::Mul(terms)))]
@@ -685,16 +1187,33 @@ fn parse_add(iter: &mut Iter) -> Expr {
             let right = parse_div(iter);
             left = Expr::Sub(Box::new(left), Box::new(right))
         },
+        Token::PlusMinus => {
+            iter.next();
+            let right = parse_div(iter);
+            left = Expr::PlusMinus(Box::new(left), Box::new(right))
+        },
         _ => return left
     }}
 }
 
-fn parse_eq(iter: &mut Iter) -> Expr {
+fn parse_compare(iter: &mut Iter) -> Expr {
     let left = parse_add(iter);
     match iter.peek().cloned().unwrap() {
-        Token::Equals => {
+        Token::Compare(op) => {
             iter.next();
             let right = parse_add(iter);
+            Expr::Compare(op, Box::new(left), Box::new(right))
+        },
+        _ => left
+    }
+}
+
+fn parse_eq(iter: &mut Iter) -> Expr {
+    let left = parse_compare(iter);
+    match iter.peek().cloned().unwrap() {
+        Token::Equals => {
+            iter.next();
+            let right = parse_compare(iter);
             Expr::Equals(Box::new(left), Box::new(right))
         },
         _ => left
@@ -770,9 +1289,94 @@ pub fn parse_query(iter: &mut Iter) -> Query {
                 return Query::Search(s.clone())
             }
         },
+        Some(Token::Ident(ref s)) if s == "vars" => {
+            let mut copy = iter.clone();
+            copy.next();
+            if let Some(Token::Eof) = copy.peek().cloned() {
+                *iter = copy;
+                return Query::Vars
+            }
+        },
+        Some(Token::Ident(ref s)) if s == "balance" => {
+            iter.next();
+            return match parse_reaction(iter) {
+                Ok(reaction) => match iter.next().unwrap() {
+                    Token::Eof => Query::Balance(reaction),
+                    x => iter.query_error(format!(
+                        "Expected end of reaction, got {}", describe(&x)))
+                },
+                Err((e, span)) => Query::Error(e, Some(span)),
+            }
+        },
+        Some(Token::Ident(ref s)) if s == "explain" => {
+            iter.next();
+            return Query::Explain(Box::new(parse_query(iter)))
+        },
+        Some(Token::Ident(ref s)) if s == "assert" => {
+            iter.next();
+            let expr = parse_eq(iter);
+            let within = match iter.peek().cloned().unwrap() {
+                Token::Ident(ref s) if s == "within" => {
+                    iter.next();
+                    Some(parse_eq(iter))
+                },
+                _ => None
+            };
+            return match iter.next().unwrap() {
+                Token::Eof | Token::Comment(_) => Query::Assert(expr, within),
+                x => iter.query_error(format!(
+                    "Expected end of assertion, got {}", describe(&x)))
+            }
+        },
+        Some(Token::Ident(ref s)) if s == "unset" => {
+            iter.next();
+            return match iter.next().unwrap() {
+                Token::Ident(name) => Query::Unset(name),
+                x => iter.query_error(format!(
+                    "Expected variable name, got {}", describe(&x)))
+            }
+        },
+        Some(Token::Ident(ref name)) => {
+            let mut copy = iter.clone();
+            copy.next();
+            if let Some(params) = parse_params(&mut copy) {
+                if let (Some(Token::Colon), Some(Token::Equals)) = (copy.next(), copy.next()) {
+                    *iter = copy;
+                    // so that calls to itself are found
+                    Rc::make_mut(&mut iter.functions).insert(name.clone());
+                    let body = parse_eq(iter);
+                    let result = match iter.peek().cloned().unwrap() {
+                        Token::DashArrow => {
+                            iter.next();
+                            Some(parse_eq(iter))
+                        },
+                        _ => None
+                    };
+                    return Query::Function(name.clone(), FunctionDef {
+                        params: params,
+                        body: body,
+                        result: result,
+                    })
+                }
+            }
+        },
         _ => ()
     }
     let left = parse_eq(iter);
+    if at_reaction(iter) {
+        iter.next();
+        iter.next();
+        let reaction = match parse_reaction(iter) {
+            Ok(reaction) => reaction,
+            Err((e, span)) => return Query::Error(e, Some(span)),
+        };
+        return match iter.next().unwrap() {
+            Token::DashArrow => Query::Stoichiometry(left, reaction, parse_eq(iter)),
+            x => iter.query_error(format!(
+                "Expected `->` followed by what to compute, as in `-> mass of CO2`, got {}",
+                describe(&x)))
+        }
+    }
     match iter.peek().cloned().unwrap() {
         Token::DashArrow => {
             use std::str::FromStr;
@@ -792,7 +1396,7 @@ pub fn parse_query(iter: &mut Iter) -> Query {
                             iter.next();
                             match u64::from_str_radix(&*int, 10) {
                                 Ok(v) => Digits::Digits(v),
-                                Err(e) => return Query::Error(format!(
+                                Err(e) => return iter.query_error(format!(
                                     "Failed to parse digits: {}", e
                                 ))
                             }
@@ -810,14 +1414,14 @@ pub fn parse_query(iter: &mut Iter) -> Query {
                             Ok(v) if v >= 2 && v <= 36 => {
                                 Some(v as u8)
                             },
-                            Ok(v) => return Query::Error(format!(
+                            Ok(v) => return iter.query_error(format!(
                                 "Unsupported base {}, must be from 2 to 36", v)),
-                            Err(e) => return Query::Error(format!(
+                            Err(e) => return iter.query_error(format!(
                                 "Failed to parse base: {}", e))
                         },
-                        Some(x) => return Query::Error(format!(
+                        Some(x) => return iter.query_error(format!(
                             "Expected decimal base, got {}", describe(&x))),
-                        None => return Query::Error(format!(
+                        None => return iter.query_error(format!(
                             "Expected decimal base, got eof"))
                     }
                 },
@@ -848,9 +1452,31 @@ pub fn parse_query(iter: &mut Iter) -> Query {
                     if let Some(off) = parse_offset(iter) {
                         Conversion::Offset(off)
                     } else {
-                        Conversion::Expr(parse_eq(&mut old))
+                        let res = parse_eq(&mut old);
+                        *iter = old;
+                        Conversion::Expr(res)
                     }
                 },
+                Token::Ident(ref s) if s == "interval" => {
+                    iter.next();
+                    Conversion::Interval
+                },
+                Token::Ident(ref s) if s == "polar" => {
+                    iter.next();
+                    Conversion::Polar
+                },
+                Token::Ident(ref s) if s == "composition" => {
+                    iter.next();
+                    Conversion::Composition
+                },
+                Token::Ident(ref s) if s == "alternatives" => {
+                    iter.next();
+                    Conversion::Alternatives
+                },
+                Token::Ident(ref s) if UnitSystem::parse(s).is_some() => {
+                    iter.next();
+                    Conversion::System(UnitSystem::parse(s).unwrap())
+                },
                 Token::Ident(ref s) if Tz::from_str(s).is_ok() => {
                     Conversion::Timezone(Tz::from_str(s).expect(
                         "Running from_str a second time failed"
@@ -867,9 +1493,10 @@ pub fn parse_query(iter: &mut Iter) -> Query {
 #[cfg(test)]
 mod test {
     use super::*;
+    use context::Context;
 
     fn parse(input: &str) -> String {
-        parse_expr(&mut TokenIterator::new(input).peekable()).to_string()
+        parse_expr(&mut TokenStream::new(input)).to_string()
     }
 
     #[test]
@@ -878,6 +1505,13 @@ mod test {
                    "(((a + b) - c) + d) - e");
     }
 
+    #[test]
+    fn plus_minus() {
+        assert_eq!(parse("5.0 ± 0.2 m"), "(5 ± 0.2) m");
+        assert_eq!(parse("5 m +- 2 cm"), "5 m ± 2 cm");
+        assert_eq!(parse("+-42"), "+-42");
+    }
+
     #[test]
     fn sub_crash_regression() {
         assert_eq!(parse("-"),
@@ -894,6 +1528,13 @@ mod test {
                    "(a / b) / c");
     }
 
+    #[test]
+    fn comparisons() {
+        assert_eq!(parse("a b > c + d"), "a b > c + d");
+        assert_eq!(parse("x = a ~= b"), "x = a ~= b");
+        assert_eq!(parse("if(a == b, c, d)"), "if(a == b, c, d)");
+    }
+
     #[test]
     fn suffix_prec() {
         assert_eq!(parse("a b °C + x y °F"),
@@ -917,15 +1558,106 @@ mod test {
     #[test]
     fn mono_unit_list() {
         use ast::*;
-        match parse_query(&mut TokenIterator::new("foo -> bar").peekable()) {
+        match parse_query(&mut TokenStream::new("foo -> bar")) {
             Query::Convert(_, Conversion::Expr(_), _, _) => (),
             x => panic!("Expected Convert(_, Expr(_), _), got {:?}", x),
         }
     }
 
+    #[test]
+    fn test_unset() {
+        match parse_query(&mut TokenStream::new("unset x")) {
+            Query::Unset(ref name) if name == "x" => (),
+            x => panic!("Expected Unset(x), got {:?}", x),
+        }
+        match parse_query(&mut TokenStream::new("vars")) {
+            Query::Vars => (),
+            x => panic!("Expected Vars, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_function_def() {
+        match parse_query(&mut TokenStream::new("f(x, y) := x^2 / y -> m")) {
+            Query::Function(ref name, ref def) if name == "f" =>
+                assert_eq!(def.to_string(), "(x, y) := x^2 / y -> m"),
+            x => panic!("Expected Function(f, _), got {:?}", x),
+        }
+        let mut functions = BTreeSet::new();
+        functions.insert("f".to_owned());
+        let mut iter = TokenStream::new("f(1, 2 m) + kg (3)").with_functions(functions);
+        assert_eq!(parse_expr(&mut iter).to_string(), "f(1, 2 m) + kg 3");
+        assert_eq!(parse("g(1, 2)"), "g <error: Expected `)`, got `,`> 2");
+    }
+
+    #[test]
+    fn test_reaction() {
+        match parse_query(&mut TokenStream::new("balance CH4 + O2 -> CO2 + H2O")) {
+            Query::Balance(ref reaction) =>
+                assert_eq!(reaction.to_string(), "CH4 + O2 -> CO2 + H2O"),
+            x => panic!("Expected Balance(_), got {:?}", x),
+        }
+        match parse_query(&mut TokenStream::new(
+            "10 g CH4 in reaction CH4 + 2 O2 -> CO2 + 2H2O -> mass of CO2"
+        )) {
+            Query::Stoichiometry(ref given, ref reaction, ref wanted) => {
+                assert_eq!(given.to_string(), "10 g CH4");
+                assert_eq!(reaction.to_string(), "CH4 + 2 O2 -> CO2 + 2 H2O");
+                assert_eq!(wanted.to_string(), "mass of CO2");
+            },
+            x => panic!("Expected Stoichiometry(_, _, _), got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_assert() {
+        match parse_query(&mut TokenStream::new("assert 2 mile == 3218 m within 0.1%")) {
+            Query::Assert(ref expr, Some(ref within)) => {
+                assert_eq!(expr.to_string(), "2 mile == 3218 m");
+                assert_eq!(within.to_string(), "0.1 percent");
+            },
+            x => panic!("Expected Assert(_, Some(_)), got {:?}", x),
+        }
+        match parse_query(&mut TokenStream::new("assert a > b // note")) {
+            Query::Assert(ref expr, None) => assert_eq!(expr.to_string(), "a > b"),
+            x => panic!("Expected Assert(_, None), got {:?}", x),
+        }
+        match parse_query(&mut TokenStream::new("assert a == b c)")) {
+            Query::Error(ref e, _) => assert_eq!(e, "Expected end of assertion, got `)`"),
+            x => panic!("Expected Error(_), got {:?}", x),
+        }
+    }
+
     #[test]
     fn test_of() {
         assert_eq!(parse("foo of 1 abc def / 12"),
                    "(foo of 1 abc def) / 12");
     }
+
+    #[test]
+    fn test_spans() {
+        let mut tokens = TokenIterator::new("2  km->µm");
+        let mut spans = vec![];
+        loop {
+            match tokens.next_spanned() {
+                (Token::Eof, span) => {
+                    spans.push(span);
+                    break
+                },
+                (_, span) => spans.push(span),
+            }
+        }
+        let spans = spans.iter().map(|s| (s.start, s.end)).collect::<Vec<_>>();
+        assert_eq!(spans, vec![(0, 1), (3, 5), (5, 7), (7, 10), (10, 10)]);
+
+        let error_span = |input: &str| {
+            let query = parse_query(&mut TokenStream::new(input));
+            let error = Context::new().eval_query(&query).unwrap_err();
+            error.span().unwrap().underline(input)
+        };
+        assert_eq!(error_span("(2 m"), "    ^");
+        assert_eq!(error_span("3 (4 ] m"), "     ^");
+        assert_eq!(error_span("*"), "^");
+        assert_eq!(error_span("0x"), "^~");
+    }
 }
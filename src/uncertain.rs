@@ -0,0 +1,263 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use context::Context;
+use number::{Number, NumberParts};
+use num::Num;
+use value::Show;
+
+/// A measured value with a standard uncertainty, like `5.0 ± 0.2 m`.
+/// Uncertainties are propagated to first order, treating every
+/// operand as independent of the others.
+#[derive(Debug, Clone)]
+pub struct Uncertain {
+    pub value: Number,
+    /// The uncertainty, in the same units as the value. Always
+    /// non-negative.
+    pub error: Num,
+}
+
+fn is_zero(num: &Num) -> bool {
+    num.to_f64() == 0.0
+}
+
+/// Adds two independent uncertainties, keeping the result exact when
+/// one of them is zero.
+fn quadrature(left: &Num, right: &Num) -> Num {
+    if is_zero(right) {
+        left.abs()
+    } else if is_zero(left) {
+        right.abs()
+    } else {
+        Num::Float(left.to_f64().hypot(right.to_f64()))
+    }
+}
+
+/// Formats a number rounded to the given decimal place, which is
+/// negative for digits after the decimal point.
+fn fixed(value: f64, place: i32) -> String {
+    if place >= 0 {
+        let scale = 10f64.powi(place);
+        format!("{:.0}", (value / scale).round() * scale)
+    } else {
+        format!("{:.*}", (-place) as usize, value)
+    }
+}
+
+/// Rounds a value to the precision of its uncertainty, returning the
+/// value and the uncertainty for the `5.0 ± 0.2` form, and the concise
+/// form `5.00(20)`, which always gives two digits of the uncertainty.
+pub fn format_uncertain(value: f64, error: f64) -> (String, String, String) {
+    let magnitude = value.abs().max(error);
+    let exponent = if magnitude >= 1e9 || magnitude < 1e-4 {
+        magnitude.log10().floor() as i32
+    } else {
+        0
+    };
+    let suffix = if exponent != 0 {
+        format!("e{}", exponent)
+    } else {
+        String::new()
+    };
+    let value = value / 10f64.powi(exponent);
+    let error = error / 10f64.powi(exponent);
+
+    // place of the second significant digit of the uncertainty
+    let mut place = error.log10().floor() as i32 - 1;
+    let mut digits = (error / 10f64.powi(place)).round() as i64;
+    if digits < 10 {
+        place -= 1;
+        digits = (error / 10f64.powi(place)).round() as i64;
+    }
+    if digits >= 100 {
+        place += 1;
+        digits = (digits as f64 / 10.0).round() as i64;
+    }
+    let concise = format!(
+        "{}({}){}",
+        fixed(value, place),
+        if place > 0 { digits * 10i64.pow(place as u32) } else { digits },
+        suffix
+    );
+    if digits % 10 == 0 {
+        digits /= 10;
+        place += 1;
+    }
+    (format!("{}{}", fixed(value, place), suffix),
+     format!("{}{}", fixed(digits as f64 * 10f64.powi(place), place), suffix),
+     concise)
+}
+
+impl Uncertain {
+    /// Creates an uncertain value, as in `5 m ± 2 cm`.
+    pub fn new(value: &Number, error: &Number) -> Result<Uncertain, String> {
+        if value.unit != error.unit {
+            return Err(format!(
+                "Uncertainty must have the same units as the value"
+            ))
+        }
+        Ok(Uncertain {
+            value: value.clone(),
+            error: error.value.abs(),
+        })
+    }
+
+    /// Treats a number as a value without uncertainty, so that it can
+    /// be combined with uncertain values.
+    pub fn exact(value: &Number) -> Uncertain {
+        Uncertain {
+            value: value.clone(),
+            error: Num::zero(),
+        }
+    }
+
+    pub fn add(&self, other: &Uncertain) -> Result<Uncertain, String> {
+        let value = try!((&self.value + &other.value).ok_or(format!(
+            "Addition of units with mismatched units is not meaningful"
+        )));
+        Ok(Uncertain {
+            value: value,
+            error: quadrature(&self.error, &other.error),
+        })
+    }
+
+    pub fn sub(&self, other: &Uncertain) -> Result<Uncertain, String> {
+        let value = try!((&self.value - &other.value).ok_or(format!(
+            "Subtraction of units with mismatched units is not meaningful"
+        )));
+        Ok(Uncertain {
+            value: value,
+            error: quadrature(&self.error, &other.error),
+        })
+    }
+
+    pub fn neg(&self) -> Uncertain {
+        Uncertain {
+            value: (-&self.value).expect("Bug: Negation should not fail"),
+            error: self.error.clone(),
+        }
+    }
+
+    pub fn mul(&self, other: &Uncertain) -> Result<Uncertain, String> {
+        let value = try!((&self.value * &other.value).ok_or(format!(
+            "Bug: Mul should not fail"
+        )));
+        Ok(Uncertain {
+            value: value,
+            error: quadrature(
+                &(&other.value.value.abs() * &self.error),
+                &(&self.value.value.abs() * &other.error)
+            ),
+        })
+    }
+
+    pub fn div(&self, other: &Uncertain) -> Result<Uncertain, String> {
+        let value = try!((&self.value / &other.value).ok_or(format!(
+            "Division by zero"
+        )));
+        let divisor = other.value.value.abs();
+        Ok(Uncertain {
+            value: value,
+            error: quadrature(
+                &(&self.error / &divisor),
+                &(&(&self.value.value.abs() * &other.error) / &(&divisor * &divisor))
+            ),
+        })
+    }
+
+    pub fn pow(&self, exp: &Uncertain) -> Result<Uncertain, String> {
+        if !is_zero(&exp.error) && !self.value.dimless() {
+            return Err(format!(
+                "Exponent with an uncertainty requires a dimensionless base"
+            ))
+        }
+        let value = try!(self.value.pow(&exp.value));
+        let x = self.value.value.to_f64();
+        let n = exp.value.value.to_f64();
+        let base = if is_zero(&self.error) {
+            Num::zero()
+        } else {
+            Num::Float((n * x.powf(n - 1.0) * self.error.to_f64()).abs())
+        };
+        let power = if is_zero(&exp.error) {
+            Num::zero()
+        } else {
+            Num::Float((value.value.to_f64() * x.ln() * exp.error.to_f64()).abs())
+        };
+        Ok(Uncertain {
+            value: value,
+            error: quadrature(&base, &power),
+        })
+    }
+
+    pub fn sqrt(&self) -> Result<Uncertain, String> {
+        let value = try!(self.value.root(2));
+        let error = if is_zero(&self.error) {
+            Num::zero()
+        } else {
+            Num::Float(self.error.to_f64() / (2.0 * value.value.to_f64()))
+        };
+        Ok(Uncertain {
+            value: value,
+            error: error,
+        })
+    }
+
+    /// Applies a function to the value, propagating the uncertainty
+    /// through its derivative.
+    pub fn apply(&self, func: fn(f64) -> f64, derivative: fn(f64) -> f64) -> Uncertain {
+        let x = self.value.value.to_f64();
+        Uncertain {
+            value: Number {
+                value: Num::Float(func(x)),
+                unit: self.value.unit.clone(),
+            },
+            error: Num::Float((derivative(x) * self.error.to_f64()).abs()),
+        }
+    }
+
+    /// Replaces the numeric value in the parts with the value and its
+    /// uncertainty, scaled by the given factor.
+    pub fn fill_parts(&self, factor: f64, parts: NumberParts) -> NumberParts {
+        if is_zero(&self.error) {
+            return parts
+        }
+        let (value, error, concise) = format_uncertain(
+            self.value.value.to_f64() * factor,
+            self.error.to_f64() * factor
+        );
+        NumberParts {
+            exact_value: None,
+            approx_value: Some(value),
+            uncertainty: Some(error),
+            concise_value: Some(concise),
+            ..parts
+        }
+    }
+
+    pub fn to_parts(&self, context: &Context) -> NumberParts {
+        // the prefix is picked for the value, or for the uncertainty
+        // when the value is zero
+        let basis = if is_zero(&self.value.value) {
+            Number {
+                value: self.error.clone(),
+                unit: self.value.unit.clone(),
+            }
+        } else {
+            self.value.clone()
+        };
+        let factor = if is_zero(&basis.value) {
+            1.0
+        } else {
+            basis.prettify(context).value.to_f64() / basis.value.to_f64()
+        };
+        self.fill_parts(factor, basis.to_parts(context))
+    }
+}
+
+impl Show for Uncertain {
+    fn show(&self, context: &Context) -> String {
+        format!("{}", self.to_parts(context))
+    }
+}
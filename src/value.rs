@@ -2,11 +2,17 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use number::Number;
+use number::{Number, Unit};
 use chrono::{DateTime, FixedOffset};
 use chrono_tz::Tz;
 use context::Context;
 use substance::Substance;
+use logarithmic::Logarithmic;
+use uncertain::Uncertain;
+use complex::Complex;
+use vector::Vector;
+use mixture::Mixture;
+use num::{Num, Int};
 use std::ops::{Add, Div, Mul, Neg, Sub};
 use date;
 use date::GenericDateTime;
@@ -16,6 +22,13 @@ pub enum Value {
     Number(Number),
     DateTime(date::GenericDateTime),
     Substance(Substance),
+    Logarithmic(Logarithmic),
+    Uncertain(Uncertain),
+    Complex(Complex),
+    Vector(Vector),
+    Mixture(Mixture),
+    /// The result of a comparison.
+    Bool(bool),
 }
 
 pub trait Show {
@@ -58,16 +71,84 @@ impl Show for Value {
             Value::Number(ref num) => num.show(context),
             Value::DateTime(ref dt) => dt.show(context),
             Value::Substance(ref v) => v.show(context),
+            Value::Logarithmic(ref v) => v.show(context),
+            Value::Uncertain(ref v) => v.show(context),
+            Value::Complex(ref v) => v.show(context),
+            Value::Vector(ref v) => v.show(context),
+            Value::Mixture(ref v) => v.show(context),
+            Value::Bool(v) => v.to_string(),
         }
     }
 }
 
+/// The substances in a mixture, or the substance itself.
+fn components(value: &Value) -> Vec<Substance> {
+    match *value {
+        Value::Substance(ref sub) => vec![sub.clone()],
+        Value::Mixture(ref mix) => mix.components.clone(),
+        _ => vec![],
+    }
+}
+
+/// Treats a pair of values as uncertain when at least one of them is,
+/// so that uncertainties propagate through arithmetic with exact
+/// numbers.
+fn uncertain_pair(left: &Value, right: &Value) -> Option<(Uncertain, Uncertain)> {
+    match (left, right) {
+        (&Value::Uncertain(ref left), &Value::Uncertain(ref right)) =>
+            Some((left.clone(), right.clone())),
+        (&Value::Uncertain(ref left), &Value::Number(ref right)) =>
+            Some((left.clone(), Uncertain::exact(right))),
+        (&Value::Number(ref left), &Value::Uncertain(ref right)) =>
+            Some((Uncertain::exact(left), right.clone())),
+        _ => None
+    }
+}
+
+/// Treats a pair of values as complex when at least one of them is.
+fn complex_pair(left: &Value, right: &Value) -> Option<(Complex, Complex)> {
+    match (left, right) {
+        (&Value::Complex(ref left), &Value::Complex(ref right)) =>
+            Some((left.clone(), right.clone())),
+        (&Value::Complex(ref left), &Value::Number(ref right)) =>
+            Some((left.clone(), Complex::from_number(right))),
+        (&Value::Number(ref left), &Value::Complex(ref right)) =>
+            Some((Complex::from_number(left), right.clone())),
+        _ => None
+    }
+}
+
 impl Value {
     pub fn pow(&self, exp: &Value) -> Result<Value, String> {
         match (self, exp) {
+            // fractional powers of negative numbers are complex
+            (&Value::Number(ref left), &Value::Number(ref right))
+                if left.value < Num::zero() && right.value.to_rational().1 != Int::one() =>
+                Complex::from_number(left).pow(&Complex::from_number(right))
+                .map(Complex::into_value),
             (&Value::Number(ref left), &Value::Number(ref right)) =>
                 left.pow(right).map(Value::Number),
-            (_, _) => Err(format!("Operation is not defined"))
+            (left, right) => if let Some((left, right)) = uncertain_pair(left, right) {
+                left.pow(&right).map(Value::Uncertain)
+            } else if let Some((left, right)) = complex_pair(left, right) {
+                left.pow(&right).map(Complex::into_value)
+            } else {
+                Err(format!("Operation is not defined"))
+            }
+        }
+    }
+
+    /// The units of the numbers making up the value, one per vector
+    /// component. Levels have the unit of their linear value. Values
+    /// that aren't measured in units, like dates, have none.
+    pub fn units(&self) -> Option<Vec<Unit>> {
+        match *self {
+            Value::Number(ref num) => Some(vec![num.unit.clone()]),
+            Value::Uncertain(ref unc) => Some(vec![unc.value.unit.clone()]),
+            Value::Complex(ref c) => Some(vec![c.unit.clone()]),
+            Value::Vector(ref v) => Some(v.elems.iter().map(|x| x.unit.clone()).collect()),
+            Value::Logarithmic(ref log) => Some(vec![log.to_linear().unit]),
+            _ => None,
         }
     }
 }
@@ -93,10 +174,33 @@ impl<'a,'b> Add<&'b Value> for &'a Value {
                 }
                 .ok_or(format!("Implementation error: value is out of range representable by datetime"))
                 .map(Value::DateTime),
-            (&Value::Substance(ref left), &Value::Substance(ref right)) =>
+            (&Value::Substance(ref left), &Value::Substance(ref right))
+                if left.amount.dimless() && right.amount.dimless() =>
                 left.add(right)
                 .map(Value::Substance),
-            (_, _) => Err(format!("Operation is not defined"))
+            // `100 ml water + 5 g NaCl`
+            (&Value::Substance(_), &Value::Substance(_)) |
+            (&Value::Substance(_), &Value::Mixture(_)) |
+            (&Value::Mixture(_), &Value::Substance(_)) |
+            (&Value::Mixture(_), &Value::Mixture(_)) =>
+                Mixture::new(components(self).into_iter().chain(components(other)).collect())
+                .map(Value::Mixture),
+            (&Value::Logarithmic(ref left), &Value::Logarithmic(ref right)) =>
+                left.add(right)
+                .map(Value::Logarithmic),
+            (&Value::Logarithmic(_), &Value::Number(_)) |
+            (&Value::Number(_), &Value::Logarithmic(_)) =>
+                Err(format!("Addition of logarithmic and linear values is not meaningful, \
+                             convert one of them with ->")),
+            (&Value::Vector(ref left), &Value::Vector(ref right)) =>
+                left.add(right).map(Value::Vector),
+            (left, right) => if let Some((left, right)) = uncertain_pair(left, right) {
+                left.add(&right).map(Value::Uncertain)
+            } else if let Some((left, right)) = complex_pair(left, right) {
+                left.add(&right).map(Complex::into_value)
+            } else {
+                Err(format!("Operation is not defined"))
+            }
         }
     }
 }
@@ -134,7 +238,22 @@ impl<'a,'b> Sub<&'b Value> for &'a Value {
                         *left - *right,
                 })
                 .map(Value::Number),
-            (_, _) => Err(format!("Operation is not defined"))
+            (&Value::Logarithmic(ref left), &Value::Logarithmic(ref right)) =>
+                left.sub(right)
+                .map(Value::Logarithmic),
+            (&Value::Logarithmic(_), &Value::Number(_)) |
+            (&Value::Number(_), &Value::Logarithmic(_)) =>
+                Err(format!("Subtraction of logarithmic and linear values is not meaningful, \
+                             convert one of them with ->")),
+            (&Value::Vector(ref left), &Value::Vector(ref right)) =>
+                left.sub(right).map(Value::Vector),
+            (left, right) => if let Some((left, right)) = uncertain_pair(left, right) {
+                left.sub(&right).map(Value::Uncertain)
+            } else if let Some((left, right)) = complex_pair(left, right) {
+                left.sub(&right).map(Complex::into_value)
+            } else {
+                Err(format!("Operation is not defined"))
+            }
         }
     }
 }
@@ -146,6 +265,11 @@ impl<'a> Neg for &'a Value {
         match *self {
             Value::Number(ref num) =>
                 (-num).ok_or(format!("Bug: Negation should not fail")).map(Value::Number),
+            Value::Logarithmic(ref log) if !log.is_absolute() =>
+                Ok(Value::Logarithmic(Logarithmic::new(-&log.level, log.scale.clone()))),
+            Value::Uncertain(ref v) => Ok(Value::Uncertain(v.neg())),
+            Value::Complex(ref v) => Ok(Value::Complex(v.neg())),
+            Value::Vector(ref v) => Ok(Value::Vector(v.neg())),
             _ => Err(format!("Operation is not defined"))
         }
     }
@@ -163,7 +287,21 @@ impl<'a,'b> Mul<&'b Value> for &'a Value {
             (&Value::Number(ref co), &Value::Substance(ref sub)) |
             (&Value::Substance(ref sub), &Value::Number(ref co)) =>
                 (sub * co).map(Value::Substance),
-            (_, _) => Err(format!("Operation is not defined"))
+            (&Value::Number(ref co), &Value::Logarithmic(ref log)) |
+            (&Value::Logarithmic(ref log), &Value::Number(ref co)) =>
+                log.mul(co).map(Value::Logarithmic),
+            (&Value::Number(ref co), &Value::Vector(ref vec)) |
+            (&Value::Vector(ref vec), &Value::Number(ref co)) =>
+                Ok(Value::Vector(vec.scale(co))),
+            (&Value::Vector(ref left), &Value::Vector(ref right)) =>
+                left.mul(right).map(Value::Vector),
+            (left, right) => if let Some((left, right)) = uncertain_pair(left, right) {
+                left.mul(&right).map(Value::Uncertain)
+            } else if let Some((left, right)) = complex_pair(left, right) {
+                Ok(left.mul(&right).into_value())
+            } else {
+                Err(format!("Operation is not defined"))
+            }
         }
     }
 }
@@ -179,7 +317,20 @@ impl<'a,'b> Div<&'b Value> for &'a Value {
                 .map(Value::Number),
             (&Value::Substance(ref sub), &Value::Number(ref co)) =>
                 (sub / co).map(Value::Substance),
-            (_, _) => Err(format!("Operation is not defined"))
+            (&Value::Logarithmic(ref log), &Value::Number(ref co)) =>
+                (&Number::one() / co)
+                .ok_or(format!("Division by zero"))
+                .and_then(|co| log.mul(&co))
+                .map(Value::Logarithmic),
+            (&Value::Vector(ref vec), &Value::Number(ref co)) =>
+                vec.div(co).map(Value::Vector),
+            (left, right) => if let Some((left, right)) = uncertain_pair(left, right) {
+                left.div(&right).map(Value::Uncertain)
+            } else if let Some((left, right)) = complex_pair(left, right) {
+                left.div(&right).map(Complex::into_value)
+            } else {
+                Err(format!("Operation is not defined"))
+            }
         }
     }
 }
@@ -0,0 +1,336 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use ast::Digits;
+use context::Context;
+use number::{Number, NumberParts, to_string};
+use num::Num;
+use value::{Value, Show};
+
+/// The shape of a vector with the given number of components, or of a
+/// matrix with the given number of rows and columns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Vector(usize),
+    Matrix(usize, usize),
+}
+
+/// A vector or matrix of quantities, like the force `[3 N, 4 N, 0 N]`.
+/// Every component has its own unit, so dimensions are checked per
+/// component and vectors like `[2 m, 90 deg]` are also possible.
+/// Matrices are stored in row-major order.
+#[derive(Debug, Clone)]
+pub struct Vector {
+    pub elems: Vec<Number>,
+    pub shape: Shape,
+}
+
+/// Adds up the products of pairs of components, as in a dot product.
+fn sum_products<'a, I>(pairs: I) -> Result<Number, String>
+    where I: Iterator<Item=(&'a Number, &'a Number)> {
+    let mut sum: Option<Number> = None;
+    for (left, right) in pairs {
+        let prod = (left * right).expect("Bug: Mul should not fail");
+        sum = Some(match sum {
+            Some(sum) => try!((&sum + &prod).ok_or(format!(
+                "Addition of units with mismatched units is not meaningful \
+                 in a sum of products"
+            ))),
+            None => prod,
+        });
+    }
+    Ok(sum.unwrap_or_else(Number::zero))
+}
+
+impl Vector {
+    /// Builds a vector from the values of a literal like `[1, 2, 3]`,
+    /// or a matrix when the values are themselves vectors of equal
+    /// length.
+    pub fn from_values(values: Vec<Value>) -> Result<Vector, String> {
+        if values.len() == 0 {
+            return Err(format!("Vectors must have at least one component"))
+        }
+        let rows = values.len();
+        let cols = match values[0] {
+            Value::Vector(Vector { shape: Shape::Vector(len), .. }) => Some(len),
+            _ => None,
+        };
+        let mut elems = vec![];
+        for value in values {
+            match (value, cols) {
+                (Value::Number(num), None) => elems.push(num),
+                (Value::Vector(Vector { elems: row, shape: Shape::Vector(len) }), Some(cols)) => {
+                    if len != cols {
+                        return Err(format!(
+                            "Matrix rows must have the same length, got {} and {}",
+                            cols, len
+                        ))
+                    }
+                    elems.extend(row)
+                },
+                (Value::Number(_), Some(_)) | (Value::Vector(_), _) => return Err(format!(
+                    "Matrices must be written as vectors of rows"
+                )),
+                _ => return Err(format!("Vector components must be numbers")),
+            }
+        }
+        Ok(Vector {
+            elems: elems,
+            shape: match cols {
+                None => Shape::Vector(rows),
+                Some(cols) => Shape::Matrix(rows, cols),
+            },
+        })
+    }
+
+    fn with_elems(&self, elems: Vec<Number>) -> Vector {
+        Vector {
+            elems: elems,
+            shape: self.shape,
+        }
+    }
+
+    /// The number of rows, which is 1 for a vector.
+    pub fn rows(&self) -> usize {
+        match self.shape {
+            Shape::Vector(_) => 1,
+            Shape::Matrix(rows, _) => rows,
+        }
+    }
+
+    /// The number of columns, which is the length for a vector.
+    pub fn cols(&self) -> usize {
+        match self.shape {
+            Shape::Vector(len) => len,
+            Shape::Matrix(_, cols) => cols,
+        }
+    }
+
+    fn get(&self, row: usize, col: usize) -> &Number {
+        &self.elems[row * self.cols() + col]
+    }
+
+    fn zip<F>(&self, other: &Vector, what: &str, op: F) -> Result<Vector, String>
+        where F: Fn(&Number, &Number) -> Option<Number> {
+        if self.shape != other.shape {
+            return Err(format!(
+                "{} of a {} and a {} is not meaningful",
+                what, self.shape, other.shape
+            ))
+        }
+        let mut elems = vec![];
+        for (i, (left, right)) in self.elems.iter().zip(other.elems.iter()).enumerate() {
+            elems.push(try!(op(left, right).ok_or(format!(
+                "{} of units with mismatched units is not meaningful in component {}",
+                what, i + 1
+            ))));
+        }
+        Ok(self.with_elems(elems))
+    }
+
+    pub fn add(&self, other: &Vector) -> Result<Vector, String> {
+        self.zip(other, "Addition", |a, b| a + b)
+    }
+
+    pub fn sub(&self, other: &Vector) -> Result<Vector, String> {
+        self.zip(other, "Subtraction", |a, b| a - b)
+    }
+
+    pub fn neg(&self) -> Vector {
+        self.with_elems(self.elems.iter().map(|x| {
+            (-x).expect("Bug: Negation should not fail")
+        }).collect())
+    }
+
+    /// Multiplies every component by a scalar.
+    pub fn scale(&self, factor: &Number) -> Vector {
+        self.with_elems(self.elems.iter().map(|x| {
+            (x * factor).expect("Bug: Mul should not fail")
+        }).collect())
+    }
+
+    /// Divides every component by a scalar.
+    pub fn div(&self, divisor: &Number) -> Result<Vector, String> {
+        let elems = try!(self.elems.iter().map(|x| {
+            (x / divisor).ok_or(format!("Division by zero"))
+        }).collect::<Result<Vec<_>, _>>());
+        Ok(self.with_elems(elems))
+    }
+
+    /// The matrix product. A vector on the left is a row vector, and a
+    /// vector on the right is a column vector.
+    pub fn mul(&self, other: &Vector) -> Result<Vector, String> {
+        let (rows, inner) = match (self.shape, other.shape) {
+            (Shape::Vector(_), Shape::Vector(_)) => return Err(format!(
+                "Multiplication of two vectors is ambiguous, use dot() or cross()"
+            )),
+            (Shape::Vector(len), _) => (1, len),
+            (Shape::Matrix(rows, cols), _) => (rows, cols),
+        };
+        let (other_rows, cols) = match other.shape {
+            Shape::Vector(len) => (len, 1),
+            Shape::Matrix(rows, cols) => (rows, cols),
+        };
+        if inner != other_rows {
+            return Err(format!(
+                "Multiplication of a {} and a {} is not meaningful",
+                self.shape, other.shape
+            ))
+        }
+        let mut elems = vec![];
+        for row in 0..rows {
+            for col in 0..cols {
+                elems.push(try!(sum_products((0..inner).map(|i| {
+                    (self.get(row, i), match other.shape {
+                        Shape::Vector(_) => &other.elems[i],
+                        Shape::Matrix(_, _) => other.get(i, col),
+                    })
+                }))));
+            }
+        }
+        let shape = match (self.shape, other.shape) {
+            (Shape::Vector(_), _) => Shape::Vector(cols),
+            (_, Shape::Vector(_)) => Shape::Vector(rows),
+            _ => Shape::Matrix(rows, cols),
+        };
+        Ok(Vector {
+            elems: elems,
+            shape: shape,
+        })
+    }
+
+    pub fn dot(&self, other: &Vector) -> Result<Number, String> {
+        match (self.shape, other.shape) {
+            (Shape::Vector(a), Shape::Vector(b)) if a == b =>
+                sum_products(self.elems.iter().zip(other.elems.iter())),
+            _ => Err(format!(
+                "Dot product of a {} and a {} is not meaningful",
+                self.shape, other.shape
+            )),
+        }
+    }
+
+    pub fn cross(&self, other: &Vector) -> Result<Vector, String> {
+        if self.shape != Shape::Vector(3) || other.shape != Shape::Vector(3) {
+            return Err(format!(
+                "Cross product is only defined for vectors with 3 components"
+            ))
+        }
+        let (a, b) = (&self.elems, &other.elems);
+        let component = |i: usize, j: usize| -> Result<Number, String> {
+            let left = (&a[i] * &b[j]).expect("Bug: Mul should not fail");
+            let right = (&a[j] * &b[i]).expect("Bug: Mul should not fail");
+            (&left - &right).ok_or(format!(
+                "Subtraction of units with mismatched units is not meaningful \
+                 in cross product"
+            ))
+        };
+        Ok(self.with_elems(vec![
+            try!(component(1, 2)),
+            try!(component(2, 0)),
+            try!(component(0, 1)),
+        ]))
+    }
+
+    /// The Euclidean length, which is exact when the result is rational.
+    pub fn norm(&self) -> Result<Number, String> {
+        let square = try!(self.dot(self));
+        let mut unit = square.clone();
+        unit.value = Num::one();
+        let unit = try!(unit.root(2));
+        let value = match square.value {
+            Num::Mpq(ref mpq) => Num::Mpq(::precise::root(mpq, 2, 20)),
+            Num::Float(f) => Num::Float(f.sqrt()),
+        };
+        Ok(Number {
+            value: value,
+            unit: unit.unit,
+        })
+    }
+
+    pub fn transpose(&self) -> Vector {
+        match self.shape {
+            Shape::Vector(_) => self.clone(),
+            Shape::Matrix(rows, cols) => Vector {
+                elems: (0..cols).flat_map(|col| {
+                    (0..rows).map(move |row| (row, col))
+                }).map(|(row, col)| self.get(row, col).clone()).collect(),
+                shape: Shape::Matrix(cols, rows),
+            },
+        }
+    }
+
+    /// Whether all components have the same unit, so that the vector
+    /// can be written as `[3, 4, 0] meter`.
+    pub fn is_homogeneous(&self) -> bool {
+        self.elems.iter().all(|x| x.unit == self.elems[0].unit)
+    }
+
+    fn bracket(&self, strings: Vec<String>) -> String {
+        match self.shape {
+            Shape::Vector(_) => format!("[{}]", strings.join(", ")),
+            Shape::Matrix(_, cols) => format!("[{}]", strings.chunks(cols).map(|row| {
+                format!("[{}]", row.join(", "))
+            }).collect::<Vec<_>>().join(", ")),
+        }
+    }
+
+    /// Replaces the numeric value in the parts with the components,
+    /// scaled by the given factor.
+    pub fn fill_parts(&self, factor: &Num, base: u8, digits: Digits, parts: NumberParts) -> NumberParts {
+        let mut exact = true;
+        let strings = self.elems.iter().map(|x| {
+            let value = &x.value * factor;
+            let (is_exact, string) = to_string(&value, base, digits);
+            if let Num::Float(_) = value {
+                exact = false;
+            }
+            exact = exact && is_exact;
+            string
+        }).collect::<Vec<_>>();
+        let value = self.bracket(strings);
+        NumberParts {
+            exact_value: if exact { Some(value.clone()) } else { None },
+            approx_value: if exact { None } else { Some(value) },
+            ..parts
+        }
+    }
+
+    pub fn to_parts(&self, context: &Context) -> NumberParts {
+        if !self.is_homogeneous() {
+            let strings = self.elems.iter().map(|x| {
+                x.to_parts(context).format("n u")
+            }).collect::<Vec<_>>();
+            return NumberParts {
+                exact_value: Some(self.bracket(strings)),
+                ..Default::default()
+            }
+        }
+        // the prefix is picked for the largest component
+        let largest = self.elems.iter().fold(&self.elems[0], |a, b| {
+            if b.value.abs() > a.value.abs() { b } else { a }
+        });
+        let factor = if largest.value == Num::zero() {
+            Num::one()
+        } else {
+            &largest.prettify(context).value / &largest.value
+        };
+        self.fill_parts(&factor, 10, Digits::Default, largest.to_parts(context))
+    }
+}
+
+impl ::std::fmt::Display for Shape {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Shape::Vector(len) => write!(fmt, "vector with {} components", len),
+            Shape::Matrix(rows, cols) => write!(fmt, "{}×{} matrix", rows, cols),
+        }
+    }
+}
+
+impl Show for Vector {
+    fn show(&self, context: &Context) -> String {
+        format!("{}", self.to_parts(context))
+    }
+}
@@ -7,17 +7,14 @@ extern crate rink;
 use rink::*;
 
 thread_local! {
-    static CONTEXT: Context = {
-        let mut ctx = load().unwrap();
-        ctx.use_humanize = false;
-        ctx
-    };
+    static CONTEXT: Context = session();
 }
 
 fn test(input: &str, output: &str) {
-    let mut iter = text_query::TokenIterator::new(input.trim()).peekable();
-    let expr = text_query::parse_query(&mut iter);
     CONTEXT.with(|ctx| {
+        let mut iter = text_query::TokenStream::new(input.trim())
+            .with_functions(ctx.function_names());
+        let expr = text_query::parse_query(&mut iter);
         let res = ctx.eval_outer(&expr);
         let res = match res {
             Ok(v) => v.to_string(),
@@ -28,9 +25,10 @@ fn test(input: &str, output: &str) {
 }
 
 fn test_starts_with(input: &str, output: &str) {
-    let mut iter = text_query::TokenIterator::new(input.trim()).peekable();
-    let expr = text_query::parse_query(&mut iter);
     CONTEXT.with(|ctx| {
+        let mut iter = text_query::TokenStream::new(input.trim())
+            .with_functions(ctx.function_names());
+        let expr = text_query::parse_query(&mut iter);
         let res = ctx.eval_outer(&expr);
         let res = match res {
             Ok(v) => v.to_string(),
@@ -43,6 +41,22 @@ fn test_starts_with(input: &str, output: &str) {
     });
 }
 
+/// A context of its own for tests that keep variables, functions or
+/// history between queries.
+fn session() -> Context {
+    let mut ctx = load().unwrap();
+    ctx.use_humanize = false;
+    ctx
+}
+
+fn test_in(ctx: &mut Context, input: &str, output: &str) {
+    let res = match one_line(ctx, input) {
+        Ok(v) => v,
+        Err(e) => e,
+    };
+    assert_eq!(res, output);
+}
+
 #[test]
 fn test_definition() {
     test("watt", "Definition: watt = J / s = 1 watt (power; kg m^2 / s^3)");
@@ -70,8 +84,7 @@ fn test_determinism() {
 
 #[test]
 fn test_sqrt_errors() {
-    test("sqrt -1",
-         "Complex numbers are not implemented: sqrt(-1 (dimensionless))");
+    test("sqrt -1", "i (dimensionless)");
     test("sqrt(2m)",
          "Result must have integer dimensions: sqrt(2 meter (length))");
 }
@@ -131,7 +144,7 @@ fn test_conformance() {
     );
     test(
         "W/s -> J^2",
-        "Conformance error: 1 newton^2 / kilogram != 1 joule^2\n\
+        "Conformance error: 1 watt / second != 1 joule^2\n\
          Suggestions: multiply left side by moment_of_inertia, divide right side by moment_of_inertia",
     );
     test(
@@ -237,6 +250,32 @@ fn test_substance_add() {
           molar_mass = approx. 28.96790 gram / mole");
 }
 
+#[test]
+fn test_mixtures() {
+    test("molarity of (100 ml water + 5.8442468 g NaCl) -> mol / L",
+         "1 mole / liter (molar_concentration)");
+    test_starts_with("molality of (1 kg water + 58.442468 g NaCl) -> mol / kg",
+                     "1 mole / kilogram");
+    test("mass of (1 L water + 10 g NaCl)", "1.01 kilogram (mass)");
+    test("mass_fraction of (95 g water + 5 g NaCl)", "0.05 (dimensionless)");
+    test("ppm of (95 g water + 5 g NaCl)", "50000 (dimensionless)");
+    test("molarity of (95 g water + 5 g NaCl + 1 g KCl)",
+         "The mixture has more than one solute, the concentration of \
+          each is listed when it is shown");
+    test_starts_with("100 m water + 5 g NaCl",
+                     "The amount of water in a mixture must be a mass, volume");
+}
+
+#[test]
+fn test_tabulated_properties() {
+    test("density of water at 60 °C", "983.2 kilogram / meter^3 (density)");
+    test("vapor_pressure of water at 25 °C -> kPa", "3.29215 kilopascal (pressure)");
+    test("vapor_pressure of water at 100 °C -> atm", "1 atm (pressure)");
+    test_starts_with("density of water at 150 °C",
+                     "The temperature 423.15 K is outside the table of density of water");
+    test("speed of water at 60 °C", "speed of water is not tabulated");
+}
+
 #[test]
 fn test_duration_add() {
     test("#jan 01, 1970# + 1 s",
@@ -276,7 +315,7 @@ fn test_kilosecond() {
 #[test]
 #[should_panic]
 fn test_second_double_prefix() {
-    let mut iter = text_query::TokenIterator::new("mks").peekable();
+    let mut iter = text_query::TokenStream::new("mks");
     let expr = text_query::parse_query(&mut iter);
     CONTEXT.with(|ctx| {
         ctx.eval_outer(&expr).unwrap();
@@ -471,7 +510,39 @@ fn test_search() {
 fn test_digits() {
     test(
         "ln(1234) -> digits 100",
-        "approx. 7.11801620446533345187845043255947530269622802734375 (dimensionless)",
+        "approx. 7.11801620446533312341480380006836739278993505099911845482608609121344814458555101581856655829511726739 (dimensionless)",
+    );
+    test(
+        "exp(1) -> digits 100",
+        "approx. 2.71828182845904523536028747135266249775724709369995957496696762772407663035354759457138217852516642742 (dimensionless)",
+    );
+    test(
+        "sqrt(2) -> digits 50",
+        "approx. 1.414213562373095048801688724209698078569671875376948 (dimensionless)",
+    );
+    test(
+        "pi -> digits 50",
+        "approx. 3.141592653589793238462643383279502884197169399375105 (dimensionless)",
+    );
+    test("sqrt(16) -> digits 50", "4 (dimensionless)");
+    test("sqrt(2 m^2) -> digits 30",
+         "approx. 1.4142135623730950488016887242096 meter (length)");
+    test_starts_with(
+        "radian -> digits 30 degree",
+        "approx. 57.2957795130823208767981548141051 degree",
+    );
+    test("ln(-1) -> digits 20", "approx. 3.141592653589793238462i (dimensionless)");
+    test("ln(-2) -> digits 20",
+         "approx. 0.6931471805599453094172 + 3.141592653589793238462i (dimensionless)");
+    test("ln(0) -> digits 20", "Logarithm of a nonpositive number is undefined: ln(0)");
+    // complex values are computed precisely too
+    test(
+        "sqrt(-2) i -> digits 50",
+        "approx. -1.414213562373095048801688724209698078569671875376948 (dimensionless)",
+    );
+    test(
+        "abs(1 + i) -> digits 50",
+        "approx. 1.414213562373095048801688724209698078569671875376948 (dimensionless)",
     );
 }
 
@@ -568,9 +639,573 @@ fn test_formula() {
         "C8H10N4O2: molar_mass = approx. 0.1941931 kilogram / mole",
     );
     test("C60", "C60: molar_mass = 0.72066 kilogram / mole");
+    test("Ca(OH)2", "Ca(OH)2: molar_mass = 0.07409268 kilogram / mole");
+    test("(NH4)2SO4", "(NH4)2SO4: molar_mass = 0.1321406 kilogram / mole");
+    test("CuSO4·5H2O", "CuSO4·5H2O: molar_mass = 0.249686 kilogram / mole");
+    test("Ca2+", "Ca2+: molar_mass = approx. 0.04007690 kilogram / mole");
+    test("[13C]O2", "[13C]O2: molar_mass = approx. 0.04500215 kilogram / mole");
+    test("¹³CO₂", "¹³CO₂: molar_mass = approx. 0.04500215 kilogram / mole");
+}
+
+#[test]
+fn test_formula_composition() {
+    test("Ca(OH)2 -> composition",
+         "Ca(OH)2: Ca 1 (54.09171%), O 2 (43.18753%), H 2 (2.720754%); \
+          molar_mass = 0.07409268 kilogram / mole");
+    test("CuSO4·5H2O -> composition",
+         "CuSO4·5H2O: Cu 1 (25.45036%), S 1 (12.84253%), O 9 (57.67027%), \
+          H 10 (4.036830%); molar_mass = 0.249686 kilogram / mole");
+    test("[Fe(CN)6]3- -> composition",
+         "[Fe(CN)6]3-: Fe 1 (26.34801%), C 6 (34.00118%), N 6 (39.65079%); \
+          charge = -3; molar_mass = approx. 0.2119530 kilogram / mole");
+    test("NO3- -> composition",
+         "NO3-: N 1 (22.58971%), O 3 (77.41028%); \
+          charge = -1; molar_mass = approx. 0.06200548 kilogram / mole");
+    test("HCO3- -> composition",
+         "HCO3-: H 1 (1.651896%), C 1 (19.68463%), O 3 (78.66347%); \
+          charge = -1; molar_mass = approx. 0.06101768 kilogram / mole");
+    test("water -> composition",
+         "Composition is only defined for chemical formulas, got water");
+}
+
+#[test]
+fn test_balance() {
+    test("balance CH4 + O2 -> CO2 + H2O", "CH4 + 2 O2 -> CO2 + 2 H2O");
+    test("balance Fe + O2 -> Fe2O3", "4 Fe + 3 O2 -> 2 Fe2O3");
+    test("balance Cu + Ag+ -> Cu2+ + Ag", "Cu + 2 Ag+ -> Cu2+ + 2 Ag");
+    test("balance H2O -> CO2", "H2O -> CO2 cannot be balanced");
+    test("balance water -> H2O", "water is not a chemical formula");
+}
+
+#[test]
+fn test_stoichiometry() {
+    test("2 mol CH4 in reaction CH4 + O2 -> CO2 + H2O -> amount of O2",
+         "4 mole (amount)");
+    test("1 mol CH4 in reaction CH4 + O2 -> CO2 + H2O -> mass of CO2",
+         "44.0098 gram (mass)");
+    test("16.04276 g CH4 in reaction CH4 + 2 O2 -> CO2 + 2 H2O -> mass of H2O",
+         "36.03056 gram (mass)");
+    test("1 mol CH4 in reaction CH4 + O2 -> CO2 + 2 H2O -> mass of CO2",
+         "CH4 + O2 -> CO2 + 2 H2O is not balanced");
+    test("1 mol NaCl in reaction CH4 + O2 -> CO2 + H2O -> mass of CO2",
+         "NaCl is not part of CH4 + O2 -> CO2 + H2O");
+    test("1 m CH4 in reaction CH4 + O2 -> CO2 + H2O -> mass of CO2",
+         "Expected an amount or mass of CH4, got <1 meter (length)>");
+}
+
+#[test]
+fn test_formula_tokens() {
+    // formulas only extend tokens when they make sense as a whole
+    test("(N)", "Definition: newton = kg m / s^2 = 1 newton (force; kg m / s^2)");
+    test("[2 N, 3 N]", "[2, 3] newton (force)");
+    test("2 N+ 3 N", "5 newton (force)");
+    test_starts_with("H2O-H2O", "Operation is not defined");
 }
 
 #[test]
 fn test_unicode_minus() {
     test("\u{2212}10", "-10 (dimensionless)");
 }
+
+#[test]
+fn test_variables() {
+    let mut ctx = session();
+    test_in(&mut ctx, "x = 5 kg", "x = 5 kilogram (mass)");
+    test_in(&mut ctx, "x * 2", "10 kilogram (mass)");
+    test_in(&mut ctx, "vars", "Variables: x = 5 kilogram (mass)");
+    test_in(&mut ctx, "m = 2", "m = 2 (dimensionless)\nWarning: m shadows an existing unit");
+    test_in(&mut ctx, "3 m", "6 (dimensionless)");
+    test_in(&mut ctx, "unset m", "Unset variable m");
+    test_in(&mut ctx, "3 m", "3 meter (length)");
+    test_in(&mut ctx, "unset x", "Unset variable x");
+    test_in(&mut ctx, "unset x", "No such variable or function x");
+    test_in(&mut ctx, "vars", "No variables defined");
+    test_in(&mut ctx, "2 = 3", "Expected variable name on left side of =, got 2");
+}
+
+#[test]
+fn test_history() {
+    let mut ctx = session();
+    test_in(&mut ctx, "ans", "There is no previous result for ans to refer to");
+    test_in(&mut ctx, "2 m", "2 meter (length)");
+    test_in(&mut ctx, "ans * 3", "6 meter (length)");
+    test_in(&mut ctx, "_ -> cm", "600 centimeter (length)");
+    test_in(&mut ctx, "$1 + $2", "8 meter (length)");
+    test_in(&mut ctx, "$9", "No such result $9, there are 4 results so far");
+    test_in(&mut ctx, "ans = 2", "Cannot assign to ans, it is a builtin");
+    // conversions keep the value they computed
+    test_in(&mut ctx, "pi -> digits 30",
+            "approx. 3.1415926535897932384626433832795 (dimensionless)");
+    test_in(&mut ctx, "ans -> digits 30",
+            "approx. 3.1415926535897932384626433832795 (dimensionless)");
+}
+
+#[test]
+fn test_user_functions() {
+    let mut ctx = session();
+    test_in(&mut ctx, "f(x, y) := x^2 / y", "f(x, y) := x^2 / y");
+    test_in(&mut ctx, "f(4 m, 2)", "8 meter^2 (area)");
+    test_in(&mut ctx, "f(1)", "Argument number mismatch for f: Expected 2, got 1");
+    test_in(&mut ctx, "g(x) := 2 x -> m", "g(x) := 2 x -> m");
+    test_in(&mut ctx, "g(3 m)", "6 meter (length)");
+    test_in(&mut ctx, "g(3 s)",
+            "Conformance error in result of g: 6 second (time) != 1 meter (length)");
+    test_in(&mut ctx, "h(x) := h(x)", "Function h cannot call itself");
+    test_in(&mut ctx, "sqrt(x) := x", "Cannot redefine sqrt, it is a builtin function");
+    test_in(&mut ctx, "if(a, b, c) := a", "Cannot redefine if, it is a builtin function");
+    test_in(&mut ctx, "kg (2 + 3)", "5 kilogram (mass)");
+    test_in(&mut ctx, "m (2, 3)", "Expected `)`, got `,`");
+    test_in(&mut ctx, "unset f", "Unset function f");
+    test_in(&mut ctx, "f(1, 2)", "No such unit f, did you mean F?");
+    test_in(&mut ctx, "k(x) := x -> 2 m", "k(x) := x -> 2 m");
+    test_in(&mut ctx, "k(x) := x -> m s", "k(x) := x -> m s");
+    test_in(&mut ctx, "k(2 m)",
+            "Conformance error in result of k: 2 meter (length) != 1 meter second");
+    test_in(&mut ctx, "dist(x) := x -> m", "dist(x) := x -> m");
+    test_in(&mut ctx, "dist(5 m +- 2 cm)", "5.00 ± 0.02 meter (length)");
+    test_in(&mut ctx, "dist(sqrt(-4) m)", "2i meter (length)");
+    test_in(&mut ctx, "dist([1 m, 2 m])", "[1, 2] meter (length)");
+    test_in(&mut ctx, "dist([1 m, 2 s])",
+            "Conformance error in result of dist: [1 meter, 2 second] != 1 meter (length)");
+    test_in(&mut ctx, "dist = 2", "dist = 2 (dimensionless)");
+    test_in(&mut ctx, "unset dist", "Unset variable and function dist");
+
+    let diagnostics = rink::check("test.units",
+                                  "!function speed(d, t) units=[m/s] d / t\n\
+                                   !function typo(x) units=[nosuchunit] x\n", false).unwrap();
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert!(diagnostics[0].to_string().starts_with(
+        "test.units:2:1: error: Function typo is malformed: No such unit nosuchunit"),
+            "{}", diagnostics[0]);
+}
+
+#[test]
+fn test_nonlinear_units() {
+    test("tempC(20)", "293.15 kelvin (temperature)");
+    test("300 K -> tempC", "26.85 tempC (temperature)");
+    test("tempcelsius(100) -> tempF", "212 tempF (temperature)");
+    test("tempC(-300)",
+         "<-300 (dimensionless)> is outside the domain of tempC, which is [-273.15, )");
+    test("-1 m -> wiregauge",
+         "<-1 meter (length)> is outside the range of wiregauge, which is (0, )");
+    // °C and °F are tempC and tempF
+    test("20 °C", "293.15 kelvin (temperature)");
+    test("300 K -> °F", "80.33 °F (temperature)");
+    test("-300 °C",
+         "<-300 (dimensionless)> is outside the domain of tempC, which is [-273.15, )");
+}
+
+#[test]
+fn test_nonlinear_units_variables() {
+    // variables don't shadow the units in definitions
+    let mut ctx = session();
+    test_in(&mut ctx, "K = 0 K", "K = 0 kelvin (temperature)\nWarning: K shadows an existing unit");
+    test_in(&mut ctx, "stdtemp = 0 K",
+            "stdtemp = 0 kelvin (temperature)\nWarning: stdtemp shadows an existing unit");
+    test_in(&mut ctx, "300 kelvin -> tempC", "26.85 tempC (temperature)");
+    test_in(&mut ctx, "tempC(20)", "293.15 kelvin (temperature)");
+    test_in(&mut ctx, "20 °C", "293.15 kelvin (temperature)");
+}
+
+#[test]
+fn test_table_units() {
+    test("zincgauge(12) -> mm", "0.7112 millimeter (length)");
+    test("0.7112 mm -> zincgauge", "12 zincgauge (length)");
+    test("plategauge(16) -> inch", "0.0625 inch (length)");
+    test("tempC(190) -> gasmark", "4.96 gasmark (temperature)");
+    test("zincgauge(30)",
+         "<30 (dimensionless)> is outside the table of zincgauge, \
+          which goes from 1 (dimensionless) to 28 (dimensionless)");
+    test_starts_with("1 kg -> zincgauge", "Conformance error");
+}
+
+#[test]
+fn test_logarithmic() {
+    test("3 dB + 3 dB", "6 dB");
+    test("33 dBm - 30 dBm", "3 dB");
+    test("10 dB -> bel", "1 bel");
+    test("30 dBm + 30 dBm",
+         "Adding the absolute levels 30 dBm and 30 dBm is ambiguous, \
+          convert them to linear units first: <30 dBm> + <30 dBm>");
+    test("3 dB + 1 W",
+         "Addition of logarithmic and linear values is not meaningful, \
+          convert one of them with ->: <3 dB> + <1 watt (power)>");
+    test("1 m -> dBm",
+         "Conformance error: <1 meter (length)> is not a level of dBm, \
+          which is relative to 1 milliwatt (power)");
+    test("30 dBm -> dB",
+         "Cannot convert the absolute level 30 dBm to the relative scale dB");
+}
+
+#[test]
+fn test_uncertainty() {
+    test("5.0 ± 0.2 m", "5.0 ± 0.2 meter (length)");
+    test("5 m +- 2 cm", "5.00 ± 0.02 meter (length)");
+    test("1500 ± 20 m", "1.50 ± 0.02 kilometer (length)");
+    test("5.0 ± 0.2 m -> cm", "500 ± 20 centimeter (length)");
+    test("(3 ± 0.3) + (4 ± 0.4)", "7.0 ± 0.5 (dimensionless)");
+    test("(2 ± 0.1) * (3 ± 0.2)", "6.0 ± 0.5 (dimensionless)");
+    test("(10 ± 1)^2", "100 ± 20 (dimensionless)");
+    test("sqrt(16 ± 2)", "4.00 ± 0.25 (dimensionless)");
+    test("ln(10 ± 1)", "2.3 ± 0.1 (dimensionless)");
+    test("sin(0 ± 0.1)", "0.0 ± 0.1 (dimensionless)");
+    test("5 m ± 2 s",
+         "Uncertainty must have the same units as the value: \
+          <5 meter (length)> ± <2 second (time)>");
+}
+
+#[test]
+fn test_uncertainty_concise() {
+    let mut iter = text_query::TokenStream::new("5.0 ± 0.2 m");
+    let expr = text_query::parse_query(&mut iter);
+    CONTEXT.with(|ctx| match ctx.eval_outer(&expr) {
+        Ok(reply::QueryReply::Number(parts)) =>
+            assert_eq!(parts.format("c u"), "5.00(20) meter"),
+        Ok(x) => panic!("Expected number, got {}", x),
+        Err(e) => panic!("{}", e),
+    });
+}
+
+#[test]
+fn test_interval() {
+    test("sqrt(2) -> interval",
+         "[1.4142135623730947, 1.4142135623730956] (dimensionless), \
+          15 certified digits: 1.41421356237309");
+    test("2^(1/2) m -> interval",
+         "[1.4142135623730947, 1.4142135623730956] meter (length), \
+          15 certified digits: 1.41421356237309");
+    test("-ln(2) -> interval",
+         "[-0.69314718055994551, -0.69314718055994506] (dimensionless), \
+          15 certified digits: -0.693147180559945");
+    test("1/3 -> interval", "1/3, approx. 0.3333333 (dimensionless)");
+    test("1 / (1 - 1) -> interval",
+         "Division by an interval containing zero: <1> / <1 - 1>");
+    test("ln(0) -> interval",
+         "ln is undefined or unbounded on part of the interval: ln(0)");
+    test("pi -> interval",
+         "[3.14159265358979323846264338327950288419706, \
+          3.14159265358979323846264338327950288419727] (dimensionless), \
+          40 certified digits: 3.141592653589793238462643383279502884197");
+    test("tempC(20) -> interval",
+         "Interval arithmetic is not implemented for <tempC(20)>");
+}
+
+#[test]
+fn test_complex() {
+    test("(3 + 4i) * (1 - 2i)", "11 - 2i (dimensionless)");
+    test("i * i", "-1 (dimensionless)");
+    test("2j + 1", "1 + 2i (dimensionless)");
+    test("abs(3 + 4i)", "5 (dimensionless)");
+    test("abs(1 + i)", "approx. 1.414213 (dimensionless)");
+    test("sqrt(-2)", "approx. 1.414213i (dimensionless)");
+    test("conj(3 + 4i)", "3 - 4i (dimensionless)");
+    test("re(3 + 4i)", "3 (dimensionless)");
+    test("im(3 + 4i)", "4 (dimensionless)");
+    test("sqrt(-4)", "2i (dimensionless)");
+    test("(1 + i) / (1 - i)", "i (dimensionless)");
+    test_starts_with("(3 + 4i) ohm", "(3 + 4i) ohm");
+    test_starts_with("(3 + 4i) kV -> V", "(3000 + 4000i) volt");
+    test("(3 + 4i) m + 1 s",
+         "Addition of units with mismatched units is not meaningful: \
+          <(3 + 4i) meter (length)> + <1 second (time)>");
+}
+
+#[test]
+fn test_polar() {
+    test("i -> polar", "1 ∠ 90° (dimensionless)");
+    test("-2 -> polar", "2 ∠ 180° (dimensionless)");
+    test_starts_with("3 + 4i -> polar", "5 ∠ 53.1301");
+}
+
+#[test]
+fn test_vectors() {
+    test("[3 m, 4 m, 0 m]", "[3, 4, 0] meter (length)");
+    test("[3, 4, 0] N", "[3, 4, 0] newton (force)");
+    test("[1 m, 2 s]", "[1 meter, 2 second]");
+    test("[1 m, 2 m] + [3 m, 4 m]", "[4, 6] meter (length)");
+    test("2 [1 m, 2 m] - [1 m, 1 m]", "[1, 3] meter (length)");
+    test("[1 m, 2 m] + [3 m, 4 s]",
+         "Addition of units with mismatched units is not meaningful in component 2: \
+          <[1, 2] meter (length)> + <[3 meter, 4 second]>");
+    test("[1, 2] + [1, 2, 3]",
+         "Addition of a vector with 2 components and a vector with 3 components \
+          is not meaningful: <[1, 2] (dimensionless)> + <[1, 2, 3] (dimensionless)>");
+    test("[1 m, 250 cm] -> cm", "[100, 250] centimeter (length)");
+    test_starts_with("[1 m, 2 s] -> cm",
+                     "Conformance error: 2 second (time) != 10 millimeter (length)");
+    test("[] m", "Vectors must have at least one component: []");
+}
+
+#[test]
+fn test_vector_functions() {
+    test("dot([1, 2, 3], [4, 5, 6])", "32 (dimensionless)");
+    test("cross([1, 0, 0], [0, 1, 0])", "[0, 0, 1] (dimensionless)");
+    test_starts_with("cross([1 m, 0 m, 0 m], [0 N, 10 N, 0 N])", "[0, 0, 10]");
+    test("norm([3 m, 4 m])", "5 meter (length)");
+    test("cross([1, 2], [3, 4])",
+         "Cross product is only defined for vectors with 3 components: \
+          cross([1, 2] (dimensionless), [3, 4] (dimensionless))");
+}
+
+#[test]
+fn test_matrices() {
+    test("[[1, 2], [3, 4]] [5, 6]", "[17, 39] (dimensionless)");
+    test("[[1, 2], [3, 4]] [[0, 1], [1, 0]]", "[[2, 1], [4, 3]] (dimensionless)");
+    test("transpose([[1, 2, 3], [4, 5, 6]])", "[[1, 4], [2, 5], [3, 6]] (dimensionless)");
+    test("[1, 2] * [3, 4]",
+         "Multiplication of two vectors is ambiguous, use dot() or cross(): \
+          <[1, 2] (dimensionless)> * <[3, 4] (dimensionless)>");
+    test("[[1, 2], [3]]",
+         "Matrix rows must have the same length, got 2 and 1: [[1, 2], [3]]");
+}
+
+#[test]
+fn test_load_diagnostics() {
+    let diagnostics = check("test.units",
+                            "m !\n\
+                             foo 2 m\n\
+                             bar 3 baz\n\
+                             foo 4 m\n\
+                             )\n", false).unwrap();
+    let diagnostics = diagnostics.iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(diagnostics.len(), 3);
+    assert_eq!(diagnostics[0], "test.units:5:1: error: Expected definition, got RPar");
+    assert_eq!(diagnostics[1], "test.units:4:1: warning: Multiple units named foo, \
+                                    overriding the one at test.units:2:1");
+    assert!(diagnostics[2].starts_with("test.units:3:1: error: Unit bar is malformed: \
+                                        No such unit baz"),
+            "{}", diagnostics[2]);
+}
+
+#[test]
+fn test_layered_units() {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("rink-test-{}", std::process::id()));
+    fs::create_dir_all(dir.join("team")).unwrap();
+    let write = |name: &str, text: &str| {
+        File::create(dir.join(name)).unwrap().write_all(text.as_bytes()).unwrap();
+    };
+    write("team/team.units", "!include parts.units\nwidget 2 gizmo\n");
+    write("team/parts.units", "gizmo 3 cm\n");
+    write("project.units", "widget 5 cm\nfoot 13 inch\n!include missing.units\n");
+    let files = vec![dir.join("team/team.units"), dir.join("project.units")];
+    let (mut ctx, diagnostics) = load_with_units(&files, false).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let diagnostics = diagnostics.iter()
+        .filter(|x| x.file.contains("rink-test"))
+        .map(|x| x.message.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(diagnostics.len(), 3, "{:?}", diagnostics);
+    assert!(diagnostics[0].starts_with("Could not include "), "{}", diagnostics[0]);
+    assert!(diagnostics[1].starts_with("Multiple units named widget, overriding the one at "));
+    assert_eq!(diagnostics[2], "foot overrides an earlier definition");
+    test_in(&mut ctx, "gizmo -> cm", "3 centimeter (length)");
+    test_in(&mut ctx, "widget -> cm", "5 centimeter (length)");
+    test_in(&mut ctx, "foot -> inch", "13 inch (length)");
+}
+
+#[test]
+fn test_output_settings() {
+    let mut ctx = session();
+    ctx.significant_digits = 3;
+    test_in(&mut ctx, "1/3", "1/3, approx. 0.333 (dimensionless)");
+    // the web and IRC frontends set short_output, which doesn't
+    // shorten numbers
+    ctx.short_output = true;
+    test_in(&mut ctx, "1/3", "1/3, approx. 0.333 (dimensionless)");
+    test_in(&mut ctx, "2 m", "2 meter (length)");
+    ctx.short_output = false;
+    ctx.brief_output = true;
+    test_in(&mut ctx, "1/3", "approx. 0.333");
+    test_in(&mut ctx, "2 m", "2 meter");
+}
+
+#[test]
+fn test_unit_systems() {
+    test("2 m -> cgs", "200 centimeter (length)");
+    test("1 N -> cgs", "100000 dyne (force)");
+    test("1 lbf * 1 ft -> imperial", "1 foot lbf (energy)");
+    test("60 mile/hr -> imperial", "60 mph (velocity)");
+    test("1 GeV -> natural", "1 gigaelectronvolt (energy)");
+    test("2 m -> si", "2 meter (length)");
+    // torque and energy
+    test("20 N m -> imperial", "approx. 14.75124 foot lbf (energy)");
+    test("20 N m -> customary", "approx. 14.75124 foot lbf (energy)");
+    test("1 eV -> imperial", "approx. 1.181704e-19 foot lbf (energy)");
+    test("1 eV -> cgs", "approx. 1.602176e-12 erg (energy)");
+    test("1 eV -> natural", "1 electronvolt (energy)");
+    // volume and temperature
+    test("1 l -> imperial", "approx. 0.2199692 brgallon (volume)");
+    test("1 l -> customary", "approx. 0.2641720 usgallon (volume)");
+    test("1 l -> cgs", "1000 centimeter^3 (volume)");
+    test("300 K -> customary", "540 degrankine (temperature)");
+    test("300 K -> cgs", "300 kelvin (temperature)");
+    test("1 T -> cgs", "10000 gauss (magnetic_flux_density)");
+    test("1 C -> cgs", "approx. 2.997924e9 statcoulomb (charge)");
+    test("1/cm -> cgs", "1 kayser (m^-1)");
+
+    let mut ctx = session();
+    ctx.unit_system = system::UnitSystem::Imperial;
+    test_in(&mut ctx, "3 ft", "3 foot (length)");
+    test_in(&mut ctx, "3 ft -> m", "0.9144 meter (length)");
+    test_in(&mut ctx, "1 A", "1 ampere (current)");
+}
+
+#[test]
+fn test_best_units() {
+    test("1.2e6 kg m^2/s^3", "1.2 megawatt (power)");
+    test("1.2e6 W/m^2", "1.2 megawatt / meter^2 (heat_flux_density)");
+    // named units only win when the base units are hard to read
+    test("9.8 m/s^2", "9.8 meter / second^2 (acceleration)");
+    test_starts_with("0.000002 kg/m", "2 milligram / meter");
+    // prefixes are only used when they save a few digits
+    test("1000 kg/m^3", "1000 kilogram / meter^3 (density)");
+    test_starts_with("1.2e6 kg m^2/s^3 -> alternatives",
+                     "Alternatives for power: 1.2 megawatt; ");
+    test_starts_with("now -> alternatives", "Alternatives are only defined for numbers");
+}
+
+#[test]
+fn test_explain() {
+    test("explain 2 km -> m",
+         "1. m is a base unit of length\n\
+          2. km = k m = 1000 m\n\
+          3. 2 km = 2000 m\n\
+          4. factor (2 km) / (m) = 2000\n\
+          Result: 2000 meter (length)");
+
+    let mut ctx = session();
+    let res = one_line(&mut ctx, "explain 3 miles -> ft").unwrap();
+    let lines = res.lines().map(|x| x.splitn(2, ". ").nth(1).unwrap_or(x)).collect::<Vec<_>>();
+    let position = |line: &str| lines.iter().position(|x| *x == line)
+        .expect(&*format!("{} is missing from:\n{}", line, res));
+    assert!(position("m is a base unit of length") < position("cm = c m = 0.01 m"));
+    assert!(position("cm = c m = 0.01 m") < position("mile := 5280 ft = 1609.344 m"));
+    assert!(position("mile := 5280 ft = 1609.344 m") < position("miles is the plural of mile"));
+    assert_eq!(lines[lines.len() - 2], "factor (3 miles) / (ft) = 15840");
+    assert_eq!(lines[lines.len() - 1], "Result: 15840 foot (length)");
+    test_starts_with("explain foo", "No such unit foo");
+
+    // showing the result in another unit system doesn't add steps
+    let mut ctx = session();
+    let steps = |ctx: &mut Context| {
+        let res = one_line(ctx, "explain 3 kW * 2 hours").unwrap();
+        let mut lines = res.lines().map(|x| x.to_owned()).collect::<Vec<_>>();
+        lines.pop();
+        lines
+    };
+    let si = steps(&mut ctx);
+    ctx.unit_system = system::UnitSystem::Imperial;
+    assert_eq!(steps(&mut ctx), si);
+    let res = one_line(&mut ctx, "explain 2 km").unwrap();
+    assert!(!res.contains("lbf") && !res.contains("horsepower"), "{}", res);
+}
+
+#[test]
+fn test_error_spans() {
+    let mut ctx = session();
+    let mut underline = |line: &str| {
+        let (message, span) = one_line_spanned(&mut ctx, line).unwrap_err();
+        (message, span.map(|span| span.underline(line.trim())))
+    };
+    let (message, span) = underline("3 m + 2 fooo");
+    assert!(message.starts_with("No such unit fooo"));
+    assert_eq!(span, Some("        ^~~~".to_owned()));
+    assert_eq!(underline("  (2 m"),
+               ("Expected `)`, got eof".to_owned(), Some("    ^".to_owned())));
+    assert_eq!(underline("2 * / 3").1, Some("    ^".to_owned()));
+    assert_eq!(underline("m -> s").1, Some("^~~~~~".to_owned()));
+    // Each error points at its own expression, not the first token
+    // with the same text.
+    assert_eq!(underline("fooo m + 2 fooo").1, Some("^~~~".to_owned()));
+    assert_eq!(underline("3 m + (2 s) / 4").1, Some("^~~~~~~~~~~~~~~".to_owned()));
+    assert_eq!(underline("2 m + sqrt(2 m)").1, Some("      ^~~~~~~~~".to_owned()));
+}
+
+#[test]
+fn test_comparisons() {
+    test("1 mile > 1600 m", "true");
+    test("1 mile < 1600 m", "false");
+    test("1 m <= 100 cm", "true");
+    test("1 m >= 101 cm", "false");
+    test("1 m == 100 cm", "true");
+    test("1 m != 100 cm", "false");
+    test("1/3 == 0.3333333", "false");
+    test("1/3 ~= 0.3333333", "true");
+    test("1/3 ~= 0.33", "false");
+    test("5 m ~= 5.1 m ± 0.2 m", "true");
+    test("#jan 01, 1970# < #jan 02, 1970#", "true");
+    test_starts_with("1 m > 1 s", "Conformance error");
+    test("if(2 m > 1 m, 3 kg, 4 kg)", "3 kilogram (mass)");
+    test("if(2 m < 1 m, 3 kg, 4 kg)", "4 kilogram (mass)");
+    // only the branch that is taken is evaluated
+    test("if(2 m > 1 m, 3 kg, nosuchunit)", "3 kilogram (mass)");
+    test_starts_with("if(1, 2, 3)", "Expected a comparison as the condition of if");
+    test("if(2 m > 1 m, pi, nosuchunit) -> digits 10", "approx. 3.14159265358 (dimensionless)");
+    test("if(2 m > 1 m, 3 kg, nosuchunit) -> interval", "3 kilogram (mass)");
+}
+
+#[test]
+fn test_assertions() {
+    test("assert 1 mile == 1609.344 m", "true");
+    test("assert 1 mile == 1600 m within 1%", "true");
+    test("assert 1 mile == 1600 m within 10 m", "true");
+    test_starts_with("assert 1 mile == 1600 m within 0.1%", "Assertion failed: <");
+    test_starts_with("assert 1 mile == 1600 m within 5 m", "Assertion failed: <");
+    test_starts_with("assert 1 mile < 1600 m", "Assertion failed: <");
+    test_starts_with("assert 1 mile == 1600 m within 1 s", "Conformance error");
+    test_starts_with("assert 1 mile > 1600 m within 1%", "Expected `==` before within");
+    test_starts_with("assert 1 mile", "Expected a comparison after assert");
+}
+
+#[test]
+fn test_script() {
+    let mut ctx = session();
+    let report = script::run(&mut ctx, "bike.rink", "\
+        // bike.rink\n\
+        wheel = 622 mm + 2 * 28 mm\n\
+        \n\
+        assert wheel pi == 2.13 m within 0.5%\n\
+        assert wheel > 1 m\n\
+        \x20 assert wheel == nosuchunit\n");
+    assert_eq!(report.assertions, 3);
+    assert!(!report.passed());
+    let failures = report.failures.iter().map(|x| (x.line, x.column)).collect::<Vec<_>>();
+    assert_eq!(failures, vec![(5, 8), (6, 19)]);
+    assert!(report.failures[0].to_string().starts_with("bike.rink:5:8: error: Assertion failed"));
+}
+
+#[test]
+fn test_json() {
+    let mut ctx = session();
+    let mut eval = |input: &str| json_reply::to_json(&eval_spanned(&mut ctx, input));
+
+    let res = eval("3 m");
+    assert_eq!(res["version"].as_u32(), Some(json_reply::VERSION));
+    assert_eq!(res["ok"].as_bool(), Some(true));
+    assert_eq!(res["reply"]["type"].as_str(), Some("number"));
+    assert_eq!(res["reply"]["text"].as_str(), Some("3 meter (length)"));
+    assert_eq!(res["reply"]["number"]["exact_value"].as_str(), Some("3"));
+    assert_eq!(res["reply"]["number"]["quantity"].as_str(), Some("length"));
+    assert!(res["reply"]["number"]["approx_value"].is_null());
+
+    let res = eval("#jan 01, 1970#");
+    assert_eq!(res["reply"]["type"].as_str(), Some("date"));
+    assert_eq!(res["reply"]["date"]["year"].as_i32(), Some(1970));
+
+    let res = eval("W -> J");
+    assert_eq!(res["ok"].as_bool(), Some(false));
+    assert_eq!(res["error"]["type"].as_str(), Some("conformance"));
+    assert_eq!(res["error"]["suggestions"].len(), 2);
+    assert_eq!(res["span"]["start"].as_usize(), Some(0));
+    assert_eq!(res["span"]["end"].as_usize(), Some(6));
+
+    let res = eval("3 m + 2 fooo");
+    assert_eq!(res["error"]["type"].as_str(), Some("not_found"));
+    assert_eq!(res["error"]["got"].as_str(), Some("fooo"));
+    assert_eq!(res["span"]["start"].as_usize(), Some(8));
+    assert_eq!(res["span"]["end"].as_usize(), Some(12));
+}
@@ -50,18 +50,46 @@ struct Rink {
     config: Json,
 }
 
+/// How many earlier queries are kept and replayed for each request.
+/// Older ones are dropped, along with the variables they assigned.
+const MAX_HISTORY: usize = 20;
+
+fn trim_history(history: &mut Vec<String>) {
+    if history.len() > MAX_HISTORY {
+        let extra = history.len() - MAX_HISTORY;
+        history.drain(..extra);
+    }
+}
+
 fn root(rink: &Rink, req: &mut Request) -> IronResult<Response> {
     let mut data = BTreeMap::new();
 
     let map = req.get_ref::<Params>().unwrap();
+    // Previous queries of this session, carried along in a hidden form field.
+    let mut history = match map.find(&["h"]) {
+        Some(&Value::String(ref history)) => history
+            .lines()
+            .map(|x| x.trim().to_owned())
+            .filter(|x| x.len() > 0)
+            .collect::<Vec<_>>(),
+        _ => vec![],
+    };
+    trim_history(&mut history);
     match map.find(&["q"]) {
         Some(&Value::String(ref query)) if query == "" => (),
         Some(&Value::String(ref query)) => {
-            let mut reply = eval_json(query);
+            let (mut reply, ok) = eval_json(query, &history);
             reply.as_object_mut().unwrap().insert("input".to_owned(), query.to_json());
             println!("{}", reply.pretty());
             data.insert("queries".to_owned(), vec![reply].to_json());
             data.insert("title".to_owned(), query.to_json());
+            // queries that failed, timed out or ran out of memory
+            // would fail again when they are replayed
+            if ok {
+                history.push(query.clone());
+                trim_history(&mut history);
+            }
+            data.insert("history".to_owned(), history.join("\n").to_json());
         },
         _ => (),
     };
@@ -182,7 +210,8 @@ fn main() {
     if first.as_ref().map(|x| x == "--sandbox").unwrap_or(false) {
         let server = args.next().unwrap();
         let query = args.next().unwrap();
-        worker::worker(&server, &query);
+        let history = args.collect::<Vec<_>>();
+        worker::worker(&server, &query, &history);
     }
 
     let config = {
@@ -4,15 +4,18 @@ use std::process::{Command, Stdio};
 use std::env;
 use rink;
 use rink::reply::{QueryReply, QueryError};
+use rink::text_query::Span;
 use std::os::unix::process::ExitStatusExt;
 use std::io;
+use std::cmp;
 use rustc_serialize;
 use serde_json;
 use serde::ser::{Serialize, Serializer};
 
 #[derive(Debug)]
 pub enum Error {
-    Rink(QueryError),
+    /// An error from Rink, with the part of the query it is about.
+    Rink(QueryError, Option<Span>),
     Time,
     Memory,
     Generic(String),
@@ -23,7 +26,7 @@ impl Serialize for Error {
         &self, ser: &mut S
     ) -> Result<(), S::Error> where S: Serializer {
         match *self {
-            Error::Rink(ref e) =>
+            Error::Rink(ref e, _) =>
                 ser.serialize_newtype_variant("Error", 0, "Rink", e),
             Error::Time =>
                 ser.serialize_newtype_variant("Error", 1, "Time", true),
@@ -41,10 +44,36 @@ impl From<io::Error> for Error {
     }
 }
 
-pub fn worker(server_name: &str, query: &str) -> ! {
+/// CPU seconds for loading the units and replaying the history of a
+/// session, and for the query itself.
+const REPLAY_SECONDS: libc::rlim_t = 15;
+const QUERY_SECONDS: libc::rlim_t = 15;
+
+fn set_cpu_limit(soft: libc::rlim_t, hard: libc::rlim_t) {
+    let limit = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
+    };
+    let res = unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) };
+    if res == -1 {
+        panic!("Setrlimit RLIMIT_CPU failed: {}", io::Error::last_os_error())
+    }
+}
+
+/// The CPU time used so far, rounded up to whole seconds.
+fn cpu_seconds() -> libc::rlim_t {
+    let mut usage: libc::rusage = unsafe { ::std::mem::zeroed() };
+    let res = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    if res == -1 {
+        panic!("Getrusage failed: {}", io::Error::last_os_error())
+    }
+    (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec + 1) as libc::rlim_t
+}
+
+pub fn worker(server_name: &str, query: &str, history: &[String]) -> ! {
     let tx = IpcSender::connect(server_name.to_owned()).unwrap();
 
-    tx.send(Err(QueryError::Generic("".to_owned()))).unwrap();
+    tx.send(Err((QueryError::Generic("".to_owned()), None))).unwrap();
 
     unsafe {
         let limit = libc::rlimit {
@@ -56,34 +85,40 @@ pub fn worker(server_name: &str, query: &str) -> ! {
         if res == -1 {
             panic!("Setrlimit RLIMIT_AS failed: {}", io::Error::last_os_error())
         }
-        let limit = libc::rlimit {
-            // 15 seconds
-            rlim_cur: 15,
-            rlim_max: 15
-        };
-        let res = libc::setrlimit(libc::RLIMIT_CPU, &limit);
-        if res == -1 {
-            panic!("Setrlimit RLIMIT_AS failed: {}", io::Error::last_os_error())
-        }
     }
+    set_cpu_limit(REPLAY_SECONDS, REPLAY_SECONDS + QUERY_SECONDS);
 
     let mut ctx = rink::load().unwrap();
     ctx.short_output = true;
-    let mut iter = rink::text_query::TokenIterator::new(query).peekable();
+    // Replay the earlier queries of this session, so that variables
+    // and references like `ans` or `$1` see the same results. Only
+    // queries that succeeded are kept in the history.
+    for line in history {
+        let mut iter = rink::text_query::TokenStream::new(line)
+            .with_functions(ctx.function_names());
+        let expr = rink::text_query::parse_query(&mut iter);
+        let _ = ctx.eval_query(&expr);
+    }
+    // the query gets its own time, however long the replay took
+    let limit = cmp::min(cpu_seconds() + QUERY_SECONDS, REPLAY_SECONDS + QUERY_SECONDS);
+    set_cpu_limit(limit, limit);
+    let mut iter = rink::text_query::TokenStream::new(query)
+        .with_functions(ctx.function_names());
     let expr = rink::text_query::parse_query(&mut iter);
-    let reply = ctx.eval_outer(&expr);
+    let reply = ctx.eval_query(&expr).map_err(|e| e.split());
     tx.send(reply).unwrap();
 
     ::std::process::exit(0)
 }
 
-pub fn eval(query: &str) -> Result<QueryReply, Error> {
+pub fn eval(query: &str, history: &[String]) -> Result<QueryReply, Error> {
     let (server, server_name) = IpcOneShotServer::new().unwrap();
 
     let res = Command::new(env::current_exe().unwrap())
         .arg("--sandbox")
         .arg(server_name)
         .arg(query)
+        .args(history)
         .stdin(Stdio::null())
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
@@ -91,12 +126,12 @@ pub fn eval(query: &str) -> Result<QueryReply, Error> {
         .spawn();
     let child = try!(res);
     let (rx, _) = server.accept().unwrap();
-    let rx: IpcReceiver<Result<QueryReply, QueryError>> = rx;
+    let rx: IpcReceiver<Result<QueryReply, (QueryError, Option<Span>)>> = rx;
 
     match rx.recv() {
         Ok(s) => {
             try!(child.wait_with_output());
-            s.map_err(Error::Rink)
+            s.map_err(|(e, span)| Error::Rink(e, span))
         },
         Err(e) => {
             let output = try!(child.wait_with_output());
@@ -121,16 +156,34 @@ pub fn eval(query: &str) -> Result<QueryReply, Error> {
 }
 
 pub fn eval_text(query: &str) -> String {
-    match eval(query) {
+    match eval(query, &[]) {
         Ok(v) => format!("{}", v),
         Err(Error::Generic(e)) => format!("{}", e),
         Err(Error::Memory) => format!("Calculation ran out of memory"),
         Err(Error::Time) => format!("Calculation timed out"),
-        Err(Error::Rink(e)) => format!("{}", e),
+        Err(Error::Rink(e, _)) => format!("{}", e),
     }
 }
 
-pub fn eval_json(query: &str) -> rustc_serialize::json::Json {
-    let res = eval(query);
-    rustc_serialize::json::Json::from_str(&serde_json::ser::to_string(&res).unwrap()).unwrap()
+/// Evaluates a query for the web page. Errors that are about a part of
+/// the query come with a `highlight` of it, split into the text before,
+/// inside and after it.
+/// Also tells whether the query succeeded.
+pub fn eval_json(query: &str, history: &[String]) -> (rustc_serialize::json::Json, bool) {
+    use rustc_serialize::json::{Json, ToJson};
+    use std::collections::BTreeMap;
+
+    let res = eval(query, history);
+    let ok = res.is_ok();
+    let mut json = Json::from_str(&serde_json::ser::to_string(&res).unwrap()).unwrap();
+    if let Err(Error::Rink(_, Some(span))) = res {
+        let start = span.start.min(query.len());
+        let end = span.end.min(query.len()).max(start);
+        let mut highlight = BTreeMap::new();
+        highlight.insert("before".to_owned(), query[..start].to_json());
+        highlight.insert("text".to_owned(), query[start..end].to_json());
+        highlight.insert("after".to_owned(), query[end..].to_json());
+        json.as_object_mut().unwrap().insert("highlight".to_owned(), highlight.to_json());
+    }
+    (json, ok)
 }